@@ -20,7 +20,7 @@ use hyper::status::{StatusClass, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use rest::Error;
+use rest::{Error, ErrorResponse};
 
 /// Helper function to easily issue a HTTP GET request against a given URL that
 /// returns a JSON object. Handles request building, JSON deserialization and
@@ -41,23 +41,54 @@ where
 /// building, JSON serialization and deserialization, and response code
 /// checking.
 pub fn post<'a, IN>(url: &'a str, input: &IN) -> Result<(), Error>
+where
+	IN: Serialize,
+{
+	post_with_headers(url, input, vec![])
+}
+
+/// Same as post, with additional raw headers set on the request.
+pub fn post_with_headers<'a, IN>(
+	url: &'a str,
+	input: &IN,
+	headers: Vec<(&'static str, String)>,
+) -> Result<(), Error>
 where
 	IN: Serialize,
 {
 	let in_json = serde_json::to_string(input).map_err(|e| {
 		Error::Internal(format!("Could not serialize data to JSON: {}", e))
 	})?;
+	let mut req_headers = hyper::header::Headers::new();
+	for (name, value) in headers {
+		req_headers.set_raw(name, vec![value.into_bytes()]);
+	}
 	let client = hyper::Client::new();
-	let _res = check_error(client.post(url).body(&mut in_json.as_bytes()).send())?;
+	let _res = check_error(
+		client
+			.post(url)
+			.headers(req_headers)
+			.body(&mut in_json.as_bytes())
+			.send(),
+	)?;
 	Ok(())
 }
 
-// convert hyper error and check for non success response codes
+// convert hyper error and check for non success response codes, servers
+// telling why they failed in an ErrorResponse
 fn check_error(res: hyper::Result<Response>) -> Result<Response, Error> {
 	if let Err(e) = res {
 		return Err(Error::Internal(format!("Error during request: {}", e)));
 	}
-	let response = res.unwrap();
+	let mut response = res.unwrap();
+	if !response.status.is_success() {
+		if let Ok(error) = serde_json::from_reader::<_, ErrorResponse>(&mut response) {
+			return Err(Error::Remote {
+				status: response.status.to_u16(),
+				error: error,
+			});
+		}
+	}
 	match response.status.class() {
 		StatusClass::Success => Ok(response),
 		StatusClass::ServerError => Err(Error::Internal(format!("Server error."))),
@@ -25,6 +25,7 @@ use serde_json;
 
 use chain;
 use core::core::Transaction;
+use core::core::hash::Hashed;
 use core::ser;
 use pool;
 use p2p;
@@ -85,6 +86,56 @@ impl Handler for UtxoHandler {
 	}
 }
 
+/// Most blocks the outputs of which can be asked for at once
+const MAX_BLOCKS_PER_REQUEST: u64 = 1000;
+
+// Retrieval of the unspent outputs of a range of blocks, both heights
+// included, so wallets can scan the whole utxo set a page at a time -
+// GET /v1/chain/utxos/byheight?start_height=101&end_height=200
+struct UtxoByHeightHandler {
+	chain: Arc<chain::Chain>,
+}
+
+impl UtxoByHeightHandler {
+	fn get_block_outputs(&self, height: u64) -> Result<BlockOutputs, Error> {
+		let header = self.chain
+			.get_header_by_height(height)
+			.map_err(|_| Error::NotFound)?;
+		let block = self.chain
+			.get_block(&header.hash())
+			.map_err(|_| Error::NotFound)?;
+		Ok(BlockOutputs::from_block(&self.chain, &block))
+	}
+}
+
+impl Handler for UtxoByHeightHandler {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let mut start_height = 1;
+		let mut end_height = 1;
+		if let Ok(params) = req.get_ref::<UrlEncodedQuery>() {
+			if let Some(height) = params.get("start_height").and_then(|h| h.first()) {
+				start_height = str::parse(height).unwrap_or(start_height);
+			}
+			if let Some(height) = params.get("end_height").and_then(|h| h.first()) {
+				end_height = str::parse(height).unwrap_or(end_height);
+			}
+		}
+		if end_height < start_height || end_height - start_height >= MAX_BLOCKS_PER_REQUEST {
+			return Ok(Response::with((status::BadRequest, "")));
+		}
+
+		// blocks past the chain head are just missing
+		let mut blocks: Vec<BlockOutputs> = vec![];
+		for height in start_height..end_height + 1 {
+			match self.get_block_outputs(height) {
+				Ok(block) => blocks.push(block),
+				Err(_) => break,
+			}
+		}
+		json_response(&blocks)
+	}
+}
+
 // Sum tree handler. Retrieve the roots:
 // GET /v1/sumtrees/roots
 //
@@ -214,10 +265,16 @@ where
 #[derive(Serialize, Deserialize)]
 struct TxWrapper {
 	tx_hex: String,
+	#[serde(default)]
+	fluff: bool,
 }
 
 // Push new transactions to our transaction pool, that should broadcast it
-// to the network if valid.
+// to the network if valid. Fluff can be asked for either in the body or
+// in the query string -
+// POST /v1/pool/push?fluff
+// The pool doesn't relay along a stem yet, so every transaction is
+// broadcast right away for now.
 struct PoolPushHandler<T> {
 	tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
 }
@@ -229,6 +286,10 @@ where
 	fn handle(&self, req: &mut Request) -> IronResult<Response> {
 		let wrapper: TxWrapper = serde_json::from_reader(req.body.by_ref())
 			.map_err(|e| IronError::new(e, status::BadRequest))?;
+		let fluff = wrapper.fluff || match req.get_ref::<UrlEncodedQuery>() {
+			Ok(params) => params.contains_key("fluff"),
+			Err(_) => false,
+		};
 
 		let tx_bin = util::from_hex(wrapper.tx_hex).map_err(|_| {
 			Error::Argument(format!("Invalid hex in transaction wrapper."))
@@ -244,9 +305,10 @@ where
 		};
 		info!(
 			LOGGER,
-			"Pushing transaction with {} inputs and {} outputs to pool.",
+			"Pushing transaction with {} inputs and {} outputs to pool (fluff: {}).",
 			tx.inputs.len(),
-			tx.outputs.len()
+			tx.outputs.len(),
+			fluff
 		);
 		self.tx_pool
 			.write()
@@ -288,6 +350,9 @@ pub fn start_rest_apis<T>(
 		let utxo_handler = UtxoHandler {
 			chain: chain.clone(),
 		};
+		let utxo_by_height_handler = UtxoByHeightHandler {
+			chain: chain.clone(),
+		};
 		let chain_tip_handler = ChainHandler {
 			chain: chain.clone(),
 		};
@@ -310,6 +375,7 @@ pub fn start_rest_apis<T>(
 		let router = router!(
 			chain_tip: get "/chain" => chain_tip_handler,
 			chain_utxos: get "/chain/utxos" => utxo_handler,
+			chain_utxos_by_height: get "/chain/utxos/byheight" => utxo_by_height_handler,
 			sumtree_roots: get "/sumtrees/*" => sumtree_handler,
 			pool_info: get "/pool" => pool_info_handler,
 			pool_push: post "/pool/push" => pool_push_handler,
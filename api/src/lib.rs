@@ -24,6 +24,7 @@ extern crate iron;
 extern crate mount;
 #[macro_use]
 extern crate router;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -35,8 +36,10 @@ extern crate urlencoded;
 pub mod client;
 mod handlers;
 mod rest;
+mod tls;
 mod types;
 
 pub use handlers::start_rest_apis;
 pub use types::*;
 pub use rest::*;
+pub use tls::TlsServer;
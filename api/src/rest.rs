@@ -20,7 +20,7 @@
 
 use std::error;
 use std::fmt::{self, Display, Formatter};
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::string::ToString;
 use std::mem;
 
@@ -29,8 +29,10 @@ use iron::{status, Listening};
 use iron::middleware::Handler;
 use router::Router;
 use mount::Mount;
+use serde_json;
 
 use store;
+use tls::TlsServer;
 
 /// Errors that can be returned by an ApiEndpoint implementation.
 #[derive(Debug)]
@@ -38,6 +40,21 @@ pub enum Error {
 	Internal(String),
 	Argument(String),
 	NotFound,
+	/// The server answered with a non success status and an ErrorResponse
+	Remote { status: u16, error: ErrorResponse },
+}
+
+/// Body of the answer of a server that failed to process a request, so its
+/// client can tell what went wrong.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorResponse {
+	/// Stable identifier of the error, for programs to match on
+	pub code: String,
+	/// Description of the error, for humans
+	pub message: String,
+	/// Anything else worth knowing about the error, depending on its code
+	#[serde(default)]
+	pub details: serde_json::Value,
 }
 
 impl Display for Error {
@@ -46,6 +63,10 @@ impl Display for Error {
 			Error::Argument(ref s) => write!(f, "Bad arguments: {}", s),
 			Error::Internal(ref s) => write!(f, "Internal error: {}", s),
 			Error::NotFound => write!(f, "Not found."),
+			Error::Remote {
+				status,
+				ref error,
+			} => write!(f, "Server error {} ({}): {}", status, error.code, error.message),
 		}
 	}
 }
@@ -56,6 +77,7 @@ impl error::Error for Error {
 			Error::Argument(_) => "Bad arguments.",
 			Error::Internal(_) => "Internal error.",
 			Error::NotFound => "Not found.",
+			Error::Remote { .. } => "Server error.",
 		}
 	}
 }
@@ -66,6 +88,9 @@ impl From<Error> for IronError {
 			Error::Argument(_) => IronError::new(e, status::Status::BadRequest),
 			Error::Internal(_) => IronError::new(e, status::Status::InternalServerError),
 			Error::NotFound => IronError::new(e, status::Status::NotFound),
+			Error::Remote { status: code, .. } => {
+				IronError::new(e, status::Status::from_u16(code))
+			}
 		}
 	}
 }
@@ -101,14 +126,32 @@ impl ApiServer {
 
 	/// Starts the ApiServer at the provided address.
 	pub fn start<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), String> {
+		let listening = Iron::new(self.take_mount()).http(addr).map_err(|e| e.to_string())?;
+		self.server_listener = Some(listening);
+		Ok(())
+	}
+
+	/// Starts the ApiServer at the provided address, serving over TLS only.
+	pub fn start_tls<A: ToSocketAddrs>(&mut self, addr: A, tls: TlsServer) -> Result<(), String> {
+		let listening = Iron::new(self.take_mount())
+			.https(addr, tls)
+			.map_err(|e| e.to_string())?;
+		self.server_listener = Some(listening);
+		Ok(())
+	}
+
+	fn take_mount(&mut self) -> Mount {
 		// replace this value to satisfy borrow checker
 		let r = mem::replace(&mut self.router, Router::new());
 		let mut m = mem::replace(&mut self.mount, Mount::new());
 		m.mount("/", r);
-		let result = Iron::new(m).http(addr);
-		let return_value = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
-		self.server_listener = Some(result.unwrap());
-		return_value
+		m
+	}
+
+	/// Address the API server listens on once started, the port picked by the
+	/// system when it was started on port 0
+	pub fn local_addr(&self) -> Option<SocketAddr> {
+		self.server_listener.as_ref().map(|listening| listening.socket)
 	}
 
 	/// Stops the API server
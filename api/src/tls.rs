@@ -0,0 +1,159 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS termination for the API servers, done with rustls. Connections that
+//! don't complete a TLS handshake, plaintext HTTP ones included, are dropped
+//! before any request is read from them.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use rustls::{self, ServerConfig, ServerSession, Session};
+use rustls::internal::pemfile;
+use rustls::sign::RSASigningKey;
+
+/// Wraps the connections accepted by an ApiServer started with start_tls.
+#[derive(Clone)]
+pub struct TlsServer {
+	config: Arc<ServerConfig>,
+}
+
+impl TlsServer {
+	/// Serves the certificate chain of the PEM file at cert_path, with the
+	/// RSA private key of the PEM file at key_path (PKCS#1 or PKCS#8). Files
+	/// that can't be read or don't hold what's expected are errors.
+	pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> Result<TlsServer, String> {
+		let open = |path: &Path| {
+			File::open(path)
+				.map(BufReader::new)
+				.map_err(|e| format!("Can't read {}: {}", path.display(), e))
+		};
+		let certs = pemfile::certs(&mut open(cert_path)?).unwrap_or(vec![]);
+		if certs.is_empty() {
+			return Err(format!("No certificate in {}", cert_path.display()));
+		}
+		let mut keys = pemfile::rsa_private_keys(&mut open(key_path)?).unwrap_or(vec![]);
+		if keys.is_empty() {
+			keys = pemfile::pkcs8_private_keys(&mut open(key_path)?).unwrap_or(vec![]);
+		}
+		let key = match keys.into_iter().next() {
+			Some(key) => key,
+			None => return Err(format!("No private key in {}", key_path.display())),
+		};
+		// set_single_cert panics on a key it can't sign with
+		if RSASigningKey::new(&key).is_err() {
+			return Err(format!("Unsupported private key in {}", key_path.display()));
+		}
+
+		let mut config = ServerConfig::new();
+		config.set_single_cert(certs, key);
+		Ok(TlsServer {
+			config: Arc::new(config),
+		})
+	}
+}
+
+impl SslServer for TlsServer {
+	type Stream = TlsStream;
+
+	fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+		// the handshake happens on the first read, once the server has set the
+		// timeouts of the connection
+		let conn = TlsConnection {
+			session: ServerSession::new(&self.config),
+			stream: stream,
+		};
+		Ok(TlsStream(Arc::new(Mutex::new(conn))))
+	}
+}
+
+struct TlsConnection {
+	session: ServerSession,
+	stream: HttpStream,
+}
+
+/// A TLS connection accepted by the server. Hyper reads requests from and
+/// writes responses to clones of the stream, which share the connection.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<TlsConnection>>);
+
+impl TlsStream {
+	fn conn(&self) -> MutexGuard<TlsConnection> {
+		self.0.lock().unwrap_or_else(|e| e.into_inner())
+	}
+}
+
+impl Read for TlsStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut conn = self.conn();
+		let TlsConnection {
+			ref mut session,
+			ref mut stream,
+		} = *conn;
+		rustls::Stream::new(session, stream).read(buf)
+	}
+}
+
+impl Write for TlsStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut conn = self.conn();
+		let TlsConnection {
+			ref mut session,
+			ref mut stream,
+		} = *conn;
+		rustls::Stream::new(session, stream).write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		let mut conn = self.conn();
+		let TlsConnection {
+			ref mut session,
+			ref mut stream,
+		} = *conn;
+		rustls::Stream::new(session, stream).flush()
+	}
+}
+
+impl NetworkStream for TlsStream {
+	fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+		self.conn().stream.peer_addr()
+	}
+
+	fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+		self.conn().stream.set_read_timeout(dur)
+	}
+
+	fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+		self.conn().stream.set_write_timeout(dur)
+	}
+
+	fn close(&mut self, how: Shutdown) -> io::Result<()> {
+		let mut conn = self.conn();
+		conn.session.send_close_notify();
+		let TlsConnection {
+			ref mut session,
+			ref mut stream,
+		} = *conn;
+		while session.wants_write() {
+			session.write_tls(stream)?;
+		}
+		stream.close(how)
+	}
+}
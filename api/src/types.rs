@@ -203,6 +203,45 @@ impl OutputPrintable {
 	}
 }
 
+/// The outputs of a block that are still unspent, along with what a wallet
+/// needs to recognize its own among them
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockOutputs {
+	/// Height of the block
+	pub height: u64,
+	/// Hash of the block (as hex string)
+	pub hash: String,
+	/// The unspent outputs, in the order of the block
+	pub outputs: Vec<BlockOutput>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockOutput {
+	/// The switch commitment hash, only depending on the key of the output
+	pub switch_commit_hash: core::SwitchCommitHash,
+	pub output: Output,
+}
+
+impl BlockOutputs {
+	/// The outputs of the block still in the utxo set of the chain.
+	pub fn from_block(chain: &chain::Chain, block: &core::Block) -> BlockOutputs {
+		let outputs = block
+			.outputs
+			.iter()
+			.filter(|out| chain.get_unspent(&out.commit).is_ok())
+			.map(|out| BlockOutput {
+				switch_commit_hash: out.switch_commit_hash,
+				output: Output::from_output(out, &block.header),
+			})
+			.collect();
+		BlockOutputs {
+			height: block.header.height,
+			hash: util::to_hex(block.header.hash().to_vec()),
+			outputs: outputs,
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PoolInfo {
 	/// Size of the pool
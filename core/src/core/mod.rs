@@ -230,6 +230,20 @@ mod test {
 		assert_eq!(tx.hash(), dtx.hash());
 	}
 
+	#[test]
+	fn identifier_ser_deser() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+
+		let vec = ser::ser_vec(&key_id).expect("serialization failed");
+		assert_eq!(vec.len(), keychain::IDENTIFIER_SIZE);
+		assert_eq!(&vec[..], key_id.as_ref());
+
+		let dkey_id: keychain::Identifier = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dkey_id, key_id);
+		assert_eq!(ser::ser_vec(&dkey_id).unwrap(), vec);
+	}
+
 	#[test]
 	fn tx_double_ser_deser() {
 		// checks serializing doesn't mess up the tx and produces consistent results
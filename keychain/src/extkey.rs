@@ -31,9 +31,13 @@ pub const IDENTIFIER_SIZE: usize = 10;
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Error {
 	/// The size of the seed is invalid
-	InvalidSeedSize,
-	InvalidSliceSize,
+	InvalidSeedSize { got: usize },
+	/// The serialized extended key is not of the expected size
+	InvalidSliceSize { expected: usize, got: usize },
+	/// The given serialized extended key is invalid
 	InvalidExtendedKey,
+	/// The child key at the given derivation index could not be derived
+	InvalidChildKey { n_child: u32 },
 	Secp(secp::Error),
 	ParseIntError(num::ParseIntError),
 }
@@ -50,24 +54,48 @@ impl From<num::ParseIntError> for Error {
 	}
 }
 
-// Passthrough Debug to Display, since errors should be user-visible
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-		f.write_str(error::Error::description(self))
+		match *self {
+			Error::InvalidSeedSize { got } => write!(
+				f,
+				"keychain: seed isn't of size 128, 256 or 512 bits, got {} bytes",
+				got
+			),
+			Error::InvalidSliceSize { expected, got } => write!(
+				f,
+				"keychain: serialized extended key must be of size {}, got {}",
+				expected,
+				got
+			),
+			Error::InvalidExtendedKey => {
+				write!(f, "keychain: the given serialized extended key is invalid")
+			}
+			Error::InvalidChildKey { n_child } => write!(
+				f,
+				"keychain: could not derive child key at index {}",
+				n_child
+			),
+			Error::Secp(ref e) => write!(f, "keychain: secp error: {:?}", e),
+			Error::ParseIntError(ref e) => write!(f, "keychain: error parsing int: {}", e),
+		}
 	}
 }
 
 impl error::Error for Error {
 	fn cause(&self) -> Option<&error::Error> {
-		None
+		match *self {
+			Error::ParseIntError(ref e) => Some(e),
+			_ => None,
+		}
 	}
 
 	fn description(&self) -> &str {
 		match *self {
-			Error::InvalidSeedSize => "keychain: seed isn't of size 128, 256 or 512",
-			// TODO change when ser. ext. size is fixed
-			Error::InvalidSliceSize => "keychain: serialized extended key must be of size 73",
-			Error::InvalidExtendedKey => "keychain: the given serialized extended key is invalid",
+			Error::InvalidSeedSize { .. } => "keychain: invalid seed size",
+			Error::InvalidSliceSize { .. } => "keychain: invalid serialized extended key size",
+			Error::InvalidExtendedKey => "keychain: invalid serialized extended key",
+			Error::InvalidChildKey { .. } => "keychain: invalid child key",
 			Error::Secp(_) => "keychain: secp error",
 			Error::ParseIntError(_) => "keychain: error parsing int",
 		}
@@ -187,7 +215,10 @@ impl ExtendedKey {
 	pub fn from_slice(secp: &Secp256k1, slice: &[u8]) -> Result<ExtendedKey, Error> {
 		// TODO change when ser. ext. size is fixed
 		if slice.len() != 79 {
-			return Err(Error::InvalidSliceSize);
+			return Err(Error::InvalidSliceSize {
+				expected: 79,
+				got: slice.len(),
+			});
 		}
 		let depth: u8 = slice[0];
 		let root_key_id = Identifier::from_bytes(&slice[1..11]);
@@ -212,16 +243,14 @@ impl ExtendedKey {
 	pub fn from_seed(secp: &Secp256k1, seed: &[u8]) -> Result<ExtendedKey, Error> {
 		match seed.len() {
 			16 | 32 | 64 => (),
-			_ => return Err(Error::InvalidSeedSize),
+			_ => return Err(Error::InvalidSeedSize { got: seed.len() }),
 		}
 
 		let derived = blake2b(64, b"Mimble seed", seed);
 
 		let mut chaincode: [u8; 32] = [0; 32];
 		(&mut chaincode).copy_from_slice(&derived.as_bytes()[32..]);
-		// TODO Error handling
-		let secret_key = SecretKey::from_slice(&secp, &derived.as_bytes()[0..32])
-			.expect("Error generating from seed");
+		let secret_key = SecretKey::from_slice(&secp, &derived.as_bytes()[0..32])?;
 
 		let mut ext_key = ExtendedKey {
 			depth: 0,
@@ -253,11 +282,11 @@ impl ExtendedKey {
 
 		let derived = blake2b(64, &self.chaincode[..], &seed[..]);
 
-		let mut secret_key =
-			SecretKey::from_slice(&secp, &derived.as_bytes()[0..32]).expect("Error deriving key");
+		let mut secret_key = SecretKey::from_slice(&secp, &derived.as_bytes()[0..32])
+			.map_err(|_| Error::InvalidChildKey { n_child: n })?;
 		secret_key
 			.add_assign(secp, &self.key)
-			.expect("Error deriving key");
+			.map_err(|_| Error::InvalidChildKey { n_child: n })?;
 		// TODO check if key != 0 ?
 
 		let mut chain_code: [u8; 32] = [0; 32];
@@ -279,7 +308,7 @@ mod test {
 
 	use util::secp::Secp256k1;
 	use util::secp::key::SecretKey;
-	use super::{ExtendedKey, Identifier};
+	use super::{Error, ExtendedKey, Identifier};
 	use util;
 
 	fn from_hex(hex_str: &str) -> Vec<u8> {
@@ -361,4 +390,34 @@ mod test {
 		assert_eq!(derived.depth, depth);
 		assert_eq!(derived.n_child, n_child);
 	}
+
+	#[test]
+	fn extkey_from_slice_invalid_size() {
+		let s = Secp256k1::new();
+		let err = ExtendedKey::from_slice(&s, &[0; 42]).unwrap_err();
+		assert_eq!(
+			err,
+			Error::InvalidSliceSize {
+				expected: 79,
+				got: 42,
+			}
+		);
+		let msg = format!("{}", err);
+		assert!(msg.contains("79"));
+		assert!(msg.contains("42"));
+	}
+
+	#[test]
+	fn invalid_child_key_display() {
+		let err = Error::InvalidChildKey { n_child: 12345 };
+		assert!(format!("{}", err).contains("12345"));
+	}
+
+	#[test]
+	fn extkey_from_seed_invalid_size() {
+		let s = Secp256k1::new();
+		let err = ExtendedKey::from_seed(&s, &[0; 17]).unwrap_err();
+		assert_eq!(err, Error::InvalidSeedSize { got: 17 });
+		assert!(format!("{}", err).contains("17"));
+	}
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use rand::{thread_rng, Rng};
+use std::{error, fmt};
 use std::collections::HashMap;
 
 use util::secp;
@@ -23,6 +24,9 @@ use blake2;
 use blind::{BlindSum, BlindingFactor};
 use extkey::{self, Identifier};
 
+/// Secret key used for burn outputs. It's public knowledge, so anything sent
+/// to it can be considered burnt (testing only).
+pub const BURN_KEY: [u8; 32] = [1; 32];
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Error {
@@ -43,6 +47,33 @@ impl From<extkey::Error> for Error {
 	}
 }
 
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::ExtendedKey(ref e) => write!(f, "{}", e),
+			Error::Secp(ref e) => write!(f, "keychain: secp error: {:?}", e),
+			Error::KeyDerivation(ref s) => write!(f, "keychain: key derivation failed: {}", s),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::ExtendedKey(ref e) => Some(e),
+			_ => None,
+		}
+	}
+
+	fn description(&self) -> &str {
+		match *self {
+			Error::ExtendedKey(_) => "keychain: extended key error",
+			Error::Secp(_) => "keychain: secp error",
+			Error::KeyDerivation(_) => "keychain: key derivation error",
+		}
+	}
+}
+
 #[derive(Clone, Debug)]
 pub struct Keychain {
 	secp: Secp256k1,
@@ -61,7 +92,7 @@ impl Keychain {
 		let mut key_overrides = HashMap::new();
 		key_overrides.insert(
 			burn_key_id.clone(),
-			SecretKey::from_slice(&keychain.secp, &[1; 32]).unwrap(),
+			SecretKey::from_slice(&keychain.secp, &BURN_KEY).unwrap(),
 		);
 		Keychain {
 			key_overrides: key_overrides,
@@ -194,7 +225,8 @@ impl Keychain {
 
 #[cfg(test)]
 mod test {
-	use keychain::Keychain;
+	use extkey;
+	use keychain::{Error, Keychain};
 	use util::secp;
 	use util::secp::pedersen::ProofMessage;
 
@@ -263,4 +295,14 @@ mod test {
 		assert_eq!(proof_info.success, false);
 		assert_eq!(proof_info.value, 0);
 	}
+
+	#[test]
+	fn test_from_seed_invalid_size() {
+		let err = Keychain::from_seed(&[0; 17]).unwrap_err();
+		assert_eq!(
+			err,
+			Error::ExtendedKey(extkey::Error::InvalidSeedSize { got: 17 })
+		);
+		assert!(format!("{}", err).contains("17"));
+	}
 }
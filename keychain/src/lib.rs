@@ -24,7 +24,7 @@ extern crate serde_derive;
 extern crate serde_json;
 
 mod blind;
-mod extkey;
+pub mod extkey;
 
 pub use blind::{BlindSum, BlindingFactor};
 pub use extkey::{ExtendedKey, Identifier, IDENTIFIER_SIZE};
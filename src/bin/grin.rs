@@ -30,9 +30,11 @@ extern crate grin_keychain as keychain;
 extern crate grin_util as util;
 extern crate grin_wallet as wallet;
 
+use std::process;
 use std::thread;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::fs::File;
+use std::path::Path;
 use std::time::Duration;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
@@ -169,6 +171,16 @@ fn main() {
 			.help("Directory in which to store wallet files (defaults to current \
 			directory)")
 			.takes_value(true))
+		.arg(Arg::with_name("flat_data_dir")
+			.long("flat_data_dir")
+			.help("Keep the wallet files in the data directory itself rather than in a \
+			subdirectory per seed, as wallets did before")
+			.takes_value(false))
+		.arg(Arg::with_name("data_pass")
+			.long("data_pass")
+			.help("Passphrase the wallet data file is encrypted with (not encrypted \
+			without one)")
+			.takes_value(true))
 		.arg(Arg::with_name("port")
 			.short("r")
 			.long("port")
@@ -190,9 +202,16 @@ fn main() {
 				provided, will process it, otherwise runs in server mode waiting \
 				for send requests.")
 			.arg(Arg::with_name("input")
-				.help("Partial transaction to receive, expects as a JSON file.")
+				.help("Partial transaction to receive, expects a file with its JSON or \
+					its compact encoding.")
 				.short("i")
 				.long("input")
+				.takes_value(true))
+			.arg(Arg::with_name("output")
+				.help("Write the completed transaction to this file for the sender to \
+					finalize, rather than pushing it to the node.")
+				.short("o")
+				.long("output")
 				.takes_value(true)))
 
 		.subcommand(SubCommand::with_name("send")
@@ -201,19 +220,86 @@ fn main() {
 				provided, the command will attempt to contact the receiver at that \
 				address and send the transaction directly.")
 			.arg(Arg::with_name("amount")
-				.help("Amount to send in the smallest denomination")
+				.help("Amount to send in the smallest denomination, or \"max\" to send \
+					everything that's spendable")
 				.index(1))
 			.arg(Arg::with_name("minimum_confirmations")
 				.help("Minimum number of confirmations required for an output to be spendable.")
 				.short("c")
 				.long("min_conf")
-				.default_value("1")
 				.takes_value(true))
 			.arg(Arg::with_name("dest")
-				.help("Send the transaction to the provided server")
+				.help("Send the transaction to the provided server, write it to \
+					the provided file (file:/path/to/tx.json), or send it to a fresh key \
+					of this wallet (self)")
 				.short("d")
 				.long("dest")
-				.takes_value(true)))
+				.takes_value(true))
+			.arg(Arg::with_name("dest_auth")
+				.help("Secret required by the receiver to accept the transaction, can also \
+					follow its URL (https://host:port#secret)")
+				.long("dest_auth")
+				.takes_value(true))
+			.arg(Arg::with_name("compact")
+				.help("Print the transaction to stdout as a single line (grintx1...) that \
+					survives being pasted around, instead of its JSON")
+				.long("compact")
+				.conflicts_with("dest")
+				.takes_value(false))
+			.arg(Arg::with_name("overwrite")
+				.help("Overwrite the destination file if it already exists")
+				.long("overwrite")
+				.takes_value(false))
+			.arg(Arg::with_name("selection")
+				.help("Coin selection strategy")
+				.short("s")
+				.long("selection")
+				.possible_values(&["default", "smallest", "largest", "all"])
+				.default_value("default")
+				.takes_value(true))
+			.arg(Arg::with_name("fee")
+				.help("Fee to pay instead of the computed one, at least the minimum fee")
+				.short("f")
+				.long("fee")
+				.takes_value(true))
+			.arg(Arg::with_name("fee_paid_by")
+				.help("Who pays the fee, the recipient receiving the amount minus the fee or \
+					the sender spending the amount plus the fee")
+				.long("fee_paid_by")
+				.possible_values(&["recipient", "sender"])
+				.default_value("recipient")
+				.takes_value(true))
+			.arg(Arg::with_name("lock_height")
+				.help("Height before which the transaction can't be mined (no lock by default)")
+				.long("lock_height")
+				.takes_value(true))
+			.arg(Arg::with_name("change_outputs")
+				.help("Number of outputs to split the change across")
+				.long("change_outputs")
+				.takes_value(true))
+			.arg(Arg::with_name("message")
+				.help("Message for the recipient, sent along with the transaction")
+				.short("m")
+				.long("message")
+				.takes_value(true))
+			.arg(Arg::with_name("dry_run")
+				.help("Only show the outputs that would be spent, the fee and the change")
+				.long("dry_run")
+				.takes_value(false))
+			.arg(Arg::with_name("fluff")
+				.help("Ask the node to broadcast the transaction right away rather than \
+					relaying it along a stem first, the receiver pushing it included")
+				.long("fluff")
+				.takes_value(false))
+			.arg(Arg::with_name("auto_split")
+				.help("When the send needs too many inputs, first merge coins in \
+					transactions to self")
+				.long("auto_split")
+				.takes_value(false))
+			.arg(Arg::with_name("allow_self_send")
+				.help("Allow sending to this wallet's own receiver, to test loopback flows")
+				.long("allow_self_send")
+				.takes_value(false)))
 
 		.subcommand(SubCommand::with_name("burn")
 			.about("** TESTING ONLY ** Burns the provided amount to a known \
@@ -226,11 +312,204 @@ fn main() {
 				.help("Minimum number of confirmations required for an output to be spendable.")
 				.short("c")
 				.long("min_conf")
+				.takes_value(true))
+			.arg(Arg::with_name("fluff")
+				.help("Ask the node to broadcast the transaction right away rather than \
+					relaying it along a stem first")
+				.long("fluff")
+				.takes_value(false)))
+
+		.subcommand(SubCommand::with_name("consolidate")
+			.about("Merges the smallest spendable outputs of the wallet into fewer ones, \
+				through a transaction to self pushed to the node.")
+			.arg(Arg::with_name("max_inputs")
+				.help("Most outputs merged, max_inputs_per_tx of the config by default")
+				.long("max_inputs")
+				.takes_value(true))
+			.arg(Arg::with_name("outputs")
+				.help("Number of outputs they're merged into")
+				.long("outputs")
 				.default_value("1")
 				.takes_value(true)))
 
+		.subcommand(SubCommand::with_name("invoice")
+			.about("Issues an invoice for an amount, which the payer's wallet pays by \
+				getting the transaction signed by our receiver.")
+			.arg(Arg::with_name("amount")
+				.help("Amount to invoice, in base unit")
+				.index(1))
+			.arg(Arg::with_name("dest")
+				.help("Url the payer reaches our receiver at")
+				.short("d")
+				.long("dest")
+				.takes_value(true))
+			.arg(Arg::with_name("expiry")
+				.help("Number of blocks the invoice can be paid for")
+				.long("expiry")
+				.default_value("1440")
+				.takes_value(true))
+			.arg(Arg::with_name("output")
+				.help("File the invoice is written to")
+				.short("o")
+				.long("output")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("pay_invoice")
+			.about("Pays an invoice issued by a receiver and pushes the transaction to \
+				the node.")
+			.arg(Arg::with_name("input")
+				.help("Invoice to pay, expects a JSON file.")
+				.short("i")
+				.long("input")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("finalize")
+			.about("Pushes the completed transaction handed back by a receiver \
+				in a file to the node, after checking it matches one of our sends.")
+			.arg(Arg::with_name("input")
+				.help("Completed transaction, expects as a JSON file.")
+				.short("i")
+				.long("input")
+				.takes_value(true))
+			.arg(Arg::with_name("fluff")
+				.help("Ask the node to broadcast the transaction right away rather than \
+					relaying it along a stem first")
+				.long("fluff")
+				.takes_value(false)))
+
+		.subcommand(SubCommand::with_name("cancel")
+			.about("Cancels a send that never made it on chain, making its \
+				inputs spendable again.")
+			.arg(Arg::with_name("id")
+				.help("Id of the send in the transaction log, or identifier of its \
+					change output")
+				.index(1)))
+
+		.subcommand(SubCommand::with_name("label")
+			.about("Tags an output with a label, to tell it apart later. Labels are \
+				only shown, they never change which outputs get spent.")
+			.arg(Arg::with_name("id")
+				.help("Identifier of the output")
+				.index(1))
+			.arg(Arg::with_name("label")
+				.help("Label of the output, its label is removed when left out")
+				.index(2)))
+
+		.subcommand(SubCommand::with_name("export_output")
+			.about("Writes an output to a file along with its commitment, for another \
+				wallet to import it.")
+			.arg(Arg::with_name("id")
+				.help("Identifier of the output")
+				.index(1))
+			.arg(Arg::with_name("output")
+				.help("File the output is written to")
+				.short("o")
+				.long("output")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("import_output")
+			.about("Tracks an output exported by another wallet. Outputs of another \
+				seed are only watched, they're never spent.")
+			.arg(Arg::with_name("input")
+				.help("File of the exported output")
+				.index(1)))
+
+		.subcommand(SubCommand::with_name("resend")
+			.about("Posts a pending send again to the receiver it was sent to, when it \
+				couldn't be reached the first time.")
+			.arg(Arg::with_name("id")
+				.help("Id of the send in the transaction log")
+				.index(1))
+			.arg(Arg::with_name("dest_auth")
+				.help("Secret required by the receiver to accept the transaction")
+				.long("dest_auth")
+				.takes_value(true)))
+
 		.subcommand(SubCommand::with_name("info")
-			.about("basic wallet info (outputs)"))
+			.about("basic wallet info (outputs)")
+			.arg(Arg::with_name("include_archive")
+				.help("Also list the outputs moved to the archive by prune")
+				.long("include_archive")
+				.takes_value(false)))
+
+		.subcommand(SubCommand::with_name("prune")
+			.about("Moves the outputs spent before a height, and the log entries of \
+				their transactions, from the wallet data file to its archive.")
+			.arg(Arg::with_name("before_height")
+				.help("Height the outputs archived were confirmed before")
+				.index(1)))
+
+		.subcommand(SubCommand::with_name("export_log")
+			.about("Writes the log of the sends made by the wallet, or of the payments \
+				it received, to a file, for audits.")
+			.arg(Arg::with_name("receives")
+				.help("Export the payments received rather than the sends")
+				.long("receives")
+				.takes_value(false))
+			.arg(Arg::with_name("history")
+				.help("Export the sends and the payments received together")
+				.long("history")
+				.conflicts_with("receives")
+				.takes_value(false))
+			.arg(Arg::with_name("output")
+				.help("File the log is written to")
+				.short("o")
+				.long("output")
+				.takes_value(true))
+			.arg(Arg::with_name("format")
+				.help("Format of the file, csv or json")
+				.long("format")
+				.possible_values(&["csv", "json"])
+				.default_value("csv")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("export_outputs")
+			.about("Writes the outputs of the wallet to a file, for accounting.")
+			.arg(Arg::with_name("output")
+				.help("File the outputs are written to")
+				.short("o")
+				.long("output")
+				.takes_value(true))
+			.arg(Arg::with_name("status")
+				.help("Only export the outputs in that status")
+				.long("status")
+				.possible_values(&["Pending", "Unconfirmed", "Unspent", "Locked", "Spent"])
+				.takes_value(true))
+			.arg(Arg::with_name("label")
+				.help("Only export the outputs whose label holds that text")
+				.long("label")
+				.takes_value(true))
+			.arg(Arg::with_name("format")
+				.help("Format of the file, csv or json")
+				.long("format")
+				.possible_values(&["csv", "json"])
+				.default_value("csv")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("backup")
+			.about("Copies the wallet data file to a new backup.")
+			.arg(Arg::with_name("dir")
+				.help("Directory the backup goes to, backups in the data directory by default")
+				.long("dir")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("backups")
+			.about("Lists the backups of the wallet data file, oldest first.")
+			.arg(Arg::with_name("dir")
+				.help("Directory of the backups, backups in the data directory by default")
+				.long("dir")
+				.takes_value(true)))
+
+		.subcommand(SubCommand::with_name("restore_backup")
+			.about("Replaces the wallet data file with a backup, the data file replaced \
+				being backed up first.")
+			.arg(Arg::with_name("input")
+				.help("Backup to restore")
+				.index(1)))
+
+		.subcommand(SubCommand::with_name("restore")
+			.about("Restores the outputs of the wallet from its seed, scanning the chain \
+				of the node for them. Can be interrupted and run again."))
 
 		.subcommand(SubCommand::with_name("init")
 			.about("Initialize a new wallet seed file.")))
@@ -358,6 +637,12 @@ fn wallet_command(wallet_args: &ArgMatches) {
 		wallet_config.check_node_api_http_addr = sa.to_string().clone();
 	}
 
+	if wallet_args.is_present("flat_data_dir") {
+		wallet_config.flat_data_dir = true;
+	}
+	wallet::set_data_lock_max_age(wallet_config.data_lock_max_age_secs);
+	wallet::set_data_passphrase(wallet_args.value_of("data_pass"));
+
 	// Derive the keychain based on seed from seed file and specified passphrase.
 	// Generate the initial wallet seed if we are running "wallet init".
 	if let ("init", Some(_)) = wallet_args.subcommand() {
@@ -376,38 +661,149 @@ fn wallet_command(wallet_args: &ArgMatches) {
 		.derive_keychain(&passphrase)
 		.expect("Failed to derive keychain from seed file and passphrase.");
 
+	// The seed file stays in the data directory, the other wallet files go in
+	// the subdirectory of the seed, moved there if they're still in the data
+	// directory.
+	let mut wallet_config = wallet_config
+		.for_keychain(&keychain)
+		.expect("Failed to open the wallet directory of the seed.");
+
 	match wallet_args.subcommand() {
-		("receive", Some(receive_args)) => if let Some(f) = receive_args.value_of("input") {
+		("receive", Some(receive_args)) => if let (Some(f), Some(out)) =
+			(receive_args.value_of("input"), receive_args.value_of("output"))
+		{
+			let (input, output) = (Path::new(f), Path::new(out));
+			if let Err(e) = wallet::receive_from_file(&wallet_config, &keychain, input, output) {
+				error!(LOGGER, "Could not receive {}: {}", f, e);
+				process::exit(1);
+			}
+			info!(LOGGER, "Completed transaction written to {}", out);
+		} else if let Some(f) = receive_args.value_of("input") {
 			let mut file = File::open(f).expect("Unable to open transaction file.");
 			let mut contents = String::new();
 			file.read_to_string(&mut contents)
 				.expect("Unable to read transaction file.");
 			wallet::receive_json_tx_str(&wallet_config, &keychain, contents.as_str()).unwrap();
-		} else {
-			wallet::server::start_rest_apis(wallet_config, keychain);
+		} else if let Err(e) = wallet::server::start_rest_apis(wallet_config, keychain) {
+			error!(LOGGER, "Could not start the wallet receiver: {}", e);
+			process::exit(1);
 		},
 		("send", Some(send_args)) => {
-			let amount = send_args
-				.value_of("amount")
-				.expect("Amount to send required")
-				.parse()
-				.expect("Could not parse amount as a whole number.");
+			let amount = match send_args.value_of("amount").expect("Amount to send required") {
+				"max" => wallet::SendAmount::Max,
+				amount => wallet::SendAmount::Exact(
+					amount
+						.parse()
+						.expect("Could not parse amount as a whole number."),
+				),
+			};
 			let minimum_confirmations: u64 = send_args
 				.value_of("minimum_confirmations")
-				.unwrap_or("1")
-				.parse()
-				.expect("Could not parse minimum_confirmations as a whole number.");
-			let mut dest = "stdout";
+				.map(|c| {
+					c.parse()
+						.expect("Could not parse minimum_confirmations as a whole number.")
+				})
+				.unwrap_or(wallet_config.minimum_confirmations);
+			let mut dest = if send_args.is_present("compact") {
+				"stdout:compact".to_string()
+			} else {
+				"stdout".to_string()
+			};
 			if let Some(d) = send_args.value_of("dest") {
-				dest = d;
+				dest = d.to_string();
+			}
+			if let Some(secret) = send_args.value_of("dest_auth") {
+				// contacts are receivers too, reached at their url
+				let http = ["http://", "https://", "@"].iter().any(|p| dest.starts_with(p));
+				if !http {
+					error!(LOGGER, "--dest_auth only applies to http(s) destinations");
+					process::exit(1);
+				}
+				dest = format!("{}#{}", dest, secret);
+			}
+			let selection_strategy = match send_args.value_of("selection") {
+				Some("smallest") => wallet::SelectionStrategy::SmallestFirst,
+				Some("largest") => wallet::SelectionStrategy::LargestFirst,
+				Some("all") => wallet::SelectionStrategy::All,
+				_ => wallet::SelectionStrategy::Default,
+			};
+			let fee = send_args.value_of("fee").map(|f| {
+				f.parse()
+					.expect("Could not parse fee as a whole number.")
+			});
+			let fee_paid_by = match send_args.value_of("fee_paid_by") {
+				Some("sender") => wallet::FeePaidBy::Sender,
+				_ => wallet::FeePaidBy::Recipient,
+			};
+			let lock_height = send_args.value_of("lock_height").map(|h| {
+				h.parse()
+					.expect("Could not parse lock_height as a whole number.")
+			});
+			if send_args.is_present("fluff") {
+				wallet_config.fluff = true;
+			}
+			if send_args.is_present("allow_self_send") {
+				wallet_config.allow_self_send = true;
+			}
+			if send_args.is_present("auto_split") {
+				wallet_config.auto_split = true;
+			}
+			if let Some(n) = send_args.value_of("change_outputs") {
+				wallet_config.change_outputs = n.parse()
+					.expect("Could not parse change_outputs as a whole number.");
+			}
+			if send_args.is_present("dry_run") {
+				let estimate = wallet::preview_send_tx(
+					&wallet_config,
+					&keychain,
+					amount,
+					minimum_confirmations,
+					selection_strategy,
+					fee,
+					fee_paid_by,
+					lock_height,
+				).unwrap();
+				println!("{}", serde_json::to_string_pretty(&estimate).unwrap());
+				return;
 			}
-			wallet::issue_send_tx(
+			let result = wallet::issue_send_tx(
 				&wallet_config,
 				&keychain,
 				amount,
 				minimum_confirmations,
-				dest.to_string(),
+				dest,
+				send_args.is_present("overwrite"),
+				selection_strategy,
+				fee,
+				fee_paid_by,
+				lock_height,
+				send_args.value_of("message").map(|m| m.to_string()),
 			).unwrap();
+			if result.unconfirmed_inputs > 0 {
+				warn!(
+					LOGGER,
+					"Send {} spends {} unconfirmed outputs, it only goes through once \
+					 they're confirmed",
+					result.tx_id,
+					result.unconfirmed_inputs
+				);
+			}
+			if !result.consolidation_tx_ids.is_empty() {
+				info!(
+					LOGGER,
+					"Coins merged first in transactions {:?}",
+					result.consolidation_tx_ids
+				);
+			}
+			info!(
+				LOGGER,
+				"Send {} of {} grin (fee {}) delivered to {}, spending {} outputs",
+				result.tx_id,
+				wallet::format_amount(result.amount),
+				wallet::format_amount(result.fee),
+				result.dest,
+				result.input_identifiers.len()
+			);
 		}
 		("burn", Some(send_args)) => {
 			let amount = send_args
@@ -417,14 +813,253 @@ fn wallet_command(wallet_args: &ArgMatches) {
 				.expect("Could not parse amount as a whole number.");
 			let minimum_confirmations: u64 = send_args
 				.value_of("minimum_confirmations")
-				.unwrap_or("1")
+				.map(|c| {
+					c.parse()
+						.expect("Could not parse minimum_confirmations as a whole number.")
+				})
+				.unwrap_or(wallet_config.minimum_confirmations);
+			let tx_hex = wallet::issue_burn_tx(
+				&wallet_config,
+				&keychain,
+				amount,
+				minimum_confirmations,
+				send_args.is_present("fluff") || wallet_config.fluff,
+			).unwrap();
+			info!(LOGGER, "Burn transaction pushed: {}", tx_hex);
+		}
+		("consolidate", Some(consolidate_args)) => {
+			let max_inputs = consolidate_args
+				.value_of("max_inputs")
+				.map(|n| {
+					n.parse()
+						.expect("Could not parse max_inputs as a whole number.")
+				})
+				.unwrap_or(wallet_config.max_inputs_per_tx);
+			let outputs = consolidate_args
+				.value_of("outputs")
+				.unwrap()
+				.parse()
+				.expect("Could not parse outputs as a whole number.");
+			let result =
+				wallet::consolidate(&wallet_config, &keychain, max_inputs, outputs).unwrap();
+			info!(
+				LOGGER,
+				"Consolidated {} outputs into {} in transaction {}, paying a fee of {}",
+				result.input_identifiers.len(),
+				result.change_identifiers.len(),
+				result.tx_id,
+				wallet::format_amount(result.fee)
+			);
+		}
+		("invoice", Some(invoice_args)) => {
+			let amount = invoice_args
+				.value_of("amount")
+				.expect("Amount to invoice required")
+				.parse()
+				.expect("Could not parse amount as a whole number.");
+			let expiry = invoice_args
+				.value_of("expiry")
+				.unwrap()
 				.parse()
-				.expect("Could not parse minimum_confirmations as a whole number.");
-			wallet::issue_burn_tx(&wallet_config, &keychain, amount, minimum_confirmations)
+				.expect("Could not parse expiry as a whole number.");
+			let dest = invoice_args.value_of("dest").expect("Receiver url required");
+			let output = invoice_args.value_of("output").expect("Output file required");
+			let invoice =
+				wallet::issue_invoice(&wallet_config, &keychain, amount, expiry, dest).unwrap();
+			let mut file = File::create(output).expect("Unable to create invoice file.");
+			file.write_all(serde_json::to_string(&invoice).unwrap().as_bytes())
+				.expect("Unable to write invoice file.");
+			info!(
+				LOGGER,
+				"Invoice written to {}, payable until height {}",
+				output,
+				invoice.expiry_height
+			);
+		}
+		("pay_invoice", Some(pay_args)) => {
+			let input = pay_args.value_of("input").expect("Invoice file required");
+			let mut file = File::open(input).expect("Unable to open invoice file.");
+			let mut contents = String::new();
+			file.read_to_string(&mut contents)
+				.expect("Unable to read invoice file.");
+			let result = wallet::pay_invoice(&wallet_config, &keychain, &contents).unwrap();
+			info!(
+				LOGGER,
+				"Invoice paid by transaction {}, {} spent including a fee of {}",
+				result.tx_id,
+				result.amount,
+				result.fee
+			);
+		}
+		("finalize", Some(finalize_args)) => {
+			let input = finalize_args
+				.value_of("input")
+				.expect("Completed transaction file required");
+			wallet::finalize_from_file(
+				&wallet_config,
+				&keychain,
+				Path::new(input),
+				finalize_args.is_present("fluff") || wallet_config.fluff,
+			).unwrap();
+		}
+		("cancel", Some(cancel_args)) => {
+			let id = cancel_args.value_of("id").expect("Send to cancel required");
+			wallet::cancel_send(&wallet_config, &keychain, id).unwrap();
+		}
+		("label", Some(label_args)) => {
+			let id = label_args.value_of("id").expect("Output to label required");
+			let label = label_args.value_of("label").map(|label| label.to_string());
+			let res = wallet::WalletData::with_wallet(&wallet_config.data_file_dir, |wallet_data| {
+				let key_id = match wallet_data.outputs.get(id) {
+					Some(out) => out.key_id.clone(),
+					None => {
+						let msg = format!("No output {} in the wallet", id);
+						return Err(wallet::Error::WalletData(msg));
+					}
+				};
+				wallet_data.set_label(&key_id, label)
+			});
+			res.and_then(|labelled| labelled).unwrap();
+		}
+		("export_output", Some(export_args)) => {
+			let id = export_args.value_of("id").expect("Output to export required");
+			let output = export_args.value_of("output").expect("Output file required");
+			let res = wallet::WalletData::read_wallet(&wallet_config.data_file_dir, |wallet_data| {
+				let key_id = match wallet_data.outputs.get(id) {
+					Some(out) => out.key_id.clone(),
+					None => {
+						let msg = format!("No output {} in the wallet", id);
+						return Err(wallet::Error::WalletData(msg));
+					}
+				};
+				wallet_data.export_output(&keychain, &key_id)
+			});
+			let export = res.and_then(|export| export).unwrap();
+			File::create(output)
+				.unwrap()
+				.write_all(serde_json::to_string_pretty(&export).unwrap().as_bytes())
 				.unwrap();
+			info!(LOGGER, "Output {} written to {}", id, output);
+		}
+		("import_output", Some(import_args)) => {
+			let input = import_args.value_of("input").expect("File of the output required");
+			let mut json = String::new();
+			File::open(input)
+				.expect("Could not open the file of the output.")
+				.read_to_string(&mut json)
+				.expect("Could not read the file of the output.");
+			let export: wallet::OutputExport =
+				serde_json::from_str(&json).expect("Could not parse the exported output.");
+			let res = wallet::WalletData::with_wallet(&wallet_config.data_file_dir, |wallet_data| {
+				wallet_data.import_output(&keychain, export)
+			});
+			let out = res.and_then(|imported| imported).unwrap();
+			if out.is_watch_only() {
+				info!(LOGGER, "Output {} of {} imported, watch-only", out.key_id, out.root_key_id);
+			} else {
+				info!(LOGGER, "Output {} imported", out.key_id);
+			}
+		}
+		("resend", Some(resend_args)) => {
+			let id = resend_args
+				.value_of("id")
+				.expect("Send to resend required")
+				.parse()
+				.expect("Could not parse the send id as a whole number.");
+			let result =
+				wallet::resend(&wallet_config, &keychain, id, resend_args.value_of("dest_auth"))
+					.unwrap();
+			info!(LOGGER, "Send {} resent to {}", result.tx_id, result.dest);
+		}
+		("info", Some(info_args)) => {
+			wallet::show_info(&wallet_config, &keychain, info_args.is_present("include_archive"));
 		}
-		("info", Some(_)) => {
-			wallet::show_info(&wallet_config, &keychain);
+		("prune", Some(prune_args)) => {
+			let before_height = prune_args
+				.value_of("before_height")
+				.expect("Height to prune before required")
+				.parse()
+				.expect("Could not parse before_height as a whole number.");
+			let summary =
+				wallet::WalletData::prune(&wallet_config.data_file_dir, before_height).unwrap();
+			info!(
+				LOGGER,
+				"Archived {} outputs, {} sends and {} receives",
+				summary.outputs,
+				summary.tx_log_entries,
+				summary.rx_log_entries
+			);
+		}
+		("export_log", Some(export_args)) => {
+			let output = export_args.value_of("output").expect("Output file required");
+			let format = match export_args.value_of("format") {
+				Some("json") => wallet::ExportFormat::Json,
+				_ => wallet::ExportFormat::Csv,
+			};
+			if export_args.is_present("history") {
+				wallet::export_history(&wallet_config, Path::new(output), format).unwrap();
+				info!(LOGGER, "History written to {}", output);
+			} else if export_args.is_present("receives") {
+				wallet::export_rx_log(&wallet_config, Path::new(output), format).unwrap();
+				info!(LOGGER, "Receive log written to {}", output);
+			} else {
+				wallet::export_tx_log(&wallet_config, Path::new(output), format).unwrap();
+				info!(LOGGER, "Transaction log written to {}", output);
+			}
+		}
+		("export_outputs", Some(export_args)) => {
+			let output = export_args.value_of("output").expect("Output file required");
+			let format = match export_args.value_of("format") {
+				Some("json") => wallet::ExportFormat::Json,
+				_ => wallet::ExportFormat::Csv,
+			};
+			let status = export_args.value_of("status").map(|status| match status {
+				"Pending" => wallet::OutputStatus::Pending,
+				"Unconfirmed" => wallet::OutputStatus::Unconfirmed,
+				"Unspent" => wallet::OutputStatus::Unspent,
+				"Locked" => wallet::OutputStatus::Locked,
+				_ => wallet::OutputStatus::Spent,
+			});
+			let filter = wallet::OutputFilter {
+				status: status,
+				label: export_args.value_of("label").map(|label| label.to_string()),
+				..wallet::OutputFilter::default()
+			};
+			wallet::export_outputs(&wallet_config, Path::new(output), &filter, format).unwrap();
+			info!(LOGGER, "Outputs written to {}", output);
+		}
+		("restore", Some(_)) => {
+			let summary = wallet::restore(&wallet_config, &keychain).unwrap();
+			info!(
+				LOGGER,
+				"Restored {} outputs worth {} grin, scanned up to height {}, next child index {}",
+				summary.outputs,
+				wallet::format_amount(summary.value),
+				summary.height,
+				summary.next_child
+			);
+		}
+		("backup", Some(backup_args)) => {
+			let backup =
+				wallet::WalletData::backup(&wallet_config, backup_args.value_of("dir")).unwrap();
+			println!("{}", backup.path.display());
+		}
+		("backups", Some(backups_args)) => {
+			let backups =
+				wallet::WalletData::list_backups(&wallet_config, backups_args.value_of("dir"))
+					.unwrap();
+			for backup in backups {
+				println!("{} {}", backup.created_at, backup.path.display());
+			}
+		}
+		("restore_backup", Some(restore_args)) => {
+			let input = restore_args.value_of("input").expect("Backup to restore required");
+			let replaced =
+				wallet::WalletData::restore_backup(&wallet_config, &keychain, Path::new(input))
+					.unwrap();
+			if let Some(replaced) = replaced {
+				info!(LOGGER, "Replaced wallet data backed up to {}", replaced.path.display());
+			}
 		}
 		_ => panic!("Unknown wallet command, use 'grin help wallet' for details"),
 	}
@@ -0,0 +1,159 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between amounts in nanogrin, the unit the wallet counts
+//! everything in, and the decimal strings humans type and read. Everything
+//! is done on integers, floats can't represent most decimal amounts exactly.
+
+use core::consensus::GRIN_BASE;
+use types::Error;
+
+/// Number of decimals of an amount in grin (GRIN_BASE is 10^9 nanogrin)
+const GRIN_DECIMALS: u32 = 9;
+
+/// Units an amount can be suffixed with, along with the number of decimals
+/// each allows
+const UNITS: [(&'static str, u32); 3] = [("grin", GRIN_DECIMALS), ("mg", 6), ("ng", 0)];
+
+/// Parses an amount such as "1.25", "1.25 grin", "250mg" or "100 ng" into
+/// nanogrin. Without a unit the amount is in grin. Amounts more precise than
+/// what their unit allows, or that don't fit in nanogrin, are refused.
+pub fn parse_amount(amount: &str) -> Result<u64, Error> {
+	let invalid = |reason: &str| Error::InvalidAmount(format!("{:?} {}", amount, reason));
+
+	let mut number = amount.trim();
+	let mut decimals = GRIN_DECIMALS;
+	for &(unit, unit_decimals) in UNITS.iter() {
+		if number.ends_with(unit) {
+			number = number[..number.len() - unit.len()].trim_right();
+			decimals = unit_decimals;
+			break;
+		}
+	}
+
+	let (whole, fraction) = match number.find('.') {
+		Some(i) => (&number[..i], &number[i + 1..]),
+		None => (number, ""),
+	};
+	let all_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b >= b'0' && b <= b'9');
+	if !all_digits(whole) || (number.contains('.') && !all_digits(fraction)) {
+		return Err(invalid("is not a decimal number"));
+	}
+	if fraction.len() as u32 > decimals {
+		return Err(invalid(&format!("has more than {} decimals", decimals)));
+	}
+
+	let mut value = 0u64;
+	for b in whole.bytes().chain(fraction.bytes()) {
+		value = value
+			.checked_mul(10)
+			.and_then(|v| v.checked_add((b - b'0') as u64))
+			.ok_or_else(|| invalid("is too large"))?;
+	}
+	value
+		.checked_mul(10u64.pow(decimals - fraction.len() as u32))
+		.ok_or_else(|| invalid("is too large"))
+}
+
+/// Formats an amount in nanogrin as grin, with as many decimals as needed
+/// and no more ("1.25", "3", "0.000000001").
+pub fn format_amount(amount: u64) -> String {
+	let whole = amount / GRIN_BASE;
+	let fraction = amount % GRIN_BASE;
+	if fraction == 0 {
+		return whole.to_string();
+	}
+	// as many digits as GRIN_DECIMALS
+	let fraction = format!("{:09}", fraction);
+	format!("{}.{}", whole, fraction.trim_right_matches('0'))
+}
+
+#[cfg(test)]
+mod test {
+	use std::u64;
+	use super::*;
+
+	fn invalid(amount: &str) {
+		match parse_amount(amount) {
+			Err(Error::InvalidAmount(_)) => {}
+			res => panic!("{:?} should be invalid, got {:?}", amount, res),
+		}
+	}
+
+	#[test]
+	fn parse_amounts() {
+		assert_eq!(parse_amount("1").unwrap(), GRIN_BASE);
+		assert_eq!(parse_amount("1.25").unwrap(), 1_250_000_000);
+		assert_eq!(parse_amount(" 1.25 grin ").unwrap(), 1_250_000_000);
+		assert_eq!(parse_amount("1.25mg").unwrap(), 1_250_000);
+		assert_eq!(parse_amount("0.000001 mg").unwrap(), 1);
+		assert_eq!(parse_amount("100ng").unwrap(), 100);
+		assert_eq!(parse_amount("0").unwrap(), 0);
+		assert_eq!(parse_amount("007.10").unwrap(), 7_100_000_000);
+
+		// the classic float rounding traps come out exact
+		assert_eq!(parse_amount("0.3").unwrap(), 300_000_000);
+		assert_eq!(parse_amount("2.675").unwrap(), 2_675_000_000);
+		assert_eq!(parse_amount("0.000000001").unwrap(), 1);
+		assert_eq!(parse_amount("0.999999999").unwrap(), GRIN_BASE - 1);
+
+		for amount in vec!["", " ", "grin", ".", "1.", ".5", "-1", "+1", "1e9", "1,5", "1.2.3",
+		                   "1 5", "1.25 btc", "٣"]
+		{
+			invalid(amount);
+		}
+	}
+
+	#[test]
+	fn parse_too_precise() {
+		invalid("0.0000000001");
+		invalid("1.0000000000");
+		invalid("0.0000001mg");
+		invalid("1.5ng");
+		invalid("1.0ng");
+	}
+
+	#[test]
+	fn parse_overflow() {
+		assert_eq!(parse_amount("18446744073.709551615").unwrap(), u64::MAX);
+		assert_eq!(parse_amount("18446744073709551615ng").unwrap(), u64::MAX);
+		assert_eq!(parse_amount("18446744073709.551615mg").unwrap(), u64::MAX);
+		invalid("18446744073.709551616");
+		invalid("18446744074");
+		invalid("18446744073709551616ng");
+		invalid("99999999999999999999999999999");
+	}
+
+	#[test]
+	fn format_amounts() {
+		assert_eq!(format_amount(0), "0");
+		assert_eq!(format_amount(1), "0.000000001");
+		assert_eq!(format_amount(1_250_000_000), "1.25");
+		assert_eq!(format_amount(2 * GRIN_BASE), "2");
+		assert_eq!(format_amount(GRIN_BASE + 10), "1.00000001");
+		assert_eq!(format_amount(u64::MAX), "18446744073.709551615");
+	}
+
+	#[test]
+	fn round_trip() {
+		let values = vec![0, 1, 9, 10, 999_999_999, GRIN_BASE, 1_250_000_000, 60 * GRIN_BASE + 7,
+		                  u64::MAX - 1, u64::MAX];
+		for value in values {
+			assert_eq!(parse_amount(&format_amount(value)).unwrap(), value);
+		}
+		for amount in vec!["0", "1", "1.25", "0.000000001", "12345.6789"] {
+			assert_eq!(format_amount(parse_amount(amount).unwrap()), amount);
+		}
+	}
+}
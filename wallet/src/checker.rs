@@ -15,63 +15,201 @@
 //! Utilities to check the status of all the outputs we have stored in
 //! the wallet storage and update them.
 
-use std::collections::hash_map::Entry;
+use std::cmp::max;
 use std::collections::HashMap;
 
 use api;
+use core::global;
 use types::*;
 use keychain::{Identifier, Keychain};
+use timestamp::Timestamp;
 use util::secp::pedersen;
 use util;
 use util::LOGGER;
 
-// Transitions a local wallet output from Unconfirmed -> Unspent.
-// Also updates the height and lock_height based on latest from the api.
+// Updates the height of a local wallet output based on latest from the api,
+// whether the output is a coinbase one and its lock_height, its status being
+// left to the caller. The lock height of a coinbase is its maturity, computed
+// from the height of its block as consensus does, unless the node reports a
+// later one. The chain reports no lock height for other outputs, the one the
+// wallet knows of is kept.
 fn refresh_output(out: &mut OutputData, api_out: &api::Output) {
 	out.height = api_out.height;
-	out.lock_height = api_out.lock_height;
+	match api_out.output_type {
+		api::OutputType::Coinbase => {
+			let maturity = api_out.height + global::coinbase_maturity();
+			out.is_coinbase = true;
+			out.lock_height = max(maturity, api_out.lock_height);
+		}
+		api::OutputType::Transaction => {
+			out.is_coinbase = false;
+			out.lock_height = max(out.lock_height, api_out.lock_height);
+		}
+	}
+}
 
-	match out.status {
-		OutputStatus::Unconfirmed => {
-			out.status = OutputStatus::Unspent;
+// Whether an output still unspent on chain was locked by a send more than
+// `expiry` blocks ago, its spending transaction never made it there and it
+// can be unlocked. Outputs locked before the lock height was recorded start
+// their clock now.
+fn lock_expired(out: &mut OutputData, tip_height: u64, expiry: u64) -> bool {
+	if out.status != OutputStatus::Locked || expiry == 0 {
+		return false;
+	}
+	if out.locked_at == 0 {
+		out.locked_at = tip_height;
+		return false;
+	}
+	if out.locked_at + expiry > tip_height {
+		return false;
+	}
+	warn!(
+		LOGGER,
+		"Unlocking output {}, locked at height {} by a send that never confirmed",
+		out.key_id,
+		out.locked_at
+	);
+	true
+}
+
+// Unlocks an output whose lock expired. The send that locked it never made
+// it on chain, it's undone, all of its inputs unlocked along with that one.
+fn unlock_expired(wallet_data: &mut WalletData, key_id: &Identifier) -> Result<(), Error> {
+	let pending_send = match wallet_data.transaction_of(key_id) {
+		Some(tx) if tx.direction == TxDirection::Sent && tx.status == TxLogStatus::Pending => {
+			Some(tx.id)
 		}
-		_ => (),
+		_ => None,
+	};
+	match pending_send {
+		Some(tx_id) => wallet_data.undo_send(tx_id, TxLogStatus::Aborted),
+		None => wallet_data.unlock_output(key_id),
+	}
+}
+
+// A change of status found by a refresh that the wallet doesn't allow leaves
+// the output as it was, rather than failing the refresh of all the others.
+fn log_refused(result: Result<(), Error>) {
+	if let Err(e) = result {
+		error!(LOGGER, "Refresh left an output as it was: {}", e);
 	}
 }
 
-// Transitions a local wallet output (based on it not being in the node utxo
-// set) -
-// Unspent -> Spent
-// Locked -> Spent
-fn mark_spent_output(out: &mut OutputData) {
-	match out.status {
-		OutputStatus::Unspent | OutputStatus::Locked => out.status = OutputStatus::Spent,
-		_ => (),
+/// What a refresh found changed by a reorganization of the chain, nothing
+/// if the blocks our outputs were confirmed in are all still on it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReorgSummary {
+	/// Lowest height of the blocks of our outputs the chain replaced
+	pub fork_height: Option<u64>,
+	/// Outputs of the replaced blocks gone from the chain, unconfirmed again
+	pub unconfirmed: Vec<Identifier>,
+	/// Outputs spent by a transaction of the replaced blocks, unspent again
+	pub unspent: Vec<Identifier>,
+}
+
+// Whether the output was confirmed in a block the chain replaced.
+fn orphaned(out: &OutputData, fork_height: Option<u64>) -> bool {
+	fork_height.map_or(false, |fork_height| {
+		out.block_hash.is_some() && out.height >= fork_height
+	})
+}
+
+// Hash of the block of the chain of the node at the height, None if the
+// chain isn't that high. Each height is only asked for once per refresh.
+fn block_hash(
+	config: &WalletConfig,
+	hashes: &mut HashMap<u64, Option<String>>,
+	height: u64,
+) -> Result<Option<String>, Error> {
+	if let Some(hash) = hashes.get(&height) {
+		return Ok(hash.clone());
+	}
+	let url = format!(
+		"{}/v1/chain/utxos/byheight?start_height={}&end_height={}",
+		config.check_node_api_http_addr,
+		height,
+		height,
+	);
+	let blocks = api::client::get::<Vec<api::BlockOutputs>>(url.as_str())?;
+	let hash = blocks
+		.into_iter()
+		.find(|block| block.height == height)
+		.map(|block| block.hash);
+	hashes.insert(height, hash.clone());
+	Ok(hash)
+}
+
+// Lowest height of the blocks our outputs were confirmed in that the chain
+// of the node replaced, if any. A block still on the chain vouches for all
+// the ones below it, so heights are checked from the highest down until one
+// still is.
+fn find_fork(
+	config: &WalletConfig,
+	outputs: &Vec<OutputData>,
+	hashes: &mut HashMap<u64, Option<String>>,
+) -> Result<Option<u64>, Error> {
+	let mut confirmed = outputs
+		.iter()
+		.filter_map(|out| out.block_hash.as_ref().map(|hash| (out.height, hash)))
+		.collect::<Vec<_>>();
+	confirmed.sort_by(|a, b| b.cmp(a));
+	confirmed.dedup_by_key(|&mut (height, _)| height);
+
+	let mut fork_height = None;
+	for (height, hash) in confirmed {
+		if block_hash(config, hashes, height)?.as_ref() == Some(hash) {
+			break;
+		}
+		fork_height = Some(height);
 	}
+	Ok(fork_height)
 }
 
 /// Builds a single api query to retrieve the latest output data from the node.
-/// So we can refresh the local wallet outputs.
-pub fn refresh_outputs(config: &WalletConfig, keychain: &Keychain) -> Result<(), Error> {
+/// So we can refresh the local wallet outputs. The blocks our outputs were
+/// confirmed in are checked against the chain of the node first. If it
+/// replaced some, the outputs of those that are gone from the chain are
+/// unconfirmed again, and the spent outputs it has as unspent are unspent.
+/// Watch-only outputs are refreshed along, by the commitment they were
+/// imported with.
+pub fn refresh_outputs(config: &WalletConfig, keychain: &Keychain) -> Result<ReorgSummary, Error> {
 	debug!(LOGGER, "Refreshing wallet outputs");
-	let mut wallet_outputs: HashMap<pedersen::Commitment, Identifier> = HashMap::new();
-	let mut commits: Vec<pedersen::Commitment> = vec![];
-
-	// build a local map of wallet outputs by commits
- // and a list of outputs we wantot query the node for
-	let _ = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		for out in wallet_data
+	let config = &config.for_keychain(keychain)?;
+	let root_key_id = keychain.root_key_id();
+	let outputs = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data
 			.outputs
 			.values()
-			.filter(|out| out.root_key_id == keychain.root_key_id())
-			.filter(|out| out.status != OutputStatus::Spent)
-		{
-			let key_id = keychain.derive_key_id(out.n_child).unwrap();
-			let commit = keychain.commit(out.value, &key_id).unwrap();
-			commits.push(commit);
-			wallet_outputs.insert(commit, out.key_id.clone());
+			.filter(|out| out.root_key_id == root_key_id || out.is_watch_only())
+			.cloned()
+			.collect::<Vec<_>>()
+	})?;
+
+	// locks can only expire if we know how old they are
+	let tip_height = get_tip_from_node(config).ok().map(|tip| tip.height);
+
+	let mut hashes = HashMap::new();
+	let fork_height = match find_fork(config, &outputs, &mut hashes) {
+		Ok(fork_height) => fork_height,
+		Err(e) => {
+			warn!(LOGGER, "Could not check the chain for a reorganization: {}", e);
+			None
 		}
-	});
+	};
+
+	// build a local map of wallet outputs by commits
+ // and a list of outputs we want to query the node for,
+ // the spent ones too once the chain was reorganized
+	let mut wallet_outputs: HashMap<pedersen::Commitment, OutputData> = HashMap::new();
+	let mut commits: Vec<pedersen::Commitment> = vec![];
+	for out in outputs {
+		if out.status == OutputStatus::Spent && fork_height.is_none() {
+			continue;
+		}
+		let commit = out.commit(keychain)?;
+		commits.push(commit);
+		wallet_outputs.insert(commit, out);
+	}
 
 	// build the necessary query params -
  // ?id=xxx&id=yyy&id=zzz
@@ -99,22 +237,852 @@ pub fn refresh_outputs(config: &WalletConfig, keychain: &Keychain) -> Result<(),
 		Err(_) => {}
 	};
 
+	// the blocks the outputs found are in, asked for those that moved to
+	// another one or that we don't know the block of yet, one that can't be
+	// told being asked for again at the next refresh
+	let mut block_hashes: HashMap<pedersen::Commitment, Option<String>> = HashMap::new();
+	for (commit, api_out) in &api_outputs {
+		if let Some(out) = wallet_outputs.get(commit) {
+			let moved = out.height != api_out.height || orphaned(out, fork_height);
+			let hash = match out.block_hash {
+				Some(ref hash) if !moved => Some(hash.clone()),
+				_ => block_hash(config, &mut hashes, api_out.height).unwrap_or(None),
+			};
+			block_hashes.insert(*commit, hash);
+		}
+	}
+
+	// when the changes of status found are recorded as made
+	let now = Timestamp::now();
+
 	// now for each commit, find the output in the wallet and
  // the corresponding api output (if it exists)
  // and refresh it in-place in the wallet.
  // Note: minimizing the time we spend holding the wallet lock.
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| for commit in commits {
-		let id = wallet_outputs.get(&commit).unwrap();
-		if let Entry::Occupied(mut output) = wallet_data.outputs.entry(id.to_hex()) {
-			match api_outputs.get(&commit) {
-				Some(api_output) => refresh_output(&mut output.get_mut(), api_output),
-				None => mark_spent_output(&mut output.get_mut()),
+	let now = Timestamp::now();
+	let summary = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let mut summary = ReorgSummary {
+			fork_height: fork_height,
+			..Default::default()
+		};
+		for commit in commits {
+			let key_id = wallet_outputs[&commit].key_id.clone();
+			let (status, dropped) = match wallet_data.get_output(&key_id) {
+				Some(out) => (out.status.clone(), orphaned(out, fork_height)),
+				None => continue,
 			};
+			match api_outputs.get(&commit) {
+				Some(api_output) => {
+					// spent by a transaction the chain dropped
+					if status == OutputStatus::Spent {
+						let reason = "spent in a block the chain dropped";
+						let unspent =
+							wallet_data.force_status(&key_id, OutputStatus::Unspent, reason);
+						log_refused(unspent);
+						summary.unspent.push(key_id.clone());
+					}
+					let mut expired = false;
+					if let Some(out) = wallet_data.outputs.get_mut(&key_id.to_hex()) {
+						// first found on chain, those restored from it already
+						// having a height
+						let unconfirmed = match status {
+							OutputStatus::Pending | OutputStatus::Unconfirmed => true,
+							_ => out.height == 0,
+						};
+						if unconfirmed && out.confirmed_at.is_none() {
+							out.confirmed_at = Some(now);
+						}
+						if status == OutputStatus::Spent {
+							out.spent_at = None;
+						}
+						refresh_output(out, api_output);
+						out.block_hash = block_hashes.get(&commit).cloned().unwrap_or(None);
+						if let Some(tip_height) = tip_height {
+							expired = lock_expired(out, tip_height, config.lock_expiry_blocks);
+						}
+					}
+					match status {
+						OutputStatus::Pending | OutputStatus::Unconfirmed => log_refused(
+							wallet_data.set_status(&key_id, OutputStatus::Unspent, "confirmed"),
+						),
+						_ if expired => log_refused(unlock_expired(wallet_data, &key_id)),
+						_ => (),
+					}
+				}
+				// in a block the chain dropped, not spent
+				None if status == OutputStatus::Unspent && dropped => {
+					let reason = "confirmed in a block the chain dropped";
+					let unconfirmed =
+						wallet_data.force_status(&key_id, OutputStatus::Unconfirmed, reason);
+					log_refused(unconfirmed);
+					if let Some(out) = wallet_data.outputs.get_mut(&key_id.to_hex()) {
+						out.block_hash = None;
+					}
+					summary.unconfirmed.push(key_id);
+				}
+				// gone from the utxo set, pending outputs simply haven't made it
+				// yet and are left alone
+				None => match status {
+					OutputStatus::Unspent | OutputStatus::Locked => {
+						let spent =
+							wallet_data.set_status(&key_id, OutputStatus::Spent, "spent on chain");
+						if spent.is_ok() {
+							if let Some(out) = wallet_data.outputs.get_mut(&key_id.to_hex()) {
+								out.spent_at = Some(now);
+							}
+						}
+						log_refused(spent);
+					}
+					_ => (),
+				},
+			}
 		}
-	})
+		wallet_data.confirm_tx_log();
+		wallet_data.confirm_rx_log();
+		wallet_data.confirm_transactions();
+		wallet_data.expire_received_txs();
+		summary
+	})?;
+
+	if let Some(fork_height) = summary.fork_height {
+		warn!(
+			LOGGER,
+			"Chain reorganized from height {}, unconfirmed again: {:?}, unspent again: {:?}",
+			fork_height,
+			summary.unconfirmed,
+			summary.unspent
+		);
+	}
+	Ok(summary)
 }
 
 pub fn get_tip_from_node(config: &WalletConfig) -> Result<api::Tip, Error> {
 	let url = format!("{}/v1/chain", config.check_node_api_http_addr);
 	api::client::get::<api::Tip>(url.as_str()).map_err(|e| Error::Node(e))
 }
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	use core::core::build::{output, transaction};
+	use core::core::BlockHeader;
+	use core::global;
+	use keychain::Keychain;
+	use serde_json;
+	use client;
+	use super::*;
+
+	// What the node answers when asked for the block at the height.
+	fn block(height: u64, hash: &str) -> String {
+		serde_json::to_string(&vec![
+			api::BlockOutputs {
+				height: height,
+				hash: hash.to_string(),
+				outputs: vec![],
+			},
+		]).unwrap()
+	}
+
+	#[test]
+	fn pending_output_lifecycle() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let mut out = OutputData {
+			root_key_id: keychain.root_key_id(),
+			key_id: key_id.clone(),
+			n_child: 1,
+			value: 50,
+			status: OutputStatus::Pending,
+			height: 0,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: None,
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
+		};
+
+		// not found on chain, it just hasn't made it there yet
+		assert!(!out.status.can_transition_to(&OutputStatus::Spent));
+
+		// refreshing tells where it is, its status is moved by the wallet
+		let (tx, _) = transaction(vec![output(50, key_id)], &keychain).unwrap();
+		let mut header = BlockHeader::default();
+		header.height = 12;
+		let api_out = api::Output::from_output(&tx.outputs[0], &header);
+		refresh_output(&mut out, &api_out);
+		assert_eq!((out.status.clone(), out.height), (OutputStatus::Pending, 12));
+		assert!(out.status.can_transition_to(&OutputStatus::Unspent));
+		assert!(OutputStatus::Unspent.can_transition_to(&OutputStatus::Spent));
+	}
+
+	#[test]
+	fn lock_heights_refreshed() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, _) = transaction(vec![output(50, key_id.clone())], &keychain).unwrap();
+		let mut header = BlockHeader::default();
+		header.height = 12;
+		let api_out = api::Output::from_output(&tx.outputs[0], &header);
+
+		// the chain knows nothing of the lock height of a payment
+		let mut out = OutputData {
+			root_key_id: keychain.root_key_id(),
+			key_id: key_id,
+			n_child: 1,
+			value: 50,
+			status: OutputStatus::Unconfirmed,
+			height: 0,
+			block_hash: None,
+			lock_height: 500,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: None,
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
+		};
+		refresh_output(&mut out, &api_out);
+		assert_eq!((out.height, out.lock_height), (12, 500));
+
+		// it does of the maturity of a coinbase, even once it moved to
+		// another block
+		let maturity = global::coinbase_maturity();
+		let mut coinbase = OutputData {
+			status: OutputStatus::Unconfirmed,
+			lock_height: 0,
+			is_coinbase: true,
+			..out
+		};
+		let mut api_coinbase = api_out.clone();
+		api_coinbase.output_type = api::OutputType::Coinbase;
+		api_coinbase.lock_height = 12 + maturity;
+		refresh_output(&mut coinbase, &api_coinbase);
+		assert_eq!(coinbase.lock_height, 12 + maturity);
+		api_coinbase.height = 15;
+		api_coinbase.lock_height = 15 + maturity;
+		refresh_output(&mut coinbase, &api_coinbase);
+		assert_eq!((coinbase.height, coinbase.lock_height), (15, 15 + maturity));
+	}
+
+	#[test]
+	fn expired_locks_released() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config.lock_expiry_blocks = 50;
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+
+		// all locked, by sends at heights 10, 10, 90 and before locks had a
+		// height
+		let locked_at = vec![10, 10, 90, 0];
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for (i, locked_at) in locked_at.iter().enumerate() {
+				let n_child = i as u32 + 1;
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: keychain.derive_key_id(n_child).unwrap(),
+					n_child: n_child,
+					value: 10 * n_child as u64,
+					status: OutputStatus::Locked,
+					height: 1,
+					block_hash: None,
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: *locked_at,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+
+		// the node is at height 100 and only the second output got spent
+		let mut header = BlockHeader::default();
+		header.height = 1;
+		let utxos = vec![1, 3, 4]
+			.into_iter()
+			.map(|n_child| {
+				let key_id = keychain.derive_key_id(n_child).unwrap();
+				let (tx, _) = transaction(vec![output(10 * n_child as u64, key_id)], &keychain)
+					.unwrap();
+				api::Output::from_output(&tx.outputs[0], &header)
+			})
+			.collect::<Vec<_>>();
+		let tip = api::Tip {
+			height: 100,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		};
+		let mut replies = vec![
+			serde_json::to_string(&tip).unwrap(),
+			serde_json::to_string(&utxos).unwrap(),
+			block(1, "b1"),
+		].into_iter();
+		let (addr, requests) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		refresh_outputs(&config, &keychain).unwrap();
+		assert_eq!(requests.lock().unwrap().len(), 3);
+
+		let outputs = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			(1..5)
+				.map(|n_child| {
+					let key_id = keychain.derive_key_id(n_child).unwrap();
+					let out = wallet_data.get_output(&key_id).unwrap();
+					(out.status.clone(), out.locked_at)
+				})
+				.collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(
+			outputs,
+			vec![
+				// its send never made it on chain, spendable again
+				(OutputStatus::Unspent, 0),
+				// actually spent, never unlocked
+				(OutputStatus::Spent, 10),
+				// too recent to give up on
+				(OutputStatus::Locked, 90),
+				// the clock starts now
+				(OutputStatus::Locked, 100),
+			]
+		);
+
+		// without an expiry, locks stay
+		let mut out = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data
+				.get_output(&keychain.derive_key_id(3).unwrap())
+				.unwrap()
+				.clone()
+		}).unwrap();
+		assert!(!lock_expired(&mut out, 1_000, 0));
+		assert_eq!((out.status, out.locked_at), (OutputStatus::Locked, 90));
+	}
+
+	#[test]
+	fn expired_send_undone() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		let _ = fs::remove_dir_all(&dir);
+		let key_id = |n_child| keychain.derive_key_id(n_child).unwrap();
+
+		// a send of the first two outputs with the third as change, and an
+		// output locked before sends were grouped
+		let (statuses, tx) = WalletData::with_wallet(&dir, |wallet_data| {
+			let statuses = vec![
+				OutputStatus::Locked,
+				OutputStatus::Locked,
+				OutputStatus::Pending,
+				OutputStatus::Locked,
+			];
+			for (i, status) in statuses.into_iter().enumerate() {
+				let n_child = i as u32 + 1;
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: key_id(n_child),
+					n_child: n_child,
+					value: 10 * n_child as u64,
+					status: status,
+					height: if n_child == 3 { 0 } else { 1 },
+					block_hash: None,
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: 10,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+			let (inputs, change) = (vec![key_id(1), key_id(2)], vec![key_id(3)]);
+			let direction = TxDirection::Sent;
+			let tx_id =
+				wallet_data.add_transaction(direction, 25, 2, inputs.clone(), change.clone());
+			let tx_log_id = wallet_data.add_tx_log_entry(25, 2, "a", inputs, change, None);
+			wallet_data.set_transaction_log_id(tx_id, tx_log_id);
+
+			// the lock of the second input expires first, the whole send goes
+			unlock_expired(wallet_data, &key_id(2)).unwrap();
+			unlock_expired(wallet_data, &key_id(4)).unwrap();
+			let statuses = (1..5)
+				.map(|n_child| {
+					let out = wallet_data.get_output(&key_id(n_child));
+					out.map(|out| out.status.clone())
+				})
+				.collect::<Vec<_>>();
+			(statuses, wallet_data.get_transaction(tx_id).cloned().unwrap())
+		}).unwrap();
+		assert_eq!(
+			statuses,
+			vec![
+				Some(OutputStatus::Unspent),
+				Some(OutputStatus::Unspent),
+				None,
+				Some(OutputStatus::Unspent),
+			]
+		);
+		assert_eq!(tx.status, TxLogStatus::Aborted);
+		let tx_log =
+			WalletData::read_wallet(&dir, |wallet_data| wallet_data.tx_log.clone()).unwrap();
+		assert_eq!(tx_log[0].status, TxLogStatus::Aborted);
+	}
+
+	#[test]
+	fn receives_confirmed() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+
+		// two payments received, only the first made it on chain
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for n_child in 1..3 {
+				let key_id = keychain.derive_key_id(n_child).unwrap();
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: key_id.clone(),
+					n_child: n_child,
+					value: 10 * n_child as u64,
+					status: OutputStatus::Unconfirmed,
+					height: 0,
+					block_hash: None,
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: 0,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+				wallet_data.add_rx_log_entry(10 * n_child as u64, key_id, None, None, None);
+			}
+		}).unwrap();
+
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, _) = transaction(vec![output(10, key_id)], &keychain).unwrap();
+		let mut header = BlockHeader::default();
+		header.height = 5;
+		let utxos = vec![api::Output::from_output(&tx.outputs[0], &header)];
+		let tip = api::Tip {
+			height: 5,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		};
+		let mut replies = vec![
+			serde_json::to_string(&tip).unwrap(),
+			serde_json::to_string(&utxos).unwrap(),
+			block(5, "b5"),
+		].into_iter();
+		let (addr, _) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		refresh_outputs(&config, &keychain).unwrap();
+		let confirmed = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			let mut entries = wallet_data.rx_log.clone();
+			entries.sort_by_key(|entry| entry.id);
+			entries.iter().map(|entry| entry.confirmed).collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(confirmed, vec![true, false]);
+	}
+
+	#[test]
+	fn coinbase_maturation_tracked() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+
+		// a coinbase of a wallet file that predates the flag, as migrated
+		let key_id = keychain.derive_key_id(1).unwrap();
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.add_output(OutputData {
+				root_key_id: keychain.root_key_id(),
+				key_id: key_id.clone(),
+				n_child: 1,
+				value: 60,
+				status: OutputStatus::Unconfirmed,
+				height: 0,
+				block_hash: None,
+				lock_height: 0,
+				is_coinbase: false,
+				locked_at: 0,
+				account: None,
+				created_at: None,
+				confirmed_at: None,
+				spent_at: None,
+				tx_id: None,
+				label: None,
+				watch_commit: None,
+			}).unwrap();
+		}).unwrap();
+
+		// the node sees it mined at height 12, then the chain grows past its
+		// maturity, the block it's in replaced by one at height 13 on the way,
+		// the node not reporting its lock height the second time
+		let maturity = global::coinbase_maturity();
+		let (tx, _) = transaction(vec![output(60, key_id.clone())], &keychain).unwrap();
+		let coinbase = |height: u64, lock_height: u64| {
+			let mut header = BlockHeader::default();
+			header.height = height;
+			let mut api_out = api::Output::from_output(&tx.outputs[0], &header);
+			api_out.output_type = api::OutputType::Coinbase;
+			api_out.lock_height = lock_height;
+			serde_json::to_string(&vec![api_out]).unwrap()
+		};
+		let tip = |height: u64| {
+			serde_json::to_string(&api::Tip {
+				height: height,
+				last_block_pushed: String::new(),
+				prev_block_to_last: String::new(),
+				total_difficulty: 0,
+			}).unwrap()
+		};
+		let mut replies = vec![
+			tip(12),
+			coinbase(12, 12 + maturity),
+			block(12, "a12"),
+			tip(13 + maturity),
+			block(12, "b12"),
+			coinbase(13, 0),
+			block(13, "b13"),
+		].into_iter();
+		let (addr, _) = client::test::stub_server(7, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		let stored = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.get_output(&key_id).unwrap().clone()
+			}).unwrap()
+		};
+		refresh_outputs(&config, &keychain).unwrap();
+		let out = stored();
+		assert_eq!(out.status, OutputStatus::Unspent);
+		assert!(out.is_coinbase);
+		assert_eq!((out.height, out.lock_height), (12, 12 + maturity));
+		assert_eq!(out.block_hash, Some("a12".to_string()));
+		assert_eq!(out.spendability(12, 1), Spendability::Immature);
+		assert_eq!(out.spendability(12 + maturity, 1), Spendability::Spendable);
+
+		assert_eq!(refresh_outputs(&config, &keychain).unwrap().fork_height, Some(12));
+		let out = stored();
+		assert!(out.is_coinbase);
+		assert_eq!((out.height, out.lock_height), (13, 13 + maturity));
+		assert_eq!(out.block_hash, Some("b13".to_string()));
+		assert_eq!(out.spendability(12 + maturity, 1), Spendability::Immature);
+		assert_eq!(out.spendability(13 + maturity, 1), Spendability::Spendable);
+	}
+
+	#[test]
+	fn reorg_recovered() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+		let key_id = |n_child| keychain.derive_key_id(n_child).unwrap();
+
+		// children 1 to 3 just received, 4 seen in the block at height 10
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for n_child in 1..5 {
+				let seen = n_child == 4;
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: key_id(n_child),
+					n_child: n_child,
+					value: 10 * n_child as u64,
+					status: if seen {
+						OutputStatus::Unspent
+					} else {
+						OutputStatus::Unconfirmed
+					},
+					height: if seen { 10 } else { 0 },
+					block_hash: if seen { Some("a10".to_string()) } else { None },
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: 0,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+
+		// on the first chain 4 gets spent, the second replaces the blocks
+		// from height 20: 2 is gone, 3 made it at height 21 instead and the
+		// spend of 4 didn't make it
+		let api_output = |n_child: u32, height: u64| {
+			let (tx, _) = transaction(vec![output(10 * n_child as u64, key_id(n_child))], &keychain)
+				.unwrap();
+			let mut header = BlockHeader::default();
+			header.height = height;
+			api::Output::from_output(&tx.outputs[0], &header)
+		};
+		let chain_a = vec![api_output(1, 10), api_output(2, 20), api_output(3, 20)];
+		let chain_b = vec![api_output(1, 10), api_output(3, 21), api_output(4, 10)];
+		let on_b = Arc::new(AtomicBool::new(false));
+		let node_on_b = on_b.clone();
+		let (addr, requests) = client::test::node_stub_server(12, move |path| {
+			let on_b = node_on_b.load(Ordering::SeqCst);
+			let reply = if path == "/v1/chain" {
+				serde_json::to_string(&api::Tip {
+					height: if on_b { 31 } else { 30 },
+					last_block_pushed: String::new(),
+					prev_block_to_last: String::new(),
+					total_difficulty: 0,
+				}).unwrap()
+			} else if path.starts_with("/v1/chain/utxos/byheight") {
+				let height = path
+					.split("start_height=")
+					.nth(1)
+					.and_then(|rest| rest.split('&').next())
+					.and_then(|height| height.parse::<u64>().ok())
+					.unwrap();
+				let chain = if on_b && height >= 20 { "b" } else { "a" };
+				block(height, &format!("{}{}", chain, height))
+			} else {
+				let utxos = if on_b { &chain_b } else { &chain_a };
+				let asked = utxos
+					.iter()
+					.filter(|out| path.contains(&util::to_hex(out.commit.as_ref().to_vec())))
+					.cloned()
+					.collect::<Vec<_>>();
+				serde_json::to_string(&asked).unwrap()
+			};
+			Some(("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		let outputs = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				(1..5)
+					.map(|n_child| {
+						let out = wallet_data.get_output(&key_id(n_child)).unwrap();
+						(out.status.clone(), out.height, out.block_hash.clone())
+					})
+					.collect::<Vec<_>>()
+			}).unwrap()
+		};
+		let hash = |hash: &str| Some(hash.to_string());
+
+		assert_eq!(refresh_outputs(&config, &keychain).unwrap(), ReorgSummary::default());
+		assert_eq!(
+			outputs(),
+			vec![
+				(OutputStatus::Unspent, 10, hash("a10")),
+				(OutputStatus::Unspent, 20, hash("a20")),
+				(OutputStatus::Unspent, 20, hash("a20")),
+				(OutputStatus::Spent, 10, hash("a10")),
+			]
+		);
+
+		on_b.store(true, Ordering::SeqCst);
+		assert_eq!(
+			refresh_outputs(&config, &keychain).unwrap(),
+			ReorgSummary {
+				fork_height: Some(20),
+				unconfirmed: vec![key_id(2)],
+				unspent: vec![key_id(4)],
+			}
+		);
+		assert_eq!(
+			outputs(),
+			vec![
+				(OutputStatus::Unspent, 10, hash("a10")),
+				(OutputStatus::Unconfirmed, 20, None),
+				(OutputStatus::Unspent, 21, hash("b21")),
+				(OutputStatus::Unspent, 10, hash("a10")),
+			]
+		);
+
+		// the chain settled, only its highest block we know of is checked
+		assert_eq!(refresh_outputs(&config, &keychain).unwrap(), ReorgSummary::default());
+		assert_eq!(outputs()[2], (OutputStatus::Unspent, 21, hash("b21")));
+		assert_eq!(requests.lock().unwrap().len(), 12);
+
+		// the spend that didn't make it isn't remembered
+		let spent_at = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.get_output(&key_id(4)).unwrap().spent_at
+		}).unwrap();
+		assert_eq!(spent_at, None);
+	}
+
+	#[test]
+	fn timestamps_recorded() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_checker_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+		let key_id = |n_child| keychain.derive_key_id(n_child).unwrap();
+
+		// 1 just received, 2 restored from the chain at height 3, 3 never
+		// making it there
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for n_child in 1..4 {
+				let restored = n_child == 2;
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: key_id(n_child),
+					n_child: n_child,
+					value: 10 * n_child as u64,
+					status: if restored {
+						OutputStatus::Unspent
+					} else {
+						OutputStatus::Unconfirmed
+					},
+					height: if restored { 3 } else { 0 },
+					block_hash: None,
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: 0,
+					account: None,
+					created_at: if restored { None } else { Some(Timestamp(1)) },
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+
+		// 1 gets confirmed at height 5, then 2 gets spent
+		let api_output = |n_child: u32, height: u64| {
+			let (tx, _) = transaction(vec![output(10 * n_child as u64, key_id(n_child))], &keychain)
+				.unwrap();
+			let mut header = BlockHeader::default();
+			header.height = height;
+			api::Output::from_output(&tx.outputs[0], &header)
+		};
+		let unspent = vec![api_output(1, 5), api_output(2, 3)];
+		let spent = Arc::new(AtomicBool::new(false));
+		let node_spent = spent.clone();
+		let (addr, requests) = client::test::node_stub_server(10, move |path| {
+			let reply = if path == "/v1/chain" {
+				serde_json::to_string(&api::Tip {
+					height: 6,
+					last_block_pushed: String::new(),
+					prev_block_to_last: String::new(),
+					total_difficulty: 0,
+				}).unwrap()
+			} else if path.contains("start_height=5") {
+				block(5, "a5")
+			} else if path.contains("start_height=3") {
+				block(3, "a3")
+			} else {
+				let utxos = if node_spent.load(Ordering::SeqCst) {
+					&unspent[..1]
+				} else {
+					&unspent[..]
+				};
+				serde_json::to_string(&utxos).unwrap()
+			};
+			Some(("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		let timestamps = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				(1..4)
+					.map(|n_child| {
+						let out = wallet_data.get_output(&key_id(n_child)).unwrap();
+						(out.created_at, out.confirmed_at, out.spent_at)
+					})
+					.collect::<Vec<_>>()
+			}).unwrap()
+		};
+
+		// confirmed, when the restored output was isn't known
+		let before = Timestamp::now();
+		refresh_outputs(&config, &keychain).unwrap();
+		let after = Timestamp::now();
+		let found = timestamps();
+		let confirmed_at = found[0].1.unwrap();
+		assert!(before <= confirmed_at && confirmed_at <= after);
+		assert_eq!(found[0], (Some(Timestamp(1)), Some(confirmed_at), None));
+		assert_eq!(found[1], (None, None, None));
+		assert_eq!(found[2], (Some(Timestamp(1)), None, None));
+
+		// told apart from the time of a later refresh, which leaves it alone,
+		// only the spent output getting the time it was found spent
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			let out = wallet_data.outputs.get_mut(&key_id(1).to_hex()).unwrap();
+			out.confirmed_at = Some(Timestamp(2));
+		}).unwrap();
+		spent.store(true, Ordering::SeqCst);
+		let before = Timestamp::now();
+		refresh_outputs(&config, &keychain).unwrap();
+		let after = Timestamp::now();
+		let found = timestamps();
+		let spent_at = found[1].2.unwrap();
+		assert!(before <= spent_at && spent_at <= after);
+		assert_eq!(found[0], (Some(Timestamp(1)), Some(Timestamp(2)), None));
+		assert_eq!(found[1], (None, None, Some(spent_at)));
+		assert_eq!(found[2], (Some(Timestamp(1)), None, None));
+
+		refresh_outputs(&config, &keychain).unwrap();
+		assert_eq!(timestamps(), found);
+		assert_eq!(requests.lock().unwrap().len(), 10);
+	}
+}
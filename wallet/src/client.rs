@@ -12,19 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, time};
+use std::{io, thread, time};
 use std::ops::FnMut;
+use std::sync::Arc;
 
 use futures::{Future, Stream};
 use hyper;
 use hyper::{Method, Request};
-use hyper::header::ContentType;
+use hyper::client::HttpConnector;
+use hyper::header::{Authorization, Basic, ContentType};
+use hyper_rustls::HttpsConnector;
+use ring::digest;
+use rustls;
 use tokio_core::reactor;
 use tokio_retry::Retry;
 use tokio_retry::strategy::FibonacciBackoff;
 use serde_json;
+use webpki;
+use webpki_roots;
 
+use api;
 use types::*;
+use util;
 use util::LOGGER;
 
 /// Call the wallet API to create a coinbase output for the given block_fees.
@@ -32,10 +41,11 @@ use util::LOGGER;
 pub fn create_coinbase(url: &str, block_fees: &BlockFees) -> Result<CbData, Error> {
 	retry_backoff_forever(|| {
 		let res = single_create_coinbase(&url, &block_fees);
-		if let Err(_) = res {
+		if let Err(ref e) = res {
 			error!(
 				LOGGER,
-				"Failed to get coinbase via wallet API (will retry)..."
+				"Failed to get coinbase via wallet API (will retry): {}",
+				e
 			);
 		}
 		res
@@ -55,22 +65,207 @@ where
 	Ok(res)
 }
 
-pub fn send_partial_tx(url: &str, partial_tx: &JSONPartialTx) -> Result<(), Error> {
-	single_send_partial_tx(url, partial_tx)
+/// Posts the partial transaction to the receiver, making up to `attempts`
+/// attempts. Only connection failures are retried, with the delay between
+/// attempts doubling from `retry_delay_ms`. Any answer of the receiver is
+/// returned straight away: a 4xx means it rejected the transaction, a 5xx
+/// that it failed on its side, maybe after taking the transaction. Every
+/// attempt sends the exact same JSON payload. Returns the response of the
+/// receiver, holding the transaction it completed. Receivers reached over
+/// https must present a certificate the usual web roots vouch for, or a
+/// self-signed one whose SHA-256 fingerprint is pinned_cert. The secret, if any, is sent
+/// as the password of a basic authorization, a receiver refusing it
+/// answers 401 which isn't retried either. A receiver not answering within
+/// timeout_secs fails the post with NodeTimeout, not retried as it may have
+/// processed the transaction already.
+pub fn send_partial_tx(
+	url: &str,
+	partial_tx: &JSONPartialTx,
+	secret: Option<&str>,
+	attempts: u32,
+	retry_delay_ms: u64,
+	timeout_secs: u64,
+	pinned_cert: Option<&str>,
+) -> Result<ReceiveResponse, Error> {
+	let json = serde_json::to_string(&partial_tx)?;
+	let mut delay = retry_delay_ms;
+	let mut attempt = 1;
+	loop {
+		match single_send_partial_tx(url, &json, secret, timeout_secs, pinned_cert) {
+			Err(ref e) if e.is_connection_failure() && attempt < attempts => {
+				warn!(
+					LOGGER,
+					"Attempt {} of {} to post partial transaction failed, retrying in {}ms: {}",
+					attempt,
+					attempts,
+					delay,
+					e
+				);
+				thread::sleep(time::Duration::from_millis(delay));
+				delay = delay.saturating_mul(2);
+				attempt += 1;
+			}
+			res => return res,
+		}
+	}
 }
 
-fn single_send_partial_tx(url: &str, partial_tx: &JSONPartialTx) -> Result<(), Error> {
+fn single_send_partial_tx(
+	url: &str,
+	json: &str,
+	secret: Option<&str>,
+	timeout_secs: u64,
+	pinned_cert: Option<&str>,
+) -> Result<ReceiveResponse, Error> {
 	let mut core = reactor::Core::new()?;
-	let client = hyper::Client::new(&core.handle());
+	let client = hyper::Client::configure()
+		.connector(https_connector(&core.handle(), pinned_cert))
+		.build(&core.handle());
 
 	let mut req = Request::new(Method::Post, url.parse()?);
 	req.headers_mut().set(ContentType::json());
-	let json = serde_json::to_string(&partial_tx)?;
-	req.set_body(json);
+	if let Some(secret) = secret {
+		req.headers_mut().set(Authorization(Basic {
+			username: RECEIVER_AUTH_USER.to_string(),
+			password: Some(secret.to_string()),
+		}));
+	}
+	req.set_body(json.to_string());
+
+	let work = client
+		.request(req)
+		.and_then(|res| {
+			let status = res.status();
+			res.body().concat2().map(move |body| Some((status, body)))
+		})
+		.map_err(|e| match e {
+			// a certificate that doesn't check out won't get any better on retry
+			hyper::Error::Io(ref io_err) if io_err.kind() == io::ErrorKind::InvalidData => {
+				Error::Node(api::Error::Argument(format!(
+					"Could not establish a secure connection to the receiver: {}",
+					io_err
+				)))
+			}
+			_ => Error::Node(api::Error::Internal(
+				format!("Error posting partial transaction: {}", e),
+			)),
+		});
+	// whichever comes first, the response or the timeout
+	let timeout = reactor::Timeout::new(time::Duration::from_secs(timeout_secs), &core.handle())?
+		.map(|_| None)
+		.map_err(Error::from);
+	let response = core.run(work.select(timeout).map(|(res, _)| res).map_err(|(e, _)| e))?;
+	let (status, body) = match response {
+		Some(response) => response,
+		None => {
+			return Err(Error::NodeTimeout(format!(
+				"no answer from {} after {}s",
+				url,
+				timeout_secs
+			)))
+		}
+	};
+	if status == hyper::StatusCode::Unauthorized {
+		return Err(Error::Unauthorized(url.to_string()));
+	}
+	if !status.is_success() {
+		check_error_response(status, &body)?;
+	}
+	if status.is_client_error() {
+		return Err(Error::Node(api::Error::Argument(format!(
+			"Receiver rejected partial transaction: {}",
+			status
+		))));
+	}
+	if !status.is_success() {
+		return Err(Error::Node(api::Error::Remote {
+			status: status.as_u16(),
+			error: api::ErrorResponse {
+				code: "internal_error".to_string(),
+				message: format!("Receiver failed to process partial transaction: {}", status),
+				details: serde_json::Value::Null,
+			},
+		}));
+	}
+	serde_json::from_slice(&body)
+		.map_err(|e| Error::MalformedResponse(format!("invalid receiver response: {}", e)))
+}
+
+/// The error the receiver answered with, if it did answer with one. A
+/// receiver refusing our version tells which one it understands.
+fn check_error_response(status: hyper::StatusCode, body: &[u8]) -> Result<(), Error> {
+	let error: api::ErrorResponse = match serde_json::from_slice(body) {
+		Ok(error) => error,
+		Err(_) => return Ok(()),
+	};
+	if error.code == "version_mismatch" {
+		if let Some(version) = error.details.get("version").and_then(|v| v.as_u64()) {
+			return Err(Error::VersionMismatch {
+				ours: PARTIAL_TX_VERSION,
+				theirs: version as u16,
+			});
+		}
+	}
+	Err(Error::Node(api::Error::Remote {
+		status: status.as_u16(),
+		error: error,
+	}))
+}
+
+/// Connector speaking plain HTTP or TLS depending on the scheme of the URL.
+fn https_connector(handle: &reactor::Handle, pinned_cert: Option<&str>) -> HttpsConnector {
+	let mut http = HttpConnector::new(1, handle);
+	http.enforce_http(false);
+	let mut tls_config = rustls::ClientConfig::new();
+	tls_config
+		.root_store
+		.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+	if let Some(fingerprint) = pinned_cert {
+		let fingerprint = fingerprint.replace(":", "").to_lowercase();
+		tls_config
+			.dangerous()
+			.set_certificate_verifier(Arc::new(PinnedCertVerifier { fingerprint: fingerprint }));
+	}
+	HttpsConnector::from((http, tls_config))
+}
+
+/// SHA-256 fingerprint of the certificate, hex encoded, as pinned in
+/// tls_receiver_fingerprint. The one of "openssl x509 -fingerprint -sha256"
+/// once stripped of its colons.
+pub fn cert_fingerprint(cert: &rustls::Certificate) -> String {
+	util::to_hex(digest::digest(&digest::SHA256, &cert.0).as_ref().to_vec())
+}
 
-	let work = client.request(req);
-	let _ = core.run(work)?;
-	Ok(())
+// Verifies certificates just like rustls does by default, falling back to
+// accepting the self-signed certificate of the receiver when that fails, as
+// long as it's the one pinned and presented alone. The handshake still
+// proves the receiver holds the key of that certificate.
+struct PinnedCertVerifier {
+	fingerprint: String,
+}
+
+impl rustls::ServerCertVerifier for PinnedCertVerifier {
+	fn verify_server_cert(
+		&self,
+		roots: &rustls::RootCertStore,
+		presented_certs: &[rustls::Certificate],
+		dns_name: webpki::DNSNameRef,
+		ocsp_response: &[u8],
+	) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+		let verifier = rustls::WebPKIVerifier::new();
+		match verifier.verify_server_cert(roots, presented_certs, dns_name, ocsp_response) {
+			Err(e) => {
+				let pinned = presented_certs.len() == 1
+					&& cert_fingerprint(&presented_certs[0]) == self.fingerprint;
+				if !pinned {
+					return Err(e);
+				}
+				debug!(LOGGER, "Accepting the pinned certificate of the receiver ({:?})", e);
+				Ok(rustls::ServerCertVerified::assertion())
+			}
+			res => res,
+		}
+	}
 }
 
 /// Makes a single request to the wallet API to create a new coinbase output.
@@ -84,13 +279,432 @@ fn single_create_coinbase(url: &str, block_fees: &BlockFees) -> Result<CbData, E
 	req.set_body(json);
 
 	let work = client.request(req).and_then(|res| {
-		res.body().concat2().and_then(move |body| {
-			let coinbase: CbData =
-				serde_json::from_slice(&body).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-			Ok(coinbase)
-		})
+		let status = res.status();
+		res.body().concat2().map(move |body| (status, body))
 	});
 
-	let res = core.run(work)?;
-	Ok(res)
+	let (status, body) = core.run(work)?;
+	if !status.is_success() {
+		check_error_response(status, &body)?;
+		return Err(Error::Node(api::Error::Internal(format!(
+			"Wallet failed to build the coinbase: {}",
+			status
+		))));
+	}
+	serde_json::from_slice(&body)
+		.map_err(|e| Error::Format(format!("invalid coinbase from the wallet: {}", e)))
+}
+
+#[cfg(test)]
+pub mod test {
+	use std::fs::File;
+	use std::io::{BufReader, Read, Write};
+	use std::net::TcpListener;
+	use std::path::Path;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use rustls::internal::pemfile;
+	use serde_json::{Map, Value};
+
+	use api;
+	use types::*;
+	use super::*;
+
+	/// Serves `connections` connections, handing each request body to
+	/// `respond`: `None` drops the connection without answering, `Some` answers
+	/// with the provided status line and body. Returns the address of the
+	/// server and the request bodies received.
+	pub fn stub_server<F>(connections: usize, respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+	where
+		F: FnMut(&str) -> Option<(&'static str, String)> + Send + 'static,
+	{
+		auth_stub_server(connections, None, respond)
+	}
+
+	/// Same as stub_server, answering 401 to requests that don't carry the
+	/// secret if one is required.
+	pub fn auth_stub_server<F>(
+		connections: usize,
+		secret: Option<&str>,
+		mut respond: F,
+	) -> (String, Arc<Mutex<Vec<String>>>)
+	where
+		F: FnMut(&str) -> Option<(&'static str, String)> + Send + 'static,
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = format!("http://{}", listener.local_addr().unwrap());
+		let secret = secret.map(|s| s.to_string());
+		let requests = Arc::new(Mutex::new(vec![]));
+		let received = requests.clone();
+		thread::spawn(move || for _ in 0..connections {
+			let (mut stream, _) = listener.accept().unwrap();
+			serve(&mut stream, secret.as_ref(), &mut respond, &received);
+		});
+		(addr, requests)
+	}
+
+	/// Same as stub_server, recording the head of the requests along with
+	/// their body.
+	pub fn recording_stub_server<F>(
+		connections: usize,
+		mut respond: F,
+	) -> (String, Arc<Mutex<Vec<(String, String)>>>)
+	where
+		F: FnMut(&str) -> Option<(&'static str, String)> + Send + 'static,
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = format!("http://{}", listener.local_addr().unwrap());
+		let requests = Arc::new(Mutex::new(vec![]));
+		let received = requests.clone();
+		thread::spawn(move || for _ in 0..connections {
+			let (mut stream, _) = listener.accept().unwrap();
+			if let Some((head, body)) = read_request(&mut stream) {
+				received.lock().unwrap().push((head, body.clone()));
+				if let Some((status, reply_body)) = respond(&body) {
+					write_reply(&mut stream, status, &reply_body);
+				}
+			}
+		});
+		(addr, requests)
+	}
+
+	/// Same as stub_server, handing `respond` the path of each request rather
+	/// than its body, to stand in for a node. Returns the paths requested.
+	pub fn node_stub_server<F>(
+		connections: usize,
+		mut respond: F,
+	) -> (String, Arc<Mutex<Vec<String>>>)
+	where
+		F: FnMut(&str) -> Option<(&'static str, String)> + Send + 'static,
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = format!("http://{}", listener.local_addr().unwrap());
+		let requests = Arc::new(Mutex::new(vec![]));
+		let received = requests.clone();
+		thread::spawn(move || for _ in 0..connections {
+			let (mut stream, _) = listener.accept().unwrap();
+			if let Some((head, _)) = read_request(&mut stream) {
+				let path = head.split_whitespace().nth(1).unwrap_or("").to_string();
+				received.lock().unwrap().push(path.clone());
+				if let Some((status, reply_body)) = respond(&path) {
+					write_reply(&mut stream, status, &reply_body);
+				}
+			}
+		});
+		(addr, requests)
+	}
+
+	/// Same as stub_server, over TLS with the self-signed certificate for
+	/// localhost in tests/data.
+	pub fn tls_stub_server<F>(
+		connections: usize,
+		mut respond: F,
+	) -> (String, Arc<Mutex<Vec<String>>>)
+	where
+		F: FnMut(&str) -> Option<(&'static str, String)> + Send + 'static,
+	{
+		let config = Arc::new(tls_server_config());
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = format!("https://localhost:{}", listener.local_addr().unwrap().port());
+		let requests = Arc::new(Mutex::new(vec![]));
+		let received = requests.clone();
+		thread::spawn(move || for _ in 0..connections {
+			let (mut sock, _) = listener.accept().unwrap();
+			let mut session = rustls::ServerSession::new(&config);
+			let mut stream = rustls::Stream::new(&mut session, &mut sock);
+			serve(&mut stream, None, &mut respond, &received);
+		});
+		(addr, requests)
+	}
+
+	fn receiver_certs() -> Vec<rustls::Certificate> {
+		let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data");
+		pemfile::certs(&mut BufReader::new(File::open(dir.join("receiver.crt")).unwrap())).unwrap()
+	}
+
+	/// Fingerprint of the self-signed certificate test receivers present.
+	pub fn receiver_fingerprint() -> String {
+		cert_fingerprint(&receiver_certs()[0])
+	}
+
+	fn tls_server_config() -> rustls::ServerConfig {
+		let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data");
+		let certs = receiver_certs();
+		let mut keys = pemfile::rsa_private_keys(&mut BufReader::new(
+			File::open(dir.join("receiver.key")).unwrap(),
+		)).unwrap();
+		let mut config = rustls::ServerConfig::new();
+		config.set_single_cert(certs, keys.remove(0));
+		config
+	}
+
+	// Reads a request and answers it, a connection failing before the whole
+	// request is read (a refused handshake for one) is dropped.
+	fn serve<S, F>(
+		stream: &mut S,
+		secret: Option<&String>,
+		respond: &mut F,
+		received: &Mutex<Vec<String>>,
+	) where
+		S: Read + Write,
+		F: FnMut(&str) -> Option<(&'static str, String)>,
+	{
+		let (head, body) = match read_request(stream) {
+			Some(request) => request,
+			None => return,
+		};
+		received.lock().unwrap().push(body.clone());
+		let authorized = match secret {
+			None => true,
+			Some(secret) => request_secret(&head).as_ref() == Some(secret),
+		};
+		let reply = if authorized {
+			respond(&body)
+		} else {
+			Some(("401 Unauthorized", String::new()))
+		};
+		if let Some((status, reply_body)) = reply {
+			write_reply(stream, status, &reply_body);
+		}
+	}
+
+	fn write_reply<S: Write>(stream: &mut S, status: &str, body: &str) {
+		let reply = format!(
+			"HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+			status,
+			body.len(),
+			body
+		);
+		let _ = stream.write_all(reply.as_bytes()).and_then(|_| stream.flush());
+	}
+
+	// The password of the basic authorization header of the request, if any.
+	fn request_secret(head: &str) -> Option<String> {
+		head.lines()
+			.find(|l| l.to_lowercase().starts_with("authorization:"))
+			.and_then(|l| l[14..].trim().trim_left_matches("Basic ").parse::<Basic>().ok())
+			.and_then(|basic| basic.password)
+	}
+
+	fn read_request<S: Read>(stream: &mut S) -> Option<(String, String)> {
+		let mut request = String::new();
+		let mut buf = [0; 1024];
+		loop {
+			let n = match stream.read(&mut buf) {
+				Ok(n) => n,
+				Err(_) => return None,
+			};
+			request.push_str(&String::from_utf8_lossy(&buf[..n]));
+			if let Some(end) = request.find("\r\n\r\n") {
+				let len = request
+					.lines()
+					.find(|l| l.to_lowercase().starts_with("content-length:"))
+					.and_then(|l| l[15..].trim().parse::<usize>().ok())
+					.unwrap_or(0);
+				if n == 0 || request.len() >= end + 4 + len {
+					return Some((request[..end].to_string(), request[end + 4..].to_string()));
+				}
+			} else if n == 0 {
+				return Some((request, String::new()));
+			}
+		}
+	}
+
+	fn partial_tx() -> JSONPartialTx {
+		serde_json::from_str(r#"{"amount":10,"blind_sum":"00","tx":"00"}"#).unwrap()
+	}
+
+	#[test]
+	fn retries_until_receiver_answers() {
+		let mut responses = vec![None, None, Some("200 OK")].into_iter();
+		let (addr, requests) = stub_server(3, move |_| {
+			responses
+				.next()
+				.unwrap()
+				.map(|status| (status, r#"{"tx_hex":"00"}"#.to_string()))
+		});
+		let url = format!("{}/v1/receive/transaction", addr);
+		let response = send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None).unwrap();
+		assert_eq!(response.tx_hex, "00");
+
+		let requests = requests.lock().unwrap();
+		assert_eq!(requests.len(), 3);
+		let json = serde_json::to_string(&partial_tx()).unwrap();
+		assert!(requests.iter().all(|body| *body == json));
+	}
+
+	#[test]
+	fn timed_out_post_not_retried() {
+		let (addr, requests) = stub_server(1, |_| {
+			thread::sleep(Duration::from_secs(3));
+			Some(("200 OK", r#"{"tx_hex":"00"}"#.to_string()))
+		});
+		let url = format!("{}/v1/receive/transaction", addr);
+		let start = Instant::now();
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 1, None) {
+			Err(Error::NodeTimeout(_)) => {}
+			res => panic!("expected NodeTimeout, got {:?}", res),
+		}
+		let elapsed = start.elapsed();
+		assert!(elapsed >= Duration::from_secs(1), "gave up after {:?}", elapsed);
+		assert!(elapsed < Duration::from_millis(2500), "gave up after {:?}", elapsed);
+		assert_eq!(requests.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn rejected_tx_not_retried() {
+		let (addr, requests) = stub_server(1, |_| Some(("400 Bad Request", String::new())));
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(Error::Node(api::Error::Argument(_))) => {}
+			res => panic!("expected the rejection to be returned, got {:?}", res),
+		}
+		assert_eq!(requests.lock().unwrap().len(), 1);
+	}
+
+	fn reply(_: &str) -> Option<(&'static str, String)> {
+		Some(("200 OK", r#"{"tx_hex":"00"}"#.to_string()))
+	}
+
+	#[test]
+	fn self_signed_receiver() {
+		// refused by default, and not retried
+		let (addr, requests) = tls_stub_server(1, reply);
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(Error::Node(api::Error::Argument(msg))) => {
+				assert!(msg.contains("secure connection"), "{}", msg)
+			}
+			res => panic!("expected the certificate to be refused, got {:?}", res),
+		}
+		assert!(requests.lock().unwrap().is_empty());
+
+		// or when another one is pinned
+		let other = "00".repeat(32);
+		let (addr, requests) = tls_stub_server(1, reply);
+		let url = format!("{}/v1/receive/transaction", addr);
+		assert!(send_partial_tx(&url, &partial_tx(), None, 1, 1, 10, Some(&other)).is_err());
+		assert!(requests.lock().unwrap().is_empty());
+
+		// accepted once pinned, as openssl prints it too
+		let fingerprint = receiver_fingerprint();
+		let colons = fingerprint
+			.to_uppercase()
+			.as_bytes()
+			.chunks(2)
+			.map(|pair| String::from_utf8(pair.to_vec()).unwrap())
+			.collect::<Vec<_>>()
+			.join(":");
+		for pinned in vec![fingerprint, colons] {
+			let (addr, requests) = tls_stub_server(1, reply);
+			let url = format!("{}/v1/receive/transaction", addr);
+			let response =
+				send_partial_tx(&url, &partial_tx(), None, 1, 1, 10, Some(&pinned)).unwrap();
+			assert_eq!(response.tx_hex, "00");
+			assert_eq!(requests.lock().unwrap().len(), 1);
+		}
+	}
+
+	#[test]
+	fn receiver_secret() {
+		// the secret goes along with the request
+		let (addr, requests) = auth_stub_server(1, Some("s3cret"), reply);
+		let url = format!("{}/v1/receive/transaction", addr);
+		let response =
+			send_partial_tx(&url, &partial_tx(), Some("s3cret"), 1, 1, 10, None).unwrap();
+		assert_eq!(response.tx_hex, "00");
+		assert_eq!(requests.lock().unwrap().len(), 1);
+
+		// a missing or wrong secret is refused, and not retried
+		for secret in vec![None, Some("wrong")] {
+			let (addr, requests) = auth_stub_server(3, Some("s3cret"), reply);
+			let url = format!("{}/v1/receive/transaction", addr);
+			match send_partial_tx(&url, &partial_tx(), secret, 3, 1, 10, None) {
+				Err(Error::Unauthorized(ref s)) => assert_eq!(*s, url),
+				res => panic!("expected Unauthorized, got {:?}", res),
+			}
+			assert_eq!(requests.lock().unwrap().len(), 1);
+		}
+	}
+
+	#[test]
+	fn version_refused_by_receiver() {
+		let (addr, requests) = stub_server(3, |_| {
+			let mut details = Map::new();
+			details.insert("version".to_string(), Value::from(0));
+			let body = api::ErrorResponse {
+				code: "version_mismatch".to_string(),
+				message: "Unsupported partial transaction version 2".to_string(),
+				details: Value::Object(details),
+			};
+			Some(("400 Bad Request", serde_json::to_string(&body).unwrap()))
+		});
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(Error::VersionMismatch { ours, theirs }) => {
+				assert_eq!(ours, PARTIAL_TX_VERSION);
+				assert_eq!(theirs, 0);
+			}
+			res => panic!("expected VersionMismatch, got {:?}", res),
+		}
+		assert_eq!(requests.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn rejection_reason_returned() {
+		let (addr, requests) = stub_server(3, |_| {
+			let body = api::ErrorResponse {
+				code: "fee_too_low".to_string(),
+				message: "Partial transaction states a fee of 50 but its transaction has 40"
+					.to_string(),
+				details: Value::Null,
+			};
+			Some(("400 Bad Request", serde_json::to_string(&body).unwrap()))
+		});
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(Error::Node(api::Error::Remote {
+				status: 400,
+				ref error,
+			})) => {
+				assert_eq!(error.code, "fee_too_low");
+				assert!(error.message.contains("fee of 50"), "{}", error.message);
+			}
+			res => panic!("expected a fee_too_low error, got {:?}", res),
+		}
+		assert_eq!(requests.lock().unwrap().len(), 1);
+
+		// a failure of the receiver itself isn't retried either
+		let (addr, requests) = stub_server(1, |_| {
+			let body = api::ErrorResponse {
+				code: "wallet_data_error".to_string(),
+				message: "Wallet data error: busy".to_string(),
+				details: Value::Null,
+			};
+			Some(("500 Internal Server Error", serde_json::to_string(&body).unwrap()))
+		});
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(Error::Node(api::Error::Remote {
+				status: 500,
+				ref error,
+			})) => assert_eq!(error.code, "wallet_data_error"),
+			res => panic!("expected a wallet_data_error, got {:?}", res),
+		}
+		assert_eq!(requests.lock().unwrap().len(), 1);
+
+		// nor is one without an error to tell
+		let (addr, requests) =
+			stub_server(1, |_| Some(("503 Service Unavailable", String::new())));
+		let url = format!("{}/v1/receive/transaction", addr);
+		match send_partial_tx(&url, &partial_tx(), None, 3, 1, 10, None) {
+			Err(ref e @ Error::Node(api::Error::Remote { status: 503, .. })) => {
+				assert!(e.is_server_failure())
+			}
+			res => panic!("expected the failure to be returned, got {:?}", res),
+		}
+		assert_eq!(requests.lock().unwrap().len(), 1);
+	}
 }
@@ -0,0 +1,247 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encryption of the wallet data file at rest, with ChaCha20-Poly1305 under
+//! a key derived from a passphrase with PBKDF2. An encrypted file starts with
+//! a header holding what's needed to derive the key again, authenticated
+//! along with the data so any change to the file is detected.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use blake2;
+use byteorder::{BigEndian, ByteOrder};
+use ring::{aead, constant_time, digest, error, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use types::Error;
+
+/// What an encrypted data file starts with, plaintext ones being JSON
+const MAGIC: &'static [u8] = b"GRINENC1";
+
+const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// Magic, PBKDF2 iterations, salt, key check and nonce
+const HEADER_LEN: usize = 8 + 4 + SALT_LEN + CHECK_LEN + NONCE_LEN;
+
+/// PBKDF2 iterations for the keys of newly encrypted files
+const DEFAULT_ITERATIONS: u32 = 100_000;
+
+/// Most PBKDF2 iterations a file can ask for, more is taken as tampering
+/// rather than spending minutes deriving its key
+const MAX_ITERATIONS: u32 = 10_000_000;
+
+lazy_static! {
+	/// Cipher of the data files of the process, set once at startup
+	static ref DATA_CIPHER: RwLock<Option<Arc<DataCipher>>> = RwLock::new(None);
+}
+
+/// Sets the passphrase the wallet data file is encrypted with, for the whole
+/// process. Encrypted files can't be read without it, and plaintext ones get
+/// encrypted the next time they're written.
+pub fn set_data_passphrase(passphrase: Option<&str>) {
+	let mut cipher = DATA_CIPHER.write().unwrap_or_else(|e| e.into_inner());
+	*cipher = passphrase.map(|p| Arc::new(DataCipher::new(p)));
+}
+
+/// Cipher of the data files of the process, if a passphrase was set.
+pub fn data_cipher() -> Option<Arc<DataCipher>> {
+	DATA_CIPHER.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Whether the content of a data file is encrypted.
+pub fn is_encrypted(data: &[u8]) -> bool {
+	data.starts_with(MAGIC)
+}
+
+/// Encrypts and decrypts data files with a passphrase. Deriving a key is
+/// slow on purpose, the keys derived are kept for the salts they were
+/// derived with.
+pub struct DataCipher {
+	passphrase: String,
+	iterations: u32,
+	keys: Mutex<Vec<(Vec<u8>, u32, [u8; KEY_LEN])>>,
+}
+
+impl DataCipher {
+	pub fn new(passphrase: &str) -> DataCipher {
+		DataCipher {
+			passphrase: passphrase.to_string(),
+			iterations: DEFAULT_ITERATIONS,
+			keys: Mutex::new(vec![]),
+		}
+	}
+
+	// The key derived from the passphrase with the salt, for that many
+	// iterations.
+	fn key(&self, salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+		let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(&(_, _, key)) = keys
+			.iter()
+			.find(|&&(ref s, i, _)| &s[..] == salt && i == iterations)
+		{
+			return key;
+		}
+		let mut key = [0; KEY_LEN];
+		pbkdf2::derive(
+			&digest::SHA256,
+			iterations,
+			salt,
+			self.passphrase.as_bytes(),
+			&mut key,
+		);
+		keys.push((salt.to_vec(), iterations, key));
+		key
+	}
+
+	/// Encrypts the content of a data file. The salt of the key is the one
+	/// already in use, if any, so the key isn't derived again for every
+	/// write.
+	pub fn encrypt(&self, plain: &[u8]) -> Result<Vec<u8>, Error> {
+		let rng = SystemRandom::new();
+		let random_failed = |_: error::Unspecified| {
+			Error::WalletData("No randomness to encrypt with".to_string())
+		};
+		let existing = self.keys
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.iter()
+			.find(|&&(_, i, _)| i == self.iterations)
+			.map(|&(ref salt, _, _)| salt.clone());
+		let salt = match existing {
+			Some(salt) => salt,
+			None => {
+				let mut salt = vec![0; SALT_LEN];
+				rng.fill(&mut salt).map_err(&random_failed)?;
+				salt
+			}
+		};
+		let mut nonce = [0; NONCE_LEN];
+		rng.fill(&mut nonce).map_err(&random_failed)?;
+		let key = self.key(&salt, self.iterations);
+
+		let mut header = MAGIC.to_vec();
+		let mut iterations = [0; 4];
+		BigEndian::write_u32(&mut iterations, self.iterations);
+		header.extend_from_slice(&iterations);
+		header.extend_from_slice(&salt);
+		header.extend_from_slice(&key_check(&key));
+		header.extend_from_slice(&nonce);
+
+		let seal_failed = |_: error::Unspecified| {
+			Error::WalletData("Could not encrypt the wallet data".to_string())
+		};
+		let sealing_key =
+			aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key).map_err(&seal_failed)?;
+		let tag_len = aead::CHACHA20_POLY1305.tag_len();
+		let mut in_out = plain.to_vec();
+		in_out.extend(vec![0; tag_len]);
+		let len = aead::seal_in_place(&sealing_key, &nonce, &header, &mut in_out, tag_len)
+			.map_err(&seal_failed)?;
+		in_out.truncate(len);
+
+		header.extend(in_out);
+		Ok(header)
+	}
+
+	/// Decrypts the content of an encrypted data file. The wrong passphrase
+	/// is told apart from a file that was changed in any way.
+	pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+		let tampered = || {
+			Error::WalletData(
+				"Wallet data can't be decrypted, it was tampered with or is corrupted".to_string(),
+			)
+		};
+		if !is_encrypted(data) || data.len() < HEADER_LEN {
+			return Err(tampered());
+		}
+		let (header, sealed) = data.split_at(HEADER_LEN);
+		let iterations = BigEndian::read_u32(&header[8..12]);
+		let salt = &header[12..12 + SALT_LEN];
+		let check = &header[12 + SALT_LEN..12 + SALT_LEN + CHECK_LEN];
+		let nonce = &header[HEADER_LEN - NONCE_LEN..];
+		if iterations == 0 || iterations > MAX_ITERATIONS {
+			return Err(tampered());
+		}
+
+		let key = self.key(salt, iterations);
+		if constant_time::verify_slices_are_equal(&key_check(&key), check).is_err() {
+			return Err(Error::WrongPassphrase);
+		}
+		let opening_key =
+			aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key).map_err(|_| tampered())?;
+		let mut in_out = sealed.to_vec();
+		let plain = aead::open_in_place(&opening_key, nonce, header, 0, &mut in_out)
+			.map_err(|_| tampered())?;
+		Ok(plain.to_vec())
+	}
+}
+
+// Tells whether a key is the right one before trying to decrypt with it.
+fn key_check(key: &[u8]) -> Vec<u8> {
+	blake2::blake2b::blake2b(CHECK_LEN, key, b"grin wallet data key check")
+		.as_bytes()
+		.to_vec()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn cipher(passphrase: &str) -> DataCipher {
+		DataCipher {
+			iterations: 1_000,
+			..DataCipher::new(passphrase)
+		}
+	}
+
+	#[test]
+	fn encrypt_decrypt() {
+		let plain = br#"{"outputs":{}}"#;
+		let encrypted = cipher("s3cret").encrypt(plain).unwrap();
+		assert!(is_encrypted(&encrypted));
+		assert!(!is_encrypted(plain));
+		assert!(!encrypted.windows(plain.len()).any(|w| w == &plain[..]));
+		assert_eq!(cipher("s3cret").decrypt(&encrypted).unwrap(), plain.to_vec());
+
+		// same key, fresh nonce
+		let c = cipher("s3cret");
+		let (a, b) = (c.encrypt(plain).unwrap(), c.encrypt(plain).unwrap());
+		assert_eq!(a[..HEADER_LEN - NONCE_LEN], b[..HEADER_LEN - NONCE_LEN]);
+		assert!(a[HEADER_LEN - NONCE_LEN..] != b[HEADER_LEN - NONCE_LEN..]);
+
+		match cipher("wrong").decrypt(&encrypted) {
+			Err(Error::WrongPassphrase) => {}
+			res => panic!("expected WrongPassphrase, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn tampering_detected() {
+		let encrypted = cipher("s3cret").encrypt(br#"{"outputs":{}}"#).unwrap();
+		// any byte past the magic, or a byte less
+		for i in MAGIC.len()..encrypted.len() {
+			let mut tampered = encrypted.clone();
+			tampered[i] ^= 1;
+			assert!(cipher("s3cret").decrypt(&tampered).is_err(), "byte {}", i);
+		}
+		let truncated = &encrypted[..encrypted.len() - 1];
+		match cipher("s3cret").decrypt(truncated) {
+			Err(Error::WalletData(_)) => {}
+			res => panic!("expected WalletData, got {:?}", res),
+		}
+	}
+}
@@ -12,13 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::max;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use iron::prelude::*;
 use iron::Handler;
+use iron::headers::{Authorization, Basic};
 use iron::status;
+use ring::constant_time;
 use serde_json;
+use serde_json::{Map, Value};
 use bodyparser;
 
-use receiver::receive_coinbase;
+use receiver::{error_response, receive_coinbase};
 use core::ser;
 use api;
 use keychain::Keychain;
@@ -33,10 +42,7 @@ pub struct CoinbaseHandler {
 
 impl CoinbaseHandler {
 	fn build_coinbase(&self, block_fees: &BlockFees) -> Result<CbData, Error> {
-		let (out, kern, block_fees) = receive_coinbase(&self.config, &self.keychain, block_fees)
-			.map_err(|e| {
-				api::Error::Internal(format!("Error building coinbase: {:?}", e))
-			})?;
+		let (out, kern, block_fees) = receive_coinbase(&self.config, &self.keychain, block_fees)?;
 
 		let out_bin = ser::ser_vec(&out).map_err(|e| {
 			api::Error::Internal(format!("Error serializing output: {:?}", e))
@@ -61,22 +67,269 @@ impl CoinbaseHandler {
 	}
 }
 
-// TODO - error handling - what to return if we fail to get the wallet lock for
-// some reason...
+// Failures are answered with an ErrorResponse, a bad request if the block
+// fees don't parse and a server error otherwise (the wallet lock couldn't be
+// acquired, the keychain failed...)
 impl Handler for CoinbaseHandler {
 	fn handle(&self, req: &mut Request) -> IronResult<Response> {
 		let struct_body = req.get::<bodyparser::Struct<BlockFees>>();
 
 		if let Ok(Some(block_fees)) = struct_body {
-			let coinbase = self.build_coinbase(&block_fees)
-				.map_err(|e| IronError::new(e, status::BadRequest))?;
-			if let Ok(json) = serde_json::to_string(&coinbase) {
-				Ok(Response::with((status::Ok, json)))
-			} else {
-				Ok(Response::with((status::BadRequest, "")))
+			let coinbase = match self.build_coinbase(&block_fees) {
+				Ok(coinbase) => coinbase,
+				Err(ref e) => return error_response(e, status::InternalServerError, Value::Null),
+			};
+			let json = serde_json::to_string(&coinbase)
+				.map_err(|e| api::Error::Internal(format!("Error serializing coinbase: {}", e)))?;
+			Ok(Response::with((status::Ok, json)))
+		} else {
+			let err = Error::Format("request body isn't block fees".to_string());
+			error_response(&err, status::BadRequest, Value::Null)
+		}
+	}
+}
+
+/// Only lets requests through to the handler when they carry the secret, as
+/// the password of their basic authorization, others are answered with an
+/// unauthorized ErrorResponse. Without a secret every request goes through.
+pub struct Authenticated<H: Handler> {
+	pub secret: Option<String>,
+	pub handler: H,
+}
+
+impl<H: Handler> Handler for Authenticated<H> {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		if let Some(ref secret) = self.secret {
+			let authorized = match req.headers.get::<Authorization<Basic>>() {
+				Some(&Authorization(Basic {
+					password: Some(ref password),
+					..
+				})) => secret_matches(secret, password),
+				_ => false,
+			};
+			if !authorized {
+				let err = Error::Unauthorized(format!("/{}", req.url.path().join("/")));
+				let mut response = error_response(&err, status::Unauthorized, Value::Null)?;
+				response
+					.headers
+					.set_raw("WWW-Authenticate", vec![b"Basic realm=\"grin wallet\"".to_vec()]);
+				return Ok(response);
 			}
+		}
+		self.handler.handle(req)
+	}
+}
+
+/// Whether the secret given matches ours, compared in constant time so the
+/// time it takes doesn't tell how much of it is right.
+pub fn secret_matches(secret: &str, given: &str) -> bool {
+	constant_time::verify_slices_are_equal(secret.as_bytes(), given.as_bytes()).is_ok()
+}
+
+/// Most addresses a RateLimiter keeps a bucket for, past that the buckets
+/// that filled up again are dropped.
+const MAX_TRACKED_ADDRS: usize = 10_000;
+
+/// Token buckets limiting how many requests are taken per minute from each
+/// address and from all of them together. A bucket holds up to a minute
+/// worth of requests, refilled continuously, so short bursts go through. A
+/// rate of 0 doesn't limit anything.
+pub struct RateLimiter {
+	per_addr: u32,
+	global: u32,
+	buckets: Mutex<RateBuckets>,
+}
+
+struct RateBuckets {
+	global: Bucket,
+	per_addr: HashMap<IpAddr, Bucket>,
+}
+
+#[derive(Clone)]
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+impl Bucket {
+	fn full(rate: u32, now: Instant) -> Bucket {
+		Bucket {
+			tokens: rate as f64,
+			updated: now,
+		}
+	}
+
+	// Tops up the bucket for the time elapsed, then tells how long until it
+	// holds a token, none if it already does.
+	fn wait(&mut self, rate: u32, now: Instant) -> Option<Duration> {
+		let elapsed = now.duration_since(self.updated);
+		let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+		self.tokens = (self.tokens + elapsed * rate as f64 / 60.0).min(rate as f64);
+		self.updated = now;
+		if self.tokens >= 1.0 {
+			None
 		} else {
-			Ok(Response::with((status::BadRequest, "")))
+			let secs = (1.0 - self.tokens) * 60.0 / rate as f64;
+			Some(Duration::from_millis((secs * 1000.0).ceil() as u64))
+		}
+	}
+}
+
+impl RateLimiter {
+	/// Limits requests to per_addr a minute from each address and global a
+	/// minute in total.
+	pub fn new(per_addr: u32, global: u32) -> RateLimiter {
+		let now = Instant::now();
+		RateLimiter {
+			per_addr: per_addr,
+			global: global,
+			buckets: Mutex::new(RateBuckets {
+				global: Bucket::full(global, now),
+				per_addr: HashMap::new(),
+			}),
+		}
+	}
+
+	/// Takes a request from the address, or tells how long until one would
+	/// be taken. A refused request doesn't count against any limit.
+	pub fn check(&self, addr: IpAddr) -> Result<(), Duration> {
+		self.check_at(addr, Instant::now())
+	}
+
+	fn check_at(&self, addr: IpAddr, now: Instant) -> Result<(), Duration> {
+		let (per_addr, global) = (self.per_addr, self.global);
+		let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+		let RateBuckets {
+			global: ref mut global_bucket,
+			per_addr: ref mut addr_buckets,
+		} = *buckets;
+
+		if per_addr > 0 && !addr_buckets.contains_key(&addr) {
+			if addr_buckets.len() >= MAX_TRACKED_ADDRS {
+				addr_buckets.retain(|_, bucket| {
+					bucket.wait(per_addr, now);
+					bucket.tokens < per_addr as f64
+				});
+			}
+			addr_buckets.insert(addr, Bucket::full(per_addr, now));
+		}
+		let mut addr_bucket = addr_buckets.get_mut(&addr);
+		let addr_wait = addr_bucket
+			.as_mut()
+			.and_then(|bucket| bucket.wait(per_addr, now));
+		let global_wait = if global > 0 {
+			global_bucket.wait(global, now)
+		} else {
+			None
+		};
+		match (addr_wait, global_wait) {
+			(None, None) => {}
+			(Some(wait), None) | (None, Some(wait)) => return Err(wait),
+			(Some(a), Some(b)) => return Err(max(a, b)),
+		}
+		if let Some(bucket) = addr_bucket {
+			bucket.tokens -= 1.0;
+		}
+		if global > 0 {
+			global_bucket.tokens -= 1.0;
+		}
+		Ok(())
+	}
+}
+
+/// Only lets requests through to the handler while the limiter takes them,
+/// others are answered with a too_many_requests ErrorResponse telling, in
+/// its details and a Retry-After header, when to try again. Requests from
+/// localhost aren't limited, unless limit_localhost is set.
+pub struct RateLimited<H: Handler> {
+	pub limiter: Arc<RateLimiter>,
+	pub limit_localhost: bool,
+	pub handler: H,
+}
+
+impl<H: Handler> Handler for RateLimited<H> {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		let ip = req.remote_addr.ip();
+		if self.limit_localhost || !ip.is_loopback() {
+			if let Err(wait) = self.limiter.check(ip) {
+				let retry_after = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+				let err = Error::TooManyRequests {
+					retry_after_secs: retry_after,
+				};
+				let mut details = Map::new();
+				details.insert("retry_after_secs".to_string(), Value::from(retry_after));
+				let mut response =
+					error_response(&err, status::TooManyRequests, Value::Object(details))?;
+				response
+					.headers
+					.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+				return Ok(response);
+			}
+		}
+		self.handler.handle(req)
+	}
+}
+
+/// Answers anyone, so monitoring can tell the receiver is up without the
+/// secret.
+pub struct PingHandler;
+
+impl Handler for PingHandler {
+	fn handle(&self, _req: &mut Request) -> IronResult<Response> {
+		Ok(Response::with((status::Ok, "{}")))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn rate_limited() {
+		let limiter = RateLimiter::new(3, 5);
+		let start = Instant::now();
+		let (a, b, c) = (
+			"10.0.0.1".parse().unwrap(),
+			"10.0.0.2".parse().unwrap(),
+			"10.0.0.3".parse().unwrap(),
+		);
+
+		// a burst of 3 from one address, the next ones are refused until a
+		// token comes back, 20s later at 3 a minute
+		for _ in 0..3 {
+			limiter.check_at(a, start).unwrap();
+		}
+		for _ in 0..10 {
+			assert_eq!(limiter.check_at(a, start), Err(Duration::from_secs(20)));
+		}
+
+		// others still get through, up to the global limit
+		limiter.check_at(b, start).unwrap();
+		limiter.check_at(b, start).unwrap();
+		assert_eq!(limiter.check_at(c, start), Err(Duration::from_secs(12)));
+
+		// the refused requests didn't cost anything, halfway there it's 10s
+		// more
+		let later = start + Duration::from_secs(10);
+		assert_eq!(limiter.check_at(a, later), Err(Duration::from_secs(10)));
+		let later = start + Duration::from_secs(30);
+		limiter.check_at(a, later).unwrap();
+		limiter.check_at(c, later).unwrap();
+		assert!(limiter.check_at(b, later).is_err());
+
+		// no limit at all
+		let limiter = RateLimiter::new(0, 0);
+		for _ in 0..1_000 {
+			limiter.check_at(a, start).unwrap();
+		}
+	}
+
+	#[test]
+	fn secrets_compared() {
+		assert!(secret_matches("s3cret", "s3cret"));
+		assert!(secret_matches("", ""));
+		for given in vec!["", "s3cre", "s3cret ", "S3cret", "s3creT", "wrong!"] {
+			assert!(!secret_matches("s3cret", given), "{:?}", given);
 		}
 	}
 }
@@ -12,16 +12,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use serde_json;
+
+use amount::format_amount;
 use checker;
-use keychain::Keychain;
-use types::{WalletConfig, WalletData};
+use keychain::{Identifier, Keychain};
+use timestamp::Timestamp;
+use types::{Error, ExportFormat, HistoryEntry, HistoryKind, OutputData, OutputFilter, RxLogEntry,
+            TxLogEntry, TxLogStatus, ValueBucket, WalletConfig, WalletData};
 
-pub fn show_info(config: &WalletConfig, keychain: &Keychain) {
+/// Prints the outputs and balance of the wallet, the outputs moved to the
+/// archive by WalletData::prune included if include_archive is set.
+pub fn show_info(config: &WalletConfig, keychain: &Keychain, include_archive: bool) {
+	let config = &match config.for_keychain(keychain) {
+		Ok(config) => config,
+		Err(e) => {
+			println!("Could not open the wallet data: {}", e);
+			return;
+		}
+	};
 	let root_key_id = keychain.root_key_id();
-	let _ = checker::refresh_outputs(&config, &keychain);
+	if let Ok(reorg) = checker::refresh_outputs(&config, &keychain) {
+		if let Some(fork_height) = reorg.fork_height {
+			println!(
+				"Chain reorganized from height {}, {} outputs unconfirmed and {} unspent again",
+				fork_height,
+				reorg.unconfirmed.len(),
+				reorg.unspent.len()
+			);
+		}
+	}
 
 	// just read the wallet here, no need for a write lock
-	let _ = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+	let data_file_dir = &config.data_file_dir;
+	let _ = WalletData::read_wallet_with_archive(data_file_dir, include_archive, |wallet_data| {
 		// get the current height via the api
   // if we cannot get the current height use the max height known to the wallet
 		let current_height = match checker::get_tip_from_node(config) {
@@ -36,13 +64,8 @@ pub fn show_info(config: &WalletConfig, keychain: &Keychain) {
 		println!("key_id, height, lock_height, status, coinbase?, num_confs, value");
 		println!("----------------------------------");
 
-		let mut outputs = wallet_data
-			.outputs
-			.values()
-			.filter(|out| out.root_key_id == root_key_id)
-			.collect::<Vec<_>>();
-		outputs.sort_by_key(|out| out.n_child);
-		for out in outputs {
+		let outputs = wallet_data.query(&OutputFilter::fingerprint(root_key_id.clone()));
+		for out in outputs.outputs {
 			println!(
 				"{}, {}, {}, {:?}, {}, {}, {}",
 				out.key_id,
@@ -54,5 +77,684 @@ pub fn show_info(config: &WalletConfig, keychain: &Keychain) {
 				out.value,
 			);
 		}
+
+		let balance = wallet_data.balance(
+			Some(root_key_id.clone()),
+			current_height,
+			config.minimum_confirmations,
+		);
+		println!("----------------------------------");
+		println!(
+			"Spendable: {}, awaiting confirmation: {}, immature coinbase: {}, locked until a \
+			 later height: {}, locked by sends: {}, total: {}",
+			format_amount(balance.spendable),
+			format_amount(balance.awaiting_confirmation),
+			format_amount(balance.immature_coinbase),
+			format_amount(balance.height_locked),
+			format_amount(balance.locked),
+			format_amount(balance.total),
+		);
+		let stats = wallet_data.stats(
+			Some(root_key_id.clone()),
+			current_height,
+			config.minimum_confirmations,
+		);
+		println!("Outputs by value - {}", format_buckets(&stats.buckets));
+		if !config.receive_accounts.is_empty() {
+			println!(
+				"Default account: {}",
+				format_amount(wallet_data.account_total(root_key_id.clone(), None))
+			);
+			for account in &config.receive_accounts {
+				println!(
+					"Account {}: {}",
+					account,
+					format_amount(wallet_data.account_total(root_key_id.clone(), Some(account)))
+				);
+			}
+		}
+	});
+}
+
+/// Lists the sends recorded in the wallet transaction log, oldest first.
+pub fn read_tx_log(config: &WalletConfig) -> Result<Vec<TxLogEntry>, Error> {
+	WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		let mut entries = wallet_data.tx_log.clone();
+		entries.sort_by_key(|entry| entry.id);
+		entries
+	})
+}
+
+/// Lists the payments recorded in the wallet receive log, oldest first.
+pub fn read_rx_log(config: &WalletConfig) -> Result<Vec<RxLogEntry>, Error> {
+	WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		let mut entries = wallet_data.rx_log.clone();
+		entries.sort_by_key(|entry| entry.id);
+		entries
+	})
+}
+
+/// Writes the transaction log to a file, for audits, oldest send first. The
+/// partial transactions kept to resend are left out.
+pub fn export_tx_log(
+	config: &WalletConfig,
+	path: &Path,
+	format: ExportFormat,
+) -> Result<(), Error> {
+	let entries = read_tx_log(config)?;
+	let exported = format_tx_log(entries, format)?;
+	File::create(path)?.write_all(exported.as_bytes())?;
+	Ok(())
+}
+
+fn format_tx_log(entries: Vec<TxLogEntry>, format: ExportFormat) -> Result<String, Error> {
+	match format {
+		ExportFormat::Json => {
+			let entries = entries
+				.into_iter()
+				.map(|entry| TxLogEntry {
+					partial_tx: None,
+					..entry
+				})
+				.collect::<Vec<_>>();
+			Ok(serde_json::to_string_pretty(&entries)?)
+		}
+		ExportFormat::Csv => {
+			let mut csv = "id,created_at,status,amount,fee,dest,contact,tip_height,receiver_ack,\
+			               message,input_ids,change_ids\n"
+				.to_string();
+			for entry in entries {
+				let fields = vec![
+					entry.id.to_string(),
+					entry.created_at.to_string(),
+					format!("{:?}", entry.status),
+					entry.amount.to_string(),
+					entry.fee.to_string(),
+					entry.dest,
+					entry.contact.unwrap_or_default(),
+					entry.tip_height.to_string(),
+					entry.receiver_ack.unwrap_or_default(),
+					entry.message.unwrap_or_default(),
+					join_ids(&entry.input_ids),
+					join_ids(&entry.change_ids),
+				];
+				let fields = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+				csv.push_str(&fields.join(","));
+				csv.push('\n');
+			}
+			Ok(csv)
+		}
+	}
+}
+
+/// Writes the receive log to a file, for audits, oldest payment first.
+pub fn export_rx_log(
+	config: &WalletConfig,
+	path: &Path,
+	format: ExportFormat,
+) -> Result<(), Error> {
+	let entries = read_rx_log(config)?;
+	let exported = format_rx_log(entries, format)?;
+	File::create(path)?.write_all(exported.as_bytes())?;
+	Ok(())
+}
+
+fn format_rx_log(entries: Vec<RxLogEntry>, format: ExportFormat) -> Result<String, Error> {
+	match format {
+		ExportFormat::Json => Ok(serde_json::to_string_pretty(&entries)?),
+		ExportFormat::Csv => {
+			let mut csv = "id,created_at,confirmed,amount,key_id,sender,message\n".to_string();
+			for entry in entries {
+				let fields = vec![
+					entry.id.to_string(),
+					entry.created_at.to_string(),
+					entry.confirmed.to_string(),
+					entry.amount.to_string(),
+					entry.key_id.to_hex(),
+					entry.sender.unwrap_or_default(),
+					entry.message.unwrap_or_default(),
+				];
+				let fields = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+				csv.push_str(&fields.join(","));
+				csv.push('\n');
+			}
+			Ok(csv)
+		}
+	}
+}
+
+/// Writes the outputs of the wallet matching the filter to a file, for
+/// accounting, in the order of WalletData::query.
+pub fn export_outputs(
+	config: &WalletConfig,
+	path: &Path,
+	filter: &OutputFilter,
+	format: ExportFormat,
+) -> Result<(), Error> {
+	let outputs = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data
+			.query(filter)
+			.outputs
+			.into_iter()
+			.cloned()
+			.collect::<Vec<_>>()
+	})?;
+	let exported = format_outputs(outputs, format)?;
+	File::create(path)?.write_all(exported.as_bytes())?;
+	Ok(())
+}
+
+fn format_outputs(outputs: Vec<OutputData>, format: ExportFormat) -> Result<String, Error> {
+	match format {
+		ExportFormat::Json => Ok(serde_json::to_string_pretty(&outputs)?),
+		ExportFormat::Csv => {
+			let mut csv = "key_id,root_key_id,n_child,amount,value,status,height,lock_height,\
+			               is_coinbase,locked_at,account,created_at,confirmed_at,spent_at,label\n"
+				.to_string();
+			for out in outputs {
+				let fields = vec![
+					out.key_id.to_hex(),
+					out.root_key_id.to_hex(),
+					out.n_child.to_string(),
+					format_amount(out.value),
+					out.value.to_string(),
+					out.status.to_string(),
+					out.height.to_string(),
+					out.lock_height.to_string(),
+					out.is_coinbase.to_string(),
+					out.locked_at.to_string(),
+					out.account.unwrap_or_default(),
+					format_time(out.created_at),
+					format_time(out.confirmed_at),
+					format_time(out.spent_at),
+					out.label.unwrap_or_default(),
+				];
+				let fields = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+				csv.push_str(&fields.join(","));
+				csv.push('\n');
+			}
+			Ok(csv)
+		}
+	}
+}
+
+/// The sends and the payments received by the wallet together, oldest
+/// first.
+pub fn read_history(config: &WalletConfig) -> Result<Vec<HistoryEntry>, Error> {
+	let (tx_log, rx_log) = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		(wallet_data.tx_log.clone(), wallet_data.rx_log.clone())
+	})?;
+	Ok(history(tx_log, rx_log))
+}
+
+fn history(tx_log: Vec<TxLogEntry>, rx_log: Vec<RxLogEntry>) -> Vec<HistoryEntry> {
+	let sent = tx_log.into_iter().map(|entry| HistoryEntry {
+		kind: HistoryKind::Sent,
+		id: entry.id,
+		created_at: entry.created_at,
+		status: entry.status.to_string(),
+		amount: entry.amount,
+		fee: entry.fee,
+		counterparty: entry.dest,
+		message: entry.message,
+		key_ids: entry.input_ids.into_iter().chain(entry.change_ids).collect(),
+	});
+	let received = rx_log.into_iter().map(|entry| HistoryEntry {
+		kind: HistoryKind::Received,
+		id: entry.id,
+		created_at: entry.created_at,
+		status: if entry.confirmed {
+			TxLogStatus::Confirmed.to_string()
+		} else {
+			TxLogStatus::Pending.to_string()
+		},
+		amount: entry.amount,
+		fee: 0,
+		counterparty: entry.sender.unwrap_or_default(),
+		message: entry.message,
+		key_ids: vec![entry.key_id],
 	});
+	let mut entries = sent.chain(received).collect::<Vec<_>>();
+	entries.sort_by_key(|entry| (entry.created_at, entry.kind == HistoryKind::Received, entry.id));
+	entries
+}
+
+/// Writes the history of the wallet, sends and payments received, to a
+/// file, oldest first.
+pub fn export_history(
+	config: &WalletConfig,
+	path: &Path,
+	format: ExportFormat,
+) -> Result<(), Error> {
+	let entries = read_history(config)?;
+	let exported = format_history(entries, format)?;
+	File::create(path)?.write_all(exported.as_bytes())?;
+	Ok(())
+}
+
+fn format_history(entries: Vec<HistoryEntry>, format: ExportFormat) -> Result<String, Error> {
+	match format {
+		ExportFormat::Json => Ok(serde_json::to_string_pretty(&entries)?),
+		ExportFormat::Csv => {
+			let mut csv = "kind,id,created_at,status,amount,value,fee,counterparty,message,\
+			               key_ids\n"
+				.to_string();
+			for entry in entries {
+				let fields = vec![
+					format!("{:?}", entry.kind),
+					entry.id.to_string(),
+					entry.created_at.to_string(),
+					entry.status,
+					format_amount(entry.amount),
+					entry.amount.to_string(),
+					entry.fee.to_string(),
+					entry.counterparty,
+					entry.message.unwrap_or_default(),
+					join_ids(&entry.key_ids),
+				];
+				let fields = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>();
+				csv.push_str(&fields.join(","));
+				csv.push('\n');
+			}
+			Ok(csv)
+		}
+	}
+}
+
+// A timestamp of an output as RFC 3339, empty if unknown.
+// The count and total value of the outputs of each bucket of values, for
+// show_info ("dust: 3 (0.0004), below 0.1: 0 (0), ..., 10 and more: 1 (25)").
+fn format_buckets(buckets: &Vec<ValueBucket>) -> String {
+	buckets
+		.iter()
+		.enumerate()
+		.map(|(i, bucket)| {
+			let range = match bucket.below {
+				_ if i == 0 => "dust".to_string(),
+				Some(below) => format!("below {}", format_amount(below)),
+				None => format!("{} and more", format_amount(bucket.from)),
+			};
+			format!("{}: {} ({})", range, bucket.count, format_amount(bucket.value))
+		})
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+fn format_time(timestamp: Option<Timestamp>) -> String {
+	timestamp.map(|t| t.to_rfc3339()).unwrap_or_default()
+}
+
+fn join_ids(ids: &Vec<Identifier>) -> String {
+	ids.iter()
+		.map(|id| id.to_hex())
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+// Quotes a field holding a separator, a quote or a line break, doubling its
+// quotes.
+fn csv_field(field: &str) -> String {
+	if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+		format!("\"{}\"", field.replace('"', "\"\""))
+	} else {
+		field.to_string()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs;
+	use std::io::Read;
+	use std::mem;
+
+	use amount::parse_amount;
+	use types::OutputStatus;
+	use super::*;
+
+	fn entries() -> Vec<TxLogEntry> {
+		let sent = TxLogEntry {
+			id: 1,
+			created_at: 1_500_000_000,
+			amount: 1_000,
+			fee: 10,
+			dest: "http://127.0.0.1:13415".to_string(),
+			input_ids: vec![Identifier::from_bytes(&[1]), Identifier::from_bytes(&[2])],
+			change_ids: vec![Identifier::from_bytes(&[3])],
+			status: TxLogStatus::Confirmed,
+			message: Some("rent, \"march\"".to_string()),
+			partial_tx: None,
+			contact: Some("landlord".to_string()),
+			tip_height: 120,
+			receiver_ack: Some("ab".repeat(32)),
+			receiver_commit: None,
+		};
+		let to_file = TxLogEntry {
+			id: 2,
+			dest: "/tmp/out.json".to_string(),
+			input_ids: vec![Identifier::from_bytes(&[4])],
+			change_ids: vec![],
+			status: TxLogStatus::Pending,
+			message: None,
+			contact: None,
+			tip_height: 0,
+			receiver_ack: None,
+			..sent.clone()
+		};
+		vec![sent, to_file]
+	}
+
+	#[test]
+	fn export_csv() {
+		let csv = format_tx_log(entries(), ExportFormat::Csv).unwrap();
+		let lines = csv.lines().collect::<Vec<_>>();
+		assert_eq!(lines.len(), 3);
+		assert_eq!(
+			lines[0],
+			"id,created_at,status,amount,fee,dest,contact,tip_height,receiver_ack,message,\
+			 input_ids,change_ids"
+		);
+		assert_eq!(
+			lines[1],
+			format!(
+				"1,1500000000,Confirmed,1000,10,http://127.0.0.1:13415,landlord,120,{},\
+				 \"rent, \"\"march\"\"\",{} {},{}",
+				"ab".repeat(32),
+				Identifier::from_bytes(&[1]).to_hex(),
+				Identifier::from_bytes(&[2]).to_hex(),
+				Identifier::from_bytes(&[3]).to_hex()
+			)
+		);
+		assert_eq!(
+			lines[2],
+			format!(
+				"2,1500000000,Pending,1000,10,/tmp/out.json,,0,,,{},",
+				Identifier::from_bytes(&[4]).to_hex()
+			)
+		);
+	}
+
+	#[test]
+	fn export_json() {
+		let mut config = WalletConfig::default();
+		let dir = env::temp_dir().join("grin_wallet_export");
+		let _ = fs::remove_dir_all(&dir);
+		config.data_file_dir = dir.to_str().unwrap().to_string();
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.tx_log.extend(entries());
+		}).unwrap();
+
+		let path = dir.join("tx_log.json");
+		export_tx_log(&config, &path, ExportFormat::Json).unwrap();
+		let mut json = String::new();
+		File::open(&path).unwrap().read_to_string(&mut json).unwrap();
+		let exported: Vec<TxLogEntry> = serde_json::from_str(&json).unwrap();
+		assert_eq!(exported, entries());
+		assert!(!json.contains("partial_tx"));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn export_rx_csv() {
+		let received = RxLogEntry {
+			id: 1,
+			created_at: 1_500_000_000,
+			amount: 990,
+			key_id: Identifier::from_bytes(&[5]),
+			message: Some("order 7, thanks".to_string()),
+			sender: Some("10.0.0.2:51000".to_string()),
+			confirmed: true,
+			account: None,
+		};
+		let from_file = RxLogEntry {
+			id: 2,
+			message: None,
+			sender: None,
+			confirmed: false,
+			..received.clone()
+		};
+		let csv = format_rx_log(vec![received, from_file], ExportFormat::Csv).unwrap();
+		let key_id = Identifier::from_bytes(&[5]).to_hex();
+		assert_eq!(
+			csv.lines().collect::<Vec<_>>(),
+			vec![
+				"id,created_at,confirmed,amount,key_id,sender,message".to_string(),
+				format!(
+					"1,1500000000,true,990,{},10.0.0.2:51000,\"order 7, thanks\"",
+					key_id
+				),
+				format!("2,1500000000,false,990,{},,", key_id),
+			]
+		);
+	}
+
+	// Splits CSV into its records of fields, the way RFC 4180 has it.
+	fn parse_csv(csv: &str) -> Vec<Vec<String>> {
+		let mut records = vec![];
+		let mut record = vec![];
+		let mut field = String::new();
+		let mut quoted = false;
+		let mut chars = csv.chars().peekable();
+		while let Some(c) = chars.next() {
+			match c {
+				'"' if quoted => if chars.peek() == Some(&'"') {
+					chars.next();
+					field.push('"');
+				} else {
+					quoted = false;
+				},
+				'"' if field.is_empty() => quoted = true,
+				',' if !quoted => record.push(mem::replace(&mut field, String::new())),
+				'\n' if !quoted => {
+					record.push(mem::replace(&mut field, String::new()));
+					records.push(mem::replace(&mut record, vec![]));
+				}
+				c => field.push(c),
+			}
+		}
+		assert!(!quoted && record.is_empty() && field.is_empty());
+		records
+	}
+
+	fn outputs() -> Vec<OutputData> {
+		let received = OutputData {
+			root_key_id: Identifier::zero(),
+			key_id: Identifier::from_bytes(&[1]),
+			n_child: 1,
+			value: 1_250_000_000,
+			status: OutputStatus::Unspent,
+			height: 5,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: Some("shop, \"north\"\nbranch".to_string()),
+			created_at: Some(Timestamp(1_500_000_000)),
+			confirmed_at: Some(Timestamp(1_500_000_600)),
+			spent_at: None,
+			tx_id: None,
+			label: Some("rent, \"March\"".to_string()),
+			watch_commit: None,
+		};
+		let coinbase = OutputData {
+			key_id: Identifier::from_bytes(&[2]),
+			n_child: 2,
+			value: 60_000_000_000,
+			status: OutputStatus::Unconfirmed,
+			height: 7,
+			block_hash: None,
+			lock_height: 1_007,
+			is_coinbase: true,
+			account: None,
+			confirmed_at: None,
+			label: None,
+			..received.clone()
+		};
+		let spent = OutputData {
+			key_id: Identifier::from_bytes(&[3]),
+			n_child: 3,
+			value: 1,
+			status: OutputStatus::Spent,
+			locked_at: 9,
+			account: None,
+			spent_at: Some(Timestamp(1_500_086_400)),
+			..received.clone()
+		};
+		vec![received, coinbase, spent]
+	}
+
+	#[test]
+	fn export_outputs_csv() {
+		let records = parse_csv(&format_outputs(outputs(), ExportFormat::Csv).unwrap());
+		assert_eq!(
+			records[0],
+			vec![
+				"key_id",
+				"root_key_id",
+				"n_child",
+				"amount",
+				"value",
+				"status",
+				"height",
+				"lock_height",
+				"is_coinbase",
+				"locked_at",
+				"account",
+				"created_at",
+				"confirmed_at",
+				"spent_at",
+				"label",
+			]
+		);
+		assert_eq!(records.len(), 4);
+		for (record, out) in records[1..].iter().zip(outputs()) {
+			assert_eq!(record.len(), 15);
+			assert_eq!(record[0], out.key_id.to_hex());
+			assert_eq!(record[1], out.root_key_id.to_hex());
+			assert_eq!(record[2].parse::<u32>().unwrap(), out.n_child);
+			assert_eq!(parse_amount(&record[3]).unwrap(), out.value);
+			assert_eq!(record[4].parse::<u64>().unwrap(), out.value);
+			assert_eq!(record[5], out.status.to_string());
+			assert_eq!(record[6].parse::<u64>().unwrap(), out.height);
+			assert_eq!(record[7].parse::<u64>().unwrap(), out.lock_height);
+			assert_eq!(record[8].parse::<bool>().unwrap(), out.is_coinbase);
+			assert_eq!(record[9].parse::<u64>().unwrap(), out.locked_at);
+			assert_eq!(record[10], out.account.unwrap_or_default());
+			assert_eq!(record[11], "2017-07-14T02:40:00Z");
+			assert_eq!(record[14], out.label.unwrap_or_default());
+		}
+		assert_eq!(records[1][12], "2017-07-14T02:50:00Z");
+		assert_eq!((records[2][12].as_str(), records[2][13].as_str()), ("", ""));
+		assert_eq!(records[3][13], "2017-07-15T02:40:00Z");
+	}
+
+	#[test]
+	fn export_outputs_json() {
+		let mut config = WalletConfig::default();
+		let dir = env::temp_dir().join("grin_wallet_export_outputs");
+		let _ = fs::remove_dir_all(&dir);
+		config.data_file_dir = dir.to_str().unwrap().to_string();
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for out in outputs() {
+				wallet_data.add_output(out).unwrap();
+			}
+		}).unwrap();
+
+		let path = dir.join("outputs.json");
+		let filter = OutputFilter {
+			status: Some(OutputStatus::Spent),
+			label: Some("rent".to_string()),
+			..OutputFilter::default()
+		};
+		export_outputs(&config, &path, &filter, ExportFormat::Json).unwrap();
+		let mut json = String::new();
+		File::open(&path).unwrap().read_to_string(&mut json).unwrap();
+		let exported: Vec<OutputData> = serde_json::from_str(&json).unwrap();
+		assert_eq!(exported.len(), 1);
+		assert_eq!(
+			serde_json::to_value(&exported[0]).unwrap(),
+			serde_json::to_value(&outputs()[2]).unwrap()
+		);
+		assert_eq!(exported[0].label, Some("rent, \"March\"".to_string()));
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn export_history_csv() {
+		let received = RxLogEntry {
+			id: 1,
+			created_at: 1_500_000_000,
+			amount: 990,
+			key_id: Identifier::from_bytes(&[5]),
+			message: Some("invoice \"12\", paid".to_string()),
+			sender: None,
+			confirmed: true,
+			account: None,
+		};
+		let later = RxLogEntry {
+			id: 2,
+			created_at: 1_600_000_000,
+			message: None,
+			confirmed: false,
+			..received.clone()
+		};
+		let entries = history(entries(), vec![later, received]);
+		let records = parse_csv(&format_history(entries.clone(), ExportFormat::Csv).unwrap());
+		assert_eq!(
+			records[0],
+			vec![
+				"kind",
+				"id",
+				"created_at",
+				"status",
+				"amount",
+				"value",
+				"fee",
+				"counterparty",
+				"message",
+				"key_ids",
+			]
+		);
+
+		// sends first within the same second
+		let rows = records[1..]
+			.iter()
+			.map(|r| (r[0].as_str(), r[1].as_str(), r[3].as_str(), r[8].as_str()))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			rows,
+			vec![
+				("Sent", "1", "Confirmed", "rent, \"march\""),
+				("Sent", "2", "Pending", ""),
+				("Received", "1", "Confirmed", "invoice \"12\", paid"),
+				("Received", "2", "Pending", ""),
+			]
+		);
+		for (record, entry) in records[1..].iter().zip(entries) {
+			assert_eq!(parse_amount(&record[4]).unwrap(), entry.amount);
+			assert_eq!(record[5].parse::<u64>().unwrap(), entry.amount);
+			assert_eq!(record[6].parse::<u64>().unwrap(), entry.fee);
+			assert_eq!(record[7], entry.counterparty);
+			assert_eq!(record[9], join_ids(&entry.key_ids));
+		}
+	}
+
+	#[test]
+	fn buckets_formatted() {
+		let bucket = |from, below, count, value| ValueBucket {
+			from: from,
+			below: below,
+			count: count,
+			value: value,
+		};
+		let buckets = vec![
+			bucket(0, Some(1_000_000), 3, 400_000),
+			bucket(1_000_000, Some(100_000_000), 0, 0),
+			bucket(10_000_000_000, None, 1, 25_000_000_000),
+		];
+		assert_eq!(
+			format_buckets(&buckets),
+			"dust: 3 (0.0004), below 0.1: 0 (0), 10 and more: 1 (25)"
+		);
+	}
 }
@@ -14,40 +14,74 @@
 
 //! Library module for the main wallet functionalities provided by Grin.
 
+extern crate base64;
 extern crate blake2_rfc as blake2;
 extern crate byteorder;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
 extern crate rand;
+extern crate ring;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 #[macro_use]
 extern crate slog;
+extern crate time;
 
 extern crate bodyparser;
 extern crate futures;
 extern crate hyper;
+extern crate hyper_rustls;
 extern crate iron;
 #[macro_use]
 extern crate router;
+extern crate rustls;
 extern crate tokio_core;
 extern crate tokio_retry;
+extern crate urlencoded;
+extern crate webpki;
+extern crate webpki_roots;
 
 extern crate grin_api as api;
 extern crate grin_core as core;
 extern crate grin_keychain as keychain;
 extern crate grin_util as util;
 
+mod amount;
 mod checker;
+mod encryption;
 mod handlers;
 mod info;
 mod receiver;
+mod restore;
 mod sender;
+mod timestamp;
 mod types;
+mod webhook;
 pub mod client;
 pub mod server;
 
-pub use info::show_info;
-pub use receiver::{receive_json_tx, receive_json_tx_str, WalletReceiver};
-pub use sender::{issue_burn_tx, issue_send_tx};
-pub use types::{BlockFees, CbData, Error, WalletConfig, WalletReceiveRequest, WalletSeed};
+pub use amount::{format_amount, parse_amount};
+pub use encryption::set_data_passphrase;
+pub use info::{export_history, export_outputs, export_rx_log, export_tx_log, read_history,
+               read_rx_log, read_tx_log, show_info};
+pub use receiver::{issue_invoice, receive_from_file, receive_invoice_payment, receive_json_tx,
+                   receive_json_tx_from, receive_json_tx_str, InvoiceReceiver, WalletReceiver};
+pub use restore::{restore, RestoreSummary};
+pub use sender::{abort_send, build_send_tx, cancel_send, consolidate, estimate_fee,
+                 finalize_from_file, issue_batch_send, issue_burn_tx, issue_send_tx, pay_invoice,
+                 post_send, prepare_send, preview_send_tx, resend, TxBuilder};
+pub use types::{set_data_lock_max_age, ArchivedIds, Balance, BlockFees, CbData, DedupeSummary,
+                Error, ExportFormat, FeeEstimate, FeePaidBy, HistoryEntry, HistoryKind, Invoice,
+                JSONInvoice, JSONPartialTx, MergedOutput, OutputData, OutputExport, OutputFilter,
+                OutputQuery, OutputStats, OutputStatus, PendingSend, PruneSummary,
+                ReceiveNotification, ReceiveResponse, RxLogEntry, Selection, SelectionError,
+                SelectionStrategy, SendAmount, SendEstimate, SendLock, SendResult, SendSummary,
+                Spendability, TxDirection, TxLogEntry, TxLogStatus, ValueBucket, WalletBackup,
+                WalletConfig, WalletData, WalletReceiveRequest, WalletSeed, WalletTransaction,
+                COMPACT_PARTIAL_TX_PREFIX, DUST_VALUE, MAX_LABEL_LEN, MAX_MESSAGE_LEN,
+                VALUE_BUCKETS};
+pub use timestamp::Timestamp;
+pub use webhook::WEBHOOK_SIGNATURE_HEADER;
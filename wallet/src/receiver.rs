@@ -16,54 +16,386 @@
 //! receiving money in MimbleWimble requires an interactive exchange, a
 //! wallet server that's running at all time is required in many cases.
 
+use std::cmp::min;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
 use bodyparser;
 use iron::prelude::*;
 use iron::Handler;
 use iron::status;
 use serde_json;
+use serde_json::{Map, Value};
+use urlencoded::UrlEncodedQuery;
 
 use api;
+use checker;
 use core::consensus::reward;
 use core::core::{build, Block, Output, Transaction, TxKernel};
+use core::global;
 use core::ser;
-use keychain::{BlindingFactor, Identifier, Keychain};
+use keychain::{BlindSum, BlindingFactor, Identifier, Keychain};
+use timestamp::Timestamp;
 use types::*;
 use util;
 use util::LOGGER;
+use webhook;
 
 /// Dummy wrapper for the hex-encoded serialized transaction.
 #[derive(Serialize, Deserialize)]
 pub struct TxWrapper {
 	pub tx_hex: String,
+	/// Whether the node should broadcast the transaction right away rather
+	/// than relay it along a stem first, ignored by nodes without stem relay
+	#[serde(default)]
+	pub fluff: bool,
+}
+
+/// Pushes a transaction to the transaction pool of the node.
+pub fn push_tx(config: &WalletConfig, tx_hex: &str, fluff: bool) -> Result<(), Error> {
+	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
+	let wrapper = TxWrapper {
+		tx_hex: tx_hex.to_string(),
+		fluff: fluff,
+	};
+	api::client::post(url.as_str(), &wrapper).map_err(|e| Error::Node(e))
 }
 
+/// Receive a partial transaction as pasted by the sender, either its JSON or
+/// its compact encoding.
 pub fn receive_json_tx_str(
 	config: &WalletConfig,
 	keychain: &Keychain,
 	json_tx: &str,
-) -> Result<(), Error> {
-	let partial_tx = serde_json::from_str(json_tx).unwrap();
+) -> Result<ReceiveResponse, Error> {
+	let partial_tx = parse_partial_tx(json_tx)?;
 	receive_json_tx(config, keychain, &partial_tx)
 }
 
 /// Receive an already well formed JSON transaction issuance and finalize the
 /// transaction, adding our receiving output, to broadcast to the rest of the
-/// network.
+/// network. The completed transaction is handed back so the sender can check
+/// it, along with the message the sender attached (which is also kept in the
+/// receive log). A partial transaction received again, when the sender
+/// retries, gets the very same answer without a new output being built.
 pub fn receive_json_tx(
 	config: &WalletConfig,
 	keychain: &Keychain,
 	partial_tx: &JSONPartialTx,
+) -> Result<ReceiveResponse, Error> {
+	receive_json_tx_from(config, keychain, partial_tx, None, None)
+}
+
+/// Same as receive_json_tx, recording in the receive log the address the
+/// partial transaction came from. The payment goes into the account, one of
+/// the receive accounts of the config, or the default one if none. The
+/// transaction is pushed with fluff if the sender asks for it, or if it
+/// doesn't and the config does.
+pub fn receive_json_tx_from(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	partial_tx: &JSONPartialTx,
+	sender: Option<String>,
+	account: Option<String>,
+) -> Result<ReceiveResponse, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let received = receive_partial_tx(config, keychain, partial_tx, sender, account)?;
+
+	// a failed push is attempted again on the next retry, the node failing
+	// is the sender's to know about
+	if !received.pushed {
+		let fluff = partial_tx.fluff().unwrap_or(config.fluff);
+		push_tx(config, &received.response.tx_hex, fluff)?;
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.set_received_tx_pushed(&received.hash);
+		})?;
+	}
+	Ok(received.response)
+}
+
+/// Receives the partial transaction written to in_path by the sender, the
+/// same way the receiver would over HTTP, and writes the completed
+/// transaction to out_path for the sender to finalize. Nothing is pushed to
+/// the node, that's up to the sender. Processing the same file again writes
+/// the same transaction.
+pub fn receive_from_file(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	in_path: &Path,
+	out_path: &Path,
 ) -> Result<(), Error> {
+	let config = &config.for_keychain(keychain)?;
+	let mut json_tx = String::new();
+	File::open(in_path)?.read_to_string(&mut json_tx)?;
+	let partial_tx = parse_partial_tx(&json_tx)?;
+
+	let sender = Some(format!("file:{}", in_path.display()));
+	let received = receive_partial_tx(config, keychain, &partial_tx, sender, None)?;
+	let response = serde_json::to_string_pretty(&received.response)?;
+	File::create(out_path)?.write_all(response.as_bytes())?;
+	Ok(())
+}
+
+/// Checks the partial transaction and completes it with an output of ours,
+/// unless it was received before, in which case the first answer is
+/// returned.
+fn receive_partial_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	partial_tx: &JSONPartialTx,
+	sender: Option<String>,
+	account: Option<String>,
+) -> Result<ReceivedTx, Error> {
+	config.check_account(account.as_ref().map(|a| a.as_str()))?;
 	let (amount, blinding, tx) = read_partial_tx(keychain, partial_tx)?;
-	let final_tx = receive_transaction(config, keychain, amount, blinding, tx)?;
-	let tx_hex = util::to_hex(ser::ser_vec(&final_tx).unwrap());
+	check_partial_tx(config, keychain, amount, &blinding, &tx)?;
+	check_receive_amount(config, amount.saturating_sub(tx.fee))?;
 
-	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
-	api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex })
-		.map_err(|e| Error::Node(e))?;
+	let hash = partial_tx.contribution();
+	let received = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.find_received_tx(&hash).cloned()
+	})?;
+	let received = match received {
+		Some(received) => {
+			debug!(LOGGER, "Partial transaction {} received again", hash);
+			received
+		}
+		None => {
+			let message = partial_tx.message().map(|m| m.to_string());
+			let net_amount = amount.saturating_sub(tx.fee);
+			let received = receive_transaction(
+				config,
+				keychain,
+				amount,
+				blinding,
+				tx,
+				message.clone(),
+				sender,
+				account,
+				hash,
+			)?;
+			let key_id = received.key_id.clone();
+			webhook::notify_receive(config, ReceiveNotification::new(net_amount, key_id, message));
+			received
+		}
+	};
+	Ok(received)
+}
+
+/// Checks what the sender states against its transaction, before any key
+/// gets derived for it: the fee is at least the minimum for the transaction
+/// once our output is added and less than the amount, the inputs and outputs
+/// add up to the amount and the lock height is within our horizon.
+fn check_partial_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	blinding: &BlindingFactor,
+	tx: &Transaction,
+) -> Result<(), Error> {
+	// we don't necessarily want to just trust the sender, we could just
+	// overwrite the fee here (but we won't) due to the ecdsa sig, the sender
+	// is free to pay more than the minimum fee
+	let fee = tx_fee(tx.inputs.len(), tx.outputs.len() + 1, None);
+	if tx.fee < fee {
+		return Err(Error::FeeDispute {
+			sender_fee: tx.fee,
+			recipient_fee: fee,
+		});
+	}
+	if tx.fee >= amount {
+		return Err(Error::FeeExceedsAmount {
+			fee: tx.fee,
+			amount: amount,
+		});
+	}
+
+	// the inputs minus the change commit to the amount with the blinding
+	// factor of the sender
+	let secp = keychain.secp();
+	let inputs = tx.inputs.iter().map(|input| input.commitment()).collect();
+	let outputs = tx.outputs.iter().map(|out| out.commitment()).collect();
+	match (secp.commit_sum(inputs, outputs), secp.commit(amount, blinding.secret_key())) {
+		(Ok(sum), Ok(stated)) if sum == stated => {}
+		_ => return Err(Error::AmountMismatch { stated: amount }),
+	}
+
+	check_lock_height(config, tx.lock_height)
+}
+
+/// Refuses transactions locked further past the chain tip than the horizon
+/// of the config, the node only being asked for the tip when it matters.
+fn check_lock_height(config: &WalletConfig, lock_height: u64) -> Result<(), Error> {
+	if lock_height <= config.receive_lock_horizon {
+		return Ok(());
+	}
+	let tip = checker::get_tip_from_node(config)?;
+	let max_height = tip.height + config.receive_lock_horizon;
+	if lock_height > max_height {
+		return Err(Error::LockHeightTooFar {
+			lock_height: lock_height,
+			max_height: max_height,
+		});
+	}
 	Ok(())
 }
 
+/// Refuses amounts, net of the fee, outside of the receive limits of the
+/// config, before any key gets derived for them.
+fn check_receive_amount(config: &WalletConfig, amount: u64) -> Result<(), Error> {
+	let too_large = config.max_receive_amount.map_or(false, |max| amount > max);
+	if amount < config.min_receive_amount || too_large {
+		return Err(Error::ReceiveAmountRefused {
+			amount: amount,
+			minimum: config.min_receive_amount,
+			maximum: config.max_receive_amount,
+		});
+	}
+	Ok(())
+}
+
+/// Issues an invoice for the amount, payable for the next expiry_blocks
+/// blocks. Dest is where the payer reaches our receiver to get the
+/// transaction signed. The invoice keeps a key of ours aside, its output is
+/// only tracked once paid. Amounts our receiver wouldn't accept are refused.
+pub fn issue_invoice(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	expiry_blocks: u64,
+	dest: &str,
+) -> Result<JSONInvoice, Error> {
+	let config = &config.for_keychain(keychain)?;
+	check_receive_amount(config, amount)?;
+	let tip = checker::get_tip_from_node(config)?;
+	let expiry_height = tip.height + expiry_blocks;
+
+	let dir = &config.data_file_dir;
+	let key_id = WalletData::with_wallet(dir, |wallet_data| -> Result<_, Error> {
+		let n_child = wallet_data.reserve_children(dir, keychain.root_key_id(), 1)?;
+		let key_id = keychain.derive_key_id(n_child)?;
+		wallet_data.add_invoice(
+			keychain.root_key_id(),
+			key_id.clone(),
+			n_child,
+			amount,
+			expiry_height,
+		);
+		Ok(key_id)
+	})??;
+
+	let (tx, _) = build::transaction(vec![build::output(amount, key_id)], keychain)?;
+	Ok(JSONInvoice {
+		amount: amount,
+		expiry_height: expiry_height,
+		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
+		dest: dest.to_string(),
+	})
+}
+
+/// Signs the payment of one of our invoices: the transaction as the payer
+/// completed it with inputs, change and the fee, along with the blinding
+/// factors of the payer. Our own goes into the kernel and the completed
+/// transaction is handed back, the payer being the one pushing it to the
+/// node. From then on the output of the invoice is tracked. An invoice paid
+/// once the chain reached its expiry height is refused.
+pub fn receive_invoice_payment(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	partial_tx: &JSONPartialTx,
+) -> Result<ReceiveResponse, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let (_, blinding, tx) = read_partial_tx(keychain, partial_tx)?;
+	check_lock_height(config, tx.lock_height)?;
+
+	let invoices = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.invoices.clone()
+	})?;
+	let mut paid = None;
+	for invoice in invoices {
+		let commit = keychain.commit(invoice.amount, &invoice.key_id)?;
+		if tx.outputs.iter().any(|out| out.commitment() == commit) {
+			paid = Some(invoice);
+			break;
+		}
+	}
+	let invoice = paid.ok_or(Error::InvoiceNotFound(
+		"none of the outputs of the transaction is one we invoiced".to_string(),
+	))?;
+	let current_height = checker::get_tip_from_node(config)?.height;
+	if current_height >= invoice.expiry_height {
+		return Err(Error::InvoiceExpired {
+			expiry_height: invoice.expiry_height,
+			current_height: current_height,
+		});
+	}
+
+	// the payer pays for all of it, our output included
+	let fee = tx_fee(tx.inputs.len(), tx.outputs.len(), None);
+	if tx.fee < fee {
+		return Err(Error::FeeDispute {
+			sender_fee: tx.fee,
+			recipient_fee: fee,
+		});
+	}
+
+	let invoice_blinding = keychain.blind_sum(&BlindSum::new().add_key_id(invoice.key_id.clone()))?;
+	let (final_tx, _) = build::transaction(
+		vec![
+			build::initial_tx(tx),
+			build::with_excess(blinding),
+			build::with_excess(invoice_blinding),
+		],
+		keychain,
+	)?;
+	final_tx.validate(&keychain.secp())?;
+
+	let message = partial_tx.message().map(|m| m.to_string());
+	let fee = final_tx.fee;
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| -> Result<(), Error> {
+		wallet_data
+			.invoices
+			.retain(|open| open.key_id != invoice.key_id);
+		wallet_data.add_output(OutputData {
+			root_key_id: keychain.root_key_id(),
+			key_id: invoice.key_id.clone(),
+			n_child: invoice.n_child,
+			value: invoice.amount,
+			status: OutputStatus::Unconfirmed,
+			height: 0,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: Some(Timestamp::now()),
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: label_from_message(&message),
+			watch_commit: None,
+		})?;
+		let key_id = invoice.key_id.clone();
+		let amount = invoice.amount;
+		let rx_log_id =
+			wallet_data.add_rx_log_entry(amount, key_id.clone(), message.clone(), None, None);
+		let tx_id =
+			wallet_data.add_transaction(TxDirection::Received, amount, fee, vec![], vec![key_id]);
+		wallet_data.set_transaction_log_id(tx_id, rx_log_id);
+		Ok(())
+	})??;
+	let notification = ReceiveNotification::new(invoice.amount, invoice.key_id, message.clone());
+	webhook::notify_receive(config, notification);
+
+	let commit = keychain.commit(invoice.amount, &invoice.key_id)?;
+	Ok(ReceiveResponse {
+		tx_hex: util::to_hex(ser::ser_vec(&final_tx).unwrap()),
+		message: message,
+		commit: Some(util::to_hex(commit.as_ref().to_vec())),
+	})
+}
+
 /// Component used to receive coins, implements all the receiving end of the
 /// wallet REST API as well as some of the command-line operations.
 #[derive(Clone)]
@@ -74,50 +406,143 @@ pub struct WalletReceiver {
 
 impl Handler for WalletReceiver {
 	fn handle(&self, req: &mut Request) -> IronResult<Response> {
-		let struct_body = req.get::<bodyparser::Struct<JSONPartialTx>>();
-
-		if let Ok(Some(partial_tx)) = struct_body {
-			receive_json_tx(&self.config, &self.keychain, &partial_tx)
-				.map_err(|e| {
-					api::Error::Internal(
-						format!("Error processing partial transaction: {:?}", e),
-					)})
-				.unwrap();
-			Ok(Response::with(status::Ok))
-		} else {
-			Ok(Response::with((status::BadRequest, "")))
+		let sender = Some(req.remote_addr.to_string());
+		// the account to receive into, if not the default one
+		let account = match req.get_ref::<UrlEncodedQuery>() {
+			Ok(params) => params.get("account").and_then(|values| values.first()).cloned(),
+			Err(_) => None,
+		};
+		handle_partial_tx(req, |partial_tx| {
+			let (sender, account) = (sender.clone(), account.clone());
+			receive_json_tx_from(&self.config, &self.keychain, partial_tx, sender, account)
+		})
+	}
+}
+
+/// Receives the payments of the invoices we issued, see
+/// receive_invoice_payment.
+#[derive(Clone)]
+pub struct InvoiceReceiver {
+	pub keychain: Keychain,
+	pub config: WalletConfig,
+}
+
+impl Handler for InvoiceReceiver {
+	fn handle(&self, req: &mut Request) -> IronResult<Response> {
+		handle_partial_tx(req, |partial_tx| {
+			receive_invoice_payment(&self.config, &self.keychain, partial_tx)
+		})
+	}
+}
+
+/// Reads the partial transaction posted in the request and answers with what
+/// receive makes of it.
+fn handle_partial_tx<F>(req: &mut Request, receive: F) -> IronResult<Response>
+where
+	F: Fn(&JSONPartialTx) -> Result<ReceiveResponse, Error>,
+{
+	let json_body = match req.get::<bodyparser::Json>() {
+		Ok(Some(json_body)) => json_body,
+		_ => {
+			let err = Error::Format("request body isn't JSON".to_string());
+			return error_response(&err, status::BadRequest, Value::Null);
+		}
+	};
+
+	// check the version before anything else, a newer format may not
+	// even parse
+	let version = json_body
+		.get("version")
+		.and_then(|v| v.as_u64())
+		.unwrap_or(0);
+	if version > PARTIAL_TX_VERSION as u64 {
+		let err = Error::VersionMismatch {
+			ours: PARTIAL_TX_VERSION,
+			theirs: min(version, u16::max_value() as u64) as u16,
+		};
+		// the version we understand, for the sender to downgrade to
+		let mut details = Map::new();
+		details.insert("version".to_string(), Value::from(PARTIAL_TX_VERSION));
+		return error_response(&err, status::BadRequest, Value::Object(details));
+	}
+	let partial_tx: JSONPartialTx = match serde_json::from_value(json_body) {
+		Ok(partial_tx) => partial_tx,
+		Err(e) => {
+			let err = Error::Format(format!("not a partial transaction: {}", e));
+			return error_response(&err, status::BadRequest, Value::Null);
+		}
+	};
+
+	let response = match receive(&partial_tx) {
+		Ok(response) => response,
+		// the sender's doing, tell them why rather than failing
+		Err(ref e) if refused(e) => return error_response(e, status::BadRequest, Value::Null),
+		Err(ref e) => {
+			error!(LOGGER, "Error processing partial transaction: {}", e);
+			return error_response(e, status::InternalServerError, Value::Null);
 		}
+	};
+	let json = serde_json::to_string(&response)
+		.map_err(|e| api::Error::Internal(format!("Error serializing response: {}", e)))?;
+	Ok(Response::with((status::Ok, json)))
+}
+
+/// Whether the error comes from what the partial transaction holds rather
+/// than from us, the sender being told why it was refused.
+fn refused(err: &Error) -> bool {
+	match *err {
+		Error::KernelMismatch { .. }
+		| Error::MessageTooLong { .. }
+		| Error::Format(_)
+		| Error::InvoiceNotFound(_)
+		| Error::ReceiveAmountRefused { .. }
+		| Error::FeeDispute { .. }
+		| Error::FeeExceedsAmount { .. }
+		| Error::LockHeightTooFar { .. }
+		| Error::AmountMismatch { .. }
+		| Error::UnknownAccount(_) => true,
+		_ => false,
 	}
 }
 
-// Read wallet data without acquiring the write lock.
+/// Answer to a request that failed, with the code and description of the
+/// error in an ErrorResponse the other side can make sense of.
+pub fn error_response(
+	err: &Error,
+	status: status::Status,
+	details: Value,
+) -> IronResult<Response> {
+	let body = api::ErrorResponse {
+		code: err.code().to_string(),
+		message: err.to_string(),
+		details: details,
+	};
+	let json = serde_json::to_string(&body)
+		.map_err(|e| api::Error::Internal(format!("Error serializing response: {}", e)))?;
+	Ok(Response::with((status, json)))
+}
+
+// Both only valid while holding the wallet lock, the key they give out must
+// not be taken by someone else before its output gets added.
 fn retrieve_existing_key(
-	config: &WalletConfig,
+	wallet_data: &WalletData,
 	key_id: Identifier,
 ) -> Result<(Identifier, u32), Error> {
-	let res = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		if let Some(existing) = wallet_data.get_output(&key_id) {
-			let key_id = existing.key_id.clone();
-			let derivation = existing.n_child;
-			(key_id, derivation)
-		} else {
-			panic!("should never happen");
-		}
-	})?;
-	Ok(res)
+	let existing = wallet_data
+		.get_output(&key_id)
+		.map(|existing| (existing.key_id.clone(), existing.n_child));
+	existing.ok_or(Error::WalletData(format!("No existing output for key_id {}", key_id)))
 }
 
 fn next_available_key(
 	config: &WalletConfig,
+	wallet_data: &WalletData,
 	keychain: &Keychain,
 ) -> Result<(Identifier, u32), Error> {
-	let res = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		let root_key_id = keychain.root_key_id();
-		let derivation = wallet_data.next_child(root_key_id.clone());
-		let key_id = keychain.derive_key_id(derivation).unwrap();
-		(key_id, derivation)
-	})?;
-	Ok(res)
+	let derivation =
+		wallet_data.reserve_children(&config.data_file_dir, keychain.root_key_id(), 1)?;
+	let key_id = keychain.derive_key_id(derivation)?;
+	Ok((key_id, derivation))
 }
 
 /// Build a coinbase output and the corresponding kernel
@@ -126,28 +551,41 @@ pub fn receive_coinbase(
 	keychain: &Keychain,
 	block_fees: &BlockFees,
 ) -> Result<(Output, TxKernel, BlockFees), Error> {
+	let config = &config.for_keychain(keychain)?;
 	let root_key_id = keychain.root_key_id();
-	let key_id = block_fees.key_id();
-
-	let (key_id, derivation) = match key_id {
-		Some(key_id) => retrieve_existing_key(config, key_id)?,
-		None => next_available_key(config, keychain)?,
-	};
 
-	// Now acquire the wallet lock and write the new output.
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
-		// track the new output and return the stuff needed for reward
-		wallet_data.add_output(OutputData {
+	// the key is allocated and its output written under the same lock
+	let dir = &config.data_file_dir;
+	let (key_id, derivation) = WalletData::with_wallet(dir, |wallet_data| -> Result<_, Error> {
+		let (key_id, derivation) = match block_fees.key_id() {
+			Some(key_id) => retrieve_existing_key(wallet_data, key_id)?,
+			None => next_available_key(config, wallet_data, keychain)?,
+		};
+		// track the new output and return the stuff needed for reward, along
+		// with the block it's mined in so its maturity is known before the
+		// node ever reports it (the checker corrects both once it does), a
+		// candidate built again for a new block replacing the previous one
+		wallet_data.replace_output(OutputData {
 			root_key_id: root_key_id.clone(),
 			key_id: key_id.clone(),
 			n_child: derivation,
 			value: reward(block_fees.fees),
 			status: OutputStatus::Unconfirmed,
-			height: 0,
-			lock_height: 0,
+			height: block_fees.height,
+			block_hash: None,
+			lock_height: block_fees.height + global::coinbase_maturity(),
 			is_coinbase: true,
+			locked_at: 0,
+			account: None,
+			created_at: Some(Timestamp::now()),
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
 		});
-	})?;
+		Ok((key_id, derivation))
+	})??;
 
 	debug!(
 		LOGGER,
@@ -175,40 +613,53 @@ fn receive_transaction(
 	amount: u64,
 	blinding: BlindingFactor,
 	partial: Transaction,
-) -> Result<Transaction, Error> {
+	message: Option<String>,
+	sender: Option<String>,
+	account: Option<String>,
+	hash: String,
+) -> Result<ReceivedTx, Error> {
 	let root_key_id = keychain.root_key_id();
 
-	let (key_id, derivation) = next_available_key(config, keychain)?;
+	// the fee was checked along with the rest of the partial tx
+	let fee = partial.fee;
+	let out_amount = amount - fee;
+	debug!(
+		LOGGER,
+		"Receiving {} ({} sent, minus a fee of {})",
+		out_amount,
+		amount,
+		fee
+	);
 
-	// double check the fee amount included in the partial tx
- // we don't necessarily want to just trust the sender
- // we could just overwrite the fee here (but we won't) due to the ecdsa sig
-	let fee = tx_fee(partial.inputs.len(), partial.outputs.len() + 1, None);
-	if fee != partial.fee {
-		return Err(Error::FeeDispute {
-			sender_fee: partial.fee,
-			recipient_fee: fee,
-		});
-	}
+	// operate within a lock on wallet data, from allocating the key to
+	// tracking its output, so concurrent receives never share a key
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| -> Result<ReceivedTx, Error> {
+		// the sender retried while we were at it, the first answer stands
+		if let Some(received) = wallet_data.find_received_tx(&hash) {
+			return Ok(received.clone());
+		}
+		let (key_id, derivation) = next_available_key(config, wallet_data, keychain)?;
 
-	let out_amount = amount - fee;
+		let (tx_final, _) = build::transaction(
+			vec![
+				build::initial_tx(partial),
+				build::with_excess(blinding),
+				build::output(out_amount, key_id.clone()),
+			],
+			keychain,
+		)?;
 
-	let (tx_final, _) = build::transaction(
-		vec![
-			build::initial_tx(partial),
-			build::with_excess(blinding),
-			build::output(out_amount, key_id.clone()),
-		// build::with_fee(fee_amount),
-		],
-		keychain,
-	)?;
+		// make sure the resulting transaction is valid (could have been lied
+		// to on excess).
+		tx_final.validate(&keychain.secp())?;
 
-	// make sure the resulting transaction is valid (could have been lied to on
- // excess).
-	tx_final.validate(&keychain.secp())?;
+		let commit = keychain.commit(out_amount, &key_id)?;
+		let response = ReceiveResponse {
+			tx_hex: util::to_hex(ser::ser_vec(&tx_final).unwrap()),
+			message: message.clone(),
+			commit: Some(util::to_hex(commit.as_ref().to_vec())),
+		};
 
-	// operate within a lock on wallet data
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
 		wallet_data.add_output(OutputData {
 			root_key_id: root_key_id.clone(),
 			key_id: key_id.clone(),
@@ -216,18 +667,802 @@ fn receive_transaction(
 			value: out_amount,
 			status: OutputStatus::Unconfirmed,
 			height: 0,
+			block_hash: None,
 			lock_height: 0,
 			is_coinbase: false,
+			locked_at: 0,
+			account: account.clone(),
+			created_at: Some(Timestamp::now()),
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: label_from_message(&message),
+			watch_commit: None,
+		})?;
+		let rx_log_id =
+			wallet_data.add_rx_log_entry(out_amount, key_id.clone(), message, sender, account);
+		let tx_id = wallet_data.add_transaction(
+			TxDirection::Received,
+			out_amount,
+			fee,
+			vec![],
+			vec![key_id.clone()],
+		);
+		wallet_data.set_transaction_log_id(tx_id, rx_log_id);
+		debug!(
+			LOGGER,
+			"Received txn and built output - {:?}, {:?}, {}",
+			root_key_id.clone(),
+			key_id.clone(),
+			derivation,
+		);
+
+		let received = ReceivedTx {
+			hash: hash,
+			key_id: key_id,
+			response: response,
+			pushed: false,
+		};
+		wallet_data.received_txs.push(received.clone());
+		Ok(received)
+	})?
+}
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs::{self, File};
+	use std::io::Read;
+	use std::path::Path;
+	use std::thread;
+
+	use client;
+	use handlers::CoinbaseHandler;
+	use server;
+	use core::core::build::{input, output, transaction, with_fee, with_lock_height};
+	use core::core::BlockHeader;
+	use super::*;
+
+	fn wallet_dat(config: &WalletConfig) -> Vec<u8> {
+		let mut buf = vec![];
+		File::open(Path::new(&config.data_file_dir).join("wallet.dat"))
+			.unwrap()
+			.read_to_end(&mut buf)
+			.unwrap();
+		buf
+	}
+
+	// A partial transaction from a random sender, for the receiver to build
+	// its output from amount minus a fee of 80.
+	fn partial_tx(amount: u64) -> JSONPartialTx {
+		locked_partial_tx(amount, 80, 0)
+	}
+
+	// Same as partial_tx with the provided fee and lock height.
+	fn locked_partial_tx(amount: u64, fee: u64, lock_height: u64) -> JSONPartialTx {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (tx, blind_sum) = transaction(
+			vec![
+				input(amount + 100, keychain.derive_key_id(1).unwrap()),
+				output(100, keychain.derive_key_id(2).unwrap()),
+				with_fee(fee),
+				with_lock_height(lock_height),
+			],
+			&keychain,
+		).unwrap();
+		build_partial_tx(amount, blind_sum, tx, None)
+	}
+
+	// An empty receiving wallet in a fresh temporary directory.
+	fn test_wallet() -> (WalletConfig, Keychain) {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_receiver_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		config = config.for_keychain(&keychain).unwrap();
+		(config, keychain)
+	}
+
+	#[test]
+	fn receive_limits() {
+		let (mut config, keychain) = test_wallet();
+		config.min_receive_amount = 1_000;
+		config.max_receive_amount = Some(10_000);
+		WalletData::with_wallet(&config.data_file_dir, |_| {}).unwrap();
+		let before = wallet_dat(&config);
+
+		// net of the fee, 920 is too little and 19920 too much
+		for &amount in [1_000, 20_000].iter() {
+			match receive_json_tx(&config, &keychain, &partial_tx(amount)) {
+				Err(ref e @ Error::ReceiveAmountRefused { .. }) => {
+					assert!(refused(e));
+					assert!(e.to_string().contains("from 1000 to 10000"), "{}", e);
+				}
+				res => panic!("expected ReceiveAmountRefused, got {:?}", res),
+			}
+			assert_eq!(wallet_dat(&config), before);
+		}
+
+		// in range, the output gets tracked once pushed
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		receive_json_tx(&config, &keychain, &partial_tx(5_000)).unwrap();
+		assert!(wallet_dat(&config) != before);
+		let values = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data
+				.outputs
+				.values()
+				.map(|out| out.value)
+				.collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(values, vec![4_920]);
+	}
+
+	#[test]
+	fn dust_refused() {
+		// by default dust is refused, without asking the node anything
+		let (mut config, keychain) = test_wallet();
+		assert_eq!(config.min_receive_amount, 1_000);
+		WalletData::with_wallet(&config.data_file_dir, |_| {}).unwrap();
+		let before = wallet_dat(&config);
+		for &amount in [100, 500, 1_079].iter() {
+			match receive_json_tx(&config, &keychain, &partial_tx(amount)) {
+				Err(Error::ReceiveAmountRefused { minimum: 1_000, .. }) => {}
+				res => panic!("expected ReceiveAmountRefused, got {:?}", res),
+			}
+			match issue_invoice(&config, &keychain, amount, 10, "http://127.0.0.1:13415") {
+				Err(Error::ReceiveAmountRefused { minimum: 1_000, .. }) => {}
+				res => panic!("expected ReceiveAmountRefused, got {:?}", res),
+			}
+		}
+		assert_eq!(wallet_dat(&config), before);
+
+		// a config without it gets the default, which can be lowered
+		let mut json = serde_json::to_value(&config).unwrap();
+		json.as_object_mut().unwrap().remove("min_receive_amount");
+		let read: WalletConfig = serde_json::from_value(json).unwrap();
+		assert_eq!(read.min_receive_amount, 1_000);
+
+		config.min_receive_amount = 1;
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		let response = receive_json_tx(&config, &keychain, &partial_tx(500)).unwrap();
+		assert!(!response.tx_hex.is_empty());
+	}
+
+	#[test]
+	fn duplicate_partial_tx() {
+		let (mut config, keychain) = test_wallet();
+		// only the first one gets pushed
+		let (node, pushed) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+
+		let partial_tx = partial_tx(5_000);
+		let first = receive_json_tx(&config, &keychain, &partial_tx).unwrap();
+		let again = receive_json_tx(&config, &keychain, &partial_tx).unwrap();
+		assert_eq!(
+			serde_json::to_string(&first).unwrap(),
+			serde_json::to_string(&again).unwrap()
+		);
+		assert_eq!(pushed.lock().unwrap().len(), 1);
+
+		// a single key derived, for a single output
+		let (outputs, rx_log, next_child) =
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				(
+					wallet_data.outputs.len(),
+					wallet_data.rx_log.len(),
+					wallet_data.next_child(keychain.root_key_id()),
+				)
+			}).unwrap();
+		assert_eq!((outputs, rx_log, next_child), (1, 1, 2));
+
+		// forgotten once the output confirms
+		let received = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.expire_received_txs();
+			assert_eq!(wallet_data.received_txs.len(), 1);
+			for out in wallet_data.outputs.values_mut() {
+				out.status = OutputStatus::Unspent;
+			}
+			wallet_data.expire_received_txs();
+			wallet_data.received_txs.len()
+		}).unwrap();
+		assert_eq!(received, 0);
+	}
+
+	#[test]
+	fn receive_logged() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+
+		let sender = Some("10.0.0.2:51000".to_string());
+		receive_json_tx_from(&config, &keychain, &partial_tx(5_000), sender.clone(), None).unwrap();
+		let (rx_log, transactions) = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			(wallet_data.rx_log.clone(), wallet_data.transactions.clone())
+		}).unwrap();
+		assert_eq!(rx_log.len(), 1);
+		assert_eq!((rx_log[0].amount, rx_log[0].confirmed), (4_920, false));
+		assert_eq!(rx_log[0].sender, sender);
+		assert_eq!(rx_log[0].key_id, keychain.derive_key_id(1).unwrap());
+
+		// grouped under a transaction of its own, with the fee paid for it
+		assert_eq!(transactions.len(), 1);
+		let tx = &transactions[0];
+		assert_eq!((tx.direction, tx.amount, tx.fee), (TxDirection::Received, 4_920, 80));
+		assert_eq!((tx.status.clone(), tx.log_id), (TxLogStatus::Pending, Some(rx_log[0].id)));
+		assert!(tx.input_ids.is_empty());
+		assert_eq!(tx.output_ids, vec![rx_log[0].key_id.clone()]);
+	}
+
+	#[test]
+	fn message_labels_output() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(2, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+
+		let sender = Keychain::from_random_seed().unwrap();
+		for (n_child, message) in vec![(1, " rent for March "), (3, "  ")] {
+			let (tx, blind_sum) = transaction(
+				vec![
+					input(5_100, sender.derive_key_id(n_child).unwrap()),
+					output(100, sender.derive_key_id(n_child + 1).unwrap()),
+					with_fee(80),
+				],
+				&sender,
+			).unwrap();
+			let partial_tx = build_partial_tx(5_000, blind_sum, tx, Some(message.to_string()));
+			receive_json_tx_from(&config, &keychain, &partial_tx, None, None).unwrap();
+		}
+		let labels = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			(1..3)
+				.map(|n_child| {
+					let key_id = keychain.derive_key_id(n_child).unwrap();
+					wallet_data.get_output(&key_id).unwrap().label.clone()
+				})
+				.collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(labels, vec![Some("rent for March".to_string()), None]);
+	}
+
+	#[test]
+	fn partial_tx_checked_before_signing() {
+		let (mut config, keychain) = test_wallet();
+		config.receive_lock_horizon = 100;
+		WalletData::with_wallet(&config.data_file_dir, |_| {}).unwrap();
+		let before = wallet_dat(&config);
+
+		// the node is at height 1000, the tip is only fetched for lock
+		// heights past the horizon
+		let tip = serde_json::to_string(&api::Tip {
+			height: 1_000,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		}).unwrap();
+		let mut replies = vec![tip.clone(), tip, String::new()].into_iter();
+		let (node, _) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
 		});
-	})?;
+		config.check_node_api_http_addr = node;
 
-	debug!(
-		LOGGER,
-		"Received txn and built output - {:?}, {:?}, {}",
-		root_key_id.clone(),
-		key_id.clone(),
-		derivation,
-	);
+		let mut inflated = serde_json::to_value(&partial_tx(5_000)).unwrap();
+		inflated["amount"] = serde_json::Value::from(6_000);
+		let inflated: JSONPartialTx = serde_json::from_value(inflated).unwrap();
+		let refusals = vec![
+			(locked_partial_tx(5_000, 0, 0), "FeeDispute"),
+			(locked_partial_tx(5_000, 6_000, 0), "FeeExceedsAmount"),
+			(inflated, "AmountMismatch { stated: 6000 }"),
+			(
+				locked_partial_tx(5_000, 80, 1_101),
+				"LockHeightTooFar { lock_height: 1101, max_height: 1100 }",
+			),
+		];
+		for (partial_tx, expected) in refusals {
+			match receive_json_tx(&config, &keychain, &partial_tx) {
+				Err(ref e) if refused(e) && format!("{:?}", e).starts_with(expected) => {}
+				res => panic!("expected {}, got {:?}", expected, res),
+			}
+			// nothing derived, nothing recorded
+			assert_eq!(wallet_dat(&config), before);
+		}
+
+		// right at the horizon it's signed
+		let response = receive_json_tx(&config, &keychain, &locked_partial_tx(5_000, 80, 1_100))
+			.unwrap();
+		let tx_bin = util::from_hex(response.tx_hex).unwrap();
+		let tx: Transaction = ser::deserialize(&mut &tx_bin[..]).unwrap();
+		assert_eq!((tx.fee, tx.lock_height), (80, 1_100));
+		tx.validate(keychain.secp()).unwrap();
+	}
+
+	#[test]
+	fn expired_invoice_payment_refused() {
+		let (mut config, keychain) = test_wallet();
+		let tip = |height| {
+			serde_json::to_string(&api::Tip {
+				height: height,
+				last_block_pushed: String::new(),
+				prev_block_to_last: String::new(),
+				total_difficulty: 0,
+			}).unwrap()
+		};
+		let mut replies = vec![tip(100), tip(110), tip(109)].into_iter();
+		let (node, _) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+		let dest = "http://127.0.0.1:13415";
+		let invoice = issue_invoice(&config, &keychain, 5_000, 10, dest).unwrap();
+		assert_eq!(invoice.expiry_height, 110);
+
+		// paid from a random wallet, the output of the invoice added to its
+		// transaction
+		let payer = Keychain::from_random_seed().unwrap();
+		let fee = tx_fee(1, 2, None);
+		let (tx, blind_sum) = transaction(
+			vec![
+				input(6_000, payer.derive_key_id(1).unwrap()),
+				output(1_000 - fee, payer.derive_key_id(2).unwrap()),
+				with_fee(fee),
+			],
+			&payer,
+		).unwrap();
+		let invoice_bin = util::from_hex(invoice.tx.clone()).unwrap();
+		let invoice_tx: Transaction = ser::deserialize(&mut &invoice_bin[..]).unwrap();
+		let tx = tx.with_output(invoice_tx.outputs[0].clone());
+		let partial_tx = build_partial_tx(5_000, blind_sum, tx, None);
+		let before = wallet_dat(&config);
+
+		// the chain reached the expiry height, nothing is signed nor recorded
+		match receive_invoice_payment(&config, &keychain, &partial_tx) {
+			Err(Error::InvoiceExpired {
+				expiry_height: 110,
+				current_height: 110,
+			}) => {}
+			res => panic!("expected InvoiceExpired, got {:?}", res),
+		}
+		assert_eq!(wallet_dat(&config), before);
+
+		// a block earlier it's paid
+		let response = receive_invoice_payment(&config, &keychain, &partial_tx).unwrap();
+		let tx_bin = util::from_hex(response.tx_hex).unwrap();
+		let tx: Transaction = ser::deserialize(&mut &tx_bin[..]).unwrap();
+		tx.validate(keychain.secp()).unwrap();
+	}
+
+	#[test]
+	fn coinbase_maturity_recorded() {
+		let (mut config, keychain) = test_wallet();
+		let maturity = global::coinbase_maturity();
+		let block_fees = BlockFees {
+			fees: 0,
+			height: 42,
+			key_id: None,
+		};
+		let (out, _, block_fees) = receive_coinbase(&config, &keychain, &block_fees).unwrap();
+
+		let coinbase_data = |config: &WalletConfig| {
+			let wallet: serde_json::Value = serde_json::from_slice(&wallet_dat(config)).unwrap();
+			let key_id = block_fees.key_id.as_ref().unwrap().to_hex();
+			let out = &wallet["outputs"][&key_id];
+			(
+				out["is_coinbase"].as_bool(),
+				out["height"].as_u64(),
+				out["lock_height"].as_u64(),
+			)
+		};
+		assert_eq!(coinbase_data(&config), (Some(true), Some(42), Some(42 + maturity)));
+
+		// the block made it at another height, the node has the last word
+		let mut header = BlockHeader::default();
+		header.height = 45;
+		let api_outputs = vec![api::Output::from_output(&out, &header)];
+		let mut replies = vec![
+			serde_json::to_string(&api::Tip {
+				height: 46,
+				last_block_pushed: String::new(),
+				prev_block_to_last: String::new(),
+				total_difficulty: 0,
+			}).unwrap(),
+			serde_json::to_string(&api_outputs).unwrap(),
+			serde_json::to_string(&vec![
+				api::BlockOutputs {
+					height: 45,
+					hash: format!("{:064}", 45),
+					outputs: vec![],
+				},
+			]).unwrap(),
+		].into_iter();
+		let (node, _) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+		checker::refresh_outputs(&config, &keychain).unwrap();
+		assert_eq!(coinbase_data(&config), (Some(true), Some(45), Some(45 + maturity)));
+	}
+
+	#[test]
+	fn concurrent_receives() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(2, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		WalletData::with_wallet(&config.data_file_dir, |_| {}).unwrap();
+
+		let handles = (0..2)
+			.map(|_| {
+				let (config, keychain) = (config.clone(), keychain.clone());
+				let partial_tx = partial_tx(5_000);
+				thread::spawn(move || receive_json_tx(&config, &keychain, &partial_tx).unwrap())
+			})
+			.collect::<Vec<_>>();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+
+		let mut n_childs = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data
+				.outputs
+				.values()
+				.map(|out| out.n_child)
+				.collect::<Vec<_>>()
+		}).unwrap();
+		n_childs.sort();
+		assert_eq!(n_childs, vec![1, 2]);
+	}
+
+	#[test]
+	fn error_responses() {
+		let (mut config, keychain) = test_wallet();
+		config.min_receive_amount = 10_000;
+		let receiver = WalletReceiver {
+			config: config.clone(),
+			keychain: keychain.clone(),
+		};
+		let mut listening = Iron::new(receiver).http("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/v1/receive/transaction", listening.socket);
+
+		match client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, None) {
+			Err(Error::Node(api::Error::Remote {
+				status: 400,
+				ref error,
+			})) => {
+				assert_eq!(error.code, "amount_out_of_range");
+				assert!(error.message.contains("at least 10000"), "{}", error.message);
+			}
+			res => panic!("expected amount_out_of_range, got {:?}", res),
+		}
+		match api::client::post(&url, &"not a partial transaction") {
+			Err(api::Error::Remote {
+				status: 400,
+				ref error,
+			}) => assert_eq!(error.code, "malformed_payload"),
+			res => panic!("expected malformed_payload, got {:?}", res),
+		}
+		listening.close().unwrap();
+
+		// a wallet directory that's a file, the coinbase can't be recorded
+		let blocker = Path::new(&config.data_file_dir).join("not_a_directory");
+		fs::create_dir_all(&config.data_file_dir).unwrap();
+		File::create(&blocker).unwrap();
+		config.data_file_dir = blocker.to_str().unwrap().to_string();
+		let coinbase = CoinbaseHandler {
+			config: config,
+			keychain: keychain,
+		};
+		let mut listening = Iron::new(coinbase).http("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/v1/receive/coinbase", listening.socket);
+		let block_fees = BlockFees {
+			fees: 0,
+			height: 1,
+			key_id: None,
+		};
+		match api::client::post(&url, &block_fees) {
+			Err(api::Error::Remote {
+				status: 500,
+				ref error,
+			}) => assert_eq!(error.code, "wallet_data_error"),
+			res => panic!("expected wallet_data_error, got {:?}", res),
+		}
+		listening.close().unwrap();
+	}
+
+	#[test]
+	fn receive_into_accounts() {
+		let (mut config, keychain) = test_wallet();
+		config.receive_accounts = vec!["donations".to_string(), "shop".to_string()];
+		let (node, _) = client::test::stub_server(3, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		let receiver = WalletReceiver {
+			config: config.clone(),
+			keychain: keychain.clone(),
+		};
+		let mut listening = Iron::new(receiver).http("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/v1/receive/transaction", listening.socket);
+
+		for &(account, amount) in [("shop", 5_000), ("donations", 3_000), ("shop", 2_000)].iter() {
+			let url = format!("{}?account={}", url, account);
+			client::send_partial_tx(&url, &partial_tx(amount), None, 1, 1, 10, None).unwrap();
+		}
+		let unknown = format!("{}?account=savings", url);
+		match client::send_partial_tx(&unknown, &partial_tx(4_000), None, 1, 1, 10, None) {
+			Err(Error::Node(api::Error::Remote {
+				status: 400,
+				ref error,
+			})) => assert_eq!(error.code, "unknown_account"),
+			res => panic!("expected unknown_account, got {:?}", res),
+		}
+		listening.close().unwrap();
+
+		let root_key_id = keychain.root_key_id();
+		WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			assert_eq!(wallet_data.account_total(root_key_id.clone(), Some("shop")), 6_840);
+			assert_eq!(wallet_data.account_total(root_key_id.clone(), Some("donations")), 2_920);
+			assert_eq!(wallet_data.account_total(root_key_id.clone(), None), 0);
+			assert_eq!(wallet_data.outputs.len(), 3);
+
+			let mut received = wallet_data
+				.rx_log
+				.iter()
+				.map(|entry| (entry.account.clone().unwrap(), entry.amount))
+				.collect::<Vec<_>>();
+			received.sort();
+			assert_eq!(
+				received,
+				vec![
+					("donations".to_string(), 2_920),
+					("shop".to_string(), 1_920),
+					("shop".to_string(), 4_920),
+				]
+			);
+		}).unwrap();
+	}
+
+	#[test]
+	fn receiver_listen_addr() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		config.api_listen_port = "0".to_string();
+
+		// on a port picked by the system, only reachable locally
+		let mut apis = server::start_receiver(config.clone(), keychain.clone()).unwrap();
+		let addr = apis.local_addr().unwrap();
+		assert!(addr.ip().is_loopback() && addr.port() != 0, "{}", addr);
+		let url = format!("http://{}/v1/receive/transaction", addr);
+		let response =
+			client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, None).unwrap();
+		assert!(!response.tx_hex.is_empty());
+
+		// a port in use or an interface that isn't an address are errors
+		let mut in_use = config.clone();
+		in_use.api_listen_port = addr.port().to_string();
+		let mut not_an_ip = config.clone();
+		not_an_ip.api_listen_interface = "localhost".to_string();
+		let cases = vec![(in_use, addr.to_string()), (not_an_ip, "localhost".to_string())];
+		for (config, expected) in cases {
+			match server::start_receiver(config, keychain.clone()) {
+				Err(Error::Listen(ref s)) => assert!(s.contains(&expected), "{}", s),
+				Err(e) => panic!("expected Listen, got {:?}", e),
+				Ok(_) => panic!("expected Listen"),
+			}
+		}
+		apis.stop();
+	}
+
+	#[test]
+	fn receiver_over_tls() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		config.api_listen_port = "0".to_string();
+		let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("data");
+		let cert_path = data.join("receiver.crt").to_str().unwrap().to_string();
+		config.tls_cert_path = Some(cert_path.clone());
+		config.tls_key_path = Some(data.join("receiver.key").to_str().unwrap().to_string());
+
+		let mut apis = server::start_receiver(config.clone(), keychain.clone()).unwrap();
+		let port = apis.local_addr().unwrap().port();
 
-	Ok(tx_final)
+		// plaintext gets nowhere, https with the pinned self-signed certificate does
+		let fingerprint = client::test::receiver_fingerprint();
+		let url = format!("http://localhost:{}/v1/receive/transaction", port);
+		assert!(client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, None).is_err());
+		let url = format!("https://localhost:{}/v1/receive/transaction", port);
+		let response =
+			client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, Some(&fingerprint))
+				.unwrap();
+		assert!(!response.tx_hex.is_empty());
+		apis.stop();
+		let outputs = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.outputs.len()
+		}).unwrap();
+		assert_eq!(outputs, 1);
+
+		// a key without its certificate, or no key at all, can't be served
+		let mut no_cert = config.clone();
+		no_cert.tls_cert_path = None;
+		let mut no_key = config.clone();
+		no_key.tls_key_path = Some(cert_path);
+		for config in vec![no_cert, no_key] {
+			match server::start_receiver(config, keychain.clone()) {
+				Err(Error::Tls(_)) => {}
+				Err(e) => panic!("expected Tls, got {:?}", e),
+				Ok(_) => panic!("expected Tls"),
+			}
+		}
+	}
+
+	#[test]
+	fn receiver_secret_required() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		config.api_listen_port = "0".to_string();
+		config.receive_secret = Some("s3cret".to_string());
+
+		let mut apis = server::start_receiver(config.clone(), keychain.clone()).unwrap();
+		let addr = apis.local_addr().unwrap();
+		let url = format!("http://{}/v1/receive/transaction", addr);
+
+		// no secret or the wrong one, with the reason in the answer
+		for secret in vec![None, Some("s3cre"), Some("wrong!")] {
+			match client::send_partial_tx(&url, &partial_tx(5_000), secret, 1, 1, 10, None) {
+				Err(Error::Unauthorized(_)) => {}
+				res => panic!("expected Unauthorized, got {:?}", res),
+			}
+		}
+		match api::client::post(&url, &partial_tx(5_000)) {
+			Err(api::Error::Remote {
+				status: 401,
+				ref error,
+			}) => assert_eq!(error.code, "unauthorized"),
+			res => panic!("expected unauthorized, got {:?}", res),
+		}
+		let tracked = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| wallet_data.outputs.len())
+				.unwrap_or(0)
+		};
+		assert_eq!(tracked(), 0);
+
+		// the right one
+		let response =
+			client::send_partial_tx(&url, &partial_tx(5_000), Some("s3cret"), 1, 1, 10, None)
+				.unwrap();
+		assert!(!response.tx_hex.is_empty());
+		assert_eq!(tracked(), 1);
+
+		// pinging and, unless configured otherwise, coinbases don't need it
+		let ping: Value = api::client::get(&format!("http://{}/v1/ping", addr)).unwrap();
+		assert_eq!(ping, Value::Object(Map::new()));
+		let block_fees = BlockFees {
+			fees: 0,
+			height: 1,
+			key_id: None,
+		};
+		api::client::post(&format!("http://{}/v1/receive/coinbase", addr), &block_fees).unwrap();
+		assert_eq!(tracked(), 2);
+		apis.stop();
+	}
+
+	#[test]
+	fn push_relay_requested() {
+		let (mut config, keychain) = test_wallet();
+
+		// what the sender asks for goes, the config otherwise
+		let cases = vec![
+			(false, None, false),
+			(false, Some(true), true),
+			(true, None, true),
+			(true, Some(false), false),
+		];
+		for (config_fluff, asked, expected) in cases {
+			let (node, pushed) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+			config.check_node_api_http_addr = node;
+			config.fluff = config_fluff;
+			receive_json_tx(&config, &keychain, &partial_tx(5_000).with_fluff(asked)).unwrap();
+			let wrapper: TxWrapper = serde_json::from_str(&pushed.lock().unwrap()[0]).unwrap();
+			assert_eq!(wrapper.fluff, expected);
+		}
+
+		// the node failing to take it is reported to the sender, whose retry
+		// gets it pushed
+		let mut replies = vec!["500 Internal Server Error", "200 OK"].into_iter();
+		let (node, pushed) = client::test::stub_server(2, move |_| {
+			replies.next().map(|status| (status, String::new()))
+		});
+		config.check_node_api_http_addr = node;
+		config.api_listen_port = "0".to_string();
+		let mut apis = server::start_receiver(config.clone(), keychain.clone()).unwrap();
+		let url = format!("http://{}/v1/receive/transaction", apis.local_addr().unwrap());
+		let partial_tx = partial_tx(5_000);
+		match client::send_partial_tx(&url, &partial_tx, None, 1, 1, 10, None) {
+			Err(Error::Node(api::Error::Remote {
+				status: 500,
+				ref error,
+			})) => assert_eq!(error.code, "node_error"),
+			res => panic!("expected node_error, got {:?}", res),
+		}
+		client::send_partial_tx(&url, &partial_tx, None, 1, 1, 10, None).unwrap();
+		assert_eq!(pushed.lock().unwrap().len(), 2);
+		apis.stop();
+	}
+
+	#[test]
+	fn receive_commit_named() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+
+		let response = receive_json_tx(&config, &keychain, &partial_tx(5_000)).unwrap();
+		let commit = keychain.commit(4_920, &keychain.derive_key_id(1).unwrap()).unwrap();
+		let commit = util::to_hex(commit.as_ref().to_vec());
+		assert_eq!(response.commit, Some(commit.clone()));
+		let tx_bin = util::from_hex(response.tx_hex).unwrap();
+		let final_tx: Transaction = ser::deserialize(&mut &tx_bin[..]).unwrap();
+		assert!(
+			final_tx
+				.outputs
+				.iter()
+				.any(|out| util::to_hex(out.commitment().as_ref().to_vec()) == commit)
+		);
+
+		// what older receivers answer still reads, without a commitment
+		let old: ReceiveResponse =
+			serde_json::from_str(r#"{"tx_hex":"00","message":null}"#).unwrap();
+		assert_eq!(old.commit, None);
+	}
+
+	#[test]
+	fn receiver_rate_limited() {
+		let (mut config, keychain) = test_wallet();
+		let (node, _) = client::test::stub_server(3, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		config.api_listen_port = "0".to_string();
+		config.receive_rate_per_addr = 3;
+		config.rate_limit_localhost = true;
+
+		let mut apis = server::start_receiver(config.clone(), keychain.clone()).unwrap();
+		let addr = apis.local_addr().unwrap();
+		let url = format!("http://{}/v1/receive/transaction", addr);
+		for _ in 0..3 {
+			client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, None).unwrap();
+		}
+
+		// the flood is turned away before the wallet gets touched
+		let before = wallet_dat(&config);
+		for _ in 0..20 {
+			match api::client::post(&url, &partial_tx(5_000)) {
+				Err(api::Error::Remote {
+					status: 429,
+					ref error,
+				}) => {
+					assert_eq!(error.code, "too_many_requests");
+					let retry_after = error.details["retry_after_secs"].as_u64().unwrap();
+					assert!(retry_after > 0 && retry_after <= 20, "{}", retry_after);
+				}
+				res => panic!("expected too_many_requests, got {:?}", res),
+			}
+		}
+		match client::send_partial_tx(&url, &partial_tx(5_000), None, 1, 1, 10, None) {
+			Err(ref e @ Error::Node(api::Error::Remote { status: 429, .. })) => {
+				assert!(e.is_server_failure())
+			}
+			res => panic!("expected too_many_requests, got {:?}", res),
+		}
+		assert_eq!(wallet_dat(&config), before);
+
+		// the miner still gets its coinbases
+		let block_fees = BlockFees {
+			fees: 0,
+			height: 1,
+			key_id: None,
+		};
+		api::client::post(&format!("http://{}/v1/receive/coinbase", addr), &block_fees).unwrap();
+		assert!(wallet_dat(&config) != before);
+		apis.stop();
+	}
 }
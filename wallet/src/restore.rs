@@ -0,0 +1,354 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Restores the outputs of a wallet from its seed alone, scanning the utxo
+//! set of the chain for the outputs derived from its keychain. Our outputs
+//! are recognized by their switch commitment hash, which only depends on
+//! their key, and their value is recovered by rewinding their range proof.
+
+use std::cmp::{max, min};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+use api;
+use checker;
+use core::core::SwitchCommitHash;
+use keychain::Keychain;
+use types::*;
+use util::LOGGER;
+
+/// Blocks the outputs of which are asked to the node at once
+const RESTORE_PAGE_BLOCKS: u64 = 100;
+
+/// Children derived past the highest one found, an output derived further
+/// away than that from any other isn't found
+const RESTORE_GAP_LIMIT: u32 = 100;
+
+/// What a restore found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestoreSummary {
+	/// Outputs found on chain that the wallet didn't know of
+	pub outputs: usize,
+	/// Total value of those outputs
+	pub value: u64,
+	/// Height of the chain the scan went up to
+	pub height: u64,
+	/// Child index handed out next, past all those found in use
+	pub next_child: u32,
+}
+
+/// Recognizes our outputs by their switch commitment hash, deriving the
+/// children of the keychain up to the gap limit past the highest one found.
+struct ChildScanner<'a> {
+	keychain: &'a Keychain,
+	hashes: HashMap<Vec<u8>, u32>,
+	derived: u32,
+	found: HashSet<u32>,
+}
+
+impl<'a> ChildScanner<'a> {
+	fn new(keychain: &'a Keychain, highest: u32) -> Result<ChildScanner<'a>, Error> {
+		let mut scanner = ChildScanner {
+			keychain: keychain,
+			hashes: HashMap::new(),
+			derived: 0,
+			found: HashSet::new(),
+		};
+		scanner.derive_past(highest)?;
+		Ok(scanner)
+	}
+
+	// Derives the children up to the gap limit past n_child.
+	fn derive_past(&mut self, n_child: u32) -> Result<(), Error> {
+		let until = n_child.saturating_add(RESTORE_GAP_LIMIT);
+		while self.derived < until {
+			self.derived += 1;
+			let key_id = self.keychain.derive_key_id(self.derived)?;
+			let switch_commit = self.keychain.switch_commit(&key_id)?;
+			let hash = SwitchCommitHash::from_switch_commit(switch_commit);
+			self.hashes.insert(hash.as_ref().to_vec(), self.derived);
+		}
+		Ok(())
+	}
+
+	/// The child the output with the switch commitment hash is derived
+	/// from, if it's one of ours.
+	fn find(&mut self, hash: &SwitchCommitHash) -> Result<Option<u32>, Error> {
+		match self.hashes.get(hash.as_ref()).cloned() {
+			Some(n_child) => {
+				self.derive_past(n_child)?;
+				self.found.insert(n_child);
+				Ok(Some(n_child))
+			}
+			None => Ok(None),
+		}
+	}
+}
+
+/// Rebuilds the outputs of the wallet from the chain, for a wallet data file
+/// that was lost. The utxo set is scanned block by block, a page at a time,
+/// the progress being saved with the outputs found so an interrupted restore
+/// picks up where it stopped. Outputs the wallet already tracks are left as
+/// they are, restoring again doesn't duplicate them.
+pub fn restore(config: &WalletConfig, keychain: &Keychain) -> Result<RestoreSummary, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let root_key_id = keychain.root_key_id();
+	let (restored_height, highest) = WalletData::read_wallet(&config.data_file_dir, |wdat| {
+		(wdat.restored_height, wdat.next_child(root_key_id.clone()) - 1)
+	})?;
+	let tip_height = checker::get_tip_from_node(config)?.height;
+	let mut scanner = ChildScanner::new(keychain, highest)?;
+	let mut summary = RestoreSummary {
+		outputs: 0,
+		value: 0,
+		height: max(restored_height, tip_height),
+		next_child: 0,
+	};
+
+	let mut start_height = restored_height + 1;
+	while start_height <= tip_height {
+		let end_height = min(start_height + RESTORE_PAGE_BLOCKS - 1, tip_height);
+		info!(LOGGER, "Restoring outputs from blocks {} to {}", start_height, end_height);
+		let url = format!(
+			"{}/v1/chain/utxos/byheight?start_height={}&end_height={}",
+			config.check_node_api_http_addr,
+			start_height,
+			end_height,
+		);
+		let blocks = api::client::get::<Vec<api::BlockOutputs>>(url.as_str())?;
+
+		let mut found = vec![];
+		for block in blocks {
+			for block_out in block.outputs {
+				if let Some(mut out) = restore_output(keychain, &mut scanner, block_out)? {
+					out.block_hash = Some(block.hash.clone());
+					found.push(out);
+				}
+			}
+		}
+		let (outputs, value) = WalletData::with_wallet(&config.data_file_dir, |wdat| {
+			let mut added = (0, 0);
+			for out in found {
+				let value = out.value;
+				if let Entry::Vacant(entry) = wdat.outputs.entry(out.key_id.to_hex()) {
+					entry.insert(out);
+					added = (added.0 + 1, added.1 + value);
+				}
+			}
+			wdat.restored_height = end_height;
+			added
+		})?;
+		summary.outputs += outputs;
+		summary.value += value;
+		start_height = end_height + 1;
+	}
+
+	// the outputs found may be far past the child index counter, and those
+	// spent since can't be found at all
+	let found = scanner
+		.found
+		.iter()
+		.map(|&n_child| keychain.derive_key_id(n_child).map(|key_id| key_id.to_hex()))
+		.collect::<Result<HashSet<_>, _>>()?;
+	summary.next_child = WalletData::with_wallet(&config.data_file_dir, |wdat| {
+		let dir = &config.data_file_dir;
+		wdat.recover_next_child(dir, keychain, RESTORE_GAP_LIMIT, |key_id| {
+			Ok(found.contains(&key_id.to_hex()))
+		})
+	})??;
+
+	info!(
+		LOGGER,
+		"Restored {} outputs worth {} up to height {}, next child index {}",
+		summary.outputs,
+		summary.value,
+		summary.height,
+		summary.next_child
+	);
+	Ok(summary)
+}
+
+// The wallet output for the output found on chain, if it's one of ours.
+fn restore_output(
+	keychain: &Keychain,
+	scanner: &mut ChildScanner,
+	block_out: api::BlockOutput,
+) -> Result<Option<OutputData>, Error> {
+	let n_child = match scanner.find(&block_out.switch_commit_hash)? {
+		Some(n_child) => n_child,
+		None => return Ok(None),
+	};
+	let key_id = keychain.derive_key_id(n_child)?;
+	let out = block_out.output;
+	let info = keychain.rewind_range_proof(&key_id, out.commit, out.proof)?;
+	if !info.success || keychain.commit(info.value, &key_id)? != out.commit {
+		warn!(
+			LOGGER,
+			"Output of child {} at height {} has our switch commitment but not our key",
+			n_child,
+			out.height
+		);
+		return Ok(None);
+	}
+	let is_coinbase = match out.output_type {
+		api::OutputType::Coinbase => true,
+		api::OutputType::Transaction => false,
+	};
+	Ok(Some(OutputData {
+		root_key_id: keychain.root_key_id(),
+		key_id: key_id,
+		n_child: n_child,
+		value: info.value,
+		status: OutputStatus::Unspent,
+		height: out.height,
+		block_hash: None,
+		lock_height: out.lock_height,
+		is_coinbase: is_coinbase,
+		locked_at: 0,
+		account: None,
+		created_at: None,
+		confirmed_at: None,
+		spent_at: None,
+		tx_id: None,
+		label: None,
+		watch_commit: None,
+	}))
+}
+
+#[cfg(test)]
+mod test {
+	use std::env;
+	use std::fs;
+
+	use core::core::build::{output, transaction};
+	use core::core::BlockHeader;
+	use serde_json;
+	use client;
+	use super::*;
+
+	// The unspent outputs of a block at the height, paying the keychain
+	// the values for the children.
+	fn block(height: u64, outputs: Vec<(&Keychain, u32, u64)>) -> api::BlockOutputs {
+		let mut header = BlockHeader::default();
+		header.height = height;
+		let outputs = outputs
+			.into_iter()
+			.map(|(keychain, n_child, value)| {
+				let key_id = keychain.derive_key_id(n_child).unwrap();
+				let (tx, _) = transaction(vec![output(value, key_id)], keychain).unwrap();
+				api::BlockOutput {
+					switch_commit_hash: tx.outputs[0].switch_commit_hash,
+					output: api::Output::from_output(&tx.outputs[0], &header),
+				}
+			})
+			.collect();
+		api::BlockOutputs {
+			height: height,
+			hash: format!("{:064}", height),
+			outputs: outputs,
+		}
+	}
+
+	fn tip(height: u64) -> String {
+		serde_json::to_string(&api::Tip {
+			height: height,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		}).unwrap()
+	}
+
+	#[test]
+	fn outputs_restored() {
+		let ours = Keychain::from_random_seed().unwrap();
+		let theirs = Keychain::from_random_seed().unwrap();
+		let dir = env::temp_dir().join(format!("grin_wallet_restore_{}", ours.root_key_id()));
+		let _ = fs::remove_dir_all(&dir);
+		let mut config = WalletConfig::default();
+		config.data_file_dir = dir.to_str().unwrap().to_string();
+		config = config.for_keychain(&ours).unwrap();
+
+		// two pages, the last output within the gap limit of the second
+		let first_page = vec![
+			block(5, vec![(&theirs, 1, 10), (&ours, 1, 50)]),
+			block(6, vec![(&theirs, 2, 20)]),
+		];
+		let second_page = vec![
+			block(120, vec![(&ours, 3, 70), (&theirs, 3, 30)]),
+			block(130, vec![(&ours, 90, 80)]),
+		];
+		let replies = vec![
+			tip(150),
+			serde_json::to_string(&first_page).unwrap(),
+			serde_json::to_string(&second_page).unwrap(),
+		];
+		let mut replies = replies.into_iter();
+		let (addr, requests) = client::test::recording_stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+
+		let summary = restore(&config, &ours).unwrap();
+		assert_eq!(
+			summary,
+			RestoreSummary {
+				outputs: 3,
+				value: 200,
+				height: 150,
+				// past a margin of 20 after the highest found
+				next_child: 111,
+			}
+		);
+		let requests = requests.lock().unwrap();
+		assert!(requests[1].0.contains("start_height=1&end_height=100"));
+		assert!(requests[2].0.contains("start_height=101&end_height=150"));
+
+		let restored = |config: &WalletConfig| {
+			WalletData::read_wallet(&config.data_file_dir, |wdat| {
+				let mut outputs = wdat.outputs
+					.values()
+					.map(|out| (out.n_child, out.value, out.height, out.status.clone()))
+					.collect::<Vec<_>>();
+				outputs.sort_by_key(|out| out.0);
+				(outputs, wdat.next_child(ours.root_key_id()))
+			}).unwrap()
+		};
+		let unspent = OutputStatus::Unspent;
+		let expected = (
+			vec![
+				(1, 50, 5, unspent.clone()),
+				(3, 70, 120, unspent.clone()),
+				(90, 80, 130, unspent),
+			],
+			91,
+		);
+		assert_eq!(restored(&config), expected);
+
+		// scanning again from the start finds nothing new
+		WalletData::with_wallet(&config.data_file_dir, |wdat| wdat.restored_height = 0).unwrap();
+		let replies = vec![
+			tip(150),
+			serde_json::to_string(&first_page).unwrap(),
+			serde_json::to_string(&second_page).unwrap(),
+		];
+		let mut replies = replies.into_iter();
+		let (addr, _) = client::test::stub_server(3, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = addr;
+		let summary = restore(&config, &ours).unwrap();
+		assert_eq!((summary.outputs, summary.next_child), (0, 111));
+		assert_eq!(restored(&config), expected);
+	}
+}
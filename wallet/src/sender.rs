@@ -12,220 +12,4762 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::{max, min};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use blake2;
 use serde_json;
 
 use api;
 use client;
 use checker;
-use core::core::{build, Transaction};
+use core::core::{build, transaction, Transaction};
 use core::ser;
 use keychain::{BlindingFactor, Identifier, Keychain};
-use receiver::TxWrapper;
+use receiver::{push_tx, TxWrapper};
+use timestamp::Timestamp;
 use types::*;
 use util::LOGGER;
+use util::secp::pedersen::Commitment;
 use util;
 
 /// Issue a new transaction to the provided sender by spending some of our
 /// wallet
-/// UTXOs. The destination can be "stdout" (for command line) or a URL to the
-/// recipients wallet receiver (to be implemented).
+/// UTXOs. The destination can be "stdout" (for command line), a URL to the
+/// recipients wallet receiver, a file path (optionally prefixed with
+/// "file:") the partial transaction gets written to, or "self" to move the
+/// amount to a fresh key of this wallet, "@name" standing for the
+/// destination of a contact. An existing file is only
+/// replaced if overwrite is set. The optional message travels along with the
+/// partial transaction, it's not part of the transaction itself. The fee is
+/// either deducted from the amount or paid on top of it, see FeePaidBy.
+/// Sending to the URL of this wallet's own receiver is refused unless
+/// allow_self_send is set in the config. A send needing more inputs than
+/// max_inputs_per_tx fails, unless auto_split is set and coins get merged by
+/// transactions to self first. Returns what the send spent and recorded.
 
 pub fn issue_send_tx(
 	config: &WalletConfig,
 	keychain: &Keychain,
-	amount: u64,
+	amount: SendAmount,
 	minimum_confirmations: u64,
 	dest: String,
-) -> Result<(), Error> {
-	checker::refresh_outputs(config, keychain)?;
+	overwrite: bool,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	lock_height: Option<u64>,
+	message: Option<String>,
+) -> Result<SendResult, Error> {
+	let config = &config.for_keychain(keychain)?;
+	if let SendAmount::Exact(amount) = amount {
+		check_amount(config, amount)?;
+	}
+
+	// validate the destination before touching any output so we never lock
+	// coins for a send that can't go anywhere
+	let contact = if dest.starts_with('@') {
+		dest[1..].split('#').next().map(|name| name.to_string())
+	} else {
+		None
+	};
+	let dest = config.resolve_dest(&dest)?;
+	let destination = Destination::parse(&dest)?;
+	config.check_not_self(&destination)?;
+
+	// held until the send is fully recorded
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
 
+	validate_message(&message)?;
+	checker::refresh_outputs(config, keychain)?;
 	let chain_tip = checker::get_tip_from_node(config)?;
 	let current_height = chain_tip.height;
+	let lock_height = resolve_lock_height(lock_height, current_height)?;
 
-	// proof of concept - set lock_height on the tx
-	let lock_height = chain_tip.height;
-
-	let (tx, blind_sum) = build_send_tx(
+	let result = match build_pending_send(
 		config,
 		keychain,
 		amount,
 		current_height,
 		minimum_confirmations,
 		lock_height,
-	)?;
+		selection_strategy,
+		fee,
+		fee_paid_by,
+		message.clone(),
+	) {
+		Err(Error::TooManyInputs { .. }) if config.auto_split => split_send_locked(
+			config,
+			keychain,
+			amount,
+			current_height,
+			minimum_confirmations,
+			lock_height,
+			selection_strategy,
+			fee,
+			fee_paid_by,
+			message,
+			&dest,
+			overwrite,
+		),
+		pending => post_send_locked(config, keychain, pending?, &dest, overwrite),
+	}?;
 
-	let partial_tx = build_partial_tx(amount, blind_sum, tx);
+	// for audits, the completed transaction stands for the receiver's
+	// acknowledgement
+	let receiver_ack = match (&destination, &result.tx_hex) {
+		(&Destination::Http(..), &Some(ref tx_hex)) if !tx_hex.is_empty() => {
+			Some(ack_hash(tx_hex))
+		}
+		_ => None,
+	};
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.set_tx_log_context(result.tx_id, contact, current_height, receiver_ack);
+	})?;
+	Ok(result)
+}
 
-	if dest == "stdout" {
-		let json_tx = serde_json::to_string_pretty(&partial_tx).unwrap();
-		println!("{}", json_tx);
-	} else if &dest[..4] == "http" {
-		let url = format!("{}/v1/receive/transaction", &dest);
-		debug!(LOGGER, "Posting partial transaction to {}", url);
-		client::send_partial_tx(&url, &partial_tx)?;
-	} else {
-		panic!("dest not in expected format: {}", dest);
+/// Hash of the completed transaction a receiver answered with, hex encoded.
+fn ack_hash(tx_hex: &str) -> String {
+	util::to_hex(blake2::blake2b::blake2b(32, &[], tx_hex.as_bytes()).as_bytes().to_vec())
+}
+
+/// Pays an invoice issued by a receiver (see receiver::issue_invoice): adds
+/// inputs, change and the fee on top of the amount to the output of the
+/// receiver, posts the transaction back to it to get the kernel signed and
+/// pushes the completed transaction to the node. Invoices past their expiry
+/// are refused before any coin gets selected. Nothing reaches the chain
+/// without us pushing it, so a send the receiver failed to sign is simply
+/// rolled back.
+pub fn pay_invoice(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	invoice_json: &str,
+) -> Result<SendResult, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let invoice: JSONInvoice = serde_json::from_str(invoice_json)?;
+	let invoice_tx = tx_from_hex(&invoice.tx)?;
+	if !invoice_tx.inputs.is_empty() || invoice_tx.outputs.len() != 1 {
+		return Err(Error::Format(
+			"An invoice holds the output of the receiver and nothing else.".to_string(),
+		));
 	}
-	Ok(())
+	check_amount(config, invoice.amount)?;
+	let destination = Destination::parse(&invoice.dest)?;
+	config.check_not_self(&destination)?;
+	let (url, secret) = match destination {
+		Destination::Http(ref uri, ref secret) => (
+			format!("{}/v1/receive/invoice", uri.to_string().trim_right_matches('/')),
+			secret.clone(),
+		),
+		_ => {
+			return Err(Error::InvalidDestination(format!(
+				"invoices are paid to the url of their receiver, not {}",
+				invoice.dest
+			)))
+		}
+	};
+
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+
+	checker::refresh_outputs(config, keychain)?;
+	let current_height = checker::get_tip_from_node(config)?.height;
+	if current_height >= invoice.expiry_height {
+		return Err(Error::InvoiceExpired {
+			expiry_height: invoice.expiry_height,
+			current_height: current_height,
+		});
+	}
+
+	let (tx, blind_sum, summary) = build_send_tx(
+		config,
+		keychain,
+		SendAmount::Exact(invoice.amount),
+		current_height,
+		config.minimum_confirmations,
+		0,
+		SelectionStrategy::Default,
+		None,
+		FeePaidBy::Sender,
+	)?;
+	let tx = tx.with_output(invoice_tx.outputs[0].clone());
+	let log_dest = invoice.dest.split('#').next().unwrap_or("");
+	let pending = log_pending_send(config, log_dest, tx, blind_sum, summary, None)?;
+
+	debug!(LOGGER, "Posting invoice payment to {}", url);
+	let posted = client::send_partial_tx(
+		&url,
+		&pending.partial_tx,
+		secret.as_ref().map(|s| s.as_str()),
+		config.send_attempts,
+		config.send_retry_delay_ms,
+		config.send_timeout_secs,
+		config.tls_receiver_fingerprint.as_ref().map(|f| f.as_str()),
+	).and_then(|response| {
+		let commit = verify_receiver_response(keychain, &pending.partial_tx, &response)?;
+		Ok((response, commit))
+	});
+	let (response, receiver_commit) = match posted {
+		Ok(posted) => posted,
+		Err(e) => {
+			error!(LOGGER, "Failed to get the invoice payment signed, rolling back: {}", e);
+			rollback_send(config, pending.tx_log_id)?;
+			return Err(e);
+		}
+	};
+	push_tx(config, &response.tx_hex, config.fluff)?;
+
+	// older receivers don't name it, it's the output of the invoice anyway
+	let receiver_commit = receiver_commit.or_else(|| {
+		Some(util::to_hex(invoice_tx.outputs[0].commitment().as_ref().to_vec()))
+	});
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let ack = Some(ack_hash(&response.tx_hex));
+		wallet_data.set_tx_log_context(pending.tx_log_id, None, current_height, ack);
+		wallet_data.set_tx_log_receiver_commit(pending.tx_log_id, receiver_commit);
+	})?;
+	Ok(SendResult {
+		tx_id: pending.tx_log_id,
+		amount: pending.summary.estimate.amount,
+		fee: pending.summary.estimate.fee,
+		change_identifiers: pending.summary.change_key_ids,
+		input_identifiers: pending.summary.estimate.inputs,
+		dest: log_dest.to_string(),
+		tx_hex: Some(response.tx_hex),
+		consolidation_tx_ids: vec![],
+		unconfirmed_inputs: pending.summary.estimate.unconfirmed_inputs,
+	})
 }
 
-/// Builds a transaction to send to someone from the HD seed associated with the
-/// wallet and the amount to send. Handles reading through the wallet data file,
-/// selecting outputs to spend and building the change.
-fn build_send_tx(
+/// Sends more coins than a transaction can spend: they're first merged,
+/// max_inputs_per_tx at a time, by transactions to self pushed to the node,
+/// and the send then spends what the merges produced. Enough is selected
+/// upfront to also pay for the merges. What the merges produce is
+/// unconfirmed until they're mined, so like any unconfirmed output it's
+/// only eligible to spend without a minimum of confirmations, a send made
+/// with one fails as if auto_split wasn't set. Has to be called holding the
+/// send lock.
+fn split_send_locked(
 	config: &WalletConfig,
 	keychain: &Keychain,
-	amount: u64,
+	amount: SendAmount,
 	current_height: u64,
 	minimum_confirmations: u64,
 	lock_height: u64,
-) -> Result<(Transaction, BlindingFactor), Error> {
-	let key_id = keychain.clone().root_key_id();
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	message: Option<String>,
+	dest: &str,
+	overwrite: bool,
+) -> Result<SendResult, Error> {
+	let max_inputs = config.max_inputs_per_tx;
 
-	// select some spendable coins from the wallet
-	let coins = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		wallet_data.select(key_id.clone(), current_height, minimum_confirmations)
+	// the more coins are selected the more merges they need, until what's
+	// selected pays for all of them
+	let mut margin = 0;
+	let mut coins;
+	loop {
+		let select_amount = match amount {
+			SendAmount::Exact(amount) => {
+				SendAmount::Exact(amount.checked_add(margin).ok_or(Error::AmountOverflow)?)
+			}
+			SendAmount::Max => SendAmount::Max,
+		};
+		coins = select_any_number_of_coins(
+			config,
+			keychain.root_key_id(),
+			select_amount,
+			current_height,
+			minimum_confirmations,
+			lock_height,
+			selection_strategy,
+			fee,
+			fee_paid_by,
+			config.change_outputs,
+		)?.0;
+		let fees = consolidation_fees(coins.len(), max_inputs);
+		if fees <= margin {
+			break;
+		}
+		margin = fees;
+	}
+	// merging pairs at the very least, into outputs the send can spend
+	if coins.len() > max_inputs {
+		let merged = OutputData {
+			status: OutputStatus::Unconfirmed,
+			height: 0,
+			lock_height: 0,
+			is_coinbase: false,
+			..coins[0].clone()
+		};
+		if max_inputs < 2 || !merged.eligible_to_spend(current_height, minimum_confirmations) {
+			return Err(Error::TooManyInputs {
+				inputs: coins.len(),
+				max: max_inputs,
+			});
+		}
+	}
+
+	let mut consolidation_tx_ids = vec![];
+	while coins.len() > max_inputs {
+		let mut merged = vec![];
+		for chunk in coins.chunks(max_inputs) {
+			if chunk.len() == 1 {
+				merged.push(chunk[0].clone());
+				continue;
+			}
+			let (tx_id, output) = merge_coins(config, keychain, chunk.to_vec(), current_height)?;
+			info!(
+				LOGGER,
+				"Merged {} coins into one in transaction {}",
+				chunk.len(),
+				tx_id
+			);
+			consolidation_tx_ids.push(tx_id);
+			merged.push(output);
+		}
+		coins = merged;
+	}
+
+	let estimate = estimate_send(
+		&coins,
+		amount,
+		lock_height,
+		fee,
+		fee_paid_by,
+		config.change_outputs,
+		config.dust_threshold,
+	)?;
+	let (tx, blind_sum, summary) =
+		build_selected_tx(config, keychain, &coins, estimate, lock_height, current_height)?;
+	let pending = log_pending_send(config, "", tx, blind_sum, summary, message)?;
+	let mut result = post_send_locked(config, keychain, pending, dest, overwrite)?;
+	result.consolidation_tx_ids = consolidation_tx_ids;
+	Ok(result)
+}
+
+/// Merges the coins into a single new output of ours, through a transaction
+/// to self pushed to the node. Returns the id of the transaction in the log
+/// along with the new output.
+fn merge_coins(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	coins: Vec<OutputData>,
+	current_height: u64,
+) -> Result<(u32, OutputData), Error> {
+	let estimate = estimate_send(&coins, SendAmount::Max, 0, None, FeePaidBy::Recipient, 0, 0)?;
+	let (tx, blind_sum, summary) =
+		build_selected_tx(config, keychain, &coins, estimate, 0, current_height)?;
+	let pending = log_pending_send(config, "self", tx, blind_sum, summary, None)?;
+	match finalize_self_spend(config, keychain, &pending.partial_tx) {
+		Ok((_, output)) => Ok((pending.tx_log_id, output)),
+		Err(e) => {
+			keep_or_rollback(config, pending.tx_log_id, &e)?;
+			Err(e)
+		}
+	}
+}
+
+/// Consolidates the wallet: merges its smallest spendable coins, up to
+/// `max_inputs` of them (no more than max_inputs_per_tx), into
+/// `target_outputs` new outputs of near-equal values, through a transaction
+/// to self pushed to the node. The fee is taken out of the coins merged, and
+/// the consolidation is refused when it would be more than
+/// max_consolidation_fee_percent of their value.
+pub fn consolidate(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	max_inputs: usize,
+	target_outputs: usize,
+) -> Result<SendResult, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+
+	checker::refresh_outputs(config, keychain)?;
+	let current_height = checker::get_tip_from_node(config)?.height;
+	consolidate_locked(config, keychain, current_height, max_inputs, target_outputs)
+}
+
+// Consolidates the coins spendable at the provided height, has to be called
+// holding the send lock.
+fn consolidate_locked(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	current_height: u64,
+	max_inputs: usize,
+	target_outputs: usize,
+) -> Result<SendResult, Error> {
+	let target_outputs = max(target_outputs, 1);
+	let mut coins = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.eligible_coins(
+			keychain.root_key_id(),
+			current_height,
+			config.minimum_confirmations,
+		)
 	})?;
+	// smallest first
+	coins.truncate(min(max_inputs, config.max_inputs_per_tx));
+	if coins.len() <= target_outputs {
+		return Err(Error::NothingToConsolidate {
+			coins: coins.len(),
+			target_outputs: target_outputs,
+		});
+	}
 
-	// build transaction skeleton with inputs and change
-	let mut parts = inputs_and_change(&coins, config, keychain, key_id, amount)?;
+	let total = total_value(&coins)?;
+	let fee = tx_fee(coins.len(), target_outputs, None);
+	if fee >= total {
+		return Err(Error::FeeExceedsAmount {
+			fee: fee,
+			amount: total,
+		});
+	}
+	let max_percent = config.max_consolidation_fee_percent;
+	if fee.saturating_mul(100) > total.saturating_mul(max_percent) {
+		return Err(Error::ConsolidationFeeTooHigh {
+			fee: fee,
+			value: total,
+			max_percent: max_percent,
+		});
+	}
 
-	// This is more proof of concept than anything but here we set lock_height
- // on tx being sent (based on current chain height via api).
-	parts.push(build::with_lock_height(lock_height));
+	// the output to self stands for the first of the new outputs, the change
+	// for the others
+	let values = split_change(total - fee, target_outputs);
+	let estimate = estimate_send(
+		&coins,
+		SendAmount::Exact(values[0] + fee),
+		0,
+		Some(fee),
+		FeePaidBy::Recipient,
+		target_outputs - 1,
+		0,
+	)?;
+	let (tx, blind_sum, summary) =
+		build_selected_tx(config, keychain, &coins, estimate, 0, current_height)?;
+	let pending = log_pending_send(config, "self", tx, blind_sum, summary, None)?;
+	let (tx_hex, output) = match finalize_self_spend(config, keychain, &pending.partial_tx) {
+		Ok(finalized) => finalized,
+		Err(e) => {
+			keep_or_rollback(config, pending.tx_log_id, &e)?;
+			return Err(e);
+		}
+	};
+	info!(
+		LOGGER,
+		"Consolidated {} coins into {} outputs in transaction {}",
+		coins.len(),
+		pending.summary.change_key_ids.len() + 1,
+		pending.tx_log_id
+	);
 
-	let (tx, blind) = build::transaction(parts, &keychain)?;
+	let mut change_identifiers = vec![output.key_id];
+	change_identifiers.extend(pending.summary.change_key_ids);
+	Ok(SendResult {
+		tx_id: pending.tx_log_id,
+		amount: pending.summary.estimate.amount,
+		fee: pending.summary.estimate.fee,
+		change_identifiers: change_identifiers,
+		input_identifiers: pending.summary.estimate.inputs,
+		dest: "self".to_string(),
+		tx_hex: Some(tx_hex),
+		consolidation_tx_ids: vec![],
+		unconfirmed_inputs: pending.summary.estimate.unconfirmed_inputs,
+	})
+}
 
-	Ok((tx, blind))
+/// Total fee of the transactions merging `count` coins, `max_inputs` at a
+/// time, until no more than `max_inputs` are left.
+fn consolidation_fees(count: usize, max_inputs: usize) -> u64 {
+	let mut count = count;
+	let mut fees = 0;
+	while max_inputs > 1 && count > max_inputs {
+		let (full, rest) = (count / max_inputs, count % max_inputs);
+		fees += full as u64 * tx_fee(max_inputs, 1, None);
+		if rest > 1 {
+			fees += tx_fee(rest, 1, None);
+		}
+		count = full + min(rest, 1);
+	}
+	fees
 }
 
-pub fn issue_burn_tx(
+/// Pays several recipients in one go, each payment of an amount to a
+/// destination being its own transaction. Outputs are refreshed from the node
+/// once for the whole batch and, as coins locked by a payment can't be spent
+/// by the following ones, a payment failing doesn't stop the others: the
+/// result of each payment is returned in order.
+pub fn issue_batch_send(
 	config: &WalletConfig,
 	keychain: &Keychain,
-	amount: u64,
+	payments: Vec<(u64, String)>,
+) -> Result<Vec<Result<SendResult, Error>>, Error> {
+	let config = &config.for_keychain(keychain)?;
+	checker::refresh_outputs(config, keychain)?;
+	let chain_tip = checker::get_tip_from_node(config)?;
+	Ok(batch_send(config, keychain, chain_tip.height, payments))
+}
+
+// Sends each payment in turn, under its own send lock.
+fn batch_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	current_height: u64,
+	payments: Vec<(u64, String)>,
+) -> Vec<Result<SendResult, Error>> {
+	payments
+		.into_iter()
+		.map(|(amount, dest)| {
+			let dest = config.resolve_dest(&dest)?;
+			config.check_not_self(&Destination::parse(&dest)?)?;
+
+			let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+			let pending = build_pending_send(
+				config,
+				keychain,
+				SendAmount::Exact(amount),
+				current_height,
+				config.minimum_confirmations,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+			)?;
+			post_send_locked(config, keychain, pending, &dest, false)
+		})
+		.collect()
+}
+
+/// First half of a send: selects and locks the coins, records the change and
+/// builds the partial transaction, without contacting the recipient. The
+/// returned pending send can be inspected (and persisted) before being
+/// handed to post_send, or given up with abort_send.
+pub fn prepare_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: SendAmount,
 	minimum_confirmations: u64,
-) -> Result<(), Error> {
-	let keychain = &Keychain::burn_enabled(keychain, &Identifier::zero());
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	lock_height: Option<u64>,
+	message: Option<String>,
+) -> Result<PendingSend, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+	prepare_send_locked(
+		config,
+		keychain,
+		amount,
+		minimum_confirmations,
+		selection_strategy,
+		fee,
+		fee_paid_by,
+		lock_height,
+		message,
+	)
+}
+
+// Same as prepare_send, the caller holding the send lock.
+fn prepare_send_locked(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: SendAmount,
+	minimum_confirmations: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	lock_height: Option<u64>,
+	message: Option<String>,
+) -> Result<PendingSend, Error> {
+	validate_message(&message)?;
+
+	checker::refresh_outputs(config, keychain)?;
 
 	let chain_tip = checker::get_tip_from_node(config)?;
 	let current_height = chain_tip.height;
+	let lock_height = resolve_lock_height(lock_height, current_height)?;
 
-	let _ = checker::refresh_outputs(config, keychain);
+	build_pending_send(
+		config,
+		keychain,
+		amount,
+		current_height,
+		minimum_confirmations,
+		lock_height,
+		selection_strategy,
+		fee,
+		fee_paid_by,
+		message,
+	)
+}
 
-	let key_id = keychain.root_key_id();
+/// Second half of a send: delivers the partial transaction of a pending send
+/// to its destination. The send is rolled back if that fails, or if the
+/// transaction completed by an HTTP receiver doesn't check out.
+pub fn post_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	pending: PendingSend,
+	dest: &str,
+	overwrite: bool,
+) -> Result<SendResult, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let dest = config.resolve_dest(dest)?;
+	config.check_not_self(&Destination::parse(&dest)?)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+	post_send_locked(config, keychain, pending, &dest, overwrite)
+}
 
-	// select some spendable coins from the wallet
-	let coins = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		wallet_data.select(key_id.clone(), current_height, minimum_confirmations)
+// Same as post_send, the caller holding the send lock.
+fn post_send_locked(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	pending: PendingSend,
+	dest: &str,
+	overwrite: bool,
+) -> Result<SendResult, Error> {
+	let destination = Destination::parse(dest)?;
+
+	// the secret of the receiver has no business in the log
+	let log_dest = match destination {
+		Destination::Http(_, Some(_)) => dest.split('#').next().unwrap_or(""),
+		_ => dest,
+	};
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.set_tx_log_dest(pending.tx_log_id, log_dest);
 	})?;
+	let tx_hex = deliver_or_rollback(
+		config,
+		keychain,
+		&destination,
+		&pending.partial_tx,
+		overwrite,
+		pending.tx_log_id,
+	)?;
 
-	let mut parts = inputs_and_change(&coins, config, keychain, key_id, amount)?;
+	Ok(SendResult {
+		tx_id: pending.tx_log_id,
+		amount: pending.summary.estimate.amount,
+		fee: pending.summary.estimate.fee,
+		change_identifiers: pending.summary.change_key_ids,
+		input_identifiers: pending.summary.estimate.inputs,
+		dest: log_dest.to_string(),
+		tx_hex: tx_hex,
+		consolidation_tx_ids: vec![],
+		unconfirmed_inputs: pending.summary.estimate.unconfirmed_inputs,
+	})
+}
 
-	// add burn output and fees
-	let fee = tx_fee(coins.len(), 2, None);
-	parts.push(build::output(amount - fee, Identifier::zero()));
+/// Gives up on a pending send that was never posted, unlocking its coins and
+/// forgetting its change.
+pub fn abort_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	pending: PendingSend,
+) -> Result<(), Error> {
+	let config = &config.for_keychain(keychain)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+	rollback_send(config, pending.tx_log_id)
+}
 
-	// finalize the burn transaction and send
-	let (tx_burn, _) = build::transaction(parts, &keychain)?;
-	tx_burn.validate(&keychain.secp())?;
+fn build_pending_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: SendAmount,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	message: Option<String>,
+) -> Result<PendingSend, Error> {
+	validate_message(&message)?;
 
-	let tx_hex = util::to_hex(ser::ser_vec(&tx_burn).unwrap());
-	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
-	let _: () =
-		api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex }).map_err(|e| Error::Node(e))?;
-	Ok(())
+	let (tx, blind_sum, summary) = build_send_tx(
+		config,
+		keychain,
+		amount,
+		current_height,
+		minimum_confirmations,
+		lock_height,
+		selection_strategy,
+		fee,
+		fee_paid_by,
+	)?;
+
+	// the destination is only known once the send gets posted
+	log_pending_send(config, "", tx, blind_sum, summary, message)
 }
 
-fn next_available_key(
+/// Logs the send of a built transaction, along with its partial transaction.
+fn log_pending_send(
+	config: &WalletConfig,
+	dest: &str,
+	tx: Transaction,
+	blind_sum: BlindingFactor,
+	summary: SendSummary,
+	message: Option<String>,
+) -> Result<PendingSend, Error> {
+	// the receiver pushing the transaction is only told to fluff when we want
+	// it to, it's up to its own config otherwise
+	let fluff = if config.fluff { Some(true) } else { None };
+	let partial_tx =
+		build_partial_tx(summary.estimate.amount, blind_sum, tx, message).with_fluff(fluff);
+	let tx_log_id = log_send(config, dest, &summary, Some(&partial_tx))?;
+
+	Ok(PendingSend {
+		partial_tx: partial_tx,
+		summary: summary,
+		tx_log_id: tx_log_id,
+	})
+}
+
+/// Finalizes a send from the completed transaction handed back by the
+/// receiver in a file (the same hex encoded transaction JSON pushed to the
+/// node), for receivers that can't be reached over HTTP. The transaction is
+/// only pushed to the node if it validates and matches a pending send: same
+/// inputs, same fee and our change included. With fluff the node broadcasts
+/// it right away instead of relaying it along a stem first.
+pub fn finalize_from_file(
 	config: &WalletConfig,
 	keychain: &Keychain,
-) -> Result<(Identifier, u32), Error> {
-	let res = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
-		let root_key_id = keychain.root_key_id();
-		let derivation = wallet_data.next_child(root_key_id.clone());
-		let key_id = keychain.derive_key_id(derivation).unwrap();
-		(key_id, derivation)
-	})?;
-	Ok(res)
+	path: &Path,
+	fluff: bool,
+) -> Result<(), Error> {
+	let config = &config.for_keychain(keychain)?;
+	let tx = read_final_tx_file(path)?;
+	let tx_log_id = verify_final_tx(config, keychain, &tx)?;
+	debug!(LOGGER, "Finalizing send {} from {}", tx_log_id, path.display());
+
+	let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+	push_tx(config, &tx_hex, fluff)
 }
 
-fn inputs_and_change(
-	coins: &Vec<OutputData>,
+fn read_final_tx_file(path: &Path) -> Result<Transaction, Error> {
+	let file = fs::File::open(path)?;
+	let wrapper: TxWrapper = serde_json::from_reader(file)?;
+	tx_from_hex(&wrapper.tx_hex)
+}
+
+fn tx_from_hex(tx_hex: &str) -> Result<Transaction, Error> {
+	let tx_bin = util::from_hex(tx_hex.to_string())?;
+	ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
+		Error::Format("Could not deserialize transaction, invalid format.".to_string())
+	})
+}
+
+/// Checks a completed transaction is valid and corresponds to one of our
+/// pending sends, returning the id of that send in the transaction log.
+fn verify_final_tx(
 	config: &WalletConfig,
 	keychain: &Keychain,
-	root_key_id: Identifier,
-	amount: u64,
-) -> Result<Vec<Box<build::Append>>, Error> {
-	let mut parts = vec![];
+	tx: &Transaction,
+) -> Result<u32, Error> {
+	tx.validate(keychain.secp())
+		.map_err(|e| Error::MalformedResponse(format!("invalid transaction: {:?}", e)))?;
 
-	// calculate the total across all inputs, and how much is left
-	let total: u64 = coins.iter().map(|c| c.value).sum();
-	let shortage = (total as i64) - (amount as i64);
-	if shortage < 0 {
-		return Err(Error::NotEnoughFunds((-shortage) as u64));
+	let (pending, outputs) = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		(
+			wallet_data
+				.tx_log
+				.iter()
+				.filter(|entry| entry.status == TxLogStatus::Pending)
+				.cloned()
+				.collect::<Vec<_>>(),
+			wallet_data.outputs.clone(),
+		)
+	})?;
+	let commit = |key_id: &Identifier| -> Result<Commitment, Error> {
+		match outputs.get(&key_id.to_hex()) {
+			Some(out) => Ok(keychain.commit(out.value, &out.key_id)?),
+			None => Err(Error::WalletData(format!("No output for key_id {}", key_id))),
+		}
+	};
+
+	let tx_inputs: HashSet<Commitment> = tx.inputs.iter().map(|i| i.commitment()).collect();
+	let tx_outputs: HashSet<Commitment> = tx.outputs.iter().map(|o| o.commitment()).collect();
+	for entry in pending {
+		let mut inputs = HashSet::new();
+		for key_id in &entry.input_ids {
+			inputs.insert(commit(key_id)?);
+		}
+		if inputs != tx_inputs {
+			continue;
+		}
+
+		for key_id in &entry.change_ids {
+			if !tx_outputs.contains(&commit(key_id)?) {
+				return Err(Error::MalformedResponse(format!(
+					"change output {} of send {} is missing or modified",
+					key_id,
+					entry.id
+				)));
+			}
+		}
+		if tx.fee != entry.fee {
+			return Err(Error::MalformedResponse(format!(
+				"fee {} doesn't match the fee {} of send {}",
+				tx.fee,
+				entry.fee,
+				entry.id
+			)));
+		}
+		return Ok(entry.id);
 	}
+	Err(Error::MalformedResponse(
+		"the transaction doesn't spend the inputs of any pending send".to_string(),
+	))
+}
 
-	// sender is responsible for setting the fee on the partial tx
- // recipient should double check the fee calculation and not blindly trust the
- // sender
-	let fee = tx_fee(coins.len(), 2, None);
-	parts.push(build::with_fee(fee));
+/// Records the send in the wallet transaction log, along with its partial
+/// transaction so it can be resent.
+fn log_send(
+	config: &WalletConfig,
+	dest: &str,
+	summary: &SendSummary,
+	partial_tx: Option<&JSONPartialTx>,
+) -> Result<u32, Error> {
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let id = wallet_data.add_tx_log_entry(
+			summary.estimate.amount,
+			summary.estimate.fee,
+			dest,
+			summary.estimate.inputs.clone(),
+			summary.change_key_ids.clone(),
+			partial_tx.and_then(|p| p.message()).map(|m| m.to_string()),
+		);
+		if let Some(partial_tx) = partial_tx {
+			wallet_data.set_tx_log_partial_tx(id, partial_tx.clone());
+		}
+		wallet_data.set_transaction_log_id(summary.tx_id, id);
+		id
+	})
+}
 
-	// if we are spending 10,000 coins to send 1,000 then our change will be 9,000
- // the fee will come out of the amount itself
- // if the fee is 80 then the recipient will only receive 920
- // but our change will still be 9,000
-	let change = total - amount;
+/// Posts the partial transaction of a pending send again, to the destination
+/// it was posted to, for when the receiver couldn't be reached the first
+/// time. Nothing is selected or derived again, the send keeps its coins and
+/// change. The secret a receiver requires isn't recorded and has to be
+/// provided again. Sends that aren't pending anymore are refused.
+pub fn resend(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	tx_id: u32,
+	secret: Option<&str>,
+) -> Result<SendResult, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
 
-	// build inputs using the appropriate derived key_ids
-	for coin in coins {
-		let key_id = keychain.derive_key_id(coin.n_child)?;
-		parts.push(build::input(coin.value, key_id));
+	let entry = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.find_tx_log_entry(&tx_id.to_string()).cloned()
+	})?;
+	let entry = match entry {
+		Some(entry) => entry,
+		None => return Err(Error::TxNotFound(tx_id.to_string())),
+	};
+	if entry.status != TxLogStatus::Pending {
+		return Err(Error::TxNotPending {
+			id: entry.id,
+			status: entry.status,
+		});
 	}
+	let partial_tx = match entry.partial_tx {
+		Some(ref partial_tx) => partial_tx.clone(),
+		None => {
+			return Err(Error::WalletData(format!(
+				"No partial transaction recorded for send {}",
+				entry.id
+			)))
+		}
+	};
 
-	let (change_key, change_derivation) = next_available_key(config, keychain)?;
+	let dest = match secret {
+		Some(secret) => format!("{}#{}", entry.dest, secret),
+		None => entry.dest.clone(),
+	};
+	let destination = Destination::parse(&dest)?;
+	config.check_not_self(&destination)?;
+	debug!(LOGGER, "Resending send {} to {}", entry.id, entry.dest);
+	let tx_hex = deliver_or_rollback(
+		config,
+		keychain,
+		&destination,
+		&partial_tx,
+		false,
+		entry.id,
+	)?;
 
-	parts.push(build::output(change, change_key.clone()));
+	Ok(SendResult {
+		tx_id: entry.id,
+		amount: entry.amount,
+		fee: entry.fee,
+		change_identifiers: entry.change_ids,
+		input_identifiers: entry.input_ids,
+		dest: entry.dest,
+		tx_hex: tx_hex,
+		consolidation_tx_ids: vec![],
+		unconfirmed_inputs: 0,
+	})
+}
 
-	// Acquire wallet lock, add the new change output and lock coins being spent.
-	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
-		// we got that far, time to start tracking the output representing our change
-		wallet_data.add_output(OutputData {
-			root_key_id: root_key_id.clone(),
-			key_id: change_key.clone(),
-			n_child: change_derivation,
-			value: change as u64,
-			status: OutputStatus::Unconfirmed,
-			height: 0,
-			lock_height: 0,
-			is_coinbase: false,
-		});
+/// Delivers the partial transaction to its destination. If the receiver
+/// rejects it, or it can't be delivered at all, the send is rolled back so
+/// we're not left with locked coins and a change output that will never
+/// exist. If the receiver just couldn't be reached, or didn't answer in time
+/// and may well have taken the transaction, the send stays pending so it can
+/// be resent, or cancelled. Once delivered, the commitment of the output the
+/// receiver got paid with is recorded in the transaction log, if known.
+fn deliver_or_rollback(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	dest: &Destination,
+	partial_tx: &JSONPartialTx,
+	overwrite: bool,
+	tx_log_id: u32,
+) -> Result<Option<String>, Error> {
+	match deliver(config, keychain, dest, partial_tx, overwrite) {
+		Ok((tx_hex, None)) => Ok(tx_hex),
+		Ok((tx_hex, receiver_commit)) => {
+			WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.set_tx_log_receiver_commit(tx_log_id, receiver_commit);
+			})?;
+			Ok(tx_hex)
+		}
+		Err(e) => {
+			keep_or_rollback(config, tx_log_id, &e)?;
+			Err(e)
+		}
+	}
+}
 
-		// now lock the ouputs we're spending so we avoid accidental double spend
-		// attempt
-		for coin in coins {
-			wallet_data.lock_output(coin);
+// What becomes of a send that failed to be delivered, see
+// deliver_or_rollback.
+fn keep_or_rollback(config: &WalletConfig, tx_log_id: u32, err: &Error) -> Result<(), Error> {
+	match *err {
+		ref e if e.is_server_failure() => {
+			warn!(
+				LOGGER,
+				"Failed to reach the receiver, send {} kept pending to be resent: {}",
+				tx_log_id,
+				e
+			);
 		}
-	})?;
+		Error::NodeTimeout(ref e) => {
+			warn!(
+				LOGGER,
+				"Receiver didn't answer, send {} kept pending to be resent: {}",
+				tx_log_id,
+				e
+			);
+		}
+		ref e => {
+			error!(LOGGER, "Failed to deliver partial transaction, rolling back: {}", e);
+			rollback_send(config, tx_log_id)?;
+		}
+	}
+	Ok(())
+}
 
-	Ok(parts)
+// Returns the completed transaction, hex encoded, if we got it back, and the
+// commitment of the output of the receiver, if it named it.
+fn deliver(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	dest: &Destination,
+	partial_tx: &JSONPartialTx,
+	overwrite: bool,
+) -> Result<(Option<String>, Option<String>), Error> {
+	match *dest {
+		Destination::Stdout => {
+			let json_tx = serde_json::to_string_pretty(partial_tx)?;
+			println!("{}", json_tx);
+		}
+		Destination::CompactStdout => {
+			println!("{}", partial_tx.to_compact());
+		}
+		Destination::Http(ref uri, ref secret) => {
+			let url = format!(
+				"{}/v1/receive/transaction",
+				uri.to_string().trim_right_matches('/')
+			);
+			debug!(LOGGER, "Posting partial transaction to {}", url);
+			let response = client::send_partial_tx(
+				&url,
+				partial_tx,
+				secret.as_ref().map(|s| s.as_str()),
+				config.send_attempts,
+				config.send_retry_delay_ms,
+				config.send_timeout_secs,
+				config.tls_receiver_fingerprint.as_ref().map(|f| f.as_str()),
+			)?;
+			let commit = verify_receiver_response(keychain, partial_tx, &response)?;
+			return Ok((Some(response.tx_hex), commit));
+		}
+		Destination::File(ref path) => {
+			write_partial_tx_file(path, partial_tx, overwrite)?;
+			println!("Partial transaction written to {}", path.display());
+		}
+		Destination::SelfSpend => {
+			let (tx_hex, output) = finalize_self_spend(config, keychain, partial_tx)?;
+			let commit = keychain.commit(output.value, &output.key_id)?;
+			return Ok((Some(tx_hex), Some(util::to_hex(commit.as_ref().to_vec()))));
+		}
+	}
+	Ok((None, None))
 }
 
-#[cfg(test)]
-mod test {
-	use core::core::build::{input, output, transaction};
-	use keychain::Keychain;
+/// Plays the part of the receiver for a send to ourselves: adds an output
+/// for a fresh key of ours to the partial transaction, pushes the completed
+/// transaction to the node and only then starts tracking the new output, so
+/// a failed push can simply be retried. Returns the transaction, hex encoded,
+/// and the new output.
+fn finalize_self_spend(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	partial_tx: &JSONPartialTx,
+) -> Result<(String, OutputData), Error> {
+	let (amount, blinding, tx) = read_partial_tx(keychain, partial_tx)?;
+	if tx.fee >= amount {
+		return Err(Error::FeeExceedsAmount {
+			fee: tx.fee,
+			amount: amount,
+		});
+	}
+	let out_amount = amount - tx.fee;
+	let (key_id, derivation) = next_available_keys(config, keychain, 1)?.remove(0);
 
-	#[test]
-	// demonstrate that input.commitment == referenced output.commitment
-	// based on the public key and amount begin spent
-	fn output_commitment_equals_input_commitment_on_spend() {
-		let keychain = Keychain::from_random_seed().unwrap();
-		let key_id1 = keychain.derive_key_id(1).unwrap();
+	let (final_tx, _) = build::transaction(
+		vec![
+			build::initial_tx(tx),
+			build::with_excess(blinding),
+			build::output(out_amount, key_id.clone()),
+		],
+		keychain,
+	)?;
+	final_tx.validate(&keychain.secp())?;
 
-		let (tx1, _) = transaction(vec![output(105, key_id1.clone())], &keychain).unwrap();
-		let (tx2, _) = transaction(vec![input(105, key_id1.clone())], &keychain).unwrap();
+	let tx_hex = util::to_hex(ser::ser_vec(&final_tx).unwrap());
+	push_tx(config, &tx_hex, config.fluff)?;
 
-		assert_eq!(tx1.outputs[0].commitment(), tx2.inputs[0].commitment());
+	let output = OutputData {
+		root_key_id: keychain.root_key_id(),
+		key_id: key_id,
+		n_child: derivation,
+		value: out_amount,
+		status: OutputStatus::Unconfirmed,
+		height: 0,
+		block_hash: None,
+		lock_height: 0,
+		is_coinbase: false,
+		locked_at: 0,
+		account: None,
+		created_at: Some(Timestamp::now()),
+		confirmed_at: None,
+		spent_at: None,
+		tx_id: None,
+		label: None,
+		watch_commit: None,
+	};
+	let fee = final_tx.fee;
+	let dir = &config.data_file_dir;
+	let output = WalletData::with_wallet(dir, |wallet_data| -> Result<OutputData, Error> {
+		wallet_data.add_output(output.clone())?;
+		let output_ids = vec![output.key_id.clone()];
+		let tx_id =
+			wallet_data.add_transaction(TxDirection::Received, out_amount, fee, vec![], output_ids);
+		Ok(OutputData {
+			tx_id: Some(tx_id),
+			..output
+		})
+	})??;
+	Ok((tx_hex, output))
+}
+
+/// Checks the transaction completed by the receiver only added to our partial
+/// transaction: same inputs, our outputs untouched, same fee and lock height,
+/// and that it's valid as a whole. The output the receiver names as the one
+/// it got paid with has to be the one it added, or the one of the invoice if
+/// it added none, and its commitment is returned.
+fn verify_receiver_response(
+	keychain: &Keychain,
+	partial_tx: &JSONPartialTx,
+	response: &ReceiveResponse,
+) -> Result<Option<String>, Error> {
+	if response.tx_hex.is_empty() {
+		return Err(Error::MalformedResponse(
+			"the receiver didn't return the completed transaction".to_string(),
+		));
+	}
+	let final_tx = tx_from_hex(&response.tx_hex)
+		.map_err(|e| Error::MalformedResponse(format!("{}", e)))?;
+	let (_, _, tx) = read_partial_tx(keychain, partial_tx)?;
+
+	let commits = |tx: &Transaction| -> HashSet<Commitment> {
+		tx.inputs.iter().map(|i| i.commitment()).collect()
+	};
+	if commits(&final_tx) != commits(&tx) {
+		return Err(Error::MalformedResponse(
+			"the inputs differ from the ones we sent".to_string(),
+		));
+	}
+	let final_outputs = final_tx
+		.outputs
+		.iter()
+		.map(|out| ser::ser_vec(out).unwrap())
+		.collect::<Vec<_>>();
+	for out in &tx.outputs {
+		if !final_outputs.contains(&ser::ser_vec(out).unwrap()) {
+			return Err(Error::MalformedResponse(format!(
+				"our output {:?} is missing or modified",
+				out.commitment()
+			)));
+		}
+	}
+	if final_tx.fee != tx.fee {
+		return Err(Error::MalformedResponse(format!(
+			"fee {} doesn't match the fee {} we sent",
+			final_tx.fee,
+			tx.fee
+		)));
+	}
+	if final_tx.lock_height != tx.lock_height {
+		return Err(Error::MalformedResponse(format!(
+			"lock height {} doesn't match the lock height {} we sent",
+			final_tx.lock_height,
+			tx.lock_height
+		)));
+	}
+	// checks the kernel excess, signed by the receiver, accounts for our
+	// blinding factors as well as its own
+	final_tx
+		.validate(keychain.secp())
+		.map_err(|e| Error::MalformedResponse(format!("invalid transaction: {:?}", e)))?;
+
+	let commit = match response.commit {
+		Some(ref commit) => commit.to_lowercase(),
+		None => return Ok(None),
+	};
+	let sent = tx.outputs.iter().map(|out| out.commitment()).collect::<Vec<_>>();
+	let mut added = final_tx
+		.outputs
+		.iter()
+		.map(|out| out.commitment())
+		.filter(|c| !sent.contains(c))
+		.collect::<Vec<_>>();
+	if added.is_empty() {
+		added = sent;
+	}
+	if !added.iter().any(|c| util::to_hex(c.as_ref().to_vec()) == commit) {
+		return Err(Error::MalformedResponse(format!(
+			"output {} isn't one the receiver added",
+			commit
+		)));
+	}
+	Ok(Some(commit))
+}
+
+/// Puts the wallet data back in the state it was in before the send was
+/// built: the coins are unlocked and the change outputs that never made it
+/// on chain are forgotten. Their derivation indexes stay reserved, a later
+/// send never derives the same change keys. The transaction log entry is kept
+/// and marked as aborted.
+fn rollback_send(config: &WalletConfig, tx_log_id: u32) -> Result<(), Error> {
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let entry = match wallet_data.find_tx_log_entry(&tx_log_id.to_string()) {
+			Some(entry) => entry.clone(),
+			None => return Ok(()),
+		};
+		undo_logged_send(wallet_data, &entry, TxLogStatus::Aborted)
+	})?
+}
+
+// Undoes the send of the transaction log entry through its wallet
+// transaction, or through the entry itself for sends recorded before wallet
+// transactions were.
+fn undo_logged_send(
+	wallet_data: &mut WalletData,
+	entry: &TxLogEntry,
+	status: TxLogStatus,
+) -> Result<(), Error> {
+	let tx_id = wallet_data.transaction_of_send(entry.id).map(|tx| tx.id);
+	if let Some(tx_id) = tx_id {
+		return wallet_data.undo_send(tx_id, status);
+	}
+	for key_id in &entry.input_ids {
+		wallet_data.unlock_output(key_id)?;
+	}
+	for change_id in &entry.change_ids {
+		wallet_data.delete_pending_output(change_id);
+	}
+	wallet_data.set_tx_log_status(entry.id, status);
+	Ok(())
+}
+
+/// Cancels a pending send, identified either by its transaction log id or by
+/// the identifier of one of its change outputs. The locked inputs are made
+/// spendable again and the pending change outputs are forgotten. Outputs are
+/// refreshed from the node first and the cancellation is refused if any input
+/// has already been spent on chain.
+pub fn cancel_send(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	id_or_change: &str,
+) -> Result<(), Error> {
+	let config = &config.for_keychain(keychain)?;
+	let _lock = SendLock::acquire(&config.data_file_dir, config.send_lock_timeout_secs)?;
+	checker::refresh_outputs(config, keychain)?;
+	cancel_logged_send(config, id_or_change)
+}
+
+fn cancel_logged_send(config: &WalletConfig, id_or_change: &str) -> Result<(), Error> {
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let entry = match wallet_data.find_tx_log_entry(id_or_change) {
+			Some(entry) => entry.clone(),
+			None => return Err(Error::TxNotFound(id_or_change.to_string())),
+		};
+		if entry.status != TxLogStatus::Pending {
+			return Err(Error::TxNotPending {
+				id: entry.id,
+				status: entry.status,
+			});
+		}
+		let spent = entry.input_ids.iter().any(|key_id| {
+			match wallet_data.outputs.get(&key_id.to_hex()) {
+				Some(out) => out.status == OutputStatus::Spent,
+				None => false,
+			}
+		});
+		if spent {
+			return Err(Error::TxInputsSpent { id: entry.id });
+		}
+		undo_logged_send(wallet_data, &entry, TxLogStatus::Cancelled)
+	})?
+}
+
+/// Writes the partial transaction JSON to the provided path. The content is
+/// first written to a temporary file next to the destination (only readable
+/// by the current user) and then renamed, so the receiver never sees a half
+/// written transaction.
+fn write_partial_tx_file(
+	path: &Path,
+	partial_tx: &JSONPartialTx,
+	overwrite: bool,
+) -> Result<(), Error> {
+	if !overwrite && path.exists() {
+		return Err(Error::IOError(io::Error::new(
+			io::ErrorKind::AlreadyExists,
+			format!("{} already exists, not overwriting", path.display()),
+		)));
+	}
+	let json_tx = serde_json::to_string_pretty(partial_tx)?;
+
+	// appended rather than replacing the extension, so "tx.json" and
+	// "tx.tmp" don't share a temporary file
+	let mut tmp_path = path.as_os_str().to_owned();
+	tmp_path.push(".tmp");
+	let tmp_path = PathBuf::from(tmp_path);
+	{
+		let mut options = OpenOptions::new();
+		options.write(true).create(true).truncate(true);
+		set_owner_only(&mut options);
+		let mut file = options.open(&tmp_path)?;
+		file.write_all(json_tx.as_bytes())?;
+		file.sync_all()?;
+	}
+	fs::rename(&tmp_path, path)?;
+	Ok(())
+}
+
+#[cfg(unix)]
+fn set_owner_only(options: &mut OpenOptions) {
+	use std::os::unix::fs::OpenOptionsExt;
+	options.mode(0o600);
+}
+
+#[cfg(not(unix))]
+fn set_owner_only(_options: &mut OpenOptions) {}
+
+/// The lock height set on a new transaction, either none at all (0, valid
+/// immediately) or an explicit height that can't be in the past.
+fn resolve_lock_height(lock_height: Option<u64>, current_height: u64) -> Result<u64, Error> {
+	match lock_height {
+		None => Ok(0),
+		Some(lock_height) if lock_height >= current_height => Ok(lock_height),
+		Some(lock_height) => Err(Error::InvalidLockHeight {
+			lock_height: lock_height,
+			current_height: current_height,
+		}),
+	}
+}
+
+/// Refuses to send nothing, or less than the dust threshold, which could only
+/// produce outputs the wallet itself would treat as dust.
+fn check_amount(config: &WalletConfig, amount: u64) -> Result<(), Error> {
+	let minimum = max(config.dust_threshold, 1);
+	if amount < minimum {
+		return Err(Error::AmountTooSmall {
+			amount: amount,
+			minimum: minimum,
+		});
+	}
+	Ok(())
+}
+
+/// Previews a send of the provided amount without building, locking or
+/// posting anything. Selects coins exactly like issue_send_tx would and
+/// reports the resulting inputs, fee and change. The wallet data is only
+/// read (outputs are not refreshed from the node).
+pub fn preview_send_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: SendAmount,
+	minimum_confirmations: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	lock_height: Option<u64>,
+) -> Result<SendEstimate, Error> {
+	let chain_tip = checker::get_tip_from_node(config)?;
+	let lock_height = resolve_lock_height(lock_height, chain_tip.height)?;
+	TxBuilder::new(config, keychain, amount, chain_tip.height)
+		.minimum_confirmations(minimum_confirmations)
+		.lock_height(lock_height)
+		.selection_strategy(selection_strategy)
+		.fee(fee)
+		.fee_paid_by(fee_paid_by)
+		.estimate()
+}
+
+/// Estimates the fee of sending the provided amount, paid out of it, along
+/// with how many inputs it would take, the change left and whether coins
+/// that aren't confirmed yet would be spent. Runs the selection of a send
+/// without building or locking anything, so a send that follows on the same
+/// wallet data computes the same. Like preview_send_tx, the outputs aren't
+/// refreshed from the node.
+pub fn estimate_fee(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	selection_strategy: SelectionStrategy,
+	minimum_confirmations: u64,
+) -> Result<FeeEstimate, Error> {
+	let config = &config.for_keychain(keychain)?;
+	let chain_tip = checker::get_tip_from_node(config)?;
+	estimate_fee_at(
+		config,
+		keychain,
+		amount,
+		chain_tip.height,
+		selection_strategy,
+		minimum_confirmations,
+	)
+}
+
+fn estimate_fee_at(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	current_height: u64,
+	selection_strategy: SelectionStrategy,
+	minimum_confirmations: u64,
+) -> Result<FeeEstimate, Error> {
+	let estimate = TxBuilder::new(config, keychain, SendAmount::Exact(amount), current_height)
+		.minimum_confirmations(minimum_confirmations)
+		.selection_strategy(selection_strategy)
+		.estimate()?;
+	Ok(FeeEstimate::from(&estimate))
+}
+
+/// Selects the coins to spend from the wallet data and computes what the
+/// transaction would look like, without mutating anything. Coinbase outputs
+/// are only spent once mature. If there aren't enough funds, the error
+/// reports what's held back by the confirmation policy and coinbase
+/// maturity. When the fee comes out of the amount sent, selecting for the
+/// amount alone is always enough. When the sender pays it, adding inputs
+/// grows the fee, so the selection is redone until it covers both. A send
+/// can't spend more than max_inputs_per_tx coins.
+fn select_coins(
+	config: &WalletConfig,
+	root_key_id: Identifier,
+	amount: SendAmount,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+) -> Result<(Vec<OutputData>, SendEstimate), Error> {
+	let (coins, estimate) = select_any_number_of_coins(
+		config,
+		root_key_id,
+		amount,
+		current_height,
+		minimum_confirmations,
+		lock_height,
+		selection_strategy,
+		fee,
+		fee_paid_by,
+		config.change_outputs,
+	)?;
+	check_input_count(config, &coins)?;
+	Ok((coins, estimate))
+}
+
+/// Refuses spending more coins than max_inputs_per_tx.
+fn check_input_count(config: &WalletConfig, coins: &Vec<OutputData>) -> Result<(), Error> {
+	if coins.len() > config.max_inputs_per_tx {
+		return Err(Error::TooManyInputs {
+			inputs: coins.len(),
+			max: config.max_inputs_per_tx,
+		});
+	}
+	Ok(())
+}
+
+// Same as select_coins however many coins it takes, the change split
+// across up to `change_outputs` outputs.
+fn select_any_number_of_coins(
+	config: &WalletConfig,
+	root_key_id: Identifier,
+	amount: SendAmount,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	change_outputs: usize,
+) -> Result<(Vec<OutputData>, SendEstimate), Error> {
+	if let SendAmount::Exact(amount) = amount {
+		if selection_strategy == SelectionStrategy::Default {
+			let exact = select_exact_coins(
+				config,
+				root_key_id.clone(),
+				amount,
+				current_height,
+				minimum_confirmations,
+				lock_height,
+				fee,
+				fee_paid_by,
+			)?;
+			if let Some(selected) = exact {
+				return Ok(selected);
+			}
+		}
+	}
+
+	// sweeping the wallet simply means selecting everything
+	let (mut select_amount, selection_strategy) = match amount {
+		SendAmount::Exact(amount) => (amount, selection_strategy),
+		SendAmount::Max => (0, SelectionStrategy::All),
+	};
+	loop {
+		let (coins, balance) = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			// short of funds, the estimate of all that's eligible tells how
+			// much is missing once the fee of its inputs is counted
+			let coins = match wallet_data.select(
+				root_key_id.clone(),
+				current_height,
+				minimum_confirmations,
+				select_amount,
+				selection_strategy,
+			) {
+				Ok(selection) => selection.coins,
+				Err(SelectionError::Insufficient { .. }) => wallet_data.eligible_coins(
+					root_key_id.clone(),
+					current_height,
+					minimum_confirmations,
+				),
+			};
+			(
+				coins,
+				wallet_data.balance(
+					Some(root_key_id.clone()),
+					current_height,
+					minimum_confirmations,
+				),
+			)
+		})?;
+
+		match estimate_send(
+			&coins,
+			amount,
+			lock_height,
+			fee,
+			fee_paid_by,
+			change_outputs,
+			config.dust_threshold,
+		) {
+			Ok(estimate) => return Ok((coins, estimate)),
+			Err(Error::NotEnoughFunds { missing, .. }) => {
+				// the selection covered what was asked but not the fee of the
+				// inputs it brought in, ask for more
+				let total = total_value(&coins)?;
+				if total >= select_amount && missing > 0 {
+					select_amount = total.checked_add(missing).ok_or(Error::AmountOverflow)?;
+					continue;
+				}
+				return Err(Error::NotEnoughFunds {
+					missing: missing,
+					spendable: balance.spendable,
+					unconfirmed: balance.awaiting_confirmation,
+					immature: balance.immature_coinbase,
+					height_locked: balance.height_locked,
+				});
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+// Looks for coins covering the amount sent and the fee of a transaction
+// without change exactly, None if the search finds none.
+fn select_exact_coins(
+	config: &WalletConfig,
+	root_key_id: Identifier,
+	amount: u64,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+) -> Result<Option<(Vec<OutputData>, SendEstimate)>, Error> {
+	let coins = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.select_exact(
+			root_key_id,
+			current_height,
+			minimum_confirmations,
+			config.max_inputs_per_tx,
+			|inputs| match fee_paid_by {
+				FeePaidBy::Recipient => Some(amount),
+				FeePaidBy::Sender => amount.checked_add(fee.unwrap_or(tx_fee(inputs, 1, None))),
+			},
+		)
+	})?;
+	let coins = match coins {
+		Some(coins) => coins,
+		None => return Ok(None),
+	};
+
+	// whatever the estimate objects to, the regular selection will too
+	match estimate_send(
+		&coins,
+		SendAmount::Exact(amount),
+		lock_height,
+		fee,
+		fee_paid_by,
+		0,
+		config.dust_threshold,
+	) {
+		Ok(ref estimate) if estimate.change == 0 => Ok(Some((coins, estimate.clone()))),
+		_ => Ok(None),
+	}
+}
+
+/// Computes the fee and change of a transaction spending the provided
+/// coins to send the amount. No change output is built when the coins
+/// exactly cover the amount, which is always the case when sending the max.
+/// The change is split across up to `change_outputs` outputs, fewer if it's
+/// too small to give each at least one nanogrin. Change below the dust
+/// threshold isn't worth an output and is added to the fee instead. A fee
+/// override must cover at least the minimum fee for the transaction weight.
+///
+/// The amount of the estimate is the one the recipient builds its output
+/// from, the fee being deducted from it. When the sender pays the fee, that's
+/// the amount sent plus the fee so the recipient still nets the amount.
+fn estimate_send(
+	coins: &Vec<OutputData>,
+	amount: SendAmount,
+	lock_height: u64,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+	change_outputs: usize,
+	dust_threshold: u64,
+) -> Result<SendEstimate, Error> {
+	// calculate the total across all inputs
+	let total = total_value(coins)?;
+	let inputs: Vec<Identifier> = coins.iter().map(|c| c.key_id.clone()).collect();
+	let unconfirmed_inputs = coins
+		.iter()
+		.filter(|c| c.status == OutputStatus::Unconfirmed)
+		.count();
+
+	// sweeping spends everything without change, whoever pays the fee the
+	// recipient gets what's left once it's paid
+	let amount = match amount {
+		SendAmount::Exact(amount) => amount,
+		SendAmount::Max => {
+			let fee = check_fee(fee, tx_fee(coins.len(), 1, None))?;
+			if fee >= total {
+				return Err(Error::NotEnoughFunds {
+					missing: fee - total + 1,
+					spendable: total,
+					unconfirmed: 0,
+					immature: 0,
+					height_locked: 0,
+				});
+			}
+			return Ok(SendEstimate {
+				amount: total,
+				inputs: inputs,
+				total_input: total,
+				fee: fee,
+				change: 0,
+				change_outputs: 0,
+				lock_height: lock_height,
+				unconfirmed_inputs: unconfirmed_inputs,
+			});
+		}
+	};
+
+	// if we are spending 10,000 coins to send 1,000 then our change will be 9,000
+ // when the recipient pays, the fee will come out of the amount itself
+ // if the fee is 80 then the recipient will only receive 920
+ // but our change will still be 9,000
+ // when the sender pays, the recipient receives 1,000 and our change is 8,920
+	let mut change_outputs = max(change_outputs, 1);
+	loop {
+		// sender is responsible for setting the fee on the partial tx
+  // recipient should double check the fee calculation and not blindly trust the
+  // sender
+		let minimum_fee = tx_fee(coins.len(), change_outputs + 1, None);
+		let applied_fee = fee.unwrap_or(minimum_fee);
+		let gross = match fee_paid_by {
+			FeePaidBy::Recipient => amount,
+			FeePaidBy::Sender => amount
+				.checked_add(applied_fee)
+				.ok_or(Error::AmountOverflow)?,
+		};
+		if total < gross {
+			if change_outputs == 0 {
+				return Err(Error::NotEnoughFunds {
+					missing: gross - total,
+					spendable: total,
+					unconfirmed: 0,
+					immature: 0,
+					height_locked: 0,
+				});
+			}
+			change_outputs -= 1;
+			continue;
+		}
+
+		// each change output needs at least a nanogrin, and change below the
+  // dust threshold isn't worth one
+		let change = total - gross;
+		let min_change = max(change_outputs as u64, max(dust_threshold, 1));
+		if change_outputs > 0 && change < min_change {
+			change_outputs -= 1;
+			continue;
+		}
+
+		let applied_fee = check_fee(fee, minimum_fee)?;
+		if fee_paid_by == FeePaidBy::Recipient && applied_fee >= amount {
+			return Err(Error::FeeExceedsAmount {
+				fee: applied_fee,
+				amount: amount,
+			});
+		}
+
+		// leftover change without an output is given up as fee, the amount has
+  // to grow with it for the recipient to still receive the same value, and
+  // as fees have to be even an odd nanogrin of dust goes to the recipient
+  // (neither sum can overflow, both are at most the total)
+		let dust = if change_outputs == 0 { change } else { 0 };
+		return Ok(SendEstimate {
+			amount: gross + dust,
+			inputs: inputs,
+			total_input: total,
+			fee: applied_fee + dust - (dust & 1),
+			change: change - dust,
+			change_outputs: change_outputs,
+			lock_height: lock_height,
+			unconfirmed_inputs: unconfirmed_inputs,
+		});
+	}
+}
+
+/// Sum of the values of the coins, refusing totals that don't fit in a u64.
+fn total_value(coins: &Vec<OutputData>) -> Result<u64, Error> {
+	coins
+		.iter()
+		.fold(Some(0u64), |total, c| total.and_then(|t| t.checked_add(c.value)))
+		.ok_or(Error::AmountOverflow)
+}
+
+/// The fee for a transaction, either the requested one which must cover the
+/// minimum and be even, or the minimum itself.
+fn check_fee(fee: Option<u64>, minimum_fee: u64) -> Result<u64, Error> {
+	match fee {
+		None => Ok(minimum_fee),
+		Some(fee) => {
+			if fee < minimum_fee {
+				return Err(Error::FeeTooLow {
+					fee: fee,
+					minimum: minimum_fee,
+				});
+			}
+			if fee & 1 != 0 {
+				return Err(Error::Transaction(transaction::Error::OddFee));
+			}
+			Ok(fee)
+		}
+	}
+}
+
+/// Builds a transaction to send to someone from the HD seed associated with the
+/// wallet and the amount to send. Handles reading through the wallet data file,
+/// selecting outputs to spend and building the change, see TxBuilder. The
+/// lock height is the absolute one, 0 for none.
+pub fn build_send_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: SendAmount,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	selection_strategy: SelectionStrategy,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+) -> Result<(Transaction, BlindingFactor, SendSummary), Error> {
+	TxBuilder::new(config, keychain, amount, current_height)
+		.minimum_confirmations(minimum_confirmations)
+		.lock_height(lock_height)
+		.selection_strategy(selection_strategy)
+		.fee(fee)
+		.fee_paid_by(fee_paid_by)
+		.build()
+}
+
+/// Builds the sender's half of a transaction, for those embedding the wallet
+/// and delivering partial transactions their own way. Starts from the wallet
+/// configuration (minimum confirmations, change outputs) with the default
+/// selection strategy, no lock height and the fee at its minimum, paid by
+/// the recipient, each of which can be changed before building.
+///
+/// Estimating only reads the wallet data. Building locks the coins spent and
+/// records the change, which is all it writes: logging the send and getting
+/// it to its recipient is up to the caller, as is taking the SendLock to keep
+/// concurrent sends from picking the same coins.
+pub struct TxBuilder<'a> {
+	config: &'a WalletConfig,
+	keychain: &'a Keychain,
+	amount: SendAmount,
+	current_height: u64,
+	minimum_confirmations: u64,
+	lock_height: u64,
+	selection_strategy: SelectionStrategy,
+	change_outputs: usize,
+	fee: Option<u64>,
+	fee_paid_by: FeePaidBy,
+}
+
+impl<'a> TxBuilder<'a> {
+	/// Builder for a send of the amount, with the chain at the provided
+	/// height.
+	pub fn new(
+		config: &'a WalletConfig,
+		keychain: &'a Keychain,
+		amount: SendAmount,
+		current_height: u64,
+	) -> TxBuilder<'a> {
+		TxBuilder {
+			config: config,
+			keychain: keychain,
+			amount: amount,
+			current_height: current_height,
+			minimum_confirmations: config.minimum_confirmations,
+			lock_height: 0,
+			selection_strategy: SelectionStrategy::Default,
+			change_outputs: config.change_outputs,
+			fee: None,
+			fee_paid_by: FeePaidBy::Recipient,
+		}
+	}
+
+	/// Confirmations a coin needs before it can be spent.
+	pub fn minimum_confirmations(mut self, minimum_confirmations: u64) -> TxBuilder<'a> {
+		self.minimum_confirmations = minimum_confirmations;
+		self
+	}
+
+	/// Height before which the transaction can't be mined, 0 for none.
+	pub fn lock_height(mut self, lock_height: u64) -> TxBuilder<'a> {
+		self.lock_height = lock_height;
+		self
+	}
+
+	/// How the coins spent are picked.
+	pub fn selection_strategy(mut self, selection_strategy: SelectionStrategy) -> TxBuilder<'a> {
+		self.selection_strategy = selection_strategy;
+		self
+	}
+
+	/// Most outputs the change is split across.
+	pub fn change_outputs(mut self, change_outputs: usize) -> TxBuilder<'a> {
+		self.change_outputs = change_outputs;
+		self
+	}
+
+	/// Fee to pay instead of the minimum, which it has to cover.
+	pub fn fee(mut self, fee: Option<u64>) -> TxBuilder<'a> {
+		self.fee = fee;
+		self
+	}
+
+	/// Who pays the fee.
+	pub fn fee_paid_by(mut self, fee_paid_by: FeePaidBy) -> TxBuilder<'a> {
+		self.fee_paid_by = fee_paid_by;
+		self
+	}
+
+	/// What the transaction would look like, nothing is locked or recorded.
+	pub fn estimate(&self) -> Result<SendEstimate, Error> {
+		let config = &self.config.for_keychain(self.keychain)?;
+		self.select(config).map(|(_, estimate)| estimate)
+	}
+
+	/// Builds the transaction, locking the coins it spends and recording its
+	/// change. Returns it along with the blinding factor the recipient needs
+	/// to complete it and what was spent and recorded. An exact amount below
+	/// the dust threshold is refused before any of it, see check_amount.
+	pub fn build(&self) -> Result<(Transaction, BlindingFactor, SendSummary), Error> {
+		let config = &self.config.for_keychain(self.keychain)?;
+		if let SendAmount::Exact(amount) = self.amount {
+			check_amount(config, amount)?;
+		}
+		let (coins, estimate) = self.select(config)?;
+		build_selected_tx(
+			config,
+			self.keychain,
+			&coins,
+			estimate,
+			self.lock_height,
+			self.current_height,
+		)
+	}
+
+	// select some spendable coins from the wallet
+	fn select(&self, config: &WalletConfig) -> Result<(Vec<OutputData>, SendEstimate), Error> {
+		let (coins, estimate) = select_any_number_of_coins(
+			config,
+			self.keychain.root_key_id(),
+			self.amount,
+			self.current_height,
+			self.minimum_confirmations,
+			self.lock_height,
+			self.selection_strategy,
+			self.fee,
+			self.fee_paid_by,
+			self.change_outputs,
+		)?;
+		check_input_count(config, &coins)?;
+		Ok((coins, estimate))
+	}
+}
+
+/// Builds the transaction spending the coins selected for a send as
+/// estimated, locking them and recording its change.
+fn build_selected_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	coins: &Vec<OutputData>,
+	estimate: SendEstimate,
+	lock_height: u64,
+	current_height: u64,
+) -> Result<(Transaction, BlindingFactor, SendSummary), Error> {
+	// build transaction skeleton with inputs and change
+	let (mut parts, change_outputs) =
+		inputs_and_change(coins, config, keychain, keychain.root_key_id(), &estimate)?;
+
+	// the kernel commits to the lock height, so the receiver signs for the
+	// same one
+	parts.push(build::with_lock_height(lock_height));
+
+	let (tx, blind) = build::transaction(parts, &keychain)?;
+	let (change_key_ids, tx_id) =
+		record_send(config, coins, change_outputs, &estimate, current_height)?;
+
+	Ok((
+		tx,
+		blind,
+		SendSummary {
+			estimate: estimate,
+			change_key_ids: change_key_ids,
+			tx_id: tx_id,
+		},
+	))
+}
+
+/// Burns the provided amount by sending it to an output whose key is public
+/// knowledge (testing only). As for a send, the fee comes out of the amount.
+/// With fluff the node broadcasts it right away instead of relaying it along
+/// a stem first. Returns the burn transaction pushed to the node, hex
+/// encoded.
+pub fn issue_burn_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	minimum_confirmations: u64,
+	fluff: bool,
+) -> Result<String, Error> {
+	let config = &config.for_keychain(keychain)?;
+	check_amount(config, amount)?;
+
+	let chain_tip = checker::get_tip_from_node(config)?;
+	let current_height = chain_tip.height;
+
+	let _ = checker::refresh_outputs(config, keychain);
+
+	let tx_burn = build_burn_tx(
+		config,
+		keychain,
+		amount,
+		current_height,
+		minimum_confirmations,
+	)?;
+
+	let tx_hex = util::to_hex(ser::ser_vec(&tx_burn).unwrap());
+	push_tx(config, &tx_hex, fluff)?;
+	Ok(tx_hex)
+}
+
+/// Builds and validates a transaction burning the amount, locking only the
+/// coins needed to cover it.
+fn build_burn_tx(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	amount: u64,
+	current_height: u64,
+	minimum_confirmations: u64,
+) -> Result<Transaction, Error> {
+	let keychain = &Keychain::burn_enabled(keychain, &Identifier::zero());
+	let key_id = keychain.root_key_id();
+
+	// the burn output plays the part of the recipient output, so the estimate
+	// accounts for it in the fee
+	let (coins, estimate) = select_coins(
+		config,
+		key_id.clone(),
+		SendAmount::Exact(amount),
+		current_height,
+		minimum_confirmations,
+		0,
+		SelectionStrategy::Default,
+		None,
+		FeePaidBy::Recipient,
+	)?;
+	let (mut parts, change_outputs) =
+		inputs_and_change(&coins, config, keychain, key_id, &estimate)?;
+	// the estimate refuses fees that don't leave anything of the amount
+	parts.push(build::output(
+		estimate.amount - estimate.fee,
+		Identifier::zero(),
+	));
+
+	let (tx_burn, _) = build::transaction(parts, &keychain)?;
+	tx_burn.validate(&keychain.secp())?;
+	record_send(config, &coins, change_outputs, &estimate, current_height)?;
+	Ok(tx_burn)
+}
+
+/// Derives the next `count` unused keys, along with their derivation index.
+/// They're reserved, never handed out again even if what they're derived
+/// for fails.
+fn next_available_keys(
+	config: &WalletConfig,
+	keychain: &Keychain,
+	count: usize,
+) -> Result<Vec<(Identifier, u32)>, Error> {
+	let root_key_id = keychain.root_key_id();
+	let dir = &config.data_file_dir;
+	let derivation = WalletData::with_wallet(dir, |wallet_data| {
+		wallet_data.reserve_children(dir, root_key_id.clone(), count)
+	})??;
+	let mut keys = vec![];
+	for n_child in derivation..(derivation + count as u32) {
+		keys.push((keychain.derive_key_id(n_child)?, n_child));
+	}
+	Ok(keys)
+}
+
+/// Splits the change into `count` near-equal values, the remainder going to
+/// the last one.
+fn split_change(change: u64, count: usize) -> Vec<u64> {
+	if count == 0 {
+		return vec![];
+	}
+	let part = change / count as u64;
+	let mut values = vec![part; count];
+	values[count - 1] = change - part * (count as u64 - 1);
+	values
+}
+
+/// Builds the inputs spending the coins and the change outputs of a
+/// transaction. The change outputs only get tracked by `record_send` once the
+/// transaction is built, their derivation indexes are reserved right away
+/// though.
+fn inputs_and_change(
+	coins: &Vec<OutputData>,
+	config: &WalletConfig,
+	keychain: &Keychain,
+	root_key_id: Identifier,
+	estimate: &SendEstimate,
+) -> Result<(Vec<Box<build::Append>>, Vec<OutputData>), Error> {
+	let mut parts = vec![];
+
+	parts.push(build::with_fee(estimate.fee));
+
+	// build inputs using the appropriate derived key_ids, the keychain can
+	// only sign for its own
+	for coin in coins {
+		if coin.root_key_id != root_key_id {
+			return Err(Error::ForeignInput {
+				key_id: coin.key_id.clone(),
+				fingerprint: coin.root_key_id.clone(),
+			});
+		}
+		let key_id = keychain.derive_key_id(coin.n_child)?;
+		parts.push(build::input(coin.value, key_id));
+	}
+
+	// no change output at all if the inputs exactly cover the amount
+	let mut change_outputs = vec![];
+	if estimate.change > 0 {
+		let values = split_change(estimate.change, estimate.change_outputs);
+		let keys = next_available_keys(config, keychain, values.len())?;
+		for (value, (change_key, change_derivation)) in values.into_iter().zip(keys) {
+			parts.push(build::output(value, change_key.clone()));
+			change_outputs.push(OutputData {
+				root_key_id: root_key_id.clone(),
+				key_id: change_key,
+				n_child: change_derivation,
+				value: value,
+				status: OutputStatus::Pending,
+				height: 0,
+				block_hash: None,
+				lock_height: 0,
+				is_coinbase: false,
+				locked_at: 0,
+				account: None,
+				created_at: Some(Timestamp::now()),
+				confirmed_at: None,
+				spent_at: None,
+				tx_id: None,
+				label: None,
+				watch_commit: None,
+			});
+		}
+	}
+
+	Ok((parts, change_outputs))
+}
+
+/// Last step of building a send, returning the identifiers of its change and
+/// the id of the wallet transaction grouping them with the coins spent.
+fn record_send(
+	config: &WalletConfig,
+	coins: &Vec<OutputData>,
+	change_outputs: Vec<OutputData>,
+	estimate: &SendEstimate,
+	current_height: u64,
+) -> Result<(Vec<Identifier>, u32), Error> {
+	let change_key_ids = change_outputs
+		.iter()
+		.map(|out| out.key_id.clone())
+		.collect::<Vec<_>>();
+
+	// Acquire wallet lock, add the new change outputs and lock coins being
+	// spent, grouped under their wallet transaction.
+	let tx_id = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		// the keys were derived without holding the lock, a receive may have
+		// taken one of them since
+		if change_outputs
+			.iter()
+			.any(|out| wallet_data.outputs.contains_key(&out.key_id.to_hex()))
+		{
+			return Err(Error::WalletData(
+				"Change key already in use, the wallet changed while sending".to_string(),
+			));
+		}
+
+		// we got that far, time to start tracking the outputs representing our change
+		for change_output in change_outputs {
+			wallet_data.add_output(change_output)?;
+		}
+
+		// now lock the ouputs we're spending so we avoid accidental double spend
+		// attempt
+		for coin in coins {
+			wallet_data.lock_output(coin, current_height)?;
+		}
+		Ok(wallet_data.add_transaction(
+			TxDirection::Sent,
+			estimate.amount,
+			estimate.fee,
+			estimate.inputs.clone(),
+			change_key_ids.clone(),
+		))
+	})??;
+
+	Ok((change_key_ids, tx_id))
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+	use std::env;
+	use std::fs::{self, File};
+	use std::io::{Read, Write};
+	use std::path::Path;
+	use std::thread;
+	use std::time::{Duration, Instant};
+	use std::u64;
+
+	use rand::{thread_rng, Rng, ThreadRng};
+
+	use core::core::build::{input, output, transaction};
+	use core::core::BlockHeader;
+	use core::global;
+	use info::read_tx_log;
+	use keychain::Keychain;
+	use receiver::{issue_invoice, receive_from_file, receive_invoice_payment};
+	use types::*;
+	use super::*;
+
+	// Creates a wallet in a fresh temporary directory holding confirmed
+	// outputs of the provided values.
+	fn test_wallet(values: Vec<u64>) -> (WalletConfig, Keychain) {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let mut config = WalletConfig::default();
+		config.data_file_dir = env::temp_dir()
+			.join(format!("grin_wallet_test_{}", keychain.root_key_id()))
+			.to_str()
+			.unwrap()
+			.to_string();
+		// the amounts sent around are below what a receiver takes by default
+		config.min_receive_amount = 0;
+		config = config.for_keychain(&keychain).unwrap();
+		let _ = fs::remove_dir_all(&config.data_file_dir);
+
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for (i, value) in values.iter().enumerate() {
+				let n_child = i as u32 + 1;
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: keychain.derive_key_id(n_child).unwrap(),
+					n_child: n_child,
+					value: *value,
+					status: OutputStatus::Unspent,
+					height: 1,
+					block_hash: None,
+					lock_height: 0,
+					is_coinbase: false,
+					locked_at: 0,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+		(config, keychain)
+	}
+
+	fn read_file(path: &Path) -> Vec<u8> {
+		let mut buf = vec![];
+		File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+		buf
+	}
+
+	fn outputs_with_status(config: &WalletConfig, status: OutputStatus) -> Vec<OutputData> {
+		WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data
+				.outputs
+				.values()
+				.filter(|out| out.status == status)
+				.cloned()
+				.collect()
+		}).unwrap()
+	}
+
+	#[test]
+	// demonstrate that input.commitment == referenced output.commitment
+	// based on the public key and amount begin spent
+	fn output_commitment_equals_input_commitment_on_spend() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id1 = keychain.derive_key_id(1).unwrap();
+
+		let (tx1, _) = transaction(vec![output(105, key_id1.clone())], &keychain).unwrap();
+		let (tx2, _) = transaction(vec![input(105, key_id1.clone())], &keychain).unwrap();
+
+		assert_eq!(tx1.outputs[0].commitment(), tx2.inputs[0].commitment());
+	}
+
+	#[test]
+	fn partial_tx_file_no_overwrite() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, blind) = transaction(vec![input(10, key_id)], &keychain).unwrap();
+		let partial_tx = build_partial_tx(10, blind, tx, None);
+
+		let path = env::temp_dir().join(format!("grin_partial_tx_{}.json", key_id));
+		let _ = fs::remove_file(&path);
+		// a file sharing its name but for the extension is left alone
+		let sibling = path.with_extension("tmp");
+		File::create(&sibling).unwrap().write_all(b"other").unwrap();
+
+		write_partial_tx_file(&path, &partial_tx, false).unwrap();
+		assert!(path.exists());
+		assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+		let mut content = String::new();
+		File::open(&sibling).unwrap().read_to_string(&mut content).unwrap();
+		assert_eq!(content, "other");
+		fs::remove_file(&sibling).unwrap();
+		assert!(write_partial_tx_file(&path, &partial_tx, false).is_err());
+		write_partial_tx_file(&path, &partial_tx, true).unwrap();
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			let mode = fs::metadata(&path).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn preview_matches_send() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let dat_file = Path::new(&config.data_file_dir).join("wallet.dat");
+		let before = read_file(&dat_file);
+
+		let (_, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(read_file(&dat_file), before);
+		assert_eq!(estimate.total_input, 300);
+		assert_eq!(estimate.fee, tx_fee(1, 2, None));
+		assert_eq!(estimate.change, 50);
+
+		let (tx, _, _) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(tx.fee, estimate.fee);
+		assert_eq!(tx.lock_height, estimate.lock_height);
+		assert_eq!(tx.inputs.len(), estimate.inputs.len());
+
+		let change = outputs_with_status(&config, OutputStatus::Pending);
+		assert_eq!(change.len(), 1);
+		assert_eq!(change[0].value, estimate.change);
+		let locked = outputs_with_status(&config, OutputStatus::Locked);
+		assert_eq!(
+			locked.iter().map(|out| out.key_id.clone()).collect::<Vec<_>>(),
+			estimate.inputs
+		);
+	}
+
+	#[test]
+	fn send_max_sweeps_wallet() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+
+		let (tx, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Max,
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		let estimate = summary.estimate;
+		assert!(summary.change_key_ids.is_empty());
+		assert_eq!(estimate.amount, 600);
+		assert_eq!(estimate.change, 0);
+		assert_eq!(estimate.fee, tx_fee(3, 1, None));
+		assert_eq!(tx.fee, estimate.fee);
+		assert_eq!(tx.inputs.len(), 3);
+		assert_eq!(tx.outputs.len(), 0);
+
+		assert!(outputs_with_status(&config, OutputStatus::Unspent).is_empty());
+		assert!(outputs_with_status(&config, OutputStatus::Pending).is_empty());
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 3);
+	}
+
+	#[test]
+	fn fee_override() {
+		let coins = vec![OutputData {
+			root_key_id: Identifier::zero(),
+			key_id: Identifier::zero(),
+			n_child: 1,
+			value: 1_000,
+			status: OutputStatus::Unspent,
+			height: 1,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: None,
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
+		}];
+		let minimum = tx_fee(1, 2, None);
+
+		match estimate_send(
+			&coins,
+			SendAmount::Exact(500),
+			0,
+			Some(minimum - 2),
+			FeePaidBy::Recipient,
+			1,
+			0,
+		) {
+			Err(Error::FeeTooLow { fee, minimum: min }) => {
+				assert_eq!(fee, minimum - 2);
+				assert_eq!(min, minimum);
+			}
+			res => panic!("expected FeeTooLow, got {:?}", res),
+		}
+		match estimate_send(
+			&coins,
+			SendAmount::Exact(500),
+			0,
+			Some(500),
+			FeePaidBy::Recipient,
+			1,
+			0,
+		) {
+			Err(Error::FeeExceedsAmount { .. }) => {}
+			res => panic!("expected FeeExceedsAmount, got {:?}", res),
+		}
+
+		let (config, keychain) = test_wallet(vec![1_000]);
+		let (tx, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			Some(minimum * 2),
+			FeePaidBy::Recipient,
+		).unwrap();
+		let estimate = summary.estimate;
+		assert_eq!(estimate.fee, minimum * 2);
+		assert_eq!(tx.fee, minimum * 2);
+		assert_eq!(estimate.change, 500);
+	}
+
+	#[test]
+	fn lock_height_resolution() {
+		assert_eq!(resolve_lock_height(None, 100).unwrap(), 0);
+		assert_eq!(resolve_lock_height(Some(100), 100).unwrap(), 100);
+		assert_eq!(resolve_lock_height(Some(1_100), 100).unwrap(), 1_100);
+		match resolve_lock_height(Some(99), 100) {
+			Err(Error::InvalidLockHeight {
+				lock_height,
+				current_height,
+			}) => {
+				assert_eq!(lock_height, 99);
+				assert_eq!(current_height, 100);
+			}
+			res => panic!("expected InvalidLockHeight, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn failed_post_rolls_back() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_retry_delay_ms = 1;
+		let before = outputs_with_status(&config, OutputStatus::Unspent);
+
+		let (tx, blind, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+		assert_eq!(outputs_with_status(&config, OutputStatus::Pending).len(), 1);
+
+		// the receiver refuses the transaction
+		let (addr, _) = client::test::stub_server(1, |_| Some(("400 Bad Request", String::new())));
+		let tx_log_id = log_send(&config, &addr, &summary, None).unwrap();
+		let dest = Destination::parse(&addr).unwrap();
+		let partial_tx = build_partial_tx(250, blind, tx, None);
+		match deliver_or_rollback(
+			&config,
+			&keychain,
+			&dest,
+			&partial_tx,
+			false,
+			tx_log_id,
+		) {
+			Err(Error::Node(_)) => {}
+			res => panic!("expected a node error, got {:?}", res),
+		}
+
+		let mut after = outputs_with_status(&config, OutputStatus::Unspent);
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(outputs_with_status(&config, OutputStatus::Pending).is_empty());
+		let mut before = before;
+		before.sort_by_key(|out| out.n_child);
+		after.sort_by_key(|out| out.n_child);
+		assert_eq!(
+			before.iter().map(|out| (out.n_child, out.value)).collect::<Vec<_>>(),
+			after.iter().map(|out| (out.n_child, out.value)).collect::<Vec<_>>()
+		);
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		assert_eq!(tx_log[0].status, TxLogStatus::Aborted);
+	}
+
+	#[test]
+	fn resend_after_unreachable_receiver() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_attempts = 1;
+
+		// the first connection is dropped without an answer
+		let mut first = true;
+		let (addr, requests) = client::test::stub_server(2, move |body| {
+			if first {
+				first = false;
+				return None;
+			}
+			completing_receiver(body, 0)
+		});
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let tx_id = pending.tx_log_id;
+		let key_ids = |status| {
+			outputs_with_status(&config, status)
+				.into_iter()
+				.map(|out| out.key_id)
+				.collect::<Vec<_>>()
+		};
+		let locked = key_ids(OutputStatus::Locked);
+		let change = key_ids(OutputStatus::Pending);
+		let next_child = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.next_child(keychain.root_key_id())
+		}).unwrap();
+		assert_eq!((locked.len(), change.len()), (1, 1));
+
+		match post_send(&config, &keychain, pending, &addr, false) {
+			Err(Error::Node(api::Error::Internal(_))) => {}
+			res => panic!("expected the receiver to be unreachable, got {:?}", res),
+		}
+		assert_eq!(key_ids(OutputStatus::Locked), locked);
+		assert_eq!(key_ids(OutputStatus::Pending), change);
+		assert_eq!(read_tx_log(&config).unwrap()[0].status, TxLogStatus::Pending);
+
+		let result = resend(&config, &keychain, tx_id, None).unwrap();
+		assert_eq!(result.tx_id, tx_id);
+		assert_eq!(result.change_identifiers, change);
+		assert!(result.tx_hex.is_some());
+		assert_eq!(key_ids(OutputStatus::Locked), locked);
+		assert_eq!(key_ids(OutputStatus::Pending), change);
+		assert_eq!(
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.next_child(keychain.root_key_id())
+			}).unwrap(),
+			next_child
+		);
+
+		// both posts carried the exact same partial transaction
+		let requests = requests.lock().unwrap();
+		assert_eq!(requests.len(), 2);
+		assert_eq!(requests[0], requests[1]);
+
+		// sends that are done with can't be resent
+		for status in vec![TxLogStatus::Confirmed, TxLogStatus::Cancelled] {
+			WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.set_tx_log_status(tx_id, status.clone());
+			}).unwrap();
+			match resend(&config, &keychain, tx_id, None) {
+				Err(Error::TxNotPending { id, status: ref s }) => {
+					assert_eq!(id, tx_id);
+					assert_eq!(*s, status);
+				}
+				res => panic!("expected TxNotPending, got {:?}", res),
+			}
+		}
+	}
+
+	#[test]
+	fn timed_out_send_kept_pending() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_timeout_secs = 1;
+
+		// answers, but too late
+		let (addr, requests) = client::test::stub_server(1, |body| {
+			thread::sleep(Duration::from_secs(2));
+			completing_receiver(body, 0)
+		});
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let start = Instant::now();
+		match post_send(&config, &keychain, pending, &addr, false) {
+			Err(Error::NodeTimeout(_)) => {}
+			res => panic!("expected NodeTimeout, got {:?}", res),
+		}
+		// given up on after a single attempt
+		assert!(start.elapsed() < Duration::from_secs(2));
+		assert_eq!(requests.lock().unwrap().len(), 1);
+
+		// the receiver may still broadcast it, the coins stay locked
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+		assert_eq!(outputs_with_status(&config, OutputStatus::Pending).len(), 1);
+		assert_eq!(read_tx_log(&config).unwrap()[0].status, TxLogStatus::Pending);
+	}
+
+	#[test]
+	fn send_logged() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let path = Path::new(&config.data_file_dir).join("partial_tx.json");
+		let dest_str = format!("file:{}", path.display());
+
+		let (tx, blind, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		let tx_log_id = log_send(&config, &dest_str, &summary, None).unwrap();
+		let dest = Destination::parse(&dest_str).unwrap();
+		let partial_tx = build_partial_tx(250, blind, tx, None);
+		deliver_or_rollback(
+			&config,
+			&keychain,
+			&dest,
+			&partial_tx,
+			false,
+			tx_log_id,
+		).unwrap();
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		let entry = &tx_log[0];
+		assert_eq!(entry.id, tx_log_id);
+		assert!(entry.created_at > 0);
+		assert_eq!(entry.amount, 250);
+		assert_eq!(entry.fee, summary.estimate.fee);
+		assert_eq!(entry.dest, dest_str);
+		assert_eq!(entry.input_ids, summary.estimate.inputs);
+		assert_eq!(entry.change_ids.len(), 1);
+		assert_eq!(entry.change_ids, summary.change_key_ids);
+		assert_eq!(entry.status, TxLogStatus::Pending);
+	}
+
+	#[test]
+	fn cancel_pending_send() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let (_, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		let tx_log_id = log_send(&config, "stdout", &summary, None).unwrap();
+
+		match cancel_logged_send(&config, "42") {
+			Err(Error::TxNotFound(_)) => {}
+			res => panic!("expected TxNotFound, got {:?}", res),
+		}
+		let change_id = summary.change_key_ids[0].to_hex();
+		cancel_logged_send(&config, &change_id).unwrap();
+
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 3);
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(outputs_with_status(&config, OutputStatus::Pending).is_empty());
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log[0].status, TxLogStatus::Cancelled);
+
+		match cancel_logged_send(&config, &tx_log_id.to_string()) {
+			Err(Error::TxNotPending { id, status }) => {
+				assert_eq!(id, tx_log_id);
+				assert_eq!(status, TxLogStatus::Cancelled);
+			}
+			res => panic!("expected TxNotPending, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn cancel_refused_once_spent() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let (_, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		let tx_log_id = log_send(&config, "stdout", &summary, None).unwrap();
+
+		// the receiver broadcast the transaction after all
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for key_id in &summary.estimate.inputs {
+				wallet_data.outputs.get_mut(&key_id.to_hex()).unwrap().status =
+					OutputStatus::Spent;
+			}
+		}).unwrap();
+
+		match cancel_logged_send(&config, &tx_log_id.to_string()) {
+			Err(Error::TxInputsSpent { id }) => assert_eq!(id, tx_log_id),
+			res => panic!("expected TxInputsSpent, got {:?}", res),
+		}
+		assert_eq!(outputs_with_status(&config, OutputStatus::Pending).len(), 1);
+		assert_eq!(read_tx_log(&config).unwrap()[0].status, TxLogStatus::Pending);
+	}
+
+	#[test]
+	fn transaction_groups_send_lifecycle() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let send = |amount| {
+			let (_, _, summary) = build_send_tx(
+				&config,
+				&keychain,
+				amount,
+				10,
+				1,
+				10,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+			).unwrap();
+			let tx_log_id = log_send(&config, "stdout", &summary, None).unwrap();
+			(summary, tx_log_id)
+		};
+		let transaction = |id| {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.get_transaction(id).cloned().unwrap()
+			}).unwrap()
+		};
+		let tx_ids = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data
+					.outputs
+					.values()
+					.map(|out| (out.key_id.clone(), out.tx_id))
+					.collect::<HashMap<_, _>>()
+			}).unwrap()
+		};
+
+		// the coins spent and the change all point to the send
+		let (first, first_log_id) = send(SendAmount::Exact(250));
+		let tx = transaction(first.tx_id);
+		assert_eq!((tx.direction, tx.amount), (TxDirection::Sent, 250));
+		assert_eq!((tx.fee, tx.log_id), (first.estimate.fee, Some(first_log_id)));
+		assert_eq!(tx.status, TxLogStatus::Pending);
+		assert_eq!(tx.input_ids, first.estimate.inputs);
+		assert_eq!(tx.output_ids, first.change_key_ids);
+		let change_id = tx.output_ids[0].clone();
+		let grouped = |key_id: &Identifier| {
+			tx.input_ids.contains(key_id) || tx.output_ids.contains(key_id)
+		};
+		for (key_id, tx_id) in tx_ids() {
+			assert_eq!(tx_id == Some(first.tx_id), grouped(&key_id));
+		}
+
+		// it makes it on chain, its change confirmed
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			for key_id in &tx.input_ids {
+				let input = wallet_data.outputs.get_mut(&key_id.to_hex()).unwrap();
+				input.status = OutputStatus::Spent;
+			}
+			let change = wallet_data.outputs.get_mut(&change_id.to_hex()).unwrap();
+			change.status = OutputStatus::Unspent;
+			change.height = 5;
+			wallet_data.confirm_transactions();
+		}).unwrap();
+		assert_eq!(transaction(first.tx_id).status, TxLogStatus::Confirmed);
+
+		// a second send spends the change along with what's left, the change
+		// now pointing to it and the spent coins still to the first one
+		let (second, second_log_id) = send(SendAmount::Max);
+		assert!(second.estimate.inputs.contains(&change_id));
+		assert_eq!(second.estimate.inputs.len(), 4 - tx.input_ids.len());
+		assert!(second.tx_id > first.tx_id);
+		for (key_id, tx_id) in tx_ids() {
+			if second.estimate.inputs.contains(&key_id) {
+				assert_eq!(tx_id, Some(second.tx_id));
+			} else {
+				assert_eq!(tx_id, Some(first.tx_id));
+			}
+		}
+
+		// cancelled, the change goes back to the send that created it and
+		// the coins no send created to none
+		cancel_logged_send(&config, &second_log_id.to_string()).unwrap();
+		assert_eq!(transaction(second.tx_id).status, TxLogStatus::Cancelled);
+		assert_eq!(read_tx_log(&config).unwrap()[1].status, TxLogStatus::Cancelled);
+		for (key_id, tx_id) in tx_ids() {
+			let expected = if grouped(&key_id) { Some(first.tx_id) } else { None };
+			assert_eq!(tx_id, expected, "output {}", key_id);
+		}
+		let unspent = outputs_with_status(&config, OutputStatus::Unspent);
+		assert_eq!(unspent.len(), 4 - tx.input_ids.len());
+		assert_eq!(transaction(first.tx_id).status, TxLogStatus::Confirmed);
+	}
+
+	#[test]
+	fn change_split_across_outputs() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		config.change_outputs = 3;
+
+		let (tx, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		let estimate = summary.estimate;
+		assert_eq!(estimate.change, 500);
+		assert_eq!(estimate.change_outputs, 3);
+		assert_eq!(estimate.fee, tx_fee(1, 4, None));
+		assert_eq!(tx.fee, estimate.fee);
+		assert_eq!(tx.outputs.len(), 3);
+		assert_eq!(summary.change_key_ids.len(), 3);
+
+		let mut change = outputs_with_status(&config, OutputStatus::Pending);
+		change.sort_by_key(|out| out.n_child);
+		assert_eq!(
+			change.iter().map(|out| out.value).collect::<Vec<_>>(),
+			vec![166, 166, 168]
+		);
+		assert_eq!(
+			change.iter().map(|out| out.n_child).collect::<Vec<_>>(),
+			vec![2, 3, 4]
+		);
+
+		// not enough change to give each output a nanogrin
+		let coins = outputs_with_status(&config, OutputStatus::Locked);
+		let estimate = estimate_send(
+			&coins,
+			SendAmount::Exact(998),
+			0,
+			None,
+			FeePaidBy::Recipient,
+			3,
+			0,
+		).unwrap();
+		assert_eq!(estimate.change_outputs, 2);
+		assert_eq!(estimate.fee, tx_fee(1, 3, None));
+		assert_eq!(split_change(estimate.change, estimate.change_outputs), vec![1, 1]);
+	}
+
+	#[test]
+	fn dust_change_folded_into_fee() {
+		let coins = vec![OutputData {
+			root_key_id: Identifier::zero(),
+			key_id: Identifier::zero(),
+			n_child: 1,
+			value: 1_000,
+			status: OutputStatus::Unspent,
+			height: 1,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: None,
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
+		}];
+		let fee = tx_fee(1, 1, None);
+
+		// no change at all
+		let estimate = estimate_send(
+			&coins,
+			SendAmount::Exact(1_000),
+			0,
+			None,
+			FeePaidBy::Recipient,
+			1,
+			100,
+		).unwrap();
+		assert_eq!((estimate.amount, estimate.fee, estimate.change), (1_000, fee, 0));
+
+		// just below the threshold, the odd nanogrin goes to the recipient
+		let estimate = estimate_send(
+			&coins,
+			SendAmount::Exact(901),
+			0,
+			None,
+			FeePaidBy::Recipient,
+			1,
+			100,
+		).unwrap();
+		assert_eq!(estimate.change, 0);
+		assert_eq!(estimate.change_outputs, 0);
+		assert_eq!(estimate.amount, 1_000);
+		assert_eq!(estimate.fee, fee + 98);
+		assert_eq!(estimate.amount - estimate.fee, 901 - fee + 1);
+
+		// at the threshold, change is kept
+		let estimate = estimate_send(
+			&coins,
+			SendAmount::Exact(900),
+			0,
+			None,
+			FeePaidBy::Recipient,
+			1,
+			100,
+		).unwrap();
+		assert_eq!(estimate.amount, 900);
+		assert_eq!(estimate.fee, tx_fee(1, 2, None));
+		assert_eq!(estimate.change, 100);
+
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		config.dust_threshold = 100;
+		let (tx, _, summary) = build_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(950),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert!(tx.outputs.is_empty());
+		assert!(summary.change_key_ids.is_empty());
+		assert_eq!(tx.fee, fee + 50);
+		assert_eq!(summary.estimate.amount, 1_000);
+		assert!(outputs_with_status(&config, OutputStatus::Pending).is_empty());
+	}
+
+	#[test]
+	fn burn_locks_only_needed_inputs() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300, 400]);
+
+		let tx = build_burn_tx(&config, &keychain, 250, 10, 1).unwrap();
+		tx.validate(&keychain.secp()).unwrap();
+		assert_eq!(tx.inputs.len(), 1);
+		assert_eq!(tx.fee, tx_fee(1, 2, None));
+
+		let locked = outputs_with_status(&config, OutputStatus::Locked);
+		assert_eq!(locked.len(), 1);
+		assert_eq!(locked[0].value, 300);
+		let change = outputs_with_status(&config, OutputStatus::Pending);
+		assert_eq!(change.len(), 1);
+		assert_eq!(change[0].value, 50);
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 3);
+	}
+
+	#[test]
+	fn prepare_then_post() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let path = Path::new(&config.data_file_dir).join("partial_tx.json");
+		let dest = format!("file:{}", path.display());
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			Some("order 42".to_string()),
+		).unwrap();
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+		assert!(!path.exists());
+
+		// a pending send survives a round trip through its JSON form
+		let json = serde_json::to_string(&pending).unwrap();
+		let pending: PendingSend = serde_json::from_str(&json).unwrap();
+
+		let expected = serde_json::to_vec(&pending.partial_tx).unwrap();
+		post_send(&config, &keychain, pending, &dest, false).unwrap();
+		let written: JSONPartialTx = serde_json::from_slice(&read_file(&path)).unwrap();
+		assert_eq!(serde_json::to_vec(&written).unwrap(), expected);
+
+		assert_eq!(written.message(), Some("order 42"));
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		assert_eq!(tx_log[0].dest, dest);
+		assert_eq!(tx_log[0].message, Some("order 42".to_string()));
+		assert_eq!(tx_log[0].status, TxLogStatus::Pending);
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+	}
+
+	#[test]
+	fn prepare_then_abort() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		abort_send(&config, &keychain, pending).unwrap();
+
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 3);
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(outputs_with_status(&config, OutputStatus::Pending).is_empty());
+		assert_eq!(read_tx_log(&config).unwrap()[0].status, TxLogStatus::Aborted);
+	}
+
+	#[test]
+	fn abort_resolves_wallet_dir() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		// the configuration as a library caller has it, before it's resolved to
+		// the directory of the seed
+		let mut unresolved = config.clone();
+		unresolved.data_file_dir = Path::new(&config.data_file_dir)
+			.parent()
+			.unwrap()
+			.to_str()
+			.unwrap()
+			.to_string();
+		unresolved.flat_data_dir = false;
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		abort_send(&unresolved, &keychain, pending).unwrap();
+
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 3);
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert_eq!(read_tx_log(&config).unwrap()[0].status, TxLogStatus::Aborted);
+	}
+
+	#[test]
+	fn pending_change_never_spent() {
+		let (config, keychain) = test_wallet(vec![1_000]);
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let change = pending.summary.change_key_ids.clone();
+		assert_eq!(change.len(), 1);
+		let pending_outputs = outputs_with_status(&config, OutputStatus::Pending);
+		assert_eq!(pending_outputs.len(), 1);
+		assert_eq!(pending_outputs[0].key_id, change[0]);
+
+		// not even picked when no confirmation is required
+		let eligible = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.eligible_coins(keychain.root_key_id(), 10, 0)
+		}).unwrap();
+		assert!(eligible.is_empty());
+
+		// once seen on chain the change is left alone by a rollback
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			let out = wallet_data.outputs.get_mut(&change[0].to_hex()).unwrap();
+			out.status = OutputStatus::Unspent;
+			out.height = 11;
+		}).unwrap();
+		rollback_send(&config, pending.tx_log_id).unwrap();
+		let unspent = outputs_with_status(&config, OutputStatus::Unspent);
+		assert!(unspent.iter().any(|out| out.key_id == change[0]));
+	}
+
+	// Completes the partial transaction of a pending send the way a receiver
+	// would, writing the result where finalize_from_file expects it.
+	fn receiver_response(pending: &PendingSend, out_amount_delta: u64, path: &Path) {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (amount, blinding, tx) = read_partial_tx(&keychain, &pending.partial_tx).unwrap();
+		let out_amount = amount - tx.fee + out_amount_delta;
+		let (final_tx, _) = build::transaction(
+			vec![
+				build::initial_tx(tx),
+				build::with_excess(blinding),
+				build::output(out_amount, key_id),
+			],
+			&keychain,
+		).unwrap();
+		let wrapper = TxWrapper {
+			tx_hex: util::to_hex(ser::ser_vec(&final_tx).unwrap()),
+			fluff: false,
+		};
+		let mut file = File::create(path).unwrap();
+		file.write_all(serde_json::to_string(&wrapper).unwrap().as_bytes())
+			.unwrap();
+	}
+
+	#[test]
+	fn finalize_receiver_response() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let path = Path::new(&config.data_file_dir).join("response.json");
+
+		receiver_response(&pending, 0, &path);
+		let tx = read_final_tx_file(&path).unwrap();
+		assert_eq!(verify_final_tx(&config, &keychain, &tx).unwrap(), pending.tx_log_id);
+
+		// the receiver claims more than it was sent
+		receiver_response(&pending, 2, &path);
+		let tx = read_final_tx_file(&path).unwrap();
+		match verify_final_tx(&config, &keychain, &tx) {
+			Err(Error::MalformedResponse(_)) => {}
+			res => panic!("expected MalformedResponse, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn partial_tx_message() {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, blind) = transaction(vec![input(10, key_id.clone())], &keychain).unwrap();
+		let partial_tx = build_partial_tx(10, blind, tx.clone(), Some("héllo".to_string()));
+
+		let json = serde_json::to_string(&partial_tx).unwrap();
+		let read: JSONPartialTx = serde_json::from_str(&json).unwrap();
+		assert_eq!(read.message(), Some("héllo"));
+		let (amount, _, read_tx) = read_partial_tx(&keychain, &read).unwrap();
+		assert_eq!(amount, 10);
+		assert_eq!(ser::ser_vec(&read_tx).unwrap(), ser::ser_vec(&tx).unwrap());
+
+		// older peers don't know about messages
+		let (tx, blind) = transaction(vec![input(10, key_id)], &keychain).unwrap();
+		let json = serde_json::to_string(&build_partial_tx(10, blind, tx, None)).unwrap();
+		assert!(!json.contains("message"));
+		let read: JSONPartialTx = serde_json::from_str(&json).unwrap();
+		assert_eq!(read.message(), None);
+	}
+
+	#[test]
+	fn message_too_long() {
+		let (config, keychain) = test_wallet(vec![100, 200, 300]);
+		assert!(validate_message(&Some("a".repeat(MAX_MESSAGE_LEN))).is_ok());
+
+		let res = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			Some("a".repeat(MAX_MESSAGE_LEN + 1)),
+		);
+		match res {
+			Err(Error::MessageTooLong { len, max }) => {
+				assert_eq!(len, MAX_MESSAGE_LEN + 1);
+				assert_eq!(max, MAX_MESSAGE_LEN);
+			}
+			res => panic!("expected MessageTooLong, got {:?}", res),
+		}
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(read_tx_log(&config).unwrap().is_empty());
+	}
+
+	// Receiver answering with the completed transaction, paying itself
+	// `fee_delta` less in exchange for a higher fee.
+	fn completing_receiver(partial_json: &str, fee_delta: u64) -> Option<(&'static str, String)> {
+		let keychain = Keychain::from_random_seed().unwrap();
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let partial_tx: JSONPartialTx = serde_json::from_str(partial_json).unwrap();
+		let (amount, blinding, tx) = read_partial_tx(&keychain, &partial_tx).unwrap();
+		let fee = tx.fee + fee_delta;
+		let (final_tx, _) = build::transaction(
+			vec![
+				build::initial_tx(tx),
+				build::with_excess(blinding),
+				build::with_fee(fee),
+				build::output(amount - fee, key_id),
+			],
+			&keychain,
+		).unwrap();
+		let response = ReceiveResponse {
+			tx_hex: util::to_hex(ser::ser_vec(&final_tx).unwrap()),
+			message: None,
+			commit: None,
+		};
+		Some(("200 OK", serde_json::to_string(&response).unwrap()))
+	}
+
+	#[test]
+	fn receiver_response_verified() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_attempts = 1;
+
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 0));
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		post_send(&config, &keychain, pending, &addr, false).unwrap();
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+
+		// a receiver bumping the fee gets rejected and the send rolled back
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 2));
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(150),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 2);
+		match post_send(&config, &keychain, pending, &addr, false) {
+			Err(Error::MalformedResponse(msg)) => assert!(msg.contains("fee")),
+			res => panic!("expected MalformedResponse, got {:?}", res),
+		}
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log[0].status, TxLogStatus::Pending);
+		assert_eq!(tx_log[1].status, TxLogStatus::Aborted);
+	}
+
+	// Receiver answering as completing_receiver does, naming the output it
+	// added, or if not honest one of our change outputs.
+	fn naming_receiver(partial_json: &str, honest: bool) -> Option<(&'static str, String)> {
+		let (status, body) = completing_receiver(partial_json, 0).unwrap();
+		let mut response: ReceiveResponse = serde_json::from_str(&body).unwrap();
+		let partial_tx: JSONPartialTx = serde_json::from_str(partial_json).unwrap();
+		let keychain = Keychain::from_random_seed().unwrap();
+		let (_, _, tx) = read_partial_tx(&keychain, &partial_tx).unwrap();
+		let sent = tx.outputs.iter().map(|out| out.commitment()).collect::<Vec<_>>();
+		let named = tx_from_hex(&response.tx_hex)
+			.unwrap()
+			.outputs
+			.iter()
+			.map(|out| out.commitment())
+			.find(|commit| sent.contains(commit) != honest)
+			.unwrap();
+		response.commit = Some(util::to_hex(named.as_ref().to_vec()));
+		Some((status, serde_json::to_string(&response).unwrap()))
+	}
+
+	#[test]
+	fn receiver_commit_recorded() {
+		let (mut config, keychain) = test_wallet(vec![200, 200, 200]);
+		config.send_attempts = 1;
+		let send = |config: &WalletConfig, addr: &str| {
+			let pending = build_pending_send(
+				config,
+				&keychain,
+				SendAmount::Exact(150),
+				10,
+				1,
+				10,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+			).unwrap();
+			post_send(config, &keychain, pending, addr, false)
+		};
+
+		// the output named is in the completed transaction, ours to look up
+		let (addr, _) = client::test::stub_server(1, |body| naming_receiver(body, true));
+		let result = send(&config, &addr).unwrap();
+		let final_tx = tx_from_hex(&result.tx_hex.unwrap()).unwrap();
+		let commit = read_tx_log(&config).unwrap()[0].receiver_commit.clone().unwrap();
+		let ours = final_tx
+			.outputs
+			.iter()
+			.filter(|out| util::to_hex(out.commitment().as_ref().to_vec()) == commit)
+			.count();
+		assert_eq!(ours, 1);
+
+		// older receivers don't name any
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 0));
+		send(&config, &addr).unwrap();
+		assert_eq!(read_tx_log(&config).unwrap()[1].receiver_commit, None);
+
+		// a receiver naming our change gets the send rolled back
+		let (addr, _) = client::test::stub_server(1, |body| naming_receiver(body, false));
+		match send(&config, &addr) {
+			Err(Error::MalformedResponse(msg)) => assert!(msg.contains("isn't one"), "{}", msg),
+			res => panic!("expected MalformedResponse, got {:?}", res),
+		}
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log[2].status, TxLogStatus::Aborted);
+		assert_eq!(tx_log[2].receiver_commit, None);
+	}
+
+	#[test]
+	fn selection_covers_fee() {
+		// the coins cover the amount but not the amount and the fee
+		let (config, keychain) = test_wallet(vec![100, 150]);
+		let (coins, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(250),
+			10,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(coins.len(), 2);
+		assert_eq!(estimate.change, 0);
+		assert_eq!(estimate.fee, tx_fee(2, 1, None));
+
+		// many small coins, each added input changes the fee
+		let (config, keychain) = test_wallet(vec![5; 60]);
+		let (coins, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(297),
+			10,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(coins.len(), 60);
+		assert_eq!(estimate.change, 3);
+		assert_eq!(estimate.fee, tx_fee(60, 2, None));
+
+		match select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(301),
+			10,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		) {
+			Err(Error::NotEnoughFunds { missing, .. }) => assert_eq!(missing, 1),
+			res => panic!("expected NotEnoughFunds, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn send_result_matches_wallet() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_attempts = 1;
+		config.change_outputs = 2;
+
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 0));
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(450),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let result = post_send(&config, &keychain, pending, &addr, false).unwrap();
+
+		let key_ids = |status| {
+			let mut key_ids = outputs_with_status(&config, status)
+				.into_iter()
+				.map(|out| out.key_id)
+				.collect::<Vec<_>>();
+			key_ids.sort_by_key(|id| id.to_hex());
+			key_ids
+		};
+		let mut inputs = result.input_identifiers.clone();
+		inputs.sort_by_key(|id| id.to_hex());
+		let mut change = result.change_identifiers.clone();
+		change.sort_by_key(|id| id.to_hex());
+		assert_eq!(inputs.len(), 2);
+		assert_eq!(inputs, key_ids(OutputStatus::Locked));
+		assert_eq!(change.len(), 2);
+		assert_eq!(change, key_ids(OutputStatus::Pending));
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		assert_eq!(tx_log[0].id, result.tx_id);
+		assert_eq!(tx_log[0].amount, result.amount);
+		assert_eq!(tx_log[0].fee, result.fee);
+		assert_eq!(tx_log[0].dest, result.dest);
+		assert_eq!(result.amount, 450);
+		assert_eq!(result.dest, addr);
+		assert!(result.tx_hex.is_some());
+	}
+
+	#[test]
+	fn immature_coinbase_not_spent() {
+		let maturity = global::coinbase_maturity();
+		let current_height = 2 * maturity;
+		let (config, keychain) = test_wallet(vec![100]);
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			// mined long ago, and just now
+			for &(n_child, value, height) in
+				[(2, 500, 1), (3, 1_000, current_height - 1)].iter()
+			{
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: keychain.derive_key_id(n_child).unwrap(),
+					n_child: n_child,
+					value: value,
+					status: OutputStatus::Unspent,
+					height: height,
+					block_hash: None,
+					lock_height: height + maturity,
+					is_coinbase: true,
+					locked_at: 0,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+
+		let (coins, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Max,
+			current_height,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(coins.len(), 2);
+		assert_eq!(estimate.total_input, 600);
+
+		match select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(1_000),
+			current_height,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		) {
+			Err(Error::NotEnoughFunds {
+				missing,
+				spendable,
+				unconfirmed,
+				immature,
+				height_locked,
+			}) => {
+				assert_eq!((missing, spendable), (400, 600));
+				assert_eq!(unconfirmed, 0);
+				assert_eq!(immature, 1_000);
+				assert_eq!(height_locked, 0);
+			}
+			res => panic!("expected NotEnoughFunds, got {:?}", res),
+		}
+
+		// spendable once matured
+		let (coins, _) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(1_000),
+			current_height + maturity,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(coins.len(), 1);
+		assert_eq!(coins[0].value, 1_000);
+	}
+
+	#[test]
+	fn receiver_secret_kept_out_of_log() {
+		let (mut config, keychain) = test_wallet(vec![100, 200, 300]);
+		config.send_attempts = 1;
+
+		let (addr, _) =
+			client::test::auth_stub_server(1, Some("s3cret"), |body| completing_receiver(body, 0));
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(250),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		post_send(&config, &keychain, pending, &format!("{}#s3cret", addr), false).unwrap();
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log[0].dest, addr);
+		assert_eq!(tx_log[0].status, TxLogStatus::Pending);
+	}
+
+	#[test]
+	fn fee_paid_by_sender_or_recipient() {
+		// the recipient pays, the 500 coin covers the send and the recipient
+		// nets the amount minus the fee
+		let (config, keychain) = test_wallet(vec![600, 500]);
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let estimate = &pending.summary.estimate;
+		assert_eq!(estimate.total_input, 500);
+		assert_eq!(estimate.fee, tx_fee(1, 1, None));
+		assert_eq!(estimate.change, 0);
+		let (amount, _, _) = read_partial_tx(&keychain, &pending.partial_tx).unwrap();
+		assert_eq!(amount, 500);
+		assert_eq!(amount - estimate.fee, 460);
+
+		let path = Path::new(&config.data_file_dir).join("response.json");
+		receiver_response(&pending, 0, &path);
+		let tx = read_final_tx_file(&path).unwrap();
+		assert_eq!(verify_final_tx(&config, &keychain, &tx).unwrap(), pending.tx_log_id);
+
+		// the sender pays, the 500 coin no longer covers the amount and the
+		// fee so the 600 one gets spent and the recipient nets the amount
+		let (config, keychain) = test_wallet(vec![600, 500]);
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Sender,
+			None,
+		).unwrap();
+		let estimate = &pending.summary.estimate;
+		assert_eq!(estimate.total_input, 600);
+		assert_eq!(estimate.fee, tx_fee(1, 2, None));
+		assert_eq!(estimate.change, 600 - 500 - estimate.fee);
+		let (amount, _, _) = read_partial_tx(&keychain, &pending.partial_tx).unwrap();
+		assert_eq!(amount, 500 + estimate.fee);
+		assert_eq!(amount - estimate.fee, 500);
+		let change = outputs_with_status(&config, OutputStatus::Pending);
+		assert_eq!(change.len(), 1);
+		assert_eq!(change[0].value, estimate.change);
+
+		let path = Path::new(&config.data_file_dir).join("response.json");
+		receiver_response(&pending, 0, &path);
+		let tx = read_final_tx_file(&path).unwrap();
+		assert_eq!(verify_final_tx(&config, &keychain, &tx).unwrap(), pending.tx_log_id);
+
+		// the fee can't swallow the amount when the recipient pays it, and
+		// is simply added to it when the sender does
+		let coins = outputs_with_status(&config, OutputStatus::Unspent);
+		match estimate_send(&coins, SendAmount::Exact(40), 0, None, FeePaidBy::Recipient, 1, 0) {
+			Err(Error::FeeExceedsAmount { fee, amount }) => {
+				assert_eq!(fee, tx_fee(1, 2, None));
+				assert_eq!(amount, 40);
+			}
+			res => panic!("expected FeeExceedsAmount, got {:?}", res),
+		}
+		let estimate =
+			estimate_send(&coins, SendAmount::Exact(40), 0, None, FeePaidBy::Sender, 1, 0).unwrap();
+		assert_eq!(estimate.amount - estimate.fee, 40);
+		assert_eq!(estimate.change, 500 - 40 - estimate.fee);
+	}
+
+	fn coins(values: Vec<u64>) -> Vec<OutputData> {
+		values
+			.into_iter()
+			.enumerate()
+			.map(|(i, value)| OutputData {
+				root_key_id: Identifier::zero(),
+				key_id: Identifier::zero(),
+				n_child: i as u32 + 1,
+				value: value,
+				status: OutputStatus::Unspent,
+				height: 1,
+				block_hash: None,
+				lock_height: 0,
+				is_coinbase: false,
+				locked_at: 0,
+				account: None,
+				created_at: None,
+				confirmed_at: None,
+				spent_at: None,
+				tx_id: None,
+				label: None,
+				watch_commit: None,
+			})
+			.collect()
+	}
+
+	// Estimates either fail cleanly or balance: what the recipient builds
+	// its output from plus our change is exactly what we spend.
+	fn check_estimate(
+		values: Vec<u64>,
+		amount: SendAmount,
+		fee: Option<u64>,
+		fee_paid_by: FeePaidBy,
+		change_outputs: usize,
+		dust_threshold: u64,
+	) {
+		let coins = coins(values.clone());
+		match estimate_send(
+			&coins,
+			amount,
+			0,
+			fee,
+			fee_paid_by,
+			change_outputs,
+			dust_threshold,
+		) {
+			Ok(estimate) => {
+				assert_eq!(
+					estimate.amount.checked_add(estimate.change),
+					Some(estimate.total_input)
+				);
+				assert!(estimate.fee <= estimate.amount);
+				assert_eq!(estimate.fee & 1, 0);
+			}
+			Err(Error::NotEnoughFunds { .. })
+			| Err(Error::AmountOverflow)
+			| Err(Error::FeeExceedsAmount { .. })
+			| Err(Error::FeeTooLow { .. }) => {}
+			Err(e) => panic!(
+				"unexpected error {:?} estimating {:?} from {:?}, fee {:?} paid by {:?}",
+				e, amount, values, fee, fee_paid_by
+			),
+		}
+	}
+
+	#[test]
+	fn extreme_amounts_dont_overflow() {
+		let max = u64::MAX;
+		let values = vec![
+			vec![1],
+			vec![max],
+			vec![max - 1, 1],
+			vec![max, 1],
+			vec![max / 2, max / 2],
+			vec![max / 4; 4],
+			vec![max / 4; 5],
+			vec![max; 10],
+		];
+		let amounts = vec![0, 1, 2, 1_000, max / 2, max - 20, max - 1, max];
+		let fees = vec![None, Some(20), Some(max - 1)];
+		for v in values {
+			for fee_paid_by in vec![FeePaidBy::Recipient, FeePaidBy::Sender] {
+				for fee in fees.clone() {
+					check_estimate(v.clone(), SendAmount::Max, fee, fee_paid_by, 1, 0);
+					for &amount in amounts.iter() {
+						for &(change_outputs, dust) in [(1, 0), (3, 0), (1, max)].iter() {
+							check_estimate(
+								v.clone(),
+								SendAmount::Exact(amount),
+								fee,
+								fee_paid_by,
+								change_outputs,
+								dust,
+							);
+						}
+					}
+				}
+			}
+		}
+
+		let coins = coins(vec![max, 1]);
+		match estimate_send(&coins, SendAmount::Exact(10), 0, None, FeePaidBy::Recipient, 1, 0) {
+			Err(Error::AmountOverflow) => {}
+			res => panic!("expected an overflow, got {:?}", res),
+		}
+		let coins = vec![coins[0].clone()];
+		match estimate_send(&coins, SendAmount::Exact(max - 1), 0, None, FeePaidBy::Sender, 1, 0) {
+			Err(Error::AmountOverflow) => {}
+			res => panic!("expected an overflow, got {:?}", res),
+		}
+	}
+
+	// Small, huge or anything in between, the huge ones being where overflows
+	// lurk.
+	fn random_value(rng: &mut ThreadRng) -> u64 {
+		match rng.gen_range(0, 3) {
+			0 => rng.gen_range(0, 1_000),
+			1 => u64::MAX - rng.gen_range(0, 1_000),
+			_ => rng.gen(),
+		}
+	}
+
+	#[test]
+	fn random_amounts_dont_overflow() {
+		let mut rng = thread_rng();
+		for _ in 0..2_000 {
+			let n_coins = rng.gen_range(1, 6);
+			let values = (0..n_coins).map(|_| random_value(&mut rng)).collect();
+			let amount = SendAmount::Exact(random_value(&mut rng));
+			let fee = if rng.gen() {
+				None
+			} else {
+				Some(random_value(&mut rng) & !1)
+			};
+			let fee_paid_by = if rng.gen() {
+				FeePaidBy::Sender
+			} else {
+				FeePaidBy::Recipient
+			};
+			let dust = random_value(&mut rng);
+			check_estimate(values, amount, fee, fee_paid_by, rng.gen_range(1, 4), dust);
+		}
+	}
+
+	#[test]
+	fn selection_total_overflow() {
+		let (config, keychain) = test_wallet(vec![u64::MAX, u64::MAX, 5]);
+		let res = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(100),
+			10,
+			1,
+			0,
+			SelectionStrategy::All,
+			None,
+			FeePaidBy::Recipient,
+		);
+		match res {
+			Err(Error::AmountOverflow) => {}
+			res => panic!("expected an overflow, got {:?}", res),
+		}
+
+		// a single coin still covers it
+		let (_, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(100),
+			10,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!(estimate.total_input, u64::MAX);
+	}
+
+	#[test]
+	fn failed_sends_dont_reuse_derivation_indexes() {
+		let (config, keychain) = test_wallet(vec![1_000]);
+		let next_child = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.peek_child(&config.data_file_dir, keychain.root_key_id())
+			}).unwrap().unwrap()
+		};
+		let build = |amount| {
+			build_pending_send(
+				&config,
+				&keychain,
+				SendAmount::Exact(amount),
+				10,
+				1,
+				10,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+			)
+		};
+		let start = next_child();
+
+		// refused before anything gets built
+		for _ in 0..3 {
+			assert!(build(10_000).is_err());
+			assert_eq!(next_child(), start);
+		}
+
+		// built, then rolled back as the destination file is already there
+		let path = env::temp_dir().join(format!("grin_taken_{}.json", keychain.root_key_id()));
+		File::create(&path).unwrap();
+		// the change is forgotten but its key isn't handed out again, the
+		// counter only moves past the change keys reserved
+		let mut change = vec![];
+		let mut next = start;
+		for _ in 0..3 {
+			let pending = build(500).unwrap();
+			next += pending.summary.change_key_ids.len() as u32;
+			assert_eq!(next_child(), next);
+			change.push(pending.summary.change_key_ids.clone());
+			assert!(post_send(&config, &keychain, pending, path.to_str().unwrap(), false).is_err());
+			assert_eq!(next_child(), next);
+		}
+		assert_eq!(next, start + 3);
+		assert!(change[0] != change[1]);
+		assert!(change[1] != change[2]);
+		fs::remove_file(&path).unwrap();
+	}
+
+	// What the wallet holds or is about to, spent and locked coins aside.
+	fn balance(config: &WalletConfig) -> u64 {
+		WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data
+				.outputs
+				.values()
+				.filter(|out| {
+					[
+						OutputStatus::Pending,
+						OutputStatus::Unconfirmed,
+						OutputStatus::Unspent,
+					].contains(&out.status)
+				})
+				.map(|out| out.value)
+				.sum()
+		}).unwrap()
+	}
+
+	#[test]
+	fn self_spend_costs_only_fee() {
+		let (mut config, keychain) = test_wallet(vec![300, 1_000]);
+		assert_eq!(balance(&config), 1_300);
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(600),
+			10,
+			1,
+			10,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let tx_id = pending.tx_log_id;
+		let change = pending.summary.change_key_ids.clone();
+
+		// with the node down nothing is recorded for the new output, the send
+		// stays pending to be retried
+		let (node, _) =
+			client::test::stub_server(1, |_| Some(("500 Internal Server Error", String::new())));
+		config.check_node_api_http_addr = node;
+		match post_send(&config, &keychain, pending, "self", false) {
+			Err(Error::Node(api::Error::Internal(_))) => {}
+			res => panic!("expected the push to fail, got {:?}", res),
+		}
+		assert!(outputs_with_status(&config, OutputStatus::Unconfirmed).is_empty());
+
+		let (node, pushed) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		let result = resend(&config, &keychain, tx_id, None).unwrap();
+		assert_eq!(result.dest, "self");
+		let tx_hex = result.tx_hex.clone().unwrap();
+		let pushed: TxWrapper = serde_json::from_str(&pushed.lock().unwrap()[0]).unwrap();
+		assert_eq!(pushed.tx_hex, tx_hex);
+		assert!(!pushed.fluff);
+		tx_from_hex(&tx_hex).unwrap().validate(&keychain.secp()).unwrap();
+
+		assert_eq!(balance(&config), 1_300 - result.fee);
+		let received = outputs_with_status(&config, OutputStatus::Unconfirmed);
+		assert_eq!(received.len(), 1);
+		assert_eq!(received[0].value, 600 - result.fee);
+		let pending_change = outputs_with_status(&config, OutputStatus::Pending);
+		assert_eq!(
+			pending_change.iter().map(|out| out.key_id.clone()).collect::<Vec<_>>(),
+			change
+		);
+	}
+
+	#[test]
+	fn too_many_inputs_refused() {
+		let (config, keychain) = test_wallet(vec![1_000; 2_000]);
+		match build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(1_500_000),
+			10,
+			1,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		) {
+			Err(Error::TooManyInputs { inputs, max }) => {
+				assert_eq!(inputs, 1_500);
+				assert_eq!(max, 500);
+			}
+			res => panic!("expected TooManyInputs, got {:?}", res.map(|p| p.summary)),
+		}
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(read_tx_log(&config).unwrap().is_empty());
+
+		// below the cap it goes through
+		assert!(
+			build_pending_send(
+				&config,
+				&keychain,
+				SendAmount::Exact(500_000),
+				10,
+				1,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+			).is_ok()
+		);
+	}
+
+	#[test]
+	fn auto_split_merges_coins_first() {
+		let (mut config, keychain) = test_wallet(vec![1_000; 2_000]);
+		config.auto_split = true;
+		let (node, pushed) = client::test::stub_server(3, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 0));
+
+		// with a minimum of confirmations what the merges produce couldn't be
+		// spent, nothing is merged
+		let split = |minimum_confirmations| {
+			split_send_locked(
+				&config,
+				&keychain,
+				SendAmount::Exact(1_500_000),
+				10,
+				minimum_confirmations,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+				&addr,
+				false,
+			)
+		};
+		match split(1) {
+			Err(Error::TooManyInputs { inputs, max: 500 }) => assert!(inputs > 500),
+			res => panic!("expected TooManyInputs, got {:?}", res),
+		}
+		assert!(pushed.lock().unwrap().is_empty());
+		assert!(read_tx_log(&config).unwrap().is_empty());
+
+		let result = split(0).unwrap();
+
+		// 1,501 coins to also pay for the merges, 500 at a time, the odd one
+		// spent as is
+		let merge_fee = tx_fee(500, 1, None);
+		let pushed = pushed.lock().unwrap();
+		assert_eq!(pushed.len(), 3);
+		for push in pushed.iter() {
+			let push: TxWrapper = serde_json::from_str(push).unwrap();
+			let tx = tx_from_hex(&push.tx_hex).unwrap();
+			assert_eq!((tx.inputs.len(), tx.outputs.len()), (500, 1));
+		}
+		assert_eq!(result.consolidation_tx_ids.len(), 3);
+		assert_eq!(result.input_identifiers.len(), 4);
+		assert_eq!(result.amount, 1_500_000);
+		assert_eq!(result.unconfirmed_inputs, 3);
+		assert!(result.tx_hex.is_some());
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 4);
+		for entry in tx_log.iter() {
+			let expected = if result.consolidation_tx_ids.contains(&entry.id) {
+				"self"
+			} else {
+				addr.as_str()
+			};
+			assert_eq!(entry.dest, expected);
+		}
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1_501 + 3);
+		assert_eq!(balance(&config), 2_000_000 - 1_500_000 - 3 * merge_fee);
+	}
+
+	#[test]
+	fn unconfirmed_inputs_reported() {
+		let (config, keychain) = test_wallet(vec![100]);
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			wallet_data.add_output(OutputData {
+				root_key_id: keychain.root_key_id(),
+				key_id: keychain.derive_key_id(2).unwrap(),
+				n_child: 2,
+				value: 500,
+				status: OutputStatus::Unconfirmed,
+				height: 0,
+				block_hash: None,
+				lock_height: 0,
+				is_coinbase: false,
+				locked_at: 0,
+				account: None,
+				created_at: None,
+				confirmed_at: None,
+				spent_at: None,
+				tx_id: None,
+				label: None,
+				watch_commit: None,
+			}).unwrap();
+		}).unwrap();
+		let estimate = |amount| {
+			let (_, estimate) = select_coins(
+				&config,
+				keychain.root_key_id(),
+				SendAmount::Exact(amount),
+				10,
+				0,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+			).unwrap();
+			(estimate.inputs.len(), estimate.unconfirmed_inputs)
+		};
+		assert_eq!(estimate(50), (1, 0));
+		assert_eq!(estimate(300), (2, 1));
+
+		let pending = build_pending_send(
+			&config,
+			&keychain,
+			SendAmount::Exact(300),
+			10,
+			0,
+			0,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+		).unwrap();
+		let result = post_send(&config, &keychain, pending, "stdout", false).unwrap();
+		assert_eq!(result.unconfirmed_inputs, 1);
+	}
+
+	#[test]
+	fn consolidation_fees_cover_every_round() {
+		let merge_fee = tx_fee(500, 1, None);
+		assert_eq!(consolidation_fees(500, 500), 0);
+		assert_eq!(consolidation_fees(501, 500), merge_fee);
+		assert_eq!(consolidation_fees(1_500, 500), 3 * merge_fee);
+		assert_eq!(consolidation_fees(1_502, 500), 3 * merge_fee + tx_fee(2, 1, None));
+		// 501 merged outputs need another round
+		assert_eq!(consolidation_fees(250_001, 500), 501 * merge_fee);
+		assert_eq!(consolidation_fees(10, 1), 0);
+	}
+
+	#[test]
+	fn push_fluff_flag() {
+		let mut config = WalletConfig::default();
+		for &fluff in [false, true].iter() {
+			let (node, pushed) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+			config.check_node_api_http_addr = node;
+			push_tx(&config, "00", fluff).unwrap();
+
+			let body = pushed.lock().unwrap()[0].clone();
+			let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+			assert_eq!(json["fluff"].as_bool(), Some(fluff));
+			let wrapper: TxWrapper = serde_json::from_str(&body).unwrap();
+			assert_eq!(wrapper.fluff, fluff);
+		}
+
+		// as written before the flag existed
+		let wrapper: TxWrapper = serde_json::from_str(r#"{"tx_hex":"00"}"#).unwrap();
+		assert!(!wrapper.fluff);
+	}
+
+	#[test]
+	fn send_to_contact_resolved_first() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		config.contacts.insert("bad".to_string(), "ftp://x".to_string());
+		let send = |dest: &str| {
+			issue_send_tx(
+				&config,
+				&keychain,
+				SendAmount::Exact(500),
+				1,
+				dest.to_string(),
+				false,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+				None,
+			)
+		};
+
+		// refused before the node is even asked anything
+		match send("@nobody") {
+			Err(Error::UnknownContact(ref name)) => assert_eq!(name, "nobody"),
+			res => panic!("expected UnknownContact, got {:?}", res),
+		}
+		match send("@bad#s3cret") {
+			Err(Error::InvalidDestination(ref dest)) => assert_eq!(dest, "ftp://x#s3cret"),
+			res => panic!("expected InvalidDestination, got {:?}", res),
+		}
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 1);
+		assert!(read_tx_log(&config).unwrap().is_empty());
+	}
+
+	#[test]
+	fn batch_send_funds_what_it_can() {
+		let (config, keychain) = test_wallet(vec![1_000, 2_000, 3_000]);
+		let dir = env::temp_dir().join(format!("grin_batch_{}", keychain.root_key_id()));
+		fs::create_dir_all(&dir).unwrap();
+		let payments = vec![2_500, 1_500, 5_000, 800, 500]
+			.into_iter()
+			.enumerate()
+			.map(|(i, amount)| {
+				let path = dir.join(format!("payment_{}.json", i));
+				(amount, path.to_str().unwrap().to_string())
+			})
+			.collect::<Vec<_>>();
+
+		let results = batch_send(&config, &keychain, 10, payments);
+		assert_eq!(results.len(), 5);
+		for &i in [2, 4].iter() {
+			match results[i] {
+				Err(Error::NotEnoughFunds { .. }) => {}
+				ref res => panic!("payment {} should lack funds, got {:?}", i, res),
+			}
+		}
+		let mut spent = HashSet::new();
+		for &i in [0, 1, 3].iter() {
+			let result = results[i].as_ref().unwrap();
+			assert!(dir.join(format!("payment_{}.json", i)).exists());
+			for input in &result.input_identifiers {
+				assert!(spent.insert(input.clone()), "input shared by payment {}", i);
+			}
+		}
+		assert_eq!(spent.len(), 3);
+		assert!(!dir.join("payment_2.json").exists());
+		assert!(!dir.join("payment_4.json").exists());
+		assert_eq!(read_tx_log(&config).unwrap().len(), 3);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn fee_estimate_matches_send() {
+		let strategies = vec![
+			SelectionStrategy::Default,
+			SelectionStrategy::SmallestFirst,
+			SelectionStrategy::LargestFirst,
+			SelectionStrategy::All,
+		];
+		let fixtures = vec![
+			(vec![10_000], 5_000, false),
+			(vec![1_000, 2_000, 3_000, 4_000, 5_000], 6_000, false),
+			(vec![1_000; 8], 5_000, false),
+			// the confirmed coin isn't enough on its own
+			(vec![1_000], 3_000, true),
+		];
+		for (values, amount, unconfirmed) in fixtures {
+			for strategy in strategies.clone() {
+				let (config, keychain) = test_wallet(values.clone());
+				if unconfirmed {
+					WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+						wallet_data.add_output(OutputData {
+							root_key_id: keychain.root_key_id(),
+							key_id: keychain.derive_key_id(2).unwrap(),
+							n_child: 2,
+							value: 5_000,
+							status: OutputStatus::Unconfirmed,
+							height: 0,
+							block_hash: None,
+							lock_height: 0,
+							is_coinbase: false,
+							locked_at: 0,
+							account: None,
+							created_at: None,
+							confirmed_at: None,
+							spent_at: None,
+							tx_id: None,
+							label: None,
+							watch_commit: None,
+						}).unwrap();
+					}).unwrap();
+				}
+				let minimum_confirmations = if unconfirmed { 0 } else { 1 };
+
+				let estimate =
+					estimate_fee_at(&config, &keychain, amount, 10, strategy, minimum_confirmations)
+						.unwrap();
+				assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+				assert_eq!(estimate.needs_unconfirmed, unconfirmed);
+
+				let (tx, _, summary) = build_send_tx(
+					&config,
+					&keychain,
+					SendAmount::Exact(amount),
+					10,
+					minimum_confirmations,
+					0,
+					strategy,
+					None,
+					FeePaidBy::Recipient,
+				).unwrap();
+				assert_eq!(estimate, FeeEstimate::from(&summary.estimate), "{:?}", strategy);
+				assert_eq!((tx.fee, tx.inputs.len()), (estimate.fee, estimate.inputs));
+				let change = outputs_with_status(&config, OutputStatus::Unconfirmed)
+					.iter()
+					.filter(|out| summary.change_key_ids.contains(&out.key_id))
+					.map(|out| out.value)
+					.sum::<u64>();
+				assert_eq!(change, estimate.change);
+				fs::remove_dir_all(&config.data_file_dir).unwrap();
+			}
+		}
+	}
+
+	#[test]
+	fn too_small_amounts_refused() {
+		let (mut config, keychain) = test_wallet(vec![1_000, 2_000]);
+		config.dust_threshold = 100;
+		// nothing listens there, getting to the node means the amount passed
+		config.check_node_api_http_addr = "http://127.0.0.1:1".to_string();
+		let dat_file = Path::new(&config.data_file_dir).join("wallet.dat");
+		let before = read_file(&dat_file);
+
+		for &amount in [0, 99].iter() {
+			let send = issue_send_tx(
+				&config,
+				&keychain,
+				SendAmount::Exact(amount),
+				1,
+				"stdout".to_string(),
+				false,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+				None,
+			);
+			let burn = issue_burn_tx(&config, &keychain, amount, 1, false).map(|_| ());
+			// as is a send prepared, batched or built by a library caller
+			let pending = build_pending_send(
+				&config,
+				&keychain,
+				SendAmount::Exact(amount),
+				10,
+				1,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+				None,
+			).map(|_| ());
+			let built = TxBuilder::new(&config, &keychain, SendAmount::Exact(amount), 10)
+				.build()
+				.map(|_| ());
+			for res in vec![send.map(|_| ()), burn, pending, built] {
+				match res {
+					Err(Error::AmountTooSmall { amount: a, minimum: 100 }) if a == amount => {}
+					res => panic!("expected AmountTooSmall for {}, got {:?}", amount, res),
+				}
+			}
+			assert_eq!(read_file(&dat_file), before);
+			assert!(!Path::new(&config.data_file_dir).join("wallet.child").exists());
+		}
+
+		match issue_burn_tx(&config, &keychain, 100, 1, false) {
+			Err(Error::Node(_)) => {}
+			res => panic!("expected the node to be unreachable, got {:?}", res),
+		}
+		assert!(check_amount(&config, 100).is_ok());
+		config.dust_threshold = 0;
+		assert!(check_amount(&config, 1).is_ok());
+		match check_amount(&config, 0) {
+			Err(Error::AmountTooSmall { amount: 0, minimum: 1 }) => {}
+			res => panic!("expected AmountTooSmall, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn exact_match_without_change() {
+		let mut values = vec![1_000; 10];
+		values.push(300);
+		let (config, keychain) = test_wallet(values.clone());
+		let next_child = || {
+			WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+				wallet_data.next_child(keychain.root_key_id())
+			}).unwrap()
+		};
+		let start = next_child();
+		let build = |amount, fee_paid_by| {
+			build_send_tx(
+				&config,
+				&keychain,
+				SendAmount::Exact(amount),
+				10,
+				1,
+				0,
+				SelectionStrategy::Default,
+				None,
+				fee_paid_by,
+			).unwrap()
+		};
+
+		// three coins make the amount, no change key gets derived
+		let (tx, _, summary) = build(3_000, FeePaidBy::Recipient);
+		assert_eq!((tx.inputs.len(), tx.outputs.len()), (3, 0));
+		assert_eq!(tx.fee, tx_fee(3, 1, None));
+		assert!(summary.change_key_ids.is_empty());
+		assert_eq!(next_child(), start);
+
+		// paid by the sender, the coins make the amount and the fee
+		let (tx, _, summary) = build(2_300 - tx_fee(3, 1, None), FeePaidBy::Sender);
+		assert_eq!((tx.inputs.len(), tx.outputs.len()), (3, 0));
+		assert_eq!(summary.estimate.total_input, 2_300);
+		assert_eq!(next_child(), start);
+
+		// nothing adds up to it, the usual selection with change
+		let (tx, _, summary) = build(1_500, FeePaidBy::Recipient);
+		assert_eq!((tx.inputs.len(), tx.outputs.len()), (2, 1));
+		assert_eq!(summary.estimate.change, 500);
+		assert_eq!(next_child(), start + 1);
+
+		// other strategies don't look for a match
+		let (config, keychain) = test_wallet(values);
+		let (_, estimate) = select_coins(
+			&config,
+			keychain.root_key_id(),
+			SendAmount::Exact(2_000),
+			10,
+			1,
+			0,
+			SelectionStrategy::SmallestFirst,
+			None,
+			FeePaidBy::Recipient,
+		).unwrap();
+		assert_eq!((estimate.inputs.len(), estimate.change), (3, 300));
+	}
+
+	#[test]
+	fn send_context_logged() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+
+		// the node answers the refresh (tip, outputs and the block they're in)
+		// then the tip again
+		let mut header = BlockHeader::default();
+		header.height = 1;
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, _) = transaction(vec![output(1_000, key_id)], &keychain).unwrap();
+		let tip = api::Tip {
+			height: 100,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		};
+		let mut replies = vec![
+			serde_json::to_string(&tip).unwrap(),
+			serde_json::to_string(&vec![api::Output::from_output(&tx.outputs[0], &header)])
+				.unwrap(),
+			block_reply(1),
+			serde_json::to_string(&tip).unwrap(),
+		].into_iter();
+		let (node, _) = client::test::stub_server(4, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+		let (addr, _) = client::test::stub_server(1, |body| completing_receiver(body, 0));
+		config.contacts.insert("bob".to_string(), addr.clone());
+
+		let result = issue_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(500),
+			1,
+			"@bob".to_string(),
+			false,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+			Some("invoice 42".to_string()),
+		).unwrap();
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		let entry = &tx_log[0];
+		assert_eq!(entry.dest, addr);
+		assert_eq!(entry.contact, Some("bob".to_string()));
+		assert_eq!(entry.message, Some("invoice 42".to_string()));
+		assert_eq!(entry.tip_height, 100);
+		assert_eq!(entry.receiver_ack, Some(ack_hash(&result.tx_hex.unwrap())));
+		assert_eq!(entry.receiver_ack.as_ref().unwrap().len(), 64);
+	}
+
+	#[test]
+	fn height_locked_outputs_not_spent() {
+		let (config, keychain) = test_wallet(vec![100, 200]);
+		WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+			// locked until just now, and until later on
+			for &(n_child, value, lock_height) in [(3, 300, 10), (4, 5_000, 11)].iter() {
+				wallet_data.add_output(OutputData {
+					root_key_id: keychain.root_key_id(),
+					key_id: keychain.derive_key_id(n_child).unwrap(),
+					n_child: n_child,
+					value: value,
+					status: OutputStatus::Unspent,
+					height: 1,
+					block_hash: None,
+					lock_height: lock_height,
+					is_coinbase: false,
+					locked_at: 0,
+					account: None,
+					created_at: None,
+					confirmed_at: None,
+					spent_at: None,
+					tx_id: None,
+					label: None,
+					watch_commit: None,
+				}).unwrap();
+			}
+		}).unwrap();
+		let select = |amount, current_height| {
+			select_coins(
+				&config,
+				keychain.root_key_id(),
+				amount,
+				current_height,
+				1,
+				0,
+				SelectionStrategy::Default,
+				None,
+				FeePaidBy::Recipient,
+			)
+		};
+
+		let (coins, estimate) = select(SendAmount::Max, 10).unwrap();
+		let mut values = coins.iter().map(|c| c.value).collect::<Vec<_>>();
+		values.sort();
+		assert_eq!(values, vec![100, 200, 300]);
+		assert_eq!(estimate.total_input, 600);
+
+		match select(SendAmount::Exact(1_000), 10) {
+			Err(Error::NotEnoughFunds {
+				missing,
+				spendable,
+				unconfirmed,
+				immature,
+				height_locked,
+			}) => {
+				assert_eq!((missing, spendable), (400, 600));
+				assert_eq!((unconfirmed, immature), (0, 0));
+				assert_eq!(height_locked, 5_000);
+			}
+			res => panic!("expected NotEnoughFunds, got {:?}", res),
+		}
+
+		// past its lock height it's the coin a send picks
+		let (coins, _) = select(SendAmount::Exact(1_000), 11).unwrap();
+		assert_eq!(coins.len(), 1);
+		assert_eq!(coins[0].value, 5_000);
+	}
+
+	fn tip_reply(height: u64) -> String {
+		let tip = api::Tip {
+			height: height,
+			last_block_pushed: String::new(),
+			prev_block_to_last: String::new(),
+			total_difficulty: 0,
+		};
+		serde_json::to_string(&tip).unwrap()
+	}
+
+	// What the node answers when asked for the block at the height.
+	fn block_reply(height: u64) -> String {
+		serde_json::to_string(&vec![
+			api::BlockOutputs {
+				height: height,
+				hash: format!("{:064}", height),
+				outputs: vec![],
+			},
+		]).unwrap()
+	}
+
+	// What the node answers a wallet holding a single confirmed output of
+	// value for the refresh before a send (tip, outputs and the block they're
+	// in) then the tip again, at the given height.
+	fn single_output_replies(keychain: &Keychain, value: u64, height: u64) -> Vec<String> {
+		let mut header = BlockHeader::default();
+		header.height = 1;
+		let key_id = keychain.derive_key_id(1).unwrap();
+		let (tx, _) = transaction(vec![output(value, key_id)], keychain).unwrap();
+		vec![
+			tip_reply(height),
+			serde_json::to_string(&vec![api::Output::from_output(&tx.outputs[0], &header)])
+				.unwrap(),
+			block_reply(1),
+			tip_reply(height),
+		]
+	}
+
+	// An invoice for the amount from a fresh receiver, answering payments at
+	// the returned address, expiring at expiry_height. Its node stays 10
+	// blocks short of it.
+	fn invoice(amount: u64, expiry_height: u64) -> (JSONInvoice, WalletConfig, Keychain) {
+		let (mut config, keychain) = test_wallet(vec![]);
+		let tip = tip_reply(expiry_height - 10);
+		let (node, _) = client::test::stub_server(2, move |_| Some(("200 OK", tip.clone())));
+		config.check_node_api_http_addr = node;
+
+		let (receiver_config, receiver_keychain) = (config.clone(), keychain.clone());
+		let (addr, _) = client::test::stub_server(1, move |body| {
+			let partial_tx: JSONPartialTx = serde_json::from_str(body).unwrap();
+			let response =
+				receive_invoice_payment(&receiver_config, &receiver_keychain, &partial_tx)
+					.unwrap();
+			Some(("200 OK", serde_json::to_string(&response).unwrap()))
+		});
+		let invoice = issue_invoice(&config, &keychain, amount, 10, &addr).unwrap();
+		(invoice, config, keychain)
+	}
+
+	#[test]
+	fn invoice_round_trip() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		let (invoice, receiver_config, receiver_keychain) = invoice(600, 110);
+		assert_eq!((invoice.amount, invoice.expiry_height), (600, 110));
+
+		// the invoice keeps its key aside but isn't an output yet
+		let next_child = WalletData::read_wallet(&receiver_config.data_file_dir, |wallet_data| {
+			assert!(wallet_data.outputs.is_empty());
+			wallet_data.next_child(receiver_keychain.root_key_id())
+		}).unwrap();
+		assert_eq!(next_child, 2);
+
+		let mut replies = single_output_replies(&keychain, 1_000, 100);
+		replies.push(String::new());
+		let mut replies = replies.into_iter();
+		let (node, pushed) = client::test::stub_server(5, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+
+		let invoice_json = serde_json::to_string(&invoice).unwrap();
+		let result = pay_invoice(&config, &keychain, &invoice_json).unwrap();
+		let fee = tx_fee(1, 2, None);
+		assert_eq!((result.amount, result.fee), (600 + fee, fee));
+
+		// signed by the receiver, pushed by us
+		let tx_hex = result.tx_hex.unwrap();
+		let final_tx = tx_from_hex(&tx_hex).unwrap();
+		final_tx.validate(keychain.secp()).unwrap();
+		assert!(pushed.lock().unwrap()[4].contains(&tx_hex));
+
+		// the receiver now tracks the output of the invoice, which is closed
+		let received = outputs_with_status(&receiver_config, OutputStatus::Unconfirmed);
+		assert_eq!(received.len(), 1);
+		assert_eq!(received[0].value, 600);
+		let commit = receiver_keychain.commit(600, &received[0].key_id).unwrap();
+		assert!(final_tx.outputs.iter().any(|out| out.commitment() == commit));
+		let invoices = WalletData::read_wallet(&receiver_config.data_file_dir, |wallet_data| {
+			wallet_data.invoices.len()
+		}).unwrap();
+		assert_eq!(invoices, 0);
+
+		// while we wait for our change
+		assert_eq!(outputs_with_status(&config, OutputStatus::Locked).len(), 1);
+		let change = outputs_with_status(&config, OutputStatus::Unconfirmed);
+		assert_eq!(change.len(), 1);
+		assert_eq!(change[0].value, 1_000 - 600 - fee);
+	}
+
+	#[test]
+	fn expired_invoice_refused() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		let (invoice, _, _) = invoice(600, 100);
+
+		let mut replies = single_output_replies(&keychain, 1_000, 100).into_iter();
+		let (node, _) = client::test::stub_server(4, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+
+		let invoice_json = serde_json::to_string(&invoice).unwrap();
+		match pay_invoice(&config, &keychain, &invoice_json) {
+			Err(Error::InvoiceExpired {
+				expiry_height: 100,
+				current_height: 100,
+			}) => {}
+			res => panic!("expected InvoiceExpired, got {:?}", res),
+		}
+		// nothing was selected nor logged
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
+		assert!(read_tx_log(&config).unwrap().is_empty());
+	}
+
+	#[test]
+	fn file_round_trip() {
+		let (mut config, keychain) = test_wallet(vec![1_000]);
+		let (receiver_config, receiver_keychain) = test_wallet(vec![]);
+		let mut replies = single_output_replies(&keychain, 1_000, 100);
+		replies.push(String::new());
+		let mut replies = replies.into_iter();
+		let (node, pushed) = client::test::stub_server(5, move |_| {
+			replies.next().map(|reply| ("200 OK", reply))
+		});
+		config.check_node_api_http_addr = node;
+
+		let dir = Path::new(&config.data_file_dir);
+		let (partial_path, final_path) = (dir.join("partial.json"), dir.join("final.json"));
+		let result = issue_send_tx(
+			&config,
+			&keychain,
+			SendAmount::Exact(600),
+			1,
+			partial_path.to_str().unwrap().to_string(),
+			false,
+			SelectionStrategy::Default,
+			None,
+			FeePaidBy::Recipient,
+			None,
+			Some("offline".to_string()),
+		).unwrap();
+
+		// the receiver never needs the node, nor does it push
+		receive_from_file(&receiver_config, &receiver_keychain, &partial_path, &final_path)
+			.unwrap();
+		let received = outputs_with_status(&receiver_config, OutputStatus::Unconfirmed);
+		assert_eq!(received.len(), 1);
+		assert_eq!(received[0].value, 600 - result.fee);
+
+		// processing the file again gives the same transaction
+		let first = read_file(&final_path);
+		receive_from_file(&receiver_config, &receiver_keychain, &partial_path, &final_path)
+			.unwrap();
+		assert_eq!(read_file(&final_path), first);
+		assert_eq!(outputs_with_status(&receiver_config, OutputStatus::Unconfirmed).len(), 1);
+
+		finalize_from_file(&config, &keychain, &final_path, false).unwrap();
+		let push: TxWrapper = serde_json::from_str(&pushed.lock().unwrap()[4]).unwrap();
+		let tx = read_final_tx_file(&final_path).unwrap();
+		assert_eq!(push.tx_hex, util::to_hex(ser::ser_vec(&tx).unwrap()));
+		let commit = receiver_keychain.commit(600 - result.fee, &received[0].key_id).unwrap();
+		assert!(tx.outputs.iter().any(|out| out.commitment() == commit));
+
+		// a partial transaction that doesn't parse fails the receive
+		File::create(&partial_path).unwrap().write_all(b"{").unwrap();
+		assert!(
+			receive_from_file(&receiver_config, &receiver_keychain, &partial_path, &final_path)
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn consolidates_smallest_coins() {
+		let mut values = vec![1_000; 40];
+		values.push(1_000_000);
+		let (mut config, keychain) = test_wallet(values);
+		let (node, pushed) = client::test::stub_server(1, |_| Some(("200 OK", String::new())));
+		config.check_node_api_http_addr = node;
+
+		let result = consolidate_locked(&config, &keychain, 10, 30, 3).unwrap();
+
+		let fee = tx_fee(30, 3, None);
+		assert!(fee * 100 <= 30_000 * config.max_consolidation_fee_percent);
+		assert_eq!(result.fee, fee);
+		assert_eq!(result.dest, "self");
+		assert_eq!(result.input_identifiers.len(), 30);
+		assert_eq!(result.change_identifiers.len(), 3);
+		let pushed = pushed.lock().unwrap();
+		assert_eq!(pushed.len(), 1);
+		let push: TxWrapper = serde_json::from_str(&pushed[0]).unwrap();
+		let tx = tx_from_hex(&push.tx_hex).unwrap();
+		assert_eq!((tx.inputs.len(), tx.outputs.len(), tx.fee), (30, 3, fee));
+
+		// the large coin is left alone, the new outputs split what was merged
+		let locked = outputs_with_status(&config, OutputStatus::Locked);
+		assert_eq!(locked.len(), 30);
+		assert!(locked.iter().all(|out| out.value == 1_000));
+		let new_values = WalletData::read_wallet(&config.data_file_dir, |wallet_data| {
+			result
+				.change_identifiers
+				.iter()
+				.map(|key_id| wallet_data.outputs[&key_id.to_hex()].value)
+				.collect::<Vec<_>>()
+		}).unwrap();
+		assert_eq!(new_values.iter().sum::<u64>(), 30_000 - fee);
+		assert!(new_values.iter().max().unwrap() - new_values.iter().min().unwrap() <= 2);
+		assert_eq!(balance(&config), 1_040_000 - fee);
+
+		let tx_log = read_tx_log(&config).unwrap();
+		assert_eq!(tx_log.len(), 1);
+		assert_eq!(tx_log[0].dest, "self");
+	}
+
+	#[test]
+	fn consolidation_refused() {
+		// as many coins as outputs asked for
+		let (config, keychain) = test_wallet(vec![1_000; 2]);
+		match consolidate_locked(&config, &keychain, 10, 500, 2) {
+			Err(Error::NothingToConsolidate {
+				coins: 2,
+				target_outputs: 2,
+			}) => {}
+			res => panic!("expected NothingToConsolidate, got {:?}", res),
+		}
+
+		// dust costing more to merge than the config allows
+		let (config, keychain) = test_wallet(vec![5; 4]);
+		match consolidate_locked(&config, &keychain, 10, 500, 1) {
+			Err(Error::ConsolidationFeeTooHigh {
+				fee: 10,
+				value: 20,
+				max_percent: 5,
+			}) => {}
+			res => panic!("expected ConsolidationFeeTooHigh, got {:?}", res),
+		}
+		assert!(read_tx_log(&config).unwrap().is_empty());
+		assert_eq!(outputs_with_status(&config, OutputStatus::Unspent).len(), 4);
+	}
+
+	#[test]
+	fn inputs_of_another_root_key_refused() {
+		let (config, keychain) = test_wallet(vec![1_000]);
+		let other = Keychain::from_random_seed().unwrap();
+		let mut coins = outputs_with_status(&config, OutputStatus::Unspent);
+		coins.push(OutputData {
+			root_key_id: other.root_key_id(),
+			key_id: other.derive_key_id(1).unwrap(),
+			..coins[0].clone()
+		});
+		let estimate =
+			estimate_send(&coins, SendAmount::Exact(1_500), 0, None, FeePaidBy::Recipient, 1, 0)
+				.unwrap();
+
+		match build_selected_tx(&config, &keychain, &coins, estimate, 0, 10) {
+			Err(Error::ForeignInput {
+				ref fingerprint, ..
+			}) if *fingerprint == other.root_key_id() => {}
+			res => panic!("expected ForeignInput, got {:?}", res.map(|_| ())),
+		}
+		assert!(outputs_with_status(&config, OutputStatus::Locked).is_empty());
 	}
 }
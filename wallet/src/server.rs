@@ -12,38 +12,127 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::path::Path;
+use std::sync::Arc;
 
-use api::ApiServer;
+use api::{ApiServer, TlsServer};
 use keychain::Keychain;
-use handlers::CoinbaseHandler;
-use receiver::WalletReceiver;
-use types::WalletConfig;
+use handlers::{Authenticated, CoinbaseHandler, PingHandler, RateLimited, RateLimiter};
+use receiver::{InvoiceReceiver, WalletReceiver};
+use types::{Error, WalletConfig};
 use util::LOGGER;
 
-pub fn start_rest_apis(wallet_config: WalletConfig, keychain: Keychain) {
-	info!(
-		LOGGER,
-		"Starting the Grin wallet receiving daemon at {}...",
-		wallet_config.api_listen_addr()
-	);
+/// Runs the wallet receiver on the listen address of the config, for as long
+/// as the process lives.
+pub fn start_rest_apis(wallet_config: WalletConfig, keychain: Keychain) -> Result<(), Error> {
+	info!(LOGGER, "Starting the Grin wallet receiving daemon...");
+	// dropping the server waits on it, which serves until the process exits
+	let _apis = start_receiver(wallet_config, keychain)?;
+	Ok(())
+}
+
+/// Starts the wallet receiver on the listen address of the config and hands
+/// back the running server. An invalid address, or one already in use, is
+/// an error rather than a panic. Requests are rate limited before anything
+/// else, the secret checked included, all endpoints sharing the same global
+/// limit.
+pub fn start_receiver(wallet_config: WalletConfig, keychain: Keychain) -> Result<ApiServer, Error> {
+	let wallet_config = wallet_config.for_keychain(&keychain)?;
+	let addr = wallet_config.listen_socket_addr()?;
+	let secret = wallet_config.receive_secret.clone();
+	if addr.ip().is_unspecified() && secret.is_none() {
+		warn!(
+			LOGGER,
+			"The wallet receiver listens on all interfaces ({}) without a receive_secret, \
+			 anyone reaching it can post to it",
+			addr
+		);
+	}
 
-	let receive_tx_handler = WalletReceiver {
-		config: wallet_config.clone(),
-		keychain: keychain.clone(),
+	let limiter = Arc::new(RateLimiter::new(
+		wallet_config.receive_rate_per_addr,
+		wallet_config.receive_rate_global,
+	));
+	let limit_localhost = wallet_config.rate_limit_localhost;
+	let receive_tx_handler = RateLimited {
+		limiter: limiter.clone(),
+		limit_localhost: limit_localhost,
+		handler: Authenticated {
+			secret: secret.clone(),
+			handler: WalletReceiver {
+				config: wallet_config.clone(),
+				keychain: keychain.clone(),
+			},
+		},
+	};
+	let invoice_handler = RateLimited {
+		limiter: limiter.clone(),
+		limit_localhost: limit_localhost,
+		handler: Authenticated {
+			secret: secret.clone(),
+			handler: InvoiceReceiver {
+				config: wallet_config.clone(),
+				keychain: keychain.clone(),
+			},
+		},
 	};
-	let coinbase_handler = CoinbaseHandler {
-		config: wallet_config.clone(),
-		keychain: keychain.clone(),
+	let coinbase_handler = RateLimited {
+		// unless configured otherwise, under a limiter without any limit
+		limiter: if wallet_config.rate_limit_coinbase {
+			limiter.clone()
+		} else {
+			Arc::new(RateLimiter::new(0, 0))
+		},
+		limit_localhost: limit_localhost,
+		handler: Authenticated {
+			secret: if wallet_config.coinbase_requires_secret {
+				secret.clone()
+			} else {
+				None
+			},
+			handler: CoinbaseHandler {
+				config: wallet_config.clone(),
+				keychain: keychain.clone(),
+			},
+		},
 	};
 
 	let router = router!(
 		receive_tx: post "/receive/transaction" => receive_tx_handler,
+		receive_invoice: post "/receive/invoice" => invoice_handler,
 		receive_coinbase: post "/receive/coinbase" => coinbase_handler,
+		ping: get "/ping" => PingHandler,
 	);
 
+	let tls = receiver_tls(&wallet_config)?;
+	let scheme = if tls.is_some() { "https" } else { "http" };
 	let mut apis = ApiServer::new("/v1".to_string());
 	apis.register_handler(router);
-	apis.start(wallet_config.api_listen_addr()).unwrap_or_else(|e| {
-		error!(LOGGER, "Failed to start Grin wallet receiver: {}.", e);
-	});
+	let started = match tls {
+		Some(tls) => apis.start_tls(addr, tls),
+		None => apis.start(addr),
+	};
+	started.map_err(|e| Error::Listen(format!("{}: {}", addr, e)))?;
+	info!(
+		LOGGER,
+		"Grin wallet receiver listening at {}://{}",
+		scheme,
+		apis.local_addr().unwrap_or(addr)
+	);
+	Ok(apis)
+}
+
+/// TLS the receiver serves with, if the config has a certificate and key.
+fn receiver_tls(wallet_config: &WalletConfig) -> Result<Option<TlsServer>, Error> {
+	match (&wallet_config.tls_cert_path, &wallet_config.tls_key_path) {
+		(&Some(ref cert_path), &Some(ref key_path)) => {
+			TlsServer::from_pem_files(Path::new(cert_path), Path::new(key_path))
+				.map(Some)
+				.map_err(Error::Tls)
+		}
+		(&None, &None) => Ok(None),
+		_ => Err(Error::Tls(
+			"both a certificate and a private key are needed".to_string(),
+		)),
+	}
 }
@@ -0,0 +1,137 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Points in time the wallet records, such as when an output was received.
+//! They're written as RFC 3339 strings in UTC so the data file and exports
+//! stay readable, "2017-11-05T14:03:27Z" rather than a count of seconds.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{de, ser};
+use time;
+
+/// How timestamps are written, always in UTC
+const RFC3339_UTC: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// A point in time, to the second, as seconds since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+	/// The current time.
+	pub fn now() -> Timestamp {
+		let secs = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.as_secs())
+			.unwrap_or(0);
+		Timestamp(secs)
+	}
+
+	/// Parses a timestamp written by to_rfc3339, in UTC with no fraction of
+	/// a second.
+	pub fn from_rfc3339(s: &str) -> Result<Timestamp, String> {
+		let invalid = || {
+			format!(
+				"Invalid timestamp {:?}, expected one like 2017-11-05T14:03:27Z",
+				s
+			)
+		};
+		let tm = time::strptime(s, RFC3339_UTC).map_err(|_| invalid())?;
+		let secs = tm.to_timespec().sec;
+		if secs < 0 {
+			return Err(invalid());
+		}
+		Ok(Timestamp(secs as u64))
+	}
+
+	pub fn to_rfc3339(&self) -> String {
+		let tm = time::at_utc(time::Timespec::new(self.0 as i64, 0));
+		time::strftime(RFC3339_UTC, &tm).unwrap_or_default()
+	}
+}
+
+impl fmt::Display for Timestamp {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.to_rfc3339())
+	}
+}
+
+impl ser::Serialize for Timestamp {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: ser::Serializer,
+	{
+		serializer.serialize_str(&self.to_rfc3339())
+	}
+}
+
+impl<'de> de::Deserialize<'de> for Timestamp {
+	fn deserialize<D>(deserializer: D) -> Result<Timestamp, D::Error>
+	where
+		D: de::Deserializer<'de>,
+	{
+		deserializer.deserialize_str(TimestampVisitor)
+	}
+}
+
+struct TimestampVisitor;
+
+impl<'de> de::Visitor<'de> for TimestampVisitor {
+	type Value = Timestamp;
+
+	fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+		formatter.write_str("an RFC 3339 timestamp in UTC")
+	}
+
+	fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+	where
+		E: de::Error,
+	{
+		Timestamp::from_rfc3339(s).map_err(E::custom)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use serde_json;
+	use super::*;
+
+	#[test]
+	fn rfc3339_round_trip() {
+		assert_eq!(Timestamp(0).to_rfc3339(), "1970-01-01T00:00:00Z");
+		assert_eq!(Timestamp(1_509_890_607).to_rfc3339(), "2017-11-05T14:03:27Z");
+		for &secs in [0, 59, 951_782_400, 1_509_890_607, 4_102_444_800].iter() {
+			let timestamp = Timestamp(secs);
+			assert_eq!(Timestamp::from_rfc3339(&timestamp.to_rfc3339()), Ok(timestamp));
+		}
+
+		let json = serde_json::to_string(&Some(Timestamp(1_509_890_607))).unwrap();
+		assert_eq!(json, r#""2017-11-05T14:03:27Z""#);
+		let read: Option<Timestamp> = serde_json::from_str(&json).unwrap();
+		assert_eq!(read, Some(Timestamp(1_509_890_607)));
+		let read: Option<Timestamp> = serde_json::from_str("null").unwrap();
+		assert_eq!(read, None);
+	}
+
+	#[test]
+	fn invalid_timestamps() {
+		for s in vec!["", "1509890607", "2017-11-05", "2017-11-05 14:03:27", "2017-13-05T14:03:27Z",
+		              "2017-11-05T14:03:27+02:00", "1969-12-31T23:59:59Z"]
+		{
+			assert!(Timestamp::from_rfc3339(s).is_err(), "{:?}", s);
+		}
+		assert!(serde_json::from_str::<Timestamp>("1509890607").is_err());
+	}
+}
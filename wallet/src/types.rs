@@ -12,38 +12,130 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use base64;
 use blake2;
+use byteorder::{BigEndian, ByteOrder};
 use rand::{thread_rng, Rng};
-use std::{error, fmt, num};
+use std::{error, fmt, num, u32};
+use std::any::Any;
 use std::convert::From;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::path::MAIN_SEPARATOR;
-use std::collections::HashMap;
-use std::cmp::min;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::{max, min};
 
 use hyper;
 use serde_json;
 use util::secp;
+use util::secp::constants::PEDERSEN_COMMITMENT_SIZE;
+use util::secp::pedersen;
 use tokio_core::reactor;
 use tokio_retry::Retry;
 use tokio_retry::strategy::FibonacciBackoff;
 
 
+use amount::format_amount;
+use encryption::{data_cipher, is_encrypted, DataCipher};
 use api;
+use core::consensus::GRIN_BASE;
 use core::core::{transaction, Transaction};
+use core::global;
 use core::ser;
 use keychain;
+use timestamp::Timestamp;
 use util;
 use util::LOGGER;
 
 const DAT_FILE: &'static str = "wallet.dat";
+/// Version of the data files written, older ones are migrated when read
+const DATA_FILE_VERSION: u32 = 8;
+/// First version of the data files holding a checksum of their content,
+/// under CHECKSUM_FIELD
+const CHECKSUM_VERSION: u32 = 6;
+const CHECKSUM_FIELD: &'static str = "checksum";
+/// File of the data directory pruned outputs and log entries are moved to
+const ARCHIVE_FILE: &'static str = "wallet.archive.dat";
+/// File of the data directory counting the child indexes handed out
+const CHILD_COUNTER_FILE: &'static str = "wallet.child";
+/// Child indexes skipped past the highest one recover_next_child finds in
+/// use, for outputs of ours it can't see, like those spent on chain
+const CHILD_SAFETY_MARGIN: u32 = 20;
+/// Suffixes of the backup of the previous data file and of the temporary
+/// file the data is written to before replacing the data file
+const BAK_EXT: &'static str = ".bak";
+const TMP_EXT: &'static str = ".tmp";
 const LOCK_FILE: &'static str = "wallet.lock";
 const SEED_FILE: &'static str = "wallet.seed";
+const SEND_LOCK_FILE: &'static str = "wallet.send.lock";
+/// Directory of the data directory backups go to when none is provided
+const BACKUP_DIR: &'static str = "backups";
+
+/// Age in seconds after which a data file lock that can't be checked is
+/// considered stale, unless set otherwise
+const DEFAULT_DATA_LOCK_MAX_AGE_SECS: u64 = 300;
+
+// Max age of the data file lock set for the process, 0 until set
+static DATA_LOCK_MAX_AGE_SECS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Sets the age in seconds after which the data file lock of a process that
+/// can't be checked is considered stale, for the whole process (see
+/// WalletConfig::data_lock_max_age_secs).
+pub fn set_data_lock_max_age(secs: u64) {
+	DATA_LOCK_MAX_AGE_SECS.store(secs as usize, Ordering::Relaxed);
+}
+
+fn data_lock_max_age() -> u64 {
+	match DATA_LOCK_MAX_AGE_SECS.load(Ordering::Relaxed) {
+		0 => DEFAULT_DATA_LOCK_MAX_AGE_SECS,
+		secs => secs as u64,
+	}
+}
 
 const DEFAULT_BASE_FEE: u64 = 10;
 
+/// Maximum size in bytes of the message carried along a partial transaction
+pub const MAX_MESSAGE_LEN: usize = 256;
+
+/// Maximum size in bytes of the label of an output
+pub const MAX_LABEL_LEN: usize = 128;
+
+/// Outputs worth less than a thousandth of a grin are dust
+pub const DUST_VALUE: u64 = GRIN_BASE / 1_000;
+
+/// Upper bounds of the buckets WalletData::stats counts outputs in: dust,
+/// below 0.1 grin, then 1 and 10 grin, the last bucket holding the rest
+pub const VALUE_BUCKETS: [u64; 4] = [DUST_VALUE, GRIN_BASE / 10, GRIN_BASE, 10 * GRIN_BASE];
+
+/// Version of the partial transaction JSON built and understood by this
+/// wallet. Version 0 payloads, without any version marker, are still
+/// read for now.
+pub const PARTIAL_TX_VERSION: u16 = 1;
+
+/// Prefix of the compact, single line, encoding of a partial transaction
+pub const COMPACT_PARTIAL_TX_PREFIX: &'static str = "grintx1";
+
+/// Size of the checksum closing a compact partial transaction
+const COMPACT_CHECKSUM_LEN: usize = 4;
+
+/// User name of the basic authorization carrying the secret a receiver
+/// requires, only the password is checked
+pub const RECEIVER_AUTH_USER: &'static str = "grin";
+
+/// Current time in seconds since the unix epoch.
+fn now_secs() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or(0)
+}
+
 /// Transaction fee calculation
 pub fn tx_fee(input_len: usize, output_len: usize, base_fee: Option<u64>) -> u64 {
 	let use_base_fee = match base_fee {
@@ -61,8 +153,24 @@ pub fn tx_fee(input_len: usize, output_len: usize, base_fee: Option<u64>) -> u64
 /// Wallet errors, mostly wrappers around underlying crypto or I/O errors.
 #[derive(Debug)]
 pub enum Error {
-	NotEnoughFunds(u64),
+	/// Not enough spendable funds, reports how much is missing, how much
+	/// can be spent, how much is held back by the minimum confirmations
+	/// policy, how much is coinbase that hasn't matured yet and how much is
+	/// locked until a later height
+	NotEnoughFunds {
+		missing: u64,
+		spendable: u64,
+		unconfirmed: u64,
+		immature: u64,
+		height_locked: u64,
+	},
 	FeeDispute { sender_fee: u64, recipient_fee: u64 },
+	/// The requested fee is below the minimum for the transaction weight
+	FeeTooLow { fee: u64, minimum: u64 },
+	/// The requested fee would consume the whole amount being sent
+	FeeExceedsAmount { fee: u64, amount: u64 },
+	/// The requested lock height is already in the past
+	InvalidLockHeight { lock_height: u64, current_height: u64 },
 	Keychain(keychain::Error),
 	Transaction(transaction::Error),
 	Secp(secp::Error),
@@ -77,6 +185,148 @@ pub enum Error {
 	Hyper(hyper::Error),
 	/// Error originating from hyper uri parsing.
 	Uri(hyper::error::UriError),
+	/// The destination of a send can't be understood
+	InvalidDestination(String),
+	/// No send in the transaction log matches the provided id or change
+	/// identifier
+	TxNotFound(String),
+	/// The send can only be cancelled or resent while it's pending
+	TxNotPending { id: u32, status: TxLogStatus },
+	/// Some inputs of the send have already been spent on chain
+	TxInputsSpent { id: u32 },
+	/// The transaction handed back by the receiver doesn't match what we sent
+	MalformedResponse(String),
+	/// The receiver took the connection but didn't answer in time, it may
+	/// still have processed the transaction
+	NodeTimeout(String),
+	/// The message attached to a partial transaction is too long
+	MessageTooLong { len: usize, max: usize },
+	/// Another process holds a lock of the wallet, the send lock while in
+	/// the middle of a send or the data file one
+	WalletBusy {
+		pid: u32,
+		since: u64,
+		lock: &'static str,
+	},
+	/// The receiver requires a secret that's missing or doesn't match
+	Unauthorized(String),
+	/// The partial transaction is in a version the other side doesn't
+	/// understand
+	VersionMismatch { ours: u16, theirs: u16 },
+	/// An amount given as a decimal string can't be understood
+	InvalidAmount(String),
+	/// The amounts involved in a transaction add up past what fits in a u64
+	AmountOverflow,
+	/// No contact goes by that name
+	UnknownContact(String),
+	/// The destination of a send is this wallet's own receiver
+	SelfSend(String),
+	/// An output derived from another root key than the one of the keychain
+	/// a transaction is built with, which couldn't sign for it, or that an
+	/// output is exported with, which can't tell its commitment
+	ForeignInput {
+		key_id: keychain::Identifier,
+		fingerprint: keychain::Identifier,
+	},
+	/// A send would need more inputs than a transaction is allowed to have
+	TooManyInputs { inputs: usize, max: usize },
+	/// There aren't more spendable coins than the outputs a consolidation
+	/// would turn them into
+	NothingToConsolidate { coins: usize, target_outputs: usize },
+	/// The fee of a consolidation is more than the share of the value merged
+	/// the config allows
+	ConsolidationFeeTooHigh {
+		fee: u64,
+		value: u64,
+		max_percent: u64,
+	},
+	/// The amount of a send is zero or below the dust threshold
+	AmountTooSmall { amount: u64, minimum: u64 },
+	/// A compact partial transaction is shorter than it claims to be,
+	/// usually cut while being copied
+	CompactTxTruncated { len: usize, expected: usize },
+	/// The checksum of a compact partial transaction doesn't match its
+	/// content
+	CompactTxChecksum,
+	/// The fee or lock height a partial transaction states isn't the one its
+	/// transaction commits to
+	KernelMismatch {
+		field: &'static str,
+		stated: Option<u64>,
+		actual: u64,
+	},
+	/// The amount received is outside of the limits of the receiver
+	ReceiveAmountRefused {
+		amount: u64,
+		minimum: u64,
+		maximum: Option<u64>,
+	},
+	/// The invoice can't be paid anymore, the chain is past its expiry
+	InvoiceExpired { expiry_height: u64, current_height: u64 },
+	/// The transaction doesn't pay any of the invoices we issued
+	InvoiceNotFound(String),
+	/// The lock height of a received transaction is further past the chain
+	/// tip than the receiver accepts
+	LockHeightTooFar { lock_height: u64, max_height: u64 },
+	/// The amount a partial transaction states isn't what its inputs and
+	/// outputs add up to
+	AmountMismatch { stated: u64 },
+	/// An output with the same identifier is already tracked, its key was
+	/// given out twice
+	DuplicateOutput(keychain::Identifier),
+	/// An output with the same identifier but another value is already
+	/// tracked, only one of them can make the commitment of its key
+	ConflictingOutput {
+		key_id: keychain::Identifier,
+		tracked: u64,
+		value: u64,
+	},
+	/// Payments can't be received into an account the receiver doesn't
+	/// know of
+	UnknownAccount(String),
+	/// The receiver can't listen on the address of the config
+	Listen(String),
+	/// The certificate or key the receiver serves TLS with are missing or
+	/// can't be used
+	Tls(String),
+	/// The passphrase given isn't the one the wallet data file is encrypted
+	/// with
+	WrongPassphrase,
+	/// The wallet data file was written by a newer wallet, in a version
+	/// this one doesn't know how to read
+	DataFileVersion { ours: u32, theirs: u32 },
+	/// An output can't be archived while the send spending or creating it
+	/// isn't confirmed
+	OutputInUse {
+		key_id: keychain::Identifier,
+		tx_id: u32,
+	},
+	/// The backup to restore holds outputs of another wallet than ours
+	BackupMismatch {
+		ours: keychain::Identifier,
+		theirs: keychain::Identifier,
+	},
+	/// The receiver takes no more requests from us for now, it can be tried
+	/// again after the provided number of seconds
+	TooManyRequests { retry_after_secs: u64 },
+	/// No output with that identifier is tracked by the wallet
+	UnknownOutput(keychain::Identifier),
+	/// The status of an output can't go from one to the other, see
+	/// OutputStatus::can_transition_to
+	IllegalTransition {
+		key_id: keychain::Identifier,
+		from: OutputStatus,
+		to: OutputStatus,
+	},
+	/// A change to the wallet data panicked half way, the data it left half
+	/// changed was dropped and the data file left as it was, or a data file
+	/// doesn't hold what was written to it (see WalletData::read)
+	WalletCorrupted(String),
+	/// The label of an output is too long
+	LabelTooLong { len: usize, max: usize },
+	/// The commitment an output was exported with isn't the one its key and
+	/// value make, the export was tampered with
+	CommitmentMismatch(keychain::Identifier),
 }
 
 impl error::Error for Error {
@@ -85,12 +335,341 @@ impl error::Error for Error {
 			_ => "some kind of wallet error",
 		}
 	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::Keychain(ref e) => Some(e),
+			Error::IOError(ref e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl Error {
+	/// Stable identifier of the error, answered by the receiver along with
+	/// its description so the other side can tell what went wrong.
+	pub fn code(&self) -> &'static str {
+		match *self {
+			Error::NotEnoughFunds { .. } => "not_enough_funds",
+			Error::FeeDispute { .. } => "fee_too_low",
+			Error::FeeExceedsAmount { .. } => "fee_exceeds_amount",
+			Error::Keychain(_) | Error::Secp(_) => "keychain_error",
+			Error::Transaction(_) => "invalid_transaction",
+			Error::WalletData(_)
+			| Error::WalletBusy { .. }
+			| Error::DataFileVersion { .. }
+			| Error::WalletCorrupted(_) => "wallet_data_error",
+			Error::Format(_) | Error::CompactTxTruncated { .. } | Error::CompactTxChecksum => {
+				"malformed_payload"
+			}
+			Error::Node(_) | Error::NodeTimeout(_) => "node_error",
+			Error::MessageTooLong { .. } => "message_too_long",
+			Error::VersionMismatch { .. } => "version_mismatch",
+			Error::KernelMismatch { .. } => "kernel_mismatch",
+			Error::ReceiveAmountRefused { .. } => "amount_out_of_range",
+			Error::InvoiceExpired { .. } => "invoice_expired",
+			Error::InvoiceNotFound(_) => "invoice_not_found",
+			Error::LockHeightTooFar { .. } => "lock_height_too_far",
+			Error::AmountMismatch { .. } => "amount_mismatch",
+			Error::DuplicateOutput(_) | Error::ConflictingOutput { .. } => "duplicate_output",
+			Error::UnknownAccount(_) => "unknown_account",
+			Error::Unauthorized(_) => "unauthorized",
+			Error::TooManyRequests { .. } => "too_many_requests",
+			_ => "internal_error",
+		}
+	}
+
+	/// Whether the node or receiver couldn't be reached, failed on its side
+	/// or was too busy to answer, rather than refusing what it was sent, so
+	/// trying again later may well work.
+	pub fn is_server_failure(&self) -> bool {
+		match *self {
+			Error::Node(api::Error::Internal(_)) => true,
+			Error::Node(api::Error::Remote { status, .. }) => status >= 500 || status == 429,
+			_ => false,
+		}
+	}
+
+	/// Whether the node or receiver couldn't be reached at all, nothing it
+	/// answered, so the very same request may be sent again.
+	pub fn is_connection_failure(&self) -> bool {
+		match *self {
+			Error::Node(api::Error::Internal(_)) => true,
+			_ => false,
+		}
+	}
 }
 
 impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
-			_ => write!(f, "some kind of wallet error"),
+			Error::NotEnoughFunds {
+				missing,
+				spendable,
+				unconfirmed,
+				immature,
+				height_locked,
+			} => write!(
+				f,
+				"Not enough funds, {} grin spendable but {} more needed ({} awaiting \
+				 confirmation, {} immature coinbase, {} locked until a later height)",
+				format_amount(spendable),
+				format_amount(missing),
+				format_amount(unconfirmed),
+				format_amount(immature),
+				format_amount(height_locked)
+			),
+			Error::FeeDispute {
+				sender_fee,
+				recipient_fee,
+			} => write!(
+				f,
+				"Fee dispute, sender fee {} but recipient fee {}",
+				sender_fee,
+				recipient_fee
+			),
+			Error::FeeTooLow { fee, minimum } => {
+				write!(f, "Fee {} is below the minimum fee {}", fee, minimum)
+			}
+			Error::FeeExceedsAmount { fee, amount } => {
+				write!(f, "Fee {} exceeds the amount {} being sent", fee, amount)
+			}
+			Error::InvalidLockHeight {
+				lock_height,
+				current_height,
+			} => write!(
+				f,
+				"Lock height {} is below the current height {}",
+				lock_height,
+				current_height
+			),
+			Error::Keychain(ref e) => write!(f, "Keychain error: {}", e),
+			Error::Transaction(ref e) => write!(f, "Transaction error: {:?}", e),
+			Error::Secp(ref e) => write!(f, "Secp error: {:?}", e),
+			Error::WalletData(ref s) => write!(f, "Wallet data error: {}", s),
+			Error::Format(ref s) => write!(f, "Format error: {}", s),
+			Error::IOError(ref e) => write!(f, "IO error: {}", e),
+			Error::Node(ref e) => write!(f, "Node API error: {}", e),
+			Error::Hyper(ref e) => write!(f, "Hyper error: {}", e),
+			Error::Uri(ref e) => write!(f, "Invalid URI: {}", e),
+			Error::InvalidDestination(ref s) => write!(f, "Invalid destination: {}", s),
+			Error::TxNotFound(ref s) => write!(f, "No send found for {}", s),
+			Error::TxNotPending { id, ref status } => {
+				write!(f, "Send {} is no longer pending, it is {}", id, status)
+			}
+			Error::TxInputsSpent { id } => write!(
+				f,
+				"Send {} can't be cancelled, its inputs are already spent on chain",
+				id
+			),
+			Error::MalformedResponse(ref s) => write!(f, "Malformed receiver response: {}", s),
+			Error::NodeTimeout(ref s) => write!(f, "Timed out waiting for the receiver: {}", s),
+			Error::MessageTooLong { len, max } => {
+				write!(f, "Message of {} bytes exceeds the maximum of {}", len, max)
+			}
+			Error::WalletBusy { pid, since, lock } => write!(
+				f,
+				"Wallet busy, process {} has held its {} lock since {} ({}s ago)",
+				pid,
+				lock,
+				since,
+				now_secs().saturating_sub(since)
+			),
+			Error::Unauthorized(ref s) => {
+				write!(f, "Receiver refused the send, missing or wrong secret: {}", s)
+			}
+			Error::WrongPassphrase => write!(f, "Wrong passphrase for the wallet data file"),
+			Error::DataFileVersion { ours, theirs } => write!(
+				f,
+				"Wallet data file is in version {}, newer than the {} this wallet reads",
+				theirs,
+				ours
+			),
+			Error::BackupMismatch { ref ours, ref theirs } => write!(
+				f,
+				"Backup is of another wallet, its outputs are derived from {} and ours from {}",
+				theirs,
+				ours
+			),
+			Error::TooManyRequests { retry_after_secs } => write!(
+				f,
+				"Receiver is taking too many requests, try again in {}s",
+				retry_after_secs
+			),
+			Error::VersionMismatch { ours, theirs } => write!(
+				f,
+				"Partial transaction version mismatch, ours is {} and theirs is {}",
+				ours,
+				theirs
+			),
+			Error::CompactTxTruncated { len, expected } => write!(
+				f,
+				"Compact partial transaction truncated, {} bytes out of {}",
+				len,
+				expected
+			),
+			Error::InvalidAmount(ref s) => write!(f, "Invalid amount: {}", s),
+			Error::AmountOverflow => write!(f, "Amounts overflow, their total is too large"),
+			Error::UnknownContact(ref s) => write!(f, "Unknown contact: {}", s),
+			Error::ForeignInput {
+				ref key_id,
+				ref fingerprint,
+			} => write!(
+				f,
+				"Output {} is derived from root key {}, not from the keychain of the wallet",
+				key_id,
+				fingerprint
+			),
+			Error::TooManyInputs { inputs, max } => write!(
+				f,
+				"Sending needs {} inputs, more than the {} a transaction can have, \
+				 consolidate the wallet first or enable auto_split to send without a \
+				 minimum of confirmations",
+				inputs,
+				max
+			),
+			Error::NothingToConsolidate {
+				coins,
+				target_outputs,
+			} => write!(
+				f,
+				"Nothing to consolidate, {} spendable coins for {} outputs",
+				coins,
+				target_outputs
+			),
+			Error::ConsolidationFeeTooHigh {
+				fee,
+				value,
+				max_percent,
+			} => write!(
+				f,
+				"Consolidation refused, its fee of {} grin is more than {}% of the {} grin \
+				 merged",
+				format_amount(fee),
+				max_percent,
+				format_amount(value)
+			),
+			Error::AmountTooSmall { amount, minimum } => write!(
+				f,
+				"Amount {} is too small, sends must be of at least {}",
+				amount,
+				minimum
+			),
+			Error::SelfSend(ref s) => write!(
+				f,
+				"{} is this wallet's own receiver, send with dest \"self\" instead",
+				s
+			),
+			Error::CompactTxChecksum => write!(
+				f,
+				"Compact partial transaction checksum mismatch, it was altered when copied"
+			),
+			Error::KernelMismatch {
+				field,
+				stated: Some(stated),
+				actual,
+			} => write!(
+				f,
+				"Partial transaction states a {} of {} but its transaction has {}",
+				field,
+				stated,
+				actual
+			),
+			Error::KernelMismatch {
+				field,
+				stated: None,
+				actual,
+			} => write!(
+				f,
+				"Partial transaction doesn't state its {}, its transaction has {}",
+				field,
+				actual
+			),
+			Error::InvoiceExpired {
+				expiry_height,
+				current_height,
+			} => write!(
+				f,
+				"Invoice expired at height {}, the chain is at {}",
+				expiry_height,
+				current_height
+			),
+			Error::InvoiceNotFound(ref s) => write!(f, "No invoice found: {}", s),
+			Error::ReceiveAmountRefused {
+				amount,
+				minimum,
+				maximum: Some(maximum),
+			} => write!(
+				f,
+				"Amount {} refused, only amounts from {} to {} are received",
+				amount,
+				minimum,
+				maximum
+			),
+			Error::ReceiveAmountRefused {
+				amount,
+				minimum,
+				maximum: None,
+			} => write!(
+				f,
+				"Amount {} refused, only amounts of at least {} are received",
+				amount,
+				minimum
+			),
+			Error::LockHeightTooFar {
+				lock_height,
+				max_height,
+			} => write!(
+				f,
+				"Lock height {} refused, only transactions locked up to height {} are received",
+				lock_height,
+				max_height
+			),
+			Error::AmountMismatch { stated } => write!(
+				f,
+				"Amount {} doesn't match the inputs and outputs of the transaction",
+				stated
+			),
+			Error::DuplicateOutput(ref key_id) => {
+				write!(f, "Output {} is already tracked by the wallet", key_id)
+			}
+			Error::ConflictingOutput {
+				ref key_id,
+				tracked,
+				value,
+			} => write!(
+				f,
+				"Output {} is already tracked by the wallet with value {} rather than {}",
+				key_id,
+				tracked,
+				value
+			),
+			Error::UnknownAccount(ref account) => write!(f, "Unknown account {}", account),
+			Error::OutputInUse { ref key_id, tx_id } => write!(
+				f,
+				"Output {} can't be archived, send {} isn't confirmed yet",
+				key_id,
+				tx_id
+			),
+			Error::Listen(ref s) => write!(f, "Wallet receiver can't listen: {}", s),
+			Error::Tls(ref s) => write!(f, "Wallet receiver TLS misconfigured: {}", s),
+			Error::UnknownOutput(ref key_id) => {
+				write!(f, "Output {} isn't tracked by the wallet", key_id)
+			}
+			Error::IllegalTransition {
+				ref key_id,
+				ref from,
+				ref to,
+			} => write!(f, "Output {} can't go from {} to {}", key_id, from, to),
+			Error::WalletCorrupted(ref s) => write!(f, "{}", s),
+			Error::LabelTooLong { len, max } => {
+				write!(f, "Label of {} bytes exceeds the maximum of {}", len, max)
+			}
+			Error::CommitmentMismatch(ref key_id) => write!(
+				f,
+				"Output {} was exported with another commitment than its key and value make",
+				key_id
+			),
 		}
 	}
 }
@@ -163,6 +742,178 @@ pub struct WalletConfig {
 	pub check_node_api_http_addr: String,
 	// The directory in which wallet files are stored
 	pub data_file_dir: String,
+	// Whether the data files are kept in data_file_dir itself, as wallets
+	// did before, rather than in a subdirectory per seed (see for_keychain)
+	#[serde(default)]
+	pub flat_data_dir: bool,
+	// Minimum number of confirmations before an output can be spent
+	#[serde(default = "default_minimum_confirmations")]
+	pub minimum_confirmations: u64,
+	// Number of outputs the change of a send is split across
+	#[serde(default = "default_change_outputs")]
+	pub change_outputs: usize,
+	// Most inputs a transaction built by the wallet can spend
+	#[serde(default = "default_max_inputs_per_tx")]
+	pub max_inputs_per_tx: usize,
+	// Whether a send needing more inputs than that first merges coins in
+	// transactions to self, rather than failing. Only for sends without a
+	// minimum of confirmations, the send spending what the merges produce
+	#[serde(default)]
+	pub auto_split: bool,
+	// Largest fee a consolidation may pay, in percent of the value of the
+	// coins it merges
+	#[serde(default = "default_max_consolidation_fee_percent")]
+	pub max_consolidation_fee_percent: u64,
+	// Change below this value (in nanogrin) is added to the fee rather than
+	// getting its own output, 0 to always keep the change
+	#[serde(default)]
+	pub dust_threshold: u64,
+	// How many times posting a partial transaction to a receiver is attempted
+	// before giving up
+	#[serde(default = "default_send_attempts")]
+	pub send_attempts: u32,
+	// Delay before the first retry of a failed post, doubled on each attempt
+	#[serde(default = "default_send_retry_delay_ms")]
+	pub send_retry_delay_ms: u64,
+	// How long to wait for the receiver to answer a post, in seconds
+	#[serde(default = "default_send_timeout_secs")]
+	pub send_timeout_secs: u64,
+	// Number of blocks after which an output locked by a send that never
+	// made it on chain is unlocked again, 0 to keep it locked
+	#[serde(default = "default_lock_expiry_blocks")]
+	pub lock_expiry_blocks: u64,
+	// Age in seconds after which the send lock of another process is
+	// considered stale
+	#[serde(default = "default_send_lock_timeout_secs")]
+	pub send_lock_timeout_secs: u64,
+	// Age in seconds after which the data file lock of a process that can't
+	// be checked, running on another host, is considered stale. Set for the
+	// whole process with set_data_lock_max_age
+	#[serde(default = "default_data_lock_max_age_secs")]
+	pub data_lock_max_age_secs: u64,
+	// SHA-256 fingerprint, hex encoded, of the self-signed certificate of a
+	// receiver on a local network, trusted when sending to it over https.
+	// Any other certificate must be vouched for by the usual web roots
+	#[serde(default)]
+	pub tls_receiver_fingerprint: Option<String>,
+	// Whether the node is asked to broadcast our transactions right away
+	// (fluff) rather than relaying them along a stem first, which is more
+	// private but slower
+	#[serde(default)]
+	pub fluff: bool,
+	// Whether a send may go to this wallet's own receiver, only useful to
+	// test loopback flows as both sides of the send end up in one wallet
+	#[serde(default)]
+	pub allow_self_send: bool,
+	// Receivers we send to often, by name, so a send can go to "@name"
+	#[serde(default)]
+	pub contacts: HashMap<String, String>,
+	// Smallest amount (in nanogrin, net of the fee) our receiver accepts or
+	// issues invoices for, so dust sent to it can't bloat the wallet with
+	// worthless outputs, 0 to accept anything
+	#[serde(default = "default_min_receive_amount")]
+	pub min_receive_amount: u64,
+	// Largest amount our receiver accepts, none for no limit
+	#[serde(default)]
+	pub max_receive_amount: Option<u64>,
+	// Url notified of every payment our receiver accepts
+	#[serde(default)]
+	pub receive_webhook_url: Option<String>,
+	// Secret the notifications are signed with, so the webhook can tell
+	// they come from us
+	#[serde(default)]
+	pub receive_webhook_secret: Option<String>,
+	// How many blocks past the chain tip the lock height of a transaction our
+	// receiver signs can be, so the output it gets isn't stuck for ages
+	#[serde(default = "default_receive_lock_horizon")]
+	pub receive_lock_horizon: u64,
+	// Accounts our receiver takes payments into besides the default one, so
+	// funds received for different purposes are kept apart
+	#[serde(default)]
+	pub receive_accounts: Vec<String>,
+	// PEM files of the certificate chain and private key our receiver serves
+	// TLS with, plaintext connections being refused then. Both or neither
+	#[serde(default)]
+	pub tls_cert_path: Option<String>,
+	#[serde(default)]
+	pub tls_key_path: Option<String>,
+	// Secret senders have to give, as the password of their basic
+	// authorization, for our receiver to take their partial transactions
+	#[serde(default)]
+	pub receive_secret: Option<String>,
+	// Whether the coinbase endpoint requires the secret too, miners usually
+	// reach it from localhost
+	#[serde(default)]
+	pub coinbase_requires_secret: bool,
+	// Requests per minute our receiver takes from a single address, and from
+	// all of them together, before answering too_many_requests. 0 for no
+	// limit
+	#[serde(default = "default_receive_rate_per_addr")]
+	pub receive_rate_per_addr: u32,
+	#[serde(default = "default_receive_rate_global")]
+	pub receive_rate_global: u32,
+	// Whether requests from localhost are limited too, and the coinbase
+	// endpoint, which the miner calls for every block
+	#[serde(default)]
+	pub rate_limit_localhost: bool,
+	#[serde(default)]
+	pub rate_limit_coinbase: bool,
+}
+
+fn default_minimum_confirmations() -> u64 {
+	1
+}
+
+fn default_change_outputs() -> usize {
+	1
+}
+
+fn default_max_inputs_per_tx() -> usize {
+	500
+}
+
+fn default_send_attempts() -> u32 {
+	3
+}
+
+fn default_send_retry_delay_ms() -> u64 {
+	500
+}
+
+fn default_send_timeout_secs() -> u64 {
+	60
+}
+
+fn default_lock_expiry_blocks() -> u64 {
+	1440
+}
+
+fn default_send_lock_timeout_secs() -> u64 {
+	600
+}
+
+fn default_min_receive_amount() -> u64 {
+	1_000
+}
+
+fn default_max_consolidation_fee_percent() -> u64 {
+	5
+}
+
+fn default_receive_rate_per_addr() -> u32 {
+	30
+}
+
+fn default_receive_rate_global() -> u32 {
+	300
+}
+
+fn default_data_lock_max_age_secs() -> u64 {
+	DEFAULT_DATA_LOCK_MAX_AGE_SECS
+}
+
+fn default_receive_lock_horizon() -> u64 {
+	1440
 }
 
 impl Default for WalletConfig {
@@ -173,6 +924,37 @@ impl Default for WalletConfig {
 			api_listen_port: "13415".to_string(),
 			check_node_api_http_addr: "http://127.0.0.1:13413".to_string(),
 			data_file_dir: ".".to_string(),
+			flat_data_dir: false,
+			minimum_confirmations: default_minimum_confirmations(),
+			change_outputs: default_change_outputs(),
+			max_inputs_per_tx: default_max_inputs_per_tx(),
+			auto_split: false,
+			max_consolidation_fee_percent: default_max_consolidation_fee_percent(),
+			dust_threshold: 0,
+			send_attempts: default_send_attempts(),
+			send_retry_delay_ms: default_send_retry_delay_ms(),
+			send_timeout_secs: default_send_timeout_secs(),
+			lock_expiry_blocks: default_lock_expiry_blocks(),
+			send_lock_timeout_secs: default_send_lock_timeout_secs(),
+			data_lock_max_age_secs: default_data_lock_max_age_secs(),
+			tls_receiver_fingerprint: None,
+			fluff: false,
+			allow_self_send: false,
+			contacts: HashMap::new(),
+			min_receive_amount: default_min_receive_amount(),
+			max_receive_amount: None,
+			receive_webhook_url: None,
+			receive_webhook_secret: None,
+			receive_lock_horizon: default_receive_lock_horizon(),
+			receive_accounts: vec![],
+			tls_cert_path: None,
+			tls_key_path: None,
+			receive_secret: None,
+			coinbase_requires_secret: false,
+			receive_rate_per_addr: default_receive_rate_per_addr(),
+			receive_rate_global: default_receive_rate_global(),
+			rate_limit_localhost: false,
+			rate_limit_coinbase: false,
 		}
 	}
 }
@@ -181,23 +963,468 @@ impl WalletConfig {
 	pub fn api_listen_addr(&self) -> String {
 		format!("{}:{}", self.api_listen_interface, self.api_listen_port)
 	}
+
+	/// The configuration the wallet of the keychain runs with, its data
+	/// files and their locks kept in the subdirectory of data_file_dir named
+	/// after the fingerprint of the keychain, so wallets of several seeds
+	/// can share a data directory. The data files of a wallet that kept them
+	/// in data_file_dir itself are moved there on first use, if all the
+	/// outputs of its data file are the keychain's. With flat_data_dir set
+	/// nothing changes, the configuration returned has it set so it's only
+	/// resolved once. The sender, receiver, checker and restore resolve the
+	/// configuration they're given, the functions that aren't given a
+	/// keychain (the logs, exports and backups) use data_file_dir as is.
+	pub fn for_keychain(&self, keychain: &keychain::Keychain) -> Result<WalletConfig, Error> {
+		let mut config = self.clone();
+		if self.flat_data_dir {
+			return Ok(config);
+		}
+		let fingerprint = keychain.root_key_id();
+		config.data_file_dir =
+			format!("{}{}{}", self.data_file_dir, MAIN_SEPARATOR, fingerprint.to_hex());
+		config.flat_data_dir = true;
+		move_flat_data_files(&self.data_file_dir, &config.data_file_dir, &fingerprint)?;
+		Ok(config)
+	}
+
+	/// Address the receiver listens on, its interface has to be an IP
+	/// address ("0.0.0.0" for all of them) and its port a number, 0 for the
+	/// system to pick one.
+	pub fn listen_socket_addr(&self) -> Result<SocketAddr, Error> {
+		let ip = self.api_listen_interface.parse::<IpAddr>().map_err(|_| {
+			Error::Listen(format!("{:?} isn't an IP address", self.api_listen_interface))
+		})?;
+		let port = self.api_listen_port.parse::<u16>().map_err(|_| {
+			Error::Listen(format!("{:?} isn't a port number", self.api_listen_port))
+		})?;
+		Ok(SocketAddr::new(ip, port))
+	}
+
+	/// Adds a contact, or replaces the destination of an existing one. The
+	/// destination has to be one a send can go to.
+	pub fn add_contact(&mut self, name: &str, dest: &str) -> Result<(), Error> {
+		if name.is_empty() || name.contains(|c: char| c == '@' || c == '#' || c.is_whitespace()) {
+			return Err(Error::InvalidDestination(format!("@{}", name)));
+		}
+		Destination::parse(dest)?;
+		self.contacts.insert(name.to_string(), dest.to_string());
+		Ok(())
+	}
+
+	/// Checks payments can be received into the account, none being the
+	/// default one.
+	pub fn check_account(&self, account: Option<&str>) -> Result<(), Error> {
+		match account {
+			Some(name) if !self.receive_accounts.iter().any(|a| a == name) => {
+				Err(Error::UnknownAccount(name.to_string()))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Removes a contact, returning its destination if it was known.
+	pub fn remove_contact(&mut self, name: &str) -> Option<String> {
+		self.contacts.remove(name)
+	}
+
+	/// All contacts along with their destination, sorted by name.
+	pub fn list_contacts(&self) -> Vec<(String, String)> {
+		let mut contacts = self.contacts
+			.iter()
+			.map(|(name, dest)| (name.clone(), dest.clone()))
+			.collect::<Vec<_>>();
+		contacts.sort();
+		contacts
+	}
+
+	/// Replaces a "@name" destination with the one recorded for the contact,
+	/// keeping any secret following it after a '#'. Other destinations are
+	/// returned as is.
+	pub fn resolve_dest(&self, dest: &str) -> Result<String, Error> {
+		if !dest.starts_with('@') {
+			return Ok(dest.to_string());
+		}
+		let (name, secret) = match dest.find('#') {
+			Some(i) => (&dest[1..i], &dest[i..]),
+			None => (&dest[1..], ""),
+		};
+		match self.contacts.get(name) {
+			Some(contact_dest) => Ok(format!("{}{}", contact_dest, secret)),
+			None => Err(Error::UnknownContact(name.to_string())),
+		}
+	}
+
+	/// Whether a receiver URL points back at the address this wallet
+	/// listens on, under any name: the listen address itself, a loopback
+	/// address or a host name resolving to either. Names that don't resolve
+	/// can't be us.
+	pub fn is_own_receiver(&self, uri: &hyper::Uri) -> bool {
+		let listen_port = match self.api_listen_port.parse::<u16>() {
+			Ok(port) => port,
+			Err(_) => return false,
+		};
+		let port = match (uri.port(), uri.scheme()) {
+			(Some(port), _) => port,
+			(None, Some("https")) => 443,
+			(None, _) => 80,
+		};
+		if port != listen_port {
+			return false;
+		}
+		let host = match uri.host() {
+			Some(host) => host.trim_left_matches('[').trim_right_matches(']'),
+			None => return false,
+		};
+		let listen_ip = self.api_listen_interface.parse::<IpAddr>().ok();
+		let addrs = match (host, port).to_socket_addrs() {
+			Ok(addrs) => addrs,
+			Err(_) => return false,
+		};
+		for addr in addrs {
+			let ip = addr.ip();
+			let is_own = match listen_ip {
+				// listening on all interfaces, of which we only know loopback
+				Some(listen_ip) if listen_ip.is_unspecified() => {
+					ip.is_loopback() || ip.is_unspecified()
+				}
+				Some(listen_ip) => {
+					ip == listen_ip || (ip.is_loopback() && listen_ip.is_loopback())
+				}
+				None => ip.is_loopback(),
+			};
+			if is_own {
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Refuses a destination that is this wallet's own receiver, unless
+	/// allow_self_send is set. Posting there would have the receiver wait on
+	/// the wallet lock the send holds.
+	pub fn check_not_self(&self, dest: &Destination) -> Result<(), Error> {
+		match *dest {
+			Destination::Http(ref uri, _) if !self.allow_self_send && self.is_own_receiver(uri) => {
+				Err(Error::SelfSend(format!("{}", uri)))
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Where a partial transaction built by the sender should go.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+	/// Print the partial transaction on the command line
+	Stdout,
+	/// Print the compact encoding of the partial transaction on the
+	/// command line
+	CompactStdout,
+	/// Post the partial transaction to a wallet receiver, with the secret it
+	/// requires if any
+	Http(hyper::Uri, Option<String>),
+	/// Write the partial transaction to a file
+	File(PathBuf),
+	/// Send to a fresh key of our own, finalizing the transaction locally
+	/// and pushing it to the node
+	SelfSpend,
+}
+
+impl Destination {
+	/// Parses a destination as given on the command line: "stdout" (or
+	/// "stdout:compact" for the compact encoding), "self", an http(s) URL,
+	/// "file:" followed by a path, or a plain path. A bare host and port is
+	/// taken as a receiver to reach over https. The secret a receiver
+	/// requires can follow its URL after a '#'.
+	pub fn parse(dest: &str) -> Result<Destination, Error> {
+		if dest == "stdout" {
+			return Ok(Destination::Stdout);
+		}
+		if dest == "self" {
+			return Ok(Destination::SelfSpend);
+		}
+		if dest == "stdout:compact" {
+			return Ok(Destination::CompactStdout);
+		}
+		if dest.starts_with("file:") {
+			let path = dest.trim_left_matches("file:");
+			if path.is_empty() {
+				return Err(Error::InvalidDestination(dest.to_string()));
+			}
+			return Ok(Destination::File(PathBuf::from(path)));
+		}
+		if dest.contains("://") {
+			let (url, secret) = match dest.find('#') {
+				Some(i) if i + 1 < dest.len() => (&dest[..i], Some(dest[i + 1..].to_string())),
+				Some(_) => return Err(Error::InvalidDestination(dest.to_string())),
+				None => (dest, None),
+			};
+			let uri: hyper::Uri = url.parse()
+				.map_err(|_| Error::InvalidDestination(dest.to_string()))?;
+			return match (uri.scheme(), uri.authority()) {
+				(Some("http"), Some(_)) | (Some("https"), Some(_)) => {
+					Ok(Destination::Http(uri, secret))
+				}
+				_ => Err(Error::InvalidDestination(dest.to_string())),
+			};
+		}
+		if dest.contains('/') || dest.contains(MAIN_SEPARATOR) {
+			return Ok(Destination::File(PathBuf::from(dest)));
+		}
+		let host_port = dest.split('#').next().unwrap_or("");
+		if let Some(i) = host_port.rfind(':') {
+			if i > 0 && host_port[i + 1..].parse::<u16>().is_ok() {
+				return Destination::parse(&format!("https://{}", dest))
+					.map_err(|_| Error::InvalidDestination(dest.to_string()));
+			}
+		}
+		Err(Error::InvalidDestination(dest.to_string()))
+	}
+}
+
+/// Policy used to pick the coins spent by a new transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+	/// Spend coins adding up exactly to the amount if some do, so there's no
+	/// change, else the smallest single coin covering the amount if there is
+	/// one, largest coins first otherwise
+	Default,
+	/// Spend the smallest coins first, sweeping dust
+	SmallestFirst,
+	/// Spend the largest coins first, minimizing the number of inputs
+	LargestFirst,
+	/// Spend all eligible coins, consolidating them into the change output
+	All,
+}
+
+impl Default for SelectionStrategy {
+	fn default() -> SelectionStrategy {
+		SelectionStrategy::Default
+	}
+}
+
+/// Coins picked by WalletData::select, in the order they were picked, with
+/// what they add up to.
+#[derive(Debug, Clone)]
+pub struct Selection {
+	pub coins: Vec<OutputData>,
+	/// Total value of the coins, u64::MAX if that doesn't fit
+	pub total: u64,
+}
+
+impl Selection {
+	fn new(coins: Vec<OutputData>) -> Selection {
+		let total = coins
+			.iter()
+			.fold(0u64, |total, out| total.saturating_add(out.value));
+		Selection {
+			coins: coins,
+			total: total,
+		}
+	}
+}
+
+/// Why WalletData::select couldn't cover an amount.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionError {
+	/// The eligible coins add up to less than the amount needed
+	Insufficient { available: u64, needed: u64 },
+}
+
+impl fmt::Display for SelectionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			SelectionError::Insufficient { available, needed } => write!(
+				f,
+				"Not enough funds, {} grin spendable but {} needed",
+				format_amount(available),
+				format_amount(needed)
+			),
+		}
+	}
+}
+
+/// Who pays the fee of a send.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePaidBy {
+	/// The fee is added on top of the amount, the recipient receives the
+	/// amount in full
+	Sender,
+	/// The fee comes out of the amount, the recipient receives the amount
+	/// minus the fee
+	Recipient,
+}
+
+impl Default for FeePaidBy {
+	fn default() -> FeePaidBy {
+		FeePaidBy::Recipient
+	}
+}
+
+/// Amount to send, either an exact value or everything that's spendable.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendAmount {
+	/// Send exactly this amount
+	Exact(u64),
+	/// Sweep all spendable coins, without creating any change
+	Max,
+}
+
+/// What a send would look like: the coins it would spend, the fee and the
+/// change, computed without touching the wallet data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SendEstimate {
+	/// Amount sent to the recipient (the fee comes out of it)
+	pub amount: u64,
+	/// Identifiers of the outputs that would be spent
+	pub inputs: Vec<keychain::Identifier>,
+	/// Total value of the outputs that would be spent
+	pub total_input: u64,
+	/// Fee of the transaction
+	pub fee: u64,
+	/// Total value of the change
+	pub change: u64,
+	/// Number of outputs the change is split across
+	pub change_outputs: usize,
+	/// Lock height of the transaction
+	pub lock_height: u64,
+	/// How many of the outputs spent aren't confirmed yet, the transaction
+	/// is only valid once theirs makes it to the chain
+	#[serde(default)]
+	pub unconfirmed_inputs: usize,
+}
+
+/// The cost of sending an amount, as a frontend would show it before the
+/// send is committed to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeeEstimate {
+	/// Fee of the transaction
+	pub fee: u64,
+	/// Number of outputs the transaction would spend
+	pub inputs: usize,
+	/// Total value of the change
+	pub change: u64,
+	/// Whether outputs that aren't confirmed yet would have to be spent
+	pub needs_unconfirmed: bool,
+}
+
+impl<'a> From<&'a SendEstimate> for FeeEstimate {
+	fn from(estimate: &'a SendEstimate) -> FeeEstimate {
+		FeeEstimate {
+			fee: estimate.fee,
+			inputs: estimate.inputs.len(),
+			change: estimate.change,
+			needs_unconfirmed: estimate.unconfirmed_inputs > 0,
+		}
+	}
+}
+
+/// What a send built by the wallet did to the wallet data: the estimate it
+/// was built from (including the locked inputs) and the change outputs
+/// recorded for it, if any.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SendSummary {
+	/// Estimate the transaction was built from
+	pub estimate: SendEstimate,
+	/// Identifiers of the change outputs
+	pub change_key_ids: Vec<keychain::Identifier>,
+	/// Wallet transaction grouping the coins spent and the change
+	pub tx_id: u32,
+}
+
+/// A send that was built, with its coins locked and change recorded, but not
+/// delivered to its recipient yet. Serializable so it can be kept around
+/// between two invocations of the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingSend {
+	/// Partial transaction to deliver to the recipient
+	pub partial_tx: JSONPartialTx,
+	/// What building the send did to the wallet data
+	pub summary: SendSummary,
+	/// Id of the send in the transaction log
+	pub tx_log_id: u32,
+}
+
+/// Outcome of a send delivered to its destination.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SendResult {
+	/// Id of the send in the transaction log
+	pub tx_id: u32,
+	/// Amount the recipient builds its output from, before the fee is
+	/// deducted
+	pub amount: u64,
+	/// Fee of the transaction
+	pub fee: u64,
+	/// Identifiers of the change outputs
+	pub change_identifiers: Vec<keychain::Identifier>,
+	/// Identifiers of the outputs spent
+	pub input_identifiers: Vec<keychain::Identifier>,
+	/// Where the partial transaction went, without the receiver's secret
+	pub dest: String,
+	/// The transaction completed by the receiver, hex encoded, when posted
+	/// to one
+	pub tx_hex: Option<String>,
+	/// Ids in the transaction log of the transactions to self that merged
+	/// coins before the send, when it needed too many inputs
+	pub consolidation_tx_ids: Vec<u32>,
+	/// How many of the outputs spent weren't confirmed yet when the send was
+	/// built, not known when resending
+	pub unconfirmed_inputs: usize,
 }
 
 /// Status of an output that's being tracked by the wallet. Can either be
-/// unconfirmed, spent, unspent, or locked (when it's been used to generate
-/// a transaction but we don't have confirmation that the transaction was
-/// broadcasted or mined).
+/// pending (the change of a send we built that hasn't been seen on chain
+/// yet, and may never be), unconfirmed, spent, unspent, or locked (when it's
+/// been used to generate a transaction but we don't have confirmation that
+/// the transaction was broadcasted or mined).
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum OutputStatus {
+	Pending,
 	Unconfirmed,
 	Unspent,
 	Locked,
 	Spent,
 }
 
+impl OutputStatus {
+	/// Whether an output can go from this status to the other in the normal
+	/// course of things. Outputs only move forward: Pending and Unconfirmed
+	/// ones get confirmed as Unspent, Unconfirmed or Unspent ones get Locked
+	/// by a send, and Unspent or Locked ones end up Spent. A Locked one goes
+	/// back to Unspent when its send is cancelled. Staying in the same status
+	/// is always allowed. Anything else, like a Spent output unspent again,
+	/// takes a reorganization of the chain, see WalletData::force_status.
+	pub fn can_transition_to(&self, to: &OutputStatus) -> bool {
+		match (self, to) {
+			(from, to) if from == to => true,
+			(&OutputStatus::Pending, &OutputStatus::Unspent)
+			| (&OutputStatus::Unconfirmed, &OutputStatus::Unspent)
+			| (&OutputStatus::Unconfirmed, &OutputStatus::Locked)
+			| (&OutputStatus::Unspent, &OutputStatus::Locked)
+			| (&OutputStatus::Unspent, &OutputStatus::Spent)
+			| (&OutputStatus::Locked, &OutputStatus::Unspent)
+			| (&OutputStatus::Locked, &OutputStatus::Spent) => true,
+			_ => false,
+		}
+	}
+
+	// How far along its life an output in this status is, the status kept
+	// when entries of the same output disagree.
+	fn progress(&self) -> u8 {
+		match *self {
+			OutputStatus::Pending => 0,
+			OutputStatus::Unconfirmed => 1,
+			OutputStatus::Unspent => 2,
+			OutputStatus::Locked => 3,
+			OutputStatus::Spent => 4,
+		}
+	}
+}
+
 impl fmt::Display for OutputStatus {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
+			OutputStatus::Pending => write!(f, "Pending"),
 			OutputStatus::Unconfirmed => write!(f, "Unconfirmed"),
 			OutputStatus::Unspent => write!(f, "Unspent"),
 			OutputStatus::Locked => write!(f, "Locked"),
@@ -219,20 +1446,136 @@ pub struct OutputData {
 	pub n_child: u32,
 	/// Value of the output, necessary to rebuild the commitment
 	pub value: u64,
-	/// Current status of the output
+	/// Current status of the output, changed through WalletData::set_status
 	pub status: OutputStatus,
 	/// Height of the output
 	pub height: u64,
+	/// Hash of the block at that height when the checker last saw the output
+	/// on chain, telling whether the chain was reorganized since
+	pub block_hash: Option<String>,
 	/// Height we are locked until
 	pub lock_height: u64,
 	/// Is this a coinbase output? Is it subject to coinbase locktime?
 	pub is_coinbase: bool,
+	/// Height of the chain when a send locked the output, 0 if unknown
+	#[serde(default)]
+	pub locked_at: u64,
+	/// Account the output was received into, none for the default one
+	#[serde(default)]
+	pub account: Option<String>,
+	/// When the wallet created the output, unknown for those restored from
+	/// the chain or older than timestamps
+	pub created_at: Option<Timestamp>,
+	/// When the checker first found the output on chain
+	pub confirmed_at: Option<Timestamp>,
+	/// When the checker found the output spent
+	pub spent_at: Option<Timestamp>,
+	/// Wallet transaction the output last took part in: the send spending
+	/// it while it's locked, the send or payment that created it otherwise.
+	/// None for coinbases, outputs found by restore and those older than
+	/// wallet transactions
+	pub tx_id: Option<u32>,
+	/// Label the user tagged the output with, the message of the payment
+	/// for a received one until then. Only ever shown, never taken into
+	/// account to select or build anything
+	pub label: Option<String>,
+	/// Commitment of an output imported without the key to spend it, which
+	/// is watched on chain but never selected (see WalletData::import_output).
+	/// None for the wallet's own outputs, their commitment derived from their
+	/// key
+	pub watch_commit: Option<String>,
+}
+
+/// An output handed from one wallet to another, by WalletData::export_output
+/// and import_output, along with the fingerprint of the wallet it's from and
+/// its commitment.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputExport {
+	pub output: OutputData,
+	/// Root key of the wallet that exported the output
+	pub fingerprint: keychain::Identifier,
+	/// Commitment of the output, hex encoded
+	pub commit: String,
+}
+
+/// Which outputs WalletData::query returns, those matching everything that's
+/// set. The default filter matches all outputs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OutputFilter {
+	/// Only outputs in that status
+	#[serde(default)]
+	pub status: Option<OutputStatus>,
+	/// Only outputs of a block at that height or above
+	#[serde(default)]
+	pub min_height: Option<u64>,
+	/// Only outputs of a block at that height or below, unconfirmed ones
+	/// being at height 0
+	#[serde(default)]
+	pub max_height: Option<u64>,
+	/// Only outputs derived from that root key
+	#[serde(default)]
+	pub fingerprint: Option<keychain::Identifier>,
+	/// Only coinbase outputs, or only the others
+	#[serde(default)]
+	pub is_coinbase: Option<bool>,
+	/// Only outputs whose label holds that text
+	#[serde(default)]
+	pub label: Option<String>,
+}
+
+impl OutputFilter {
+	/// Filter of the outputs derived from the root key.
+	pub fn fingerprint(root_key_id: keychain::Identifier) -> OutputFilter {
+		OutputFilter {
+			fingerprint: Some(root_key_id),
+			..Default::default()
+		}
+	}
+
+	pub fn matches(&self, out: &OutputData) -> bool {
+		self.status.as_ref().map_or(true, |status| out.status == *status)
+			&& self.min_height.map_or(true, |height| out.height >= height)
+			&& self.max_height.map_or(true, |height| out.height <= height)
+			&& self.fingerprint
+				.as_ref()
+				.map_or(true, |root_key_id| out.root_key_id == *root_key_id)
+			&& self.is_coinbase.map_or(true, |is_coinbase| out.is_coinbase == is_coinbase)
+			&& self.label.as_ref().map_or(true, |text| {
+				out.label.as_ref().map_or(false, |label| label.contains(text.as_str()))
+			})
+	}
+}
+
+/// Outputs matching a filter, see WalletData::query.
+#[derive(Debug, Clone)]
+pub struct OutputQuery<'a> {
+	/// The outputs, by derivation index
+	pub outputs: Vec<&'a OutputData>,
+	pub count: usize,
+	/// Total value of the outputs
+	pub value: u64,
 }
 
 impl OutputData {
-	/// Lock a given output to avoid conflicting use
-	fn lock(&mut self) {
-		self.status = OutputStatus::Locked;
+	/// Whether the output was imported without the key to spend it.
+	pub fn is_watch_only(&self) -> bool {
+		self.watch_commit.is_some()
+	}
+
+	/// The commitment of the output, derived with the keychain, or the one a
+	/// watch-only output was imported with.
+	pub fn commit(&self, keychain: &keychain::Keychain) -> Result<pedersen::Commitment, Error> {
+		if let Some(ref commit) = self.watch_commit {
+			return commit_from_hex(commit);
+		}
+		if self.root_key_id != keychain.root_key_id() {
+			return Err(Error::ForeignInput {
+				key_id: self.key_id.clone(),
+				fingerprint: self.root_key_id.clone(),
+			});
+		}
+		let key_id = keychain.derive_key_id(self.n_child)?;
+		Ok(keychain.commit(self.value, &key_id)?)
 	}
 
 	/// How many confirmations has this output received?
@@ -241,35 +1584,122 @@ impl OutputData {
 	/// so we do not actually know how many confirmations this output had (and
 	/// never will).
 	pub fn num_confirmations(&self, current_height: u64) -> u64 {
-		if self.status == OutputStatus::Unconfirmed {
+		if [OutputStatus::Pending, OutputStatus::Unconfirmed].contains(&self.status) {
 			0
 		} else if self.status == OutputStatus::Spent && self.height == 0 {
 			0
 		} else {
-			current_height - self.height
+			current_height.saturating_sub(self.height)
 		}
 	}
 
+	/// Whether the output can be spent as far as coinbase maturity goes,
+	/// always true for other outputs. The lock height reported by the node
+	/// already accounts for maturity, the height of the block is a fallback
+	/// for outputs that haven't been refreshed since.
+	pub fn is_mature(&self, current_height: u64) -> bool {
+		!self.is_coinbase
+			|| max(self.lock_height, self.height + global::coinbase_maturity()) <= current_height
+	}
+
 	/// Check if output is eligible for spending based on state and height.
 	pub fn eligible_to_spend(&self, current_height: u64, minimum_confirmations: u64) -> bool {
-		if [OutputStatus::Spent, OutputStatus::Locked].contains(&self.status) {
-			return false;
-		} else if self.status == OutputStatus::Unconfirmed && self.is_coinbase {
-			return false;
-		} else if self.lock_height > current_height {
-			return false;
-		} else if self.status == OutputStatus::Unspent
-			&& self.height + minimum_confirmations <= current_height
-		{
-			return true;
-		} else if self.status == OutputStatus::Unconfirmed && minimum_confirmations == 0 {
-			return true;
-		} else {
-			return false;
+		self.spendability(current_height, minimum_confirmations) == Spendability::Spendable
+	}
+
+	/// Whether the output can be spent at the provided height, and if not
+	/// why. Unconfirmed outputs can only be spent without a minimum of
+	/// confirmations, never unconfirmed coinbase ones.
+	pub fn spendability(&self, current_height: u64, minimum_confirmations: u64) -> Spendability {
+		if self.is_watch_only() {
+			return Spendability::WatchOnly;
+		}
+		match self.status {
+			OutputStatus::Spent => Spendability::Spent,
+			OutputStatus::Locked => Spendability::Locked,
+			_ if self.is_coinbase && self.status != OutputStatus::Unspent => {
+				Spendability::AwaitingConfirmation
+			}
+			_ if !self.is_mature(current_height) => Spendability::Immature,
+			_ if self.lock_height > current_height => Spendability::HeightLocked,
+			OutputStatus::Unspent if self.height + minimum_confirmations <= current_height => {
+				Spendability::Spendable
+			}
+			OutputStatus::Unconfirmed if minimum_confirmations == 0 => Spendability::Spendable,
+			_ => Spendability::AwaitingConfirmation,
 		}
 	}
 }
 
+/// Whether an output can be spent, see OutputData::spendability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spendability {
+	Spendable,
+	/// Not on chain yet, or without enough confirmations
+	AwaitingConfirmation,
+	/// A coinbase output that hasn't matured
+	Immature,
+	/// Can't be spent before the chain reaches its lock height
+	HeightLocked,
+	/// Locked by a send that hasn't confirmed yet
+	Locked,
+	Spent,
+	/// Imported without the key to spend it
+	WatchOnly,
+}
+
+/// What the outputs of the wallet add up to, by whether they can be spent
+/// and why not. All but the total add up to the total.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Balance {
+	/// What a send can spend right now
+	pub spendable: u64,
+	/// Outputs not on chain yet, or without enough confirmations
+	pub awaiting_confirmation: u64,
+	/// Coinbase outputs that haven't matured yet
+	pub immature_coinbase: u64,
+	/// Outputs that can't be spent before the chain reaches their lock
+	/// height
+	pub height_locked: u64,
+	/// Outputs locked by sends that haven't confirmed yet
+	pub locked: u64,
+	/// All the outputs that aren't spent, watch-only ones aside
+	pub total: u64,
+}
+
+/// The outputs of a range of values, see OutputStats.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ValueBucket {
+	/// Smallest value of the range
+	pub from: u64,
+	/// Value the range stops short of, none for the last one
+	pub below: Option<u64>,
+	pub count: usize,
+	/// Total value of the outputs
+	pub value: u64,
+}
+
+/// How the outputs of the wallet are spread, to tell whether they're worth
+/// consolidating, see WalletData::stats. Watch-only outputs are only
+/// counted.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OutputStats {
+	/// The outputs counted in the total of the balance by value, in the
+	/// ranges of VALUE_BUCKETS
+	pub buckets: Vec<ValueBucket>,
+	/// Number of outputs in each status
+	pub pending: usize,
+	pub unconfirmed: usize,
+	pub unspent: usize,
+	pub locked: usize,
+	pub spent: usize,
+	/// Number of watch-only outputs, in none of the statuses
+	pub watch_only: usize,
+	/// The largest and smallest of the outputs a send can spend right now
+	pub largest_spendable: Option<OutputData>,
+	pub smallest_spendable: Option<OutputData>,
+}
+
 #[derive(Clone, PartialEq)]
 pub struct WalletSeed([u8; 32]);
 
@@ -354,249 +1784,5835 @@ impl WalletSeed {
 	}
 }
 
-/// Wallet information tracking all our outputs. Based on HD derivation and
-/// avoids storing any key data, only storing output amounts and child index.
-/// This data structure is directly based on the JSON representation stored
-/// on disk, so selection algorithms are fairly primitive and non optimized.
-///
-/// TODO optimization so everything isn't O(n) or even O(n^2)
-/// TODO account for fees
-/// TODO write locks so files don't get overwritten
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct WalletData {
-	pub outputs: HashMap<String, OutputData>,
+/// Status of a send recorded in the transaction log, or of a wallet
+/// transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TxLogStatus {
+	/// Built and handed to the receiver, not seen on chain yet
+	Pending,
+	/// The change (or the spent inputs) were seen on chain
+	Confirmed,
+	/// Delivering the partial transaction failed and the send was rolled back
+	Aborted,
+	/// Cancelled by the user, its inputs were unlocked
+	Cancelled,
 }
 
-impl WalletData {
-	/// Allows for reading wallet data (without needing to acquire the write
-	/// lock).
-	pub fn read_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
-	where
-		F: FnOnce(&WalletData) -> T,
-	{
-		// open the wallet readonly and do what needs to be done with it
-		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
-		let wdat = WalletData::read_or_create(data_file_path)?;
-		let res = f(&wdat);
-		Ok(res)
+impl fmt::Display for TxLogStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TxLogStatus::Pending => write!(f, "Pending"),
+			TxLogStatus::Confirmed => write!(f, "Confirmed"),
+			TxLogStatus::Aborted => write!(f, "Aborted"),
+			TxLogStatus::Cancelled => write!(f, "Cancelled"),
+		}
 	}
+}
 
-	/// Allows the reading and writing of the wallet data within a file lock.
-	/// Just provide a closure taking a mutable WalletData. The lock should
-	/// be held for as short a period as possible to avoid contention.
-	/// Note that due to the impossibility to do an actual file lock easily
-	/// across operating systems, this just creates a lock file with a "should
-	/// not exist" option.
-	pub fn with_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
-	where
-		F: FnOnce(&mut WalletData) -> T,
-	{
-		// create directory if it doesn't exist
-		fs::create_dir_all(data_file_dir).unwrap_or_else(|why| {
-			info!(LOGGER, "! {:?}", why.kind());
-		});
+/// Record of a send issued by the wallet, kept so there's a trace of the
+/// amount, fee and destination of a payment once it's been made.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxLogEntry {
+	/// Sequential id of the entry within the wallet
+	pub id: u32,
+	/// When the send was built, in seconds since the unix epoch
+	pub created_at: u64,
+	/// Amount sent
+	pub amount: u64,
+	/// Fee paid
+	pub fee: u64,
+	/// Where the partial transaction was delivered, empty until it's posted
+	pub dest: String,
+	/// Outputs spent by the send
+	pub input_ids: Vec<keychain::Identifier>,
+	/// Change outputs created by the send
+	pub change_ids: Vec<keychain::Identifier>,
+	/// Current status of the send
+	pub status: TxLogStatus,
+	/// Message sent along with the partial transaction
+	#[serde(default)]
+	pub message: Option<String>,
+	/// Partial transaction of the send, kept so it can be resent while it's
+	/// pending and dropped once it's not
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub partial_tx: Option<JSONPartialTx>,
+	/// Contact the destination was resolved from, if sent to "@name"
+	#[serde(default)]
+	pub contact: Option<String>,
+	/// Height of the chain tip when the send was posted, 0 if unknown
+	#[serde(default)]
+	pub tip_height: u64,
+	/// Hash of the completed transaction the receiver acknowledged the send
+	/// with, hex encoded, for sends posted over http
+	#[serde(default)]
+	pub receiver_ack: Option<String>,
+	/// Commitment of the output the receiver got paid with, hex encoded, when
+	/// it named one
+	#[serde(default)]
+	pub receiver_commit: Option<String>,
+}
 
-		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
-		let lock_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, LOCK_FILE);
+/// Formats the logs and outputs of the wallet can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+	/// One line per record, first line naming the columns
+	Csv,
+	/// An array of records
+	Json,
+}
 
-		info!(LOGGER, "Acquiring wallet lock ...");
+/// Whether an entry of the history of the wallet is a send or a payment
+/// received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+	Sent,
+	Received,
+}
 
-		let action = || {
-			debug!(LOGGER, "Attempting to acquire wallet lock");
-			OpenOptions::new()
-				.write(true)
-				.create_new(true)
-				.open(lock_file_path)
+/// A send of the transaction log or a payment of the receive log, as
+/// exported in the history of the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+	pub kind: HistoryKind,
+	/// Id of the entry in its log
+	pub id: u32,
+	/// In seconds since the unix epoch
+	pub created_at: u64,
+	/// Status of a send, whether a payment was confirmed
+	pub status: String,
+	/// Amount sent, or received net of the fee
+	pub amount: u64,
+	/// Fee paid by a send, 0 for payments received
+	pub fee: u64,
+	/// Where a send went, where a payment came from when known
+	pub counterparty: String,
+	pub message: Option<String>,
+	/// Outputs spent then created by a send, output of a payment
+	pub key_ids: Vec<keychain::Identifier>,
+}
+
+/// Record of a payment received by the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RxLogEntry {
+	/// Sequential id of the entry within the wallet
+	pub id: u32,
+	/// When the payment was received, in seconds since the unix epoch
+	pub created_at: u64,
+	/// Amount received, net of the fee
+	pub amount: u64,
+	/// Output created for the payment
+	pub key_id: keychain::Identifier,
+	/// Message the sender attached to the partial transaction
+	pub message: Option<String>,
+	/// Address the partial transaction was posted from, none when it was
+	/// received from a file
+	#[serde(default)]
+	pub sender: Option<String>,
+	/// Whether the output of the payment made it on chain
+	#[serde(default)]
+	pub confirmed: bool,
+	/// Account the payment was received into, none for the default one
+	#[serde(default)]
+	pub account: Option<String>,
+}
+
+/// Whether a wallet transaction is a send or a payment received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxDirection {
+	Sent,
+	Received,
+}
+
+impl fmt::Display for TxDirection {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TxDirection::Sent => write!(f, "Sent"),
+			TxDirection::Received => write!(f, "Received"),
+		}
+	}
+}
+
+/// A send or a payment received, grouping the outputs it spends and creates.
+/// Those outputs point back to it with their tx_id, so undoing a send finds
+/// everything it touched without going through the logs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletTransaction {
+	/// Sequential id of the transaction within the wallet
+	pub id: u32,
+	pub direction: TxDirection,
+	/// Amount sent, or received net of the fee
+	pub amount: u64,
+	pub fee: u64,
+	/// Pending until it makes it on chain, aborted or cancelled for a send
+	/// undone before that
+	pub status: TxLogStatus,
+	/// Outputs spent, none for a payment received
+	pub input_ids: Vec<keychain::Identifier>,
+	/// Outputs created: the change of a send, the output of a payment
+	pub output_ids: Vec<keychain::Identifier>,
+	/// Id of its entry in the transaction log for a send, the receive log
+	/// for a payment
+	pub log_id: Option<u32>,
+	pub created_at: Timestamp,
+}
+
+/// An invoice we issued and haven't been paid for yet. Its output is only
+/// tracked once paid, the invoice holds on to its derivation index meanwhile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Invoice {
+	/// Output the payer completes the transaction with
+	pub key_id: keychain::Identifier,
+	pub n_child: u32,
+	/// Root key the output is derived from, none for invoices issued
+	/// before it was kept that migrating couldn't tell the root key of,
+	/// which hold on to the index of every root key
+	#[serde(default)]
+	pub root_key_id: Option<keychain::Identifier>,
+	/// Amount invoiced, the payer pays the fee on top
+	pub amount: u64,
+	/// Height from which the invoice can't be paid anymore
+	pub expiry_height: u64,
+	/// When the invoice was issued, in seconds since the unix epoch
+	pub created_at: u64,
+}
+
+/// A partial transaction our receiver signed, kept until its output confirms
+/// so the same partial transaction received again, when the sender retries,
+/// gets the same answer instead of a second output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReceivedTx {
+	/// Hash of what the sender contributed, see JSONPartialTx::contribution
+	pub hash: String,
+	/// Output built for the partial transaction
+	pub key_id: keychain::Identifier,
+	/// What the sender was answered
+	pub response: ReceiveResponse,
+	/// Whether the completed transaction was pushed to the node
+	pub pushed: bool,
+}
+
+/// Lock held by a process for the whole duration of a send, from selecting
+/// the coins to delivering the partial transaction, so concurrent sends can't
+/// pick the same coins. The lock file holds the pid of its owner, when it
+/// was taken and on which host. A lock whose owner died or that's older than
+/// the timeout is considered stale and taken over. Released when dropped.
+pub struct SendLock {
+	path: PathBuf,
+}
+
+impl SendLock {
+	pub fn acquire(data_file_dir: &str, timeout_secs: u64) -> Result<SendLock, Error> {
+		fs::create_dir_all(data_file_dir)?;
+		let path = Path::new(data_file_dir).join(SEND_LOCK_FILE);
+		acquire_lock_file(&path, "send", |owner| {
+			owner.stale(timeout_secs) || owner.age() > timeout_secs
+		})?;
+		Ok(SendLock { path: path })
+	}
+}
+
+impl Drop for SendLock {
+	fn drop(&mut self) {
+		if let Err(e) = fs::remove_file(&self.path) {
+			error!(LOGGER, "Could not remove send lock {:?}: {}", self.path, e);
+		}
+	}
+}
+
+/// Owner of a lock file: the pid of the process holding it, when it was
+/// taken and on which host, unknown for locks of older wallets.
+#[derive(PartialEq)]
+struct LockOwner {
+	pid: u32,
+	since: u64,
+	host: Option<String>,
+}
+
+impl LockOwner {
+	fn ours() -> LockOwner {
+		LockOwner {
+			pid: current_pid(),
+			since: now_secs(),
+			host: Some(hostname()),
+		}
+	}
+
+	// Reads the owner from the lock file. A lock file that can't be
+	// understood (its owner died while writing it) is dated from its
+	// modification time.
+	fn read(path: &Path) -> Result<LockOwner, Error> {
+		let mut content = String::new();
+		File::open(path)?.read_to_string(&mut content)?;
+		let mut parts = content.split_whitespace();
+		let pid = parts.next().and_then(|p| p.parse::<u32>().ok());
+		let since = parts.next().and_then(|p| p.parse::<u64>().ok());
+		let host = parts.next().map(|h| h.to_string());
+		match (pid, since) {
+			(Some(pid), Some(since)) => Ok(LockOwner {
+				pid: pid,
+				since: since,
+				host: host,
+			}),
+			_ => {
+				let since = fs::metadata(path)?
+					.modified()?
+					.duration_since(UNIX_EPOCH)
+					.map(|d| d.as_secs())
+					.unwrap_or(0);
+				Ok(LockOwner {
+					pid: 0,
+					since: since,
+					host: None,
+				})
+			}
+		}
+	}
+
+	fn write(&self, file: &mut File) -> Result<(), Error> {
+		write!(file, "{} {}", self.pid, self.since)?;
+		if let Some(ref host) = self.host {
+			write!(file, " {}", host)?;
+		}
+		Ok(())
+	}
+
+	fn age(&self) -> u64 {
+		now_secs().saturating_sub(self.since)
+	}
+
+	// Whether the lock can be taken over: its owner ran on this host and
+	// died, or it can't be checked and the lock is older than max_age_secs.
+	// A lock of a live process is never stale.
+	fn stale(&self, max_age_secs: u64) -> bool {
+		let same_host = match self.host {
+			Some(ref host) => *host == hostname(),
+			None => true,
 		};
+		if same_host && self.pid != 0 && can_check_pids() {
+			!pid_alive(self.pid)
+		} else {
+			self.age() > max_age_secs
+		}
+	}
+}
 
-		// use tokio_retry to cleanly define some retry logic
-		let mut core = reactor::Core::new().unwrap();
-		let retry_strategy = FibonacciBackoff::from_millis(10).take(10);
-		let retry_future = Retry::spawn(core.handle(), retry_strategy, action);
-		let retry_result = core.run(retry_future);
+// What a panic was about, the message it was given if any.
+fn panic_message(cause: Box<Any + Send>) -> String {
+	match cause.downcast::<String>() {
+		Ok(message) => *message,
+		Err(cause) => match cause.downcast::<&'static str>() {
+			Ok(message) => message.to_string(),
+			Err(_) => "unknown cause".to_string(),
+		},
+	}
+}
 
-		match retry_result {
-			Ok(_) => {}
-			Err(_) => {
-				error!(
+// Creates the lock file at path, holding our pid, taking it over if its owner
+// is stale. A lock held by someone else is a WalletBusy error naming them.
+fn acquire_lock_file<F>(path: &Path, lock: &'static str, stale: F) -> Result<(), Error>
+where
+	F: Fn(&LockOwner) -> bool,
+{
+	// a second attempt is only made after moving a stale lock away
+	for _ in 0..2 {
+		match OpenOptions::new().write(true).create_new(true).open(path) {
+			Ok(mut file) => return LockOwner::ours().write(&mut file),
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+				let owner = match LockOwner::read(path) {
+					Ok(owner) => owner,
+					// released in the meantime
+					Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::NotFound => continue,
+					Err(e) => return Err(e),
+				};
+				if !stale(&owner) {
+					return Err(Error::WalletBusy {
+						pid: owner.pid,
+						since: owner.since,
+						lock: lock,
+					});
+				}
+				warn!(
 					LOGGER,
-					"Failed to acquire wallet lock file (multiple retries)",
+					"Removing stale {} lock of process {} on {} taken at {}",
+					lock,
+					owner.pid,
+					owner.host.as_ref().map(|h| h.as_str()).unwrap_or("an unknown host"),
+					owner.since
 				);
-				return Err(Error::WalletData(format!("Failed to acquire lock file")));
+				take_over_lock_file(path, &owner)?;
 			}
+			Err(e) => return Err(Error::IOError(e)),
 		}
+	}
+	Err(Error::WalletData(format!("Failed to acquire the {} lock", lock)))
+}
 
-		// We successfully acquired the lock - so do what needs to be done.
-		let mut wdat = WalletData::read_or_create(data_file_path)?;
-		let res = f(&mut wdat);
-		wdat.write(data_file_path)?;
+static STALE_LOCKS: AtomicUsize = ATOMIC_USIZE_INIT;
 
-		// delete the lock file
-		fs::remove_file(lock_file_path).map_err(|_| {
-			Error::WalletData(format!(
-				"Could not remove wallet lock file. Maybe insufficient rights?"
-			))
-		})?;
+// Removes the stale lock file at path, if it's still the one of owner. The
+// file is first renamed, which only one of the processes taking it over at
+// once gets to do, and only deleted once read back as the stale one. A lock
+// that was taken over and retaken in the meantime is put back where it was,
+// unless yet another one already stands there.
+fn take_over_lock_file(path: &Path, owner: &LockOwner) -> Result<(), Error> {
+	let moved = PathBuf::from(format!(
+		"{}.stale-{}-{}",
+		path.display(),
+		current_pid(),
+		STALE_LOCKS.fetch_add(1, Ordering::SeqCst)
+	));
+	match fs::rename(path, &moved) {
+		Ok(()) => {}
+		// taken over by someone else first
+		Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(Error::IOError(e)),
+	}
+	if LockOwner::read(&moved)? != *owner {
+		match fs::hard_link(&moved, path) {
+			Ok(()) => {}
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+			Err(e) => return Err(Error::IOError(e)),
+		}
+	}
+	fs::remove_file(&moved)?;
+	Ok(())
+}
 
-		info!(LOGGER, "... released wallet lock");
+#[cfg(unix)]
+fn hostname() -> String {
+	let mut buf = [0u8; 256];
+	let res = unsafe { ::libc::gethostname(buf.as_mut_ptr() as *mut ::libc::c_char, buf.len()) };
+	if res != 0 {
+		return String::new();
+	}
+	let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+	String::from_utf8_lossy(&buf[..len]).into_owned()
+}
 
-		Ok(res)
+#[cfg(not(unix))]
+fn hostname() -> String {
+	::std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+fn can_check_pids() -> bool {
+	cfg!(unix)
+}
+
+#[cfg(unix)]
+fn current_pid() -> u32 {
+	unsafe { ::libc::getpid() as u32 }
+}
+
+#[cfg(not(unix))]
+fn current_pid() -> u32 {
+	0
+}
+
+// Whether the process with the provided pid is still running. Signal 0 only
+// checks the process exists, EPERM meaning it runs as another user.
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+	if pid == 0 {
+		return true;
 	}
+	unsafe { ::libc::kill(pid as ::libc::pid_t, 0) == 0 }
+		|| io::Error::last_os_error().raw_os_error() == Some(::libc::EPERM)
+}
 
-	/// Read the wallet data or created a brand new one if it doesn't exist yet
-	fn read_or_create(data_file_path: &str) -> Result<WalletData, Error> {
-		if Path::new(data_file_path).exists() {
-			WalletData::read(data_file_path)
-		} else {
-			// just create a new instance, it will get written afterward
-			Ok(WalletData {
-				outputs: HashMap::new(),
-			})
+// Without a way to check, locks only expire with age.
+#[cfg(not(unix))]
+fn pid_alive(_pid: u32) -> bool {
+	true
+}
+
+// Whether the file at the path exists and is encrypted.
+fn is_encrypted_file(path: &str) -> bool {
+	let mut magic = [0; 8];
+	File::open(path)
+		.and_then(|mut file| file.read_exact(&mut magic))
+		.map(|_| is_encrypted(&magic))
+		.unwrap_or(false)
+}
+
+// Keeps the data file under the backup name, linked rather than copied where
+// the file system allows it, the data file staying where it is.
+fn backup_data_file(data_file_path: &str) -> Result<(), Error> {
+	let bak_file_path = format!("{}{}", data_file_path, BAK_EXT);
+	let _ = fs::remove_file(&bak_file_path);
+	fs::hard_link(data_file_path, &bak_file_path)
+		.or_else(|_| fs::copy(data_file_path, &bak_file_path).map(|_| ()))
+		.map_err(|e| Error::WalletData(format!("Could not back up {}: {}", data_file_path, e)))
+}
+
+// Keeps a copy of a data file of an older version as <data file>.v<version>,
+// as it was on disk, before it's first written back migrated. Only the first
+// copy of each version is kept.
+fn keep_before_migration(data_file_path: &str, version: u32) -> Result<(), Error> {
+	let copy_path = format!("{}.v{}", data_file_path, version);
+	if Path::new(&copy_path).exists() {
+		return Ok(());
+	}
+	fs::copy(data_file_path, &copy_path)
+		.map(|_| sync_parent_dir(&copy_path))
+		.map_err(|e| Error::WalletData(format!("Could not copy {}: {}", data_file_path, e)))
+}
+
+// Keeps a copy of a data file tracking outputs more than once as
+// <data file>.dup-<seconds since the unix epoch>, as it was on disk, before
+// it's first written back with them merged.
+fn keep_before_dedupe(data_file_path: &str) -> Result<(), Error> {
+	let copy_path = format!("{}.dup-{}", data_file_path, now_secs());
+	fs::copy(data_file_path, &copy_path)
+		.map(|_| sync_parent_dir(&copy_path))
+		.map_err(|e| Error::WalletData(format!("Could not copy {}: {}", data_file_path, e)))?;
+	info!(LOGGER, "{} kept as it was before merging its outputs", copy_path);
+	Ok(())
+}
+
+// Moves the data files a wallet kept in the data directory itself to the
+// directory of the wallet of the fingerprint, if all the outputs of the data
+// file are of that fingerprint and the wallet has no data file of its own
+// yet. The data file goes last, so a move cut short is completed the next
+// time, and the locks of both directories are held throughout.
+fn move_flat_data_files(
+	data_file_dir: &str,
+	wallet_dir: &str,
+	fingerprint: &keychain::Identifier,
+) -> Result<(), Error> {
+	let flat_path = |name: &str| format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, name);
+	let wallet_path = |name: &str| format!("{}{}{}", wallet_dir, MAIN_SEPARATOR, name);
+	let to_move = || {
+		Path::new(&flat_path(DAT_FILE)).exists() && !Path::new(&wallet_path(DAT_FILE)).exists()
+	};
+	if !to_move() {
+		return Ok(());
+	}
+	fs::create_dir_all(wallet_dir)?;
+	let move_files = || -> Result<(), Error> {
+		// another process may have moved them in the meantime
+		if !to_move() {
+			return Ok(());
+		}
+		let cipher = data_cipher();
+		let cipher = cipher.as_ref().map(|c| &**c);
+		let wdat = WalletData::read_or_create(&flat_path(DAT_FILE), cipher)?;
+		if let Some(out) = wdat.outputs.values().find(|out| out.root_key_id != *fingerprint) {
+			info!(
+				LOGGER,
+				"{} holds outputs of {}, left where it is",
+				flat_path(DAT_FILE),
+				out.root_key_id
+			);
+			return Ok(());
+		}
+		let bak = |name: &str| format!("{}{}", name, BAK_EXT);
+		let names = vec![
+			ARCHIVE_FILE.to_string(),
+			bak(ARCHIVE_FILE),
+			CHILD_COUNTER_FILE.to_string(),
+			BACKUP_DIR.to_string(),
+			bak(DAT_FILE),
+			DAT_FILE.to_string(),
+		];
+		for name in names {
+			let (from, to) = (flat_path(&name), wallet_path(&name));
+			if Path::new(&from).exists() && !Path::new(&to).exists() {
+				fs::rename(&from, &to).map_err(|e| {
+					Error::WalletData(format!("Could not move {} to {}: {}", from, to, e))
+				})?;
+			}
+		}
+		sync_parent_dir(&wallet_path(DAT_FILE));
+		sync_parent_dir(&flat_path(DAT_FILE));
+		info!(LOGGER, "Wallet data files moved from {} to {}", data_file_dir, wallet_dir);
+		Ok(())
+	};
+
+	let flat_lock_path = &WalletData::acquire_data_lock(data_file_dir)?;
+	let res = WalletData::acquire_data_lock(wallet_dir).and_then(|wallet_lock_path| {
+		let res = move_files();
+		WalletData::release_data_lock(&wallet_lock_path)?;
+		res
+	});
+	WalletData::release_data_lock(flat_lock_path)?;
+	res
+}
+
+// Brings the JSON of a data file from its version to the current one, one
+// version at a time.
+fn migrate_data(json: &mut serde_json::Value, version: u32) -> Result<(), String> {
+	for from in version..DATA_FILE_VERSION {
+		match from {
+			0 => migrate_v0(json)?,
+			1 => migrate_v1(json)?,
+			2 => migrate_v2(json)?,
+			3 => migrate_v3(json)?,
+			4 => migrate_v4(json)?,
+			// version 5 files have no checksum, it's added when they're written
+			5 => {}
+			6 => migrate_v6(json)?,
+			7 => migrate_v7(json)?,
+			_ => return Err(format!("no migration from version {}", from)),
 		}
 	}
+	json["version"] = serde_json::Value::from(DATA_FILE_VERSION);
+	Ok(())
+}
 
-	/// Read the wallet data from disk.
-	fn read(data_file_path: &str) -> Result<WalletData, Error> {
-		let data_file = File::open(data_file_path).map_err(|e| {
-			Error::WalletData(format!("Could not open {}: {}", data_file_path, e))
-		})?;
-		serde_json::from_reader(data_file).map_err(|e| {
-			Error::WalletData(format!("Error reading {}: {}", data_file_path, e))
-		})
+// Version 0 is any file written before data files had a version. The fields
+// added to them since are filled with what older wallets meant without them.
+fn migrate_v0(json: &mut serde_json::Value) -> Result<(), String> {
+	use serde_json::{Map, Value};
+
+	let data = json
+		.as_object_mut()
+		.ok_or_else(|| "the wallet data isn't a JSON object".to_string())?;
+	{
+		let outputs = data
+			.get_mut("outputs")
+			.and_then(|outputs| outputs.as_object_mut())
+			.ok_or_else(|| "no outputs".to_string())?;
+		for output in outputs.values_mut() {
+			let output = output
+				.as_object_mut()
+				.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+			// taken for a plain output until the checker finds it on chain
+			// and tells, its lock height holding it meanwhile
+			let defaults = vec![
+				("is_coinbase", Value::Bool(false)),
+				("locked_at", Value::from(0)),
+				("account", Value::Null),
+			];
+			for (field, default) in defaults {
+				if !output.contains_key(field) {
+					output.insert(field.to_string(), default);
+				}
+			}
+		}
+	}
+	let defaults = vec![
+		("tx_log", Value::Array(vec![])),
+		("rx_log", Value::Array(vec![])),
+		("invoices", Value::Array(vec![])),
+		("received_txs", Value::Array(vec![])),
+		("restored_height", Value::from(0)),
+		("archived", Value::Object(Map::new())),
+	];
+	for (field, default) in defaults {
+		if !data.contains_key(field) {
+			data.insert(field.to_string(), default);
+		}
 	}
+	Ok(())
+}
 
-	/// Write the wallet data to disk.
-	fn write(&self, data_file_path: &str) -> Result<(), Error> {
-		let mut data_file = File::create(data_file_path).map_err(|e| {
-			Error::WalletData(format!("Could not create {}: {}", data_file_path, e))
-		})?;
-		let res_json = serde_json::to_vec_pretty(self).map_err(|e| {
-			Error::WalletData(format!("Error serializing wallet data: {}", e))
-		})?;
-		data_file.write_all(res_json.as_slice()).map_err(|e| {
-			Error::WalletData(format!("Error writing {}: {}", data_file_path, e))
-		})
+// Version 1 outputs have no block hash, the checker records it the next
+// time it finds them on chain.
+fn migrate_v1(json: &mut serde_json::Value) -> Result<(), String> {
+	let outputs = json
+		.get_mut("outputs")
+		.and_then(|outputs| outputs.as_object_mut())
+		.ok_or_else(|| "no outputs".to_string())?;
+	for output in outputs.values_mut() {
+		let output = output
+			.as_object_mut()
+			.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+		if !output.contains_key("block_hash") {
+			output.insert("block_hash".to_string(), serde_json::Value::Null);
+		}
 	}
+	Ok(())
+}
 
-	/// Append a new output data to the wallet data.
-	/// TODO - we should check for overwriting here - only really valid for
-	/// unconfirmed coinbase
-	pub fn add_output(&mut self, out: OutputData) {
-		self.outputs.insert(out.key_id.to_hex(), out.clone());
+// Version 2 outputs have no timestamps, when they were created, confirmed
+// or spent isn't known anymore.
+fn migrate_v2(json: &mut serde_json::Value) -> Result<(), String> {
+	let outputs = json
+		.get_mut("outputs")
+		.and_then(|outputs| outputs.as_object_mut())
+		.ok_or_else(|| "no outputs".to_string())?;
+	for output in outputs.values_mut() {
+		let output = output
+			.as_object_mut()
+			.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+		for field in vec!["created_at", "confirmed_at", "spent_at"] {
+			if !output.contains_key(field) {
+				output.insert(field.to_string(), serde_json::Value::Null);
+			}
+		}
 	}
+	Ok(())
+}
 
-	/// Lock an output data.
-	/// TODO - we should track identifier on these outputs (not just n_child)
-	pub fn lock_output(&mut self, out: &OutputData) {
-		if let Some(out_to_lock) = self.outputs.get_mut(&out.key_id.to_hex()) {
-			if out_to_lock.value == out.value {
-				out_to_lock.lock()
+// Version 3 has no wallet transactions, its outputs are linked to none and
+// its sends are only undone through their transaction log entries.
+fn migrate_v3(json: &mut serde_json::Value) -> Result<(), String> {
+	let data = json
+		.as_object_mut()
+		.ok_or_else(|| "the wallet data isn't a JSON object".to_string())?;
+	{
+		let outputs = data
+			.get_mut("outputs")
+			.and_then(|outputs| outputs.as_object_mut())
+			.ok_or_else(|| "no outputs".to_string())?;
+		for output in outputs.values_mut() {
+			let output = output
+				.as_object_mut()
+				.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+			if !output.contains_key("tx_id") {
+				output.insert("tx_id".to_string(), serde_json::Value::Null);
 			}
 		}
 	}
+	if !data.contains_key("transactions") {
+		data.insert("transactions".to_string(), serde_json::Value::Array(vec![]));
+	}
+	Ok(())
+}
 
-	pub fn get_output(&self, key_id: &keychain::Identifier) -> Option<&OutputData> {
-		self.outputs.get(&key_id.to_hex())
+// Version 4 outputs have no label.
+fn migrate_v4(json: &mut serde_json::Value) -> Result<(), String> {
+	let outputs = json
+		.get_mut("outputs")
+		.and_then(|outputs| outputs.as_object_mut())
+		.ok_or_else(|| "no outputs".to_string())?;
+	for output in outputs.values_mut() {
+		let output = output
+			.as_object_mut()
+			.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+		if !output.contains_key("label") {
+			output.insert("label".to_string(), serde_json::Value::Null);
+		}
 	}
+	Ok(())
+}
 
-	/// Select spendable coins from the wallet
-	pub fn select(
-		&self,
-		root_key_id: keychain::Identifier,
-		current_height: u64,
-		minimum_confirmations: u64,
-	) -> Vec<OutputData> {
-		self.outputs
-			.values()
-			.filter(|out| {
-				out.root_key_id == root_key_id
-					&& out.eligible_to_spend(current_height, minimum_confirmations)
-			})
-			.map(|out| out.clone())
-			.collect()
+// Version 6 outputs are all the wallet's own, none watch-only.
+fn migrate_v6(json: &mut serde_json::Value) -> Result<(), String> {
+	let outputs = json
+		.get_mut("outputs")
+		.and_then(|outputs| outputs.as_object_mut())
+		.ok_or_else(|| "no outputs".to_string())?;
+	for output in outputs.values_mut() {
+		let output = output
+			.as_object_mut()
+			.ok_or_else(|| "an output isn't a JSON object".to_string())?;
+		if !output.contains_key("watch_commit") {
+			output.insert("watch_commit".to_string(), serde_json::Value::Null);
+		}
 	}
+	Ok(())
+}
 
-	/// Next child index when we want to create a new output.
-	pub fn next_child(&self, root_key_id: keychain::Identifier) -> u32 {
-		let mut max_n = 0;
-		for out in self.outputs.values() {
-			if max_n < out.n_child && out.root_key_id == root_key_id {
-				max_n = out.n_child;
+// Version 7 invoices issued before their root key was kept hold on to the
+// index of every root key, inflating the indexes of all of them. They're
+// given the root key of the wallet's own outputs when it holds those of a
+// single one, watch-only outputs being of other seeds. Otherwise which one
+// they're of can't be told and they're left holding on to every index.
+fn migrate_v7(json: &mut serde_json::Value) -> Result<(), String> {
+	let data = json
+		.as_object_mut()
+		.ok_or_else(|| "the wallet data isn't a JSON object".to_string())?;
+	let mut root_key_ids = HashSet::new();
+	if let Some(outputs) = data.get("outputs").and_then(|outputs| outputs.as_object()) {
+		for output in outputs.values() {
+			let watched = output
+				.get("watch_commit")
+				.map(|commit| !commit.is_null())
+				.unwrap_or(false);
+			match output.get("root_key_id").and_then(|id| id.as_str()) {
+				Some(id) if !watched => {
+					root_key_ids.insert(id.to_string());
+				}
+				_ => {}
 			}
 		}
-		max_n + 1
 	}
+	if let Some(n_child) = data.get("archived")
+		.and_then(|archived| archived.get("n_child"))
+		.and_then(|n_child| n_child.as_object())
+	{
+		root_key_ids.extend(n_child.keys().cloned());
+	}
+	if root_key_ids.len() != 1 {
+		return Ok(());
+	}
+	let root_key_id = root_key_ids.into_iter().next().unwrap();
+
+	let invoices = data
+		.get_mut("invoices")
+		.and_then(|invoices| invoices.as_array_mut())
+		.ok_or_else(|| "no invoices".to_string())?;
+	for invoice in invoices.iter_mut() {
+		let invoice = invoice
+			.as_object_mut()
+			.ok_or_else(|| "an invoice isn't a JSON object".to_string())?;
+		let rootless = invoice
+			.get("root_key_id")
+			.map(|id| id.is_null())
+			.unwrap_or(true);
+		if rootless {
+			invoice.insert("root_key_id".to_string(), serde_json::Value::from(root_key_id.clone()));
+		}
+	}
+	Ok(())
 }
 
-/// Helper in serializing the information a receiver requires to build a
-/// transaction.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct JSONPartialTx {
-	amount: u64,
-	blind_sum: String,
-	tx: String,
+// Parses a hex encoded commitment, refusing what isn't one.
+fn commit_from_hex(commit: &str) -> Result<pedersen::Commitment, Error> {
+	let is_hex = commit.bytes().all(|b| (b as char).is_digit(16));
+	if commit.len() != 2 * PEDERSEN_COMMITMENT_SIZE || !is_hex {
+		return Err(Error::Format(format!("Invalid commitment {:?}", commit)));
+	}
+	Ok(pedersen::Commitment::from_vec(util::from_hex(commit.to_string())?))
 }
 
-/// Encodes the information for a partial transaction (not yet completed by the
-/// receiver) into JSON.
-pub fn build_partial_tx(
-	receive_amount: u64,
-	blind_sum: keychain::BlindingFactor,
-	tx: Transaction,
-) -> JSONPartialTx {
-	JSONPartialTx {
-		amount: receive_amount,
-		blind_sum: util::to_hex(blind_sum.secret_key().as_ref().to_vec()),
-		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
+// Checksum of the JSON of a data file, the blake2b of its canonical
+// serialization: compact, the keys of objects sorted (serde_json maps are),
+// a checksum it holds left out.
+fn data_checksum(json: &serde_json::Value) -> Result<String, Error> {
+	let mut body = json.clone();
+	if let Some(body) = body.as_object_mut() {
+		body.remove(CHECKSUM_FIELD);
 	}
+	let canonical = serde_json::to_vec(&body)
+		.map_err(|e| Error::WalletData(format!("Error serializing wallet data: {}", e)))?;
+	let checksum = blake2::blake2b::blake2b(32, &[], &canonical);
+	Ok(util::to_hex(checksum.as_bytes().to_vec()))
 }
 
-/// Reads a partial transaction into the amount, sum of blinding
-/// factors and the transaction itself.
-pub fn read_partial_tx(
-	keychain: &keychain::Keychain,
-	partial_tx: &JSONPartialTx,
-) -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
-	// let partial_tx: JSONPartialTx = serde_json::from_str(json_str)?;
+// The error for a data file that doesn't hold what was written to it,
+// telling the most recent backup of its directory to restore, if any.
+fn corrupted(data_file_path: &str, reason: &str) -> Error {
+	let dir = Path::new(data_file_path)
+		.parent()
+		.unwrap_or(Path::new("."))
+		.join(BACKUP_DIR);
+	let latest = backups_in(&dir).ok().and_then(|backups| backups.into_iter().last());
+	let advice = match latest {
+		Some(backup) => format!(
+			"the most recent backup, taken at {}, can be restored with \"grin wallet \
+			restore_backup {}\"",
+			Timestamp(backup.created_at),
+			backup.path.display()
+		),
+		None => format!("no backup of it was found in {}", dir.display()),
+	};
+	Error::WalletCorrupted(format!("{} is corrupted, {}; {}", data_file_path, reason, advice))
+}
 
-	let blind_bin = util::from_hex(partial_tx.blind_sum.clone())?;
+// Whether the data file at path was cut short while written in place: plain
+// JSON ending before it's complete. Encrypted files were never written in
+// place.
+fn torn_write(data_file_path: &str) -> bool {
+	let mut data = vec![];
+	if File::open(data_file_path)
+		.and_then(|mut file| file.read_to_end(&mut data))
+		.is_err()
+	{
+		return false;
+	}
+	if is_encrypted(&data) {
+		return false;
+	}
+	match serde_json::from_slice::<serde_json::Value>(&data) {
+		Err(e) => e.is_eof(),
+		Ok(_) => false,
+	}
+}
 
-	let blinding = keychain::BlindingFactor::from_slice(keychain.secp(), &blind_bin[..])?;
+// Backups found in dir, oldest first.
+fn backups_in(dir: &Path) -> Result<Vec<WalletBackup>, Error> {
+	if !dir.exists() {
+		return Ok(vec![]);
+	}
+	let mut backups = vec![];
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+		let stamp = path.file_name()
+			.and_then(|name| name.to_str())
+			.and_then(backup_stamp);
+		if let Some((created_at, n)) = stamp {
+			let backup = WalletBackup {
+				path: path,
+				created_at: created_at,
+			};
+			backups.push(((created_at, n), backup));
+		}
+	}
+	backups.sort_by_key(|&(stamp, _)| stamp);
+	Ok(backups.into_iter().map(|(_, backup)| backup).collect())
+}
 
-	let tx_bin = util::from_hex(partial_tx.tx.clone())?;
-	let tx = ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
-		Error::Format("Could not deserialize transaction, invalid format.".to_string())
-	})?;
+// Directory of the backups, dir if provided.
+fn backup_dir(config: &WalletConfig, dir: Option<&str>) -> PathBuf {
+	match dir {
+		Some(dir) => PathBuf::from(dir),
+		None => Path::new(&config.data_file_dir).join(BACKUP_DIR),
+	}
+}
 
-	Ok((partial_tx.amount, blinding, tx))
+// Copies the data file to a backup in dir named wallet-<secs>.dat, a counter
+// added when several are taken within the same second.
+fn copy_to_backup(data_file_path: &str, dir: &Path) -> Result<WalletBackup, Error> {
+	fs::create_dir_all(dir)?;
+	let created_at = now_secs();
+	let mut n = 0;
+	loop {
+		let name = match n {
+			0 => format!("wallet-{}.dat", created_at),
+			n => format!("wallet-{}-{}.dat", created_at, n),
+		};
+		let path = dir.join(name);
+		match OpenOptions::new().write(true).create_new(true).open(&path) {
+			Ok(mut backup_file) => {
+				io::copy(&mut File::open(data_file_path)?, &mut backup_file)
+					.and_then(|_| backup_file.sync_all())
+					.map_err(|e| {
+						Error::WalletData(format!("Could not back up to {}: {}", path.display(), e))
+					})?;
+				return Ok(WalletBackup {
+					path: path,
+					created_at: created_at,
+				});
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+			Err(e) => return Err(Error::IOError(e)),
+		}
+	}
 }
 
-/// Amount in request to build a coinbase output.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum WalletReceiveRequest {
-	Coinbase(BlockFees),
-	PartialTransaction(String),
-	Finalize(String),
+// When the backup with the provided file name was taken and its counter
+// within that second, none if it isn't named like one.
+fn backup_stamp(name: &str) -> Option<(u64, u32)> {
+	if !name.starts_with("wallet-") || !name.ends_with(".dat") {
+		return None;
+	}
+	let stamp = &name["wallet-".len()..name.len() - ".dat".len()];
+	let mut parts = stamp.splitn(2, '-');
+	let secs = parts.next().and_then(|secs| secs.parse::<u64>().ok());
+	let n = match parts.next() {
+		Some(n) => n.parse::<u32>().ok(),
+		None => Some(0),
+	};
+	match (secs, n) {
+		(Some(secs), Some(n)) => Some((secs, n)),
+		_ => None,
+	}
 }
 
-/// Fees in block to use for coinbase amount calculation
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct BlockFees {
-	pub fees: u64,
+// Makes the rename of a file durable, directories can only be synced on unix.
+#[cfg(unix)]
+fn sync_parent_dir(path: &str) {
+	if let Some(dir) = Path::new(path).parent() {
+		let _ = File::open(dir).and_then(|dir| dir.sync_all());
+	}
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &str) {}
+
+/// A copy of the wallet data file, see WalletData::backup.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletBackup {
+	pub path: PathBuf,
+	/// When the backup was taken, in seconds since the unix epoch
+	pub created_at: u64,
+}
+
+/// Entries of the same output WalletData::dedupe merged into one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedOutput {
+	pub key_id: keychain::Identifier,
+	/// How many entries the output had
+	pub entries: usize,
+	/// Most advanced status of the entries
+	pub status: OutputStatus,
+	/// Highest height of the entries
 	pub height: u64,
-	pub key_id: Option<keychain::Identifier>,
 }
 
-impl BlockFees {
-	pub fn key_id(&self) -> Option<keychain::Identifier> {
-		self.key_id.clone()
+/// What WalletData::dedupe did, outputs in the order of their identifier.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DedupeSummary {
+	pub merged: Vec<MergedOutput>,
+	/// Outputs tracked under another name than their identifier, now
+	/// tracked under it
+	pub renamed: Vec<keychain::Identifier>,
+	/// Outputs with entries of different values, left as they are
+	pub conflicting: Vec<keychain::Identifier>,
+}
+
+impl DedupeSummary {
+	/// Whether the outputs were changed.
+	pub fn changed(&self) -> bool {
+		!self.merged.is_empty() || !self.renamed.is_empty()
 	}
 }
 
-/// Response to build a coinbase output.
+/// What WalletData::prune moved to the archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PruneSummary {
+	pub outputs: usize,
+	pub tx_log_entries: usize,
+	pub rx_log_entries: usize,
+}
+
+/// Highest ids of what was moved to the archive, so the active data file
+/// never hands them out again.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ArchivedIds {
+	/// Highest child index of the archived outputs, by root key
+	#[serde(default)]
+	pub n_child: HashMap<String, u32>,
+	#[serde(default)]
+	pub tx_log_id: u32,
+	#[serde(default)]
+	pub rx_log_id: u32,
+	#[serde(default)]
+	pub tx_id: u32,
+}
+
+/// Wallet information tracking all our outputs. Based on HD derivation and
+/// avoids storing any key data, only storing output amounts and child index.
+/// This data structure is directly based on the JSON representation stored
+/// on disk, so selection algorithms are fairly primitive and non optimized.
+///
+/// TODO optimization so everything isn't O(n) or even O(n^2)
+/// TODO account for fees
+/// TODO write locks so files don't get overwritten
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct CbData {
+pub struct WalletData {
+	/// Version of the data file, files without one being version 0
+	#[serde(default)]
+	pub version: u32,
+	pub outputs: HashMap<String, OutputData>,
+	#[serde(default)]
+	pub tx_log: Vec<TxLogEntry>,
+	#[serde(default)]
+	pub rx_log: Vec<RxLogEntry>,
+	/// Sends and payments received, each grouping the outputs it spends and
+	/// creates
+	#[serde(default)]
+	pub transactions: Vec<WalletTransaction>,
+	#[serde(default)]
+	pub invoices: Vec<Invoice>,
+	#[serde(default)]
+	pub received_txs: Vec<ReceivedTx>,
+	/// Height up to which the chain was scanned for our outputs by restore
+	#[serde(default)]
+	pub restored_height: u64,
+	#[serde(default)]
+	pub archived: ArchivedIds,
+	/// Version the data file was in when read, if it was migrated from an
+	/// older one, so a copy of it is kept when it's first written back
+	#[serde(skip_serializing, skip_deserializing)]
+	migrated_from: Option<u32>,
+	/// Whether outputs tracked more than once were merged when read, so a
+	/// copy of the data file is kept when it's first written back
+	#[serde(skip_serializing, skip_deserializing)]
+	deduped: bool,
+}
+
+impl WalletData {
+	/// Allows for reading wallet data without acquiring the lock of the data
+	/// file, so reads don't wait for a writer (like a long running receiver)
+	/// and don't hold up one either. Writes replace the data file in one
+	/// rename, a read sees the data before or after a write, never half of
+	/// it. Nothing is ever written, an older data file is only migrated in
+	/// memory.
+	pub fn read_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
+	where
+		F: FnOnce(&WalletData) -> T,
+	{
+		// open the wallet readonly and do what needs to be done with it
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		let cipher = data_cipher();
+		let wdat = WalletData::read_or_create(data_file_path, cipher.as_ref().map(|c| &**c))?;
+		let res = f(&wdat);
+		Ok(res)
+	}
+
+	/// Same as read_wallet, the outputs and log entries moved to the archive
+	/// by prune included if include_archive is set, for historical views.
+	pub fn read_wallet_with_archive<T, F>(
+		data_file_dir: &str,
+		include_archive: bool,
+		f: F,
+	) -> Result<T, Error>
+	where
+		F: FnOnce(&WalletData) -> T,
+	{
+		if !include_archive {
+			return WalletData::read_wallet(data_file_dir, f);
+		}
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		let archive_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, ARCHIVE_FILE);
+		let cipher = data_cipher();
+		let cipher = cipher.as_ref().map(|c| &**c);
+		let mut wdat = WalletData::read_or_create(data_file_path, cipher)?;
+		let archive = WalletData::read_or_create(archive_file_path, cipher)?;
+		wdat.merge_archive(archive);
+		Ok(f(&wdat))
+	}
+
+	/// Moves the spent outputs confirmed before the provided height, and the
+	/// log entries left referring to no output of the active data file, to
+	/// the archive file of the data directory. Refused if a send that isn't
+	/// confirmed yet still spends or creates one of the outputs. The archive
+	/// is written first, a crash in between leaves entries in both files
+	/// rather than in neither.
+	pub fn prune(data_file_dir: &str, before_height: u64) -> Result<PruneSummary, Error> {
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		let archive_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, ARCHIVE_FILE);
+		let lock_file_path = &WalletData::acquire_data_lock(data_file_dir)?;
+
+		let cipher = data_cipher();
+		let cipher = cipher.as_ref().map(|c| &**c);
+		let res = WalletData::read_or_create(data_file_path, cipher).and_then(|mut wdat| {
+			let mut archive = WalletData::read_or_create(archive_file_path, cipher)?;
+			let summary = wdat.move_to_archive(&mut archive, before_height)?;
+			if summary != PruneSummary::default() {
+				archive.write(archive_file_path, cipher)?;
+				wdat.write(data_file_path, cipher)?;
+			}
+			Ok(summary)
+		});
+
+		WalletData::release_data_lock(lock_file_path)?;
+		let summary = res?;
+		info!(
+			LOGGER,
+			"Archived {} outputs spent before height {}, {} sends and {} receives",
+			summary.outputs,
+			before_height,
+			summary.tx_log_entries,
+			summary.rx_log_entries
+		);
+		Ok(summary)
+	}
+
+	// Moves what prune archives from the wallet data to the archive.
+	fn move_to_archive(
+		&mut self,
+		archive: &mut WalletData,
+		before_height: u64,
+	) -> Result<PruneSummary, Error> {
+		let pruned = self.outputs
+			.values()
+			.filter(|out| out.status == OutputStatus::Spent && out.height < before_height)
+			.map(|out| out.key_id.to_hex())
+			.collect::<HashSet<_>>();
+		for entry in self.tx_log.iter() {
+			if entry.status != TxLogStatus::Pending {
+				continue;
+			}
+			let mut ids = entry.input_ids.iter().chain(entry.change_ids.iter());
+			if let Some(key_id) = ids.find(|key_id| pruned.contains(&key_id.to_hex())) {
+				return Err(Error::OutputInUse {
+					key_id: key_id.clone(),
+					tx_id: entry.id,
+				});
+			}
+		}
+
+		for key in pruned.iter() {
+			if let Some(out) = self.outputs.remove(key) {
+				let n_child = self.archived
+					.n_child
+					.entry(out.root_key_id.to_hex())
+					.or_insert(0);
+				*n_child = max(*n_child, out.n_child);
+				archive.outputs.insert(key.clone(), out);
+			}
+		}
+
+		// entries go along with the outputs they refer to, once none of them
+		// is left in the active data
+		let active = self.outputs.keys().cloned().collect::<HashSet<_>>();
+		let tx_log = self.tx_log.drain(..).collect::<Vec<_>>();
+		let (archived_txs, tx_log): (Vec<_>, Vec<_>) = tx_log.into_iter().partition(|entry| {
+			let ids = entry
+				.input_ids
+				.iter()
+				.chain(entry.change_ids.iter())
+				.map(|key_id| key_id.to_hex())
+				.collect::<Vec<_>>();
+			ids.iter().any(|id| pruned.contains(id)) && !ids.iter().any(|id| active.contains(id))
+		});
+		self.tx_log = tx_log;
+		let rx_log = self.rx_log.drain(..).collect::<Vec<_>>();
+		let (archived_rxs, rx_log): (Vec<_>, Vec<_>) = rx_log
+			.into_iter()
+			.partition(|entry| pruned.contains(&entry.key_id.to_hex()));
+		self.rx_log = rx_log;
+		let transactions = self.transactions.drain(..).collect::<Vec<_>>();
+		let (archived_wtxs, transactions): (Vec<_>, Vec<_>) =
+			transactions.into_iter().partition(|tx| {
+				let ids = tx.input_ids
+					.iter()
+					.chain(tx.output_ids.iter())
+					.map(|key_id| key_id.to_hex())
+					.collect::<Vec<_>>();
+				ids.iter().any(|id| pruned.contains(id))
+					&& !ids.iter().any(|id| active.contains(id))
+			});
+		self.transactions = transactions;
+
+		let summary = PruneSummary {
+			outputs: pruned.len(),
+			tx_log_entries: archived_txs.len(),
+			rx_log_entries: archived_rxs.len(),
+		};
+		for entry in archived_txs {
+			self.archived.tx_log_id = max(self.archived.tx_log_id, entry.id);
+			if !archive.tx_log.iter().any(|e| e.id == entry.id) {
+				archive.tx_log.push(entry);
+			}
+		}
+		for entry in archived_rxs {
+			self.archived.rx_log_id = max(self.archived.rx_log_id, entry.id);
+			if !archive.rx_log.iter().any(|e| e.id == entry.id) {
+				archive.rx_log.push(entry);
+			}
+		}
+		for tx in archived_wtxs {
+			self.archived.tx_id = max(self.archived.tx_id, tx.id);
+			if !archive.transactions.iter().any(|t| t.id == tx.id) {
+				archive.transactions.push(tx);
+			}
+		}
+		Ok(summary)
+	}
+
+	// Adds what's in the archive to the wallet data, what's in both being
+	// taken from the wallet data.
+	fn merge_archive(&mut self, archive: WalletData) {
+		for (key, out) in archive.outputs {
+			self.outputs.entry(key).or_insert(out);
+		}
+		for entry in archive.tx_log {
+			if !self.tx_log.iter().any(|e| e.id == entry.id) {
+				self.tx_log.push(entry);
+			}
+		}
+		for entry in archive.rx_log {
+			if !self.rx_log.iter().any(|e| e.id == entry.id) {
+				self.rx_log.push(entry);
+			}
+		}
+		for tx in archive.transactions {
+			if !self.transactions.iter().any(|t| t.id == tx.id) {
+				self.transactions.push(tx);
+			}
+		}
+		self.tx_log.sort_by_key(|entry| entry.id);
+		self.rx_log.sort_by_key(|entry| entry.id);
+		self.transactions.sort_by_key(|tx| tx.id);
+	}
+
+	/// Allows the reading and writing of the wallet data within a file lock.
+	/// Just provide a closure taking a mutable WalletData. The lock should
+	/// be held for as short a period as possible to avoid contention. A
+	/// closure that panics leaves the data file untouched, whatever it
+	/// changed is dropped, the lock is released and the panic resurfaces as
+	/// a WalletCorrupted error.
+	/// Note that due to the impossibility to do an actual file lock easily
+	/// across operating systems, this just creates a lock file with a "should
+	/// not exist" option. The lock file holds the pid of its owner and its
+	/// host, a lock left behind by a process that died is taken over (see
+	/// set_data_lock_max_age for those that can't be checked). The data file
+	/// is encrypted if a passphrase was set with set_data_passphrase. Reads
+	/// changing nothing should go through read_wallet instead, though the
+	/// data file is only written back if the closure changed the data (see
+	/// needs_write).
+	pub fn with_wallet<T, F>(data_file_dir: &str, f: F) -> Result<T, Error>
+	where
+		F: FnOnce(&mut WalletData) -> T,
+	{
+		// create directory if it doesn't exist
+		fs::create_dir_all(data_file_dir).unwrap_or_else(|why| {
+			info!(LOGGER, "! {:?}", why.kind());
+		});
+
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		let lock_file_path = &WalletData::acquire_data_lock(data_file_dir)?;
+
+		// We successfully acquired the lock - so do what needs to be done.
+		let cipher = data_cipher();
+		let cipher = cipher.as_ref().map(|c| &**c);
+		let res = WalletData::read_or_create(data_file_path, cipher).and_then(|mut wdat| {
+			let before = serde_json::to_vec(&wdat).ok();
+			let res = panic::catch_unwind(AssertUnwindSafe(|| f(&mut wdat))).map_err(|cause| {
+				let cause = panic_message(cause);
+				error!(
+					LOGGER,
+					"Wallet data change panicked, {} left as is: {}",
+					data_file_path,
+					cause
+				);
+				Error::WalletCorrupted(format!(
+					"Wallet data change aborted, nothing was written: {}",
+					cause
+				))
+			})?;
+			if wdat.needs_write(data_file_path, cipher, before) {
+				wdat.write(data_file_path, cipher)?;
+			} else {
+				debug!(LOGGER, "Wallet data unchanged, {} left as is", data_file_path);
+			}
+			Ok(res)
+		});
+
+		// delete the lock file, even if the data couldn't be read or written
+		WalletData::release_data_lock(lock_file_path)?;
+		res
+	}
+
+	// Whether the data has to be written back to the data file, given how it
+	// serialized before it was handed out for changes: if it changed since,
+	// or the data file is missing, in an older version or not encrypted
+	// while it should be. Comparing the serialized data catches changes made
+	// to the fields directly too, not only through the methods.
+	fn needs_write(
+		&self,
+		data_file_path: &str,
+		cipher: Option<&DataCipher>,
+		before: Option<Vec<u8>>,
+	) -> bool {
+		let changed = match before {
+			Some(before) => serde_json::to_vec(self).ok() != Some(before),
+			None => true,
+		};
+		changed || self.migrated_from.is_some() || self.deduped
+			|| !Path::new(data_file_path).exists()
+			|| (cipher.is_some() && !is_encrypted_file(data_file_path))
+	}
+
+	// Acquires the lock of the data file, retrying for a little while if
+	// another process holds it, and returns the path of the lock file.
+	fn acquire_data_lock(data_file_dir: &str) -> Result<String, Error> {
+		let lock_file_path = format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, LOCK_FILE);
+
+		info!(LOGGER, "Acquiring wallet lock ...");
+
+		let max_age = data_lock_max_age();
+		let acquire = || {
+			debug!(LOGGER, "Attempting to acquire wallet lock");
+			acquire_lock_file(Path::new(&lock_file_path), "data file", |owner| {
+				owner.stale(max_age)
+			})
+		};
+
+		// use tokio_retry to cleanly define some retry logic
+		let mut core = reactor::Core::new().unwrap();
+		let retry_strategy = FibonacciBackoff::from_millis(10).take(10);
+		let retry_future = Retry::spawn(core.handle(), retry_strategy, &acquire);
+		let retry_result = core.run(retry_future);
+
+		if retry_result.is_err() {
+			error!(
+				LOGGER,
+				"Failed to acquire wallet lock file (multiple retries)",
+			);
+			// a last attempt, telling who holds it
+			acquire()?;
+		}
+		Ok(lock_file_path.clone())
+	}
+
+	fn release_data_lock(lock_file_path: &str) -> Result<(), Error> {
+		fs::remove_file(lock_file_path).map_err(|_| {
+			Error::WalletData(format!(
+				"Could not remove wallet lock file. Maybe insufficient rights?"
+			))
+		})?;
+
+		info!(LOGGER, "... released wallet lock");
+		Ok(())
+	}
+
+	/// Copies the data file to a new backup in dest_dir, the backups
+	/// directory of the data directory by default, named after when it was
+	/// taken. The copy is made under the data lock so it's consistent, and
+	/// stays encrypted if the data file is.
+	pub fn backup(config: &WalletConfig, dest_dir: Option<&str>) -> Result<WalletBackup, Error> {
+		let data_file_dir = &config.data_file_dir;
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		if !Path::new(data_file_path).exists() {
+			return Err(Error::WalletData(format!(
+				"No wallet data to back up in {}",
+				data_file_dir
+			)));
+		}
+		let dest_dir = backup_dir(config, dest_dir);
+		let lock_file_path = &WalletData::acquire_data_lock(data_file_dir)?;
+		let res = copy_to_backup(data_file_path, &dest_dir);
+		WalletData::release_data_lock(lock_file_path)?;
+		let backup = res?;
+		info!(LOGGER, "Wallet data backed up to {}", backup.path.display());
+		Ok(backup)
+	}
+
+	/// Backups found in dir, the backups directory of the data directory by
+	/// default, oldest first.
+	pub fn list_backups(
+		config: &WalletConfig,
+		dir: Option<&str>,
+	) -> Result<Vec<WalletBackup>, Error> {
+		backups_in(&backup_dir(config, dir))
+	}
+
+	/// Replaces the data file with the backup at the provided path. The
+	/// backup must be readable and its outputs derived from the keychain, a
+	/// backup of another wallet is refused. The data file replaced is kept
+	/// as a backup of its own, returned if there was one.
+	pub fn restore_backup(
+		config: &WalletConfig,
+		keychain: &keychain::Keychain,
+		path: &Path,
+	) -> Result<Option<WalletBackup>, Error> {
+		let path_str = path.to_str()
+			.ok_or_else(|| Error::WalletData(format!("Invalid backup path {}", path.display())))?;
+		let cipher = data_cipher();
+		let cipher = cipher.as_ref().map(|c| &**c);
+		let mut restored = WalletData::read(path_str, cipher)?;
+		// the backup is the copy of the version it's in, the data file it
+		// replaces is kept as a backup of its own
+		restored.migrated_from = None;
+		restored.deduped = false;
+		let root_key_id = keychain.root_key_id();
+		if let Some(out) = restored.outputs.values().find(|out| out.root_key_id != root_key_id) {
+			return Err(Error::BackupMismatch {
+				ours: root_key_id,
+				theirs: out.root_key_id.clone(),
+			});
+		}
+
+		let data_file_dir = &config.data_file_dir;
+		fs::create_dir_all(data_file_dir)?;
+		let data_file_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, DAT_FILE);
+		let lock_file_path = &WalletData::acquire_data_lock(data_file_dir)?;
+		let res = if Path::new(data_file_path).exists() {
+			copy_to_backup(data_file_path, &backup_dir(config, None)).map(Some)
+		} else {
+			Ok(None)
+		};
+		let res = res.and_then(|replaced| {
+			restored.write(data_file_path, cipher)?;
+			Ok(replaced)
+		});
+		WalletData::release_data_lock(lock_file_path)?;
+		let replaced = res?;
+		info!(
+			LOGGER,
+			"Wallet data restored from {}, {} outputs",
+			path.display(),
+			restored.outputs.len()
+		);
+		Ok(replaced)
+	}
+
+	/// Read the wallet data or created a brand new one if it doesn't exist yet.
+	/// A data file cut short, as left by a crash of older wallets writing it
+	/// in place, falls back to the backup of the previous one. If that can't
+	/// be read either the error is the one of the data file. A data file that
+	/// was written whole but doesn't match its checksum or doesn't decrypt
+	/// has been corrupted since, it's an error telling the backups that can
+	/// be restored, the previous data file among them.
+	fn read_or_create(
+		data_file_path: &str,
+		cipher: Option<&DataCipher>,
+	) -> Result<WalletData, Error> {
+		if Path::new(data_file_path).exists() {
+			let bak_file_path = &format!("{}{}", data_file_path, BAK_EXT);
+			match WalletData::read(data_file_path, cipher) {
+				Err(Error::WalletCorrupted(cause)) => {
+					if !Path::new(bak_file_path).exists() {
+						return Err(Error::WalletCorrupted(cause));
+					}
+					if !torn_write(data_file_path) {
+						return Err(Error::WalletCorrupted(format!(
+							"{}, or the previous data file with \"grin wallet restore_backup {}\"",
+							cause,
+							bak_file_path
+						)));
+					}
+					warn!(LOGGER, "{}, falling back to the backup {}", cause, bak_file_path);
+					WalletData::read(bak_file_path, cipher).map_err(|bak_e| {
+						warn!(LOGGER, "{}", bak_e);
+						Error::WalletCorrupted(cause)
+					})
+				}
+				res => res,
+			}
+		} else {
+			// just create a new instance, it will get written afterward
+			Ok(WalletData {
+				version: DATA_FILE_VERSION,
+				outputs: HashMap::new(),
+				tx_log: vec![],
+				rx_log: vec![],
+				transactions: vec![],
+				invoices: vec![],
+				received_txs: vec![],
+				restored_height: 0,
+				archived: ArchivedIds::default(),
+				migrated_from: None,
+				deduped: false,
+			})
+		}
+	}
+
+	/// Read the wallet data from disk, decrypting it if it's encrypted. A
+	/// plaintext file is read as is, even with a cipher, to be encrypted when
+	/// written again. A file of an older version is migrated to the current
+	/// one, a copy of it kept once it's written back, and one of a newer
+	/// version is refused. A file that isn't JSON, doesn't decrypt or
+	/// doesn't match its checksum is corrupted, told apart from one of a
+	/// newer version. Nothing is written to disk.
+	fn read(data_file_path: &str, cipher: Option<&DataCipher>) -> Result<WalletData, Error> {
+		let mut data = vec![];
+		File::open(data_file_path)
+			.and_then(|mut data_file| data_file.read_to_end(&mut data))
+			.map_err(|e| Error::WalletData(format!("Could not open {}: {}", data_file_path, e)))?;
+		let data = match (is_encrypted(&data), cipher) {
+			(true, Some(cipher)) => cipher.decrypt(&data).map_err(|e| match e {
+				Error::WrongPassphrase => e,
+				_ => corrupted(data_file_path, "it can't be decrypted"),
+			})?,
+			(true, None) => {
+				return Err(Error::WalletData(format!(
+					"{} is encrypted, its passphrase is needed",
+					data_file_path
+				)))
+			}
+			(false, Some(_)) => {
+				info!(LOGGER, "{} isn't encrypted yet, it will be once written", data_file_path);
+				data
+			}
+			(false, None) => data,
+		};
+		let reading_failed = |e: serde_json::Error| {
+			Error::WalletData(format!("Error reading {}: {}", data_file_path, e))
+		};
+		let mut json: serde_json::Value = serde_json::from_slice(&data).map_err(|e| {
+			corrupted(data_file_path, &format!("it isn't valid JSON ({})", e))
+		})?;
+		// checked before the version, a damaged one isn't taken for a newer
+		let checksum = json.as_object_mut().and_then(|json| json.remove(CHECKSUM_FIELD));
+		if let Some(ref checksum) = checksum {
+			let expected = data_checksum(&json)?;
+			if checksum.as_str() != Some(expected.as_str()) {
+				return Err(corrupted(data_file_path, "its checksum doesn't match its content"));
+			}
+		}
+		let version = match json.get("version") {
+			None => 0,
+			Some(version) => version.as_u64().ok_or_else(|| {
+				Error::WalletData(format!("Invalid version in {}", data_file_path))
+			})?,
+		};
+		if version > DATA_FILE_VERSION as u64 {
+			return Err(Error::DataFileVersion {
+				ours: DATA_FILE_VERSION,
+				theirs: min(version, u32::MAX as u64) as u32,
+			});
+		}
+		if checksum.is_none() && version >= CHECKSUM_VERSION as u64 {
+			return Err(corrupted(data_file_path, "its checksum is missing"));
+		}
+		let migrated_from = if version < DATA_FILE_VERSION as u64 {
+			migrate_data(&mut json, version as u32).map_err(|e| {
+				Error::WalletData(format!("Could not migrate {}: {}", data_file_path, e))
+			})?;
+			info!(
+				LOGGER,
+				"{} migrated from version {} to {}",
+				data_file_path,
+				version,
+				DATA_FILE_VERSION
+			);
+			Some(version as u32)
+		} else {
+			None
+		};
+		let mut wdat: WalletData = serde_json::from_value(json).map_err(&reading_failed)?;
+		wdat.migrated_from = migrated_from;
+		if wdat.dedupe().changed() {
+			warn!(LOGGER, "{} tracked outputs more than once, merged", data_file_path);
+			wdat.deduped = true;
+		}
+		Ok(wdat)
+	}
+
+	/// Write the wallet data to disk. The data file is never written in
+	/// place: the data goes to a temporary file first, renamed over the data
+	/// file once all of it is on disk, so a crash at any point leaves either
+	/// the previous data or the new one. The previous data file is kept as a
+	/// backup, unless it's a plaintext one getting encrypted. A data file
+	/// read in an older version is copied first, see keep_before_migration.
+	fn write(&self, data_file_path: &str, cipher: Option<&DataCipher>) -> Result<(), Error> {
+		if let Some(version) = self.migrated_from {
+			if Path::new(data_file_path).exists() {
+				keep_before_migration(data_file_path, version)?;
+			}
+		}
+		if self.deduped && Path::new(data_file_path).exists() {
+			keep_before_dedupe(data_file_path)?;
+		}
+		let tmp_file_path = self.write_tmp(data_file_path, cipher)?;
+		if cipher.is_some() && !is_encrypted_file(data_file_path) {
+			let bak_file_path = format!("{}{}", data_file_path, BAK_EXT);
+			if Path::new(&bak_file_path).exists() {
+				fs::remove_file(&bak_file_path)?;
+			}
+		} else if Path::new(data_file_path).exists() {
+			backup_data_file(data_file_path)?;
+		}
+		fs::rename(&tmp_file_path, data_file_path).map_err(|e| {
+			Error::WalletData(format!("Could not replace {}: {}", data_file_path, e))
+		})?;
+		sync_parent_dir(data_file_path);
+		Ok(())
+	}
+
+	// Writes the wallet data to the temporary file next to the data file,
+	// encrypted if there's a cipher and synced to disk, returning its path.
+	// The checksum it holds is computed over the JSON written, as it will be
+	// read back.
+	fn write_tmp(
+		&self,
+		data_file_path: &str,
+		cipher: Option<&DataCipher>,
+	) -> Result<String, Error> {
+		let tmp_file_path = format!("{}{}", data_file_path, TMP_EXT);
+		let serializing_failed = |e: serde_json::Error| {
+			Error::WalletData(format!("Error serializing wallet data: {}", e))
+		};
+		let mut json = serde_json::to_value(self).map_err(&serializing_failed)?;
+		let checksum = data_checksum(&json)?;
+		if let Some(json) = json.as_object_mut() {
+			json.insert(CHECKSUM_FIELD.to_string(), serde_json::Value::from(checksum));
+		}
+		let mut res_json = serde_json::to_vec_pretty(&json).map_err(&serializing_failed)?;
+		if let Some(cipher) = cipher {
+			res_json = cipher.encrypt(&res_json)?;
+		}
+		let mut tmp_file = File::create(&tmp_file_path).map_err(|e| {
+			Error::WalletData(format!("Could not create {}: {}", tmp_file_path, e))
+		})?;
+		tmp_file
+			.write_all(res_json.as_slice())
+			.and_then(|_| tmp_file.sync_all())
+			.map_err(|e| Error::WalletData(format!("Error writing {}: {}", tmp_file_path, e)))?;
+		Ok(tmp_file_path)
+	}
+
+	/// Append a new output data to the wallet data. An output already
+	/// tracked under the same identifier is never overwritten, its key was
+	/// handed out twice, told apart when the one tracked has another value.
+	pub fn add_output(&mut self, out: OutputData) -> Result<(), Error> {
+		match self.outputs.entry(out.key_id.to_hex()) {
+			Entry::Occupied(entry) => {
+				let tracked = entry.get().value;
+				if tracked != out.value {
+					return Err(Error::ConflictingOutput {
+						key_id: out.key_id,
+						tracked: tracked,
+						value: out.value,
+					});
+				}
+				Err(Error::DuplicateOutput(out.key_id))
+			}
+			Entry::Vacant(entry) => {
+				entry.insert(out);
+				Ok(())
+			}
+		}
+	}
+
+	/// Merges the entries tracking the same output, as earlier versions
+	/// could leave under names differing from its identifier, so it isn't
+	/// counted more than once. The entry of the most advanced status is kept,
+	/// at the highest height of them all, with a label or account only
+	/// another entry has. Entries of different values are left as they are,
+	/// only one of them can make the commitment of the key. Outputs tracked
+	/// under another name than their identifier are tracked under it. Run
+	/// whenever the data is read.
+	pub fn dedupe(&mut self) -> DedupeSummary {
+		let mut by_id: HashMap<String, Vec<(String, OutputData)>> = HashMap::new();
+		for (name, out) in self.outputs.drain() {
+			by_id
+				.entry(out.key_id.to_hex())
+				.or_insert(vec![])
+				.push((name, out));
+		}
+		let mut ids = by_id.keys().cloned().collect::<Vec<_>>();
+		ids.sort();
+
+		let mut summary = DedupeSummary::default();
+		for id in ids {
+			let mut entries = by_id.remove(&id).unwrap();
+			entries.sort_by(|a, b| a.0.cmp(&b.0));
+			let key_id = entries[0].1.key_id.clone();
+			let value = entries[0].1.value;
+			if entries.iter().any(|&(_, ref out)| out.value != value) {
+				warn!(
+					LOGGER,
+					"Output {} tracked {} times with different values, left as is",
+					key_id,
+					entries.len()
+				);
+				self.outputs.extend(entries);
+				summary.conflicting.push(key_id);
+				continue;
+			}
+			if entries.len() == 1 {
+				let (name, out) = entries.pop().unwrap();
+				if name != id {
+					info!(LOGGER, "Output {} tracked as {}, renamed", key_id, name);
+					summary.renamed.push(key_id);
+				}
+				self.outputs.insert(id, out);
+				continue;
+			}
+
+			// ties go to the entry named after the identifier
+			let kept = (0..entries.len())
+				.max_by_key(|&i| (entries[i].1.status.progress(), entries[i].0 == id))
+				.unwrap();
+			let (_, mut out) = entries.remove(kept);
+			for &(_, ref other) in &entries {
+				out.height = max(out.height, other.height);
+				if out.label.is_none() {
+					out.label = other.label.clone();
+				}
+				if out.account.is_none() {
+					out.account = other.account.clone();
+				}
+			}
+			let merged = MergedOutput {
+				key_id: key_id,
+				entries: entries.len() + 1,
+				status: out.status.clone(),
+				height: out.height,
+			};
+			info!(
+				LOGGER,
+				"Output {} tracked {} times, merged as {} at height {}",
+				merged.key_id,
+				merged.entries,
+				merged.status,
+				merged.height
+			);
+			self.outputs.insert(id, out);
+			summary.merged.push(merged);
+		}
+		summary
+	}
+
+	/// Tracks the output in place of the one with the same identifier, only
+	/// valid for an unconfirmed coinbase built again for a new block.
+	pub fn replace_output(&mut self, out: OutputData) {
+		self.outputs.insert(out.key_id.to_hex(), out);
+	}
+
+	/// Moves the output to the status, if it can go there from the one it's
+	/// in (see OutputStatus::can_transition_to). The reason is logged along
+	/// with the transition.
+	pub fn set_status(
+		&mut self,
+		key_id: &keychain::Identifier,
+		status: OutputStatus,
+		reason: &str,
+	) -> Result<(), Error> {
+		let out = self.outputs
+			.get_mut(&key_id.to_hex())
+			.ok_or(Error::UnknownOutput(key_id.clone()))?;
+		if !out.status.can_transition_to(&status) {
+			warn!(
+				LOGGER,
+				"Refused to move output {} from {} to {}: {}",
+				key_id,
+				out.status,
+				status,
+				reason
+			);
+			return Err(Error::IllegalTransition {
+				key_id: key_id.clone(),
+				from: out.status.clone(),
+				to: status,
+			});
+		}
+		if out.status != status {
+			debug!(
+				LOGGER,
+				"Output {} from {} to {}: {}", key_id, out.status, status, reason
+			);
+			out.status = status;
+		}
+		Ok(())
+	}
+
+	/// Moves the output to the status whatever the one it's in, for what the
+	/// normal course of things doesn't allow: a reorganization of the chain
+	/// dropping the block an output was confirmed or spent in, or a coin
+	/// never confirmed unlocked by a cancelled send.
+	pub fn force_status(
+		&mut self,
+		key_id: &keychain::Identifier,
+		status: OutputStatus,
+		reason: &str,
+	) -> Result<(), Error> {
+		let out = self.outputs
+			.get_mut(&key_id.to_hex())
+			.ok_or(Error::UnknownOutput(key_id.clone()))?;
+		if out.status != status {
+			warn!(
+				LOGGER,
+				"Output {} forced from {} to {}: {}", key_id, out.status, status, reason
+			);
+			out.status = status;
+		}
+		Ok(())
+	}
+
+	/// Unlock a previously locked output, putting it back as unspent (or
+	/// unconfirmed if it was never seen on chain). Outputs that aren't
+	/// locked are left alone.
+	pub fn unlock_output(&mut self, key_id: &keychain::Identifier) -> Result<(), Error> {
+		let confirmed = match self.outputs.get(&key_id.to_hex()) {
+			Some(out) if out.status == OutputStatus::Locked => out.height > 0,
+			_ => return Ok(()),
+		};
+		if confirmed {
+			self.set_status(key_id, OutputStatus::Unspent, "unlocked")?;
+		} else {
+			self.force_status(key_id, OutputStatus::Unconfirmed, "unlocked, never confirmed")?;
+		}
+		if let Some(out) = self.outputs.get_mut(&key_id.to_hex()) {
+			out.locked_at = 0;
+		}
+		Ok(())
+	}
+
+	/// Stop tracking an output we created that was never seen on chain,
+	/// outputs in any other state are left alone.
+	pub fn delete_pending_output(&mut self, key_id: &keychain::Identifier) {
+		let pending = self.outputs
+			.get(&key_id.to_hex())
+			.map(|out| out.status == OutputStatus::Pending)
+			.unwrap_or(false);
+		if pending {
+			self.outputs.remove(&key_id.to_hex());
+		}
+	}
+
+	/// Record a new send in the transaction log, returning the id of the
+	/// entry.
+	pub fn add_tx_log_entry(
+		&mut self,
+		amount: u64,
+		fee: u64,
+		dest: &str,
+		input_ids: Vec<keychain::Identifier>,
+		change_ids: Vec<keychain::Identifier>,
+		message: Option<String>,
+	) -> u32 {
+		let id = self.tx_log
+			.iter()
+			.map(|entry| entry.id)
+			.fold(self.archived.tx_log_id, max) + 1;
+		self.tx_log.push(TxLogEntry {
+			id: id,
+			created_at: now_secs(),
+			amount: amount,
+			fee: fee,
+			dest: dest.to_string(),
+			input_ids: input_ids,
+			change_ids: change_ids,
+			status: TxLogStatus::Pending,
+			message: message,
+			partial_tx: None,
+			contact: None,
+			tip_height: 0,
+			receiver_ack: None,
+			receiver_commit: None,
+		});
+		id
+	}
+
+	/// Record a payment received in the receive log, returning the id of the
+	/// entry.
+	pub fn add_rx_log_entry(
+		&mut self,
+		amount: u64,
+		key_id: keychain::Identifier,
+		message: Option<String>,
+		sender: Option<String>,
+		account: Option<String>,
+	) -> u32 {
+		let id = self.rx_log
+			.iter()
+			.map(|entry| entry.id)
+			.fold(self.archived.rx_log_id, max) + 1;
+		self.rx_log.push(RxLogEntry {
+			id: id,
+			created_at: now_secs(),
+			amount: amount,
+			key_id: key_id,
+			message: message,
+			sender: sender,
+			confirmed: false,
+			account: account,
+		});
+		id
+	}
+
+	/// Keeps track of an invoice we issued until it gets paid.
+	pub fn add_invoice(
+		&mut self,
+		root_key_id: keychain::Identifier,
+		key_id: keychain::Identifier,
+		n_child: u32,
+		amount: u64,
+		expiry_height: u64,
+	) {
+		self.invoices.push(Invoice {
+			key_id: key_id,
+			n_child: n_child,
+			root_key_id: Some(root_key_id),
+			amount: amount,
+			expiry_height: expiry_height,
+			created_at: now_secs(),
+		});
+	}
+
+	/// Find a transaction log entry either by its id or by the identifier
+	/// (in hex) of one of its change outputs.
+	pub fn find_tx_log_entry(&self, id_or_change: &str) -> Option<&TxLogEntry> {
+		match id_or_change.parse::<u32>() {
+			Ok(id) => self.tx_log.iter().find(|entry| entry.id == id),
+			Err(_) => self.tx_log.iter().find(|entry| {
+				entry
+					.change_ids
+					.iter()
+					.any(|change_id| change_id.to_hex() == id_or_change)
+			}),
+		}
+	}
+
+	/// Update the destination of the transaction log entry with the provided
+	/// id.
+	pub fn set_tx_log_dest(&mut self, id: u32, dest: &str) {
+		if let Some(entry) = self.tx_log.iter_mut().find(|entry| entry.id == id) {
+			entry.dest = dest.to_string();
+		}
+	}
+
+	/// Record the context of the send of the transaction log entry with the
+	/// provided id, for audits: the contact it went to, the tip height when
+	/// it was posted and the acknowledgement of the receiver.
+	pub fn set_tx_log_context(
+		&mut self,
+		id: u32,
+		contact: Option<String>,
+		tip_height: u64,
+		receiver_ack: Option<String>,
+	) {
+		if let Some(entry) = self.tx_log.iter_mut().find(|entry| entry.id == id) {
+			entry.contact = contact;
+			entry.tip_height = tip_height;
+			entry.receiver_ack = receiver_ack;
+		}
+	}
+
+	/// Record the commitment of the output the receiver of the send of the
+	/// transaction log entry with the provided id got paid with.
+	pub fn set_tx_log_receiver_commit(&mut self, id: u32, commit: Option<String>) {
+		if let Some(entry) = self.tx_log.iter_mut().find(|entry| entry.id == id) {
+			entry.receiver_commit = commit;
+		}
+	}
+
+	/// Keep the partial transaction of the transaction log entry with the
+	/// provided id.
+	pub fn set_tx_log_partial_tx(&mut self, id: u32, partial_tx: JSONPartialTx) {
+		if let Some(entry) = self.tx_log.iter_mut().find(|entry| entry.id == id) {
+			entry.partial_tx = Some(partial_tx);
+		}
+	}
+
+	/// Update the status of the transaction log entry with the provided id.
+	pub fn set_tx_log_status(&mut self, id: u32, status: TxLogStatus) {
+		if let Some(entry) = self.tx_log.iter_mut().find(|entry| entry.id == id) {
+			if status != TxLogStatus::Pending {
+				entry.partial_tx = None;
+			}
+			entry.status = status;
+		}
+	}
+
+	/// Mark pending sends as confirmed once their change made it on chain.
+	/// Sends without change are confirmed when all their inputs are spent.
+	pub fn confirm_tx_log(&mut self) {
+		let outputs = &self.outputs;
+		let on_chain = |key_id: &keychain::Identifier, spent_only: bool| {
+			outputs
+				.get(&key_id.to_hex())
+				.map(|out| {
+					out.status == OutputStatus::Spent
+						|| (!spent_only && out.status == OutputStatus::Unspent)
+				})
+				.unwrap_or(false)
+		};
+		for entry in self.tx_log.iter_mut() {
+			if entry.status != TxLogStatus::Pending {
+				continue;
+			}
+			let confirmed = if entry.change_ids.is_empty() {
+				entry.input_ids.iter().all(|key_id| on_chain(key_id, true))
+			} else {
+				entry.change_ids.iter().any(|key_id| on_chain(key_id, false))
+			};
+			if confirmed {
+				entry.status = TxLogStatus::Confirmed;
+				entry.partial_tx = None;
+			}
+		}
+	}
+
+	/// Mark received payments as confirmed once their output made it on chain.
+	pub fn confirm_rx_log(&mut self) {
+		let outputs = &self.outputs;
+		for entry in self.rx_log.iter_mut() {
+			let status = outputs.get(&entry.key_id.to_hex()).map(|out| out.status.clone());
+			match status {
+				Some(OutputStatus::Unspent)
+				| Some(OutputStatus::Locked)
+				| Some(OutputStatus::Spent) => entry.confirmed = true,
+				_ => {}
+			}
+		}
+	}
+
+	/// Record a new wallet transaction grouping the outputs it spends and
+	/// creates, linking those of them we track to it. Returns the id of the
+	/// transaction.
+	pub fn add_transaction(
+		&mut self,
+		direction: TxDirection,
+		amount: u64,
+		fee: u64,
+		input_ids: Vec<keychain::Identifier>,
+		output_ids: Vec<keychain::Identifier>,
+	) -> u32 {
+		let id = self.transactions
+			.iter()
+			.map(|tx| tx.id)
+			.fold(self.archived.tx_id, max) + 1;
+		for key_id in input_ids.iter().chain(output_ids.iter()) {
+			if let Some(out) = self.outputs.get_mut(&key_id.to_hex()) {
+				out.tx_id = Some(id);
+			}
+		}
+		self.transactions.push(WalletTransaction {
+			id: id,
+			direction: direction,
+			amount: amount,
+			fee: fee,
+			status: TxLogStatus::Pending,
+			input_ids: input_ids,
+			output_ids: output_ids,
+			log_id: None,
+			created_at: Timestamp::now(),
+		});
+		id
+	}
+
+	pub fn get_transaction(&self, id: u32) -> Option<&WalletTransaction> {
+		self.transactions.iter().find(|tx| tx.id == id)
+	}
+
+	/// The wallet transaction the output last took part in, see
+	/// OutputData::tx_id.
+	pub fn transaction_of(&self, key_id: &keychain::Identifier) -> Option<&WalletTransaction> {
+		self.outputs
+			.get(&key_id.to_hex())
+			.and_then(|out| out.tx_id)
+			.and_then(|id| self.get_transaction(id))
+	}
+
+	/// The wallet transaction of a send, from the id of its transaction log
+	/// entry.
+	pub fn transaction_of_send(&self, tx_log_id: u32) -> Option<&WalletTransaction> {
+		self.transactions
+			.iter()
+			.find(|tx| tx.direction == TxDirection::Sent && tx.log_id == Some(tx_log_id))
+	}
+
+	/// Link the wallet transaction with the provided id to its entry in the
+	/// transaction or receive log.
+	pub fn set_transaction_log_id(&mut self, id: u32, log_id: u32) {
+		if let Some(tx) = self.transactions.iter_mut().find(|tx| tx.id == id) {
+			tx.log_id = Some(log_id);
+		}
+	}
+
+	/// Undo a send that didn't make it on chain: its inputs are unlocked and
+	/// linked back to the transaction that created them, its change outputs
+	/// never seen on chain are forgotten. The transaction and its transaction
+	/// log entry get the status.
+	pub fn undo_send(&mut self, id: u32, status: TxLogStatus) -> Result<(), Error> {
+		let tx = match self.get_transaction(id) {
+			Some(tx) => tx.clone(),
+			None => return Err(Error::TxNotFound(id.to_string())),
+		};
+		for key_id in &tx.input_ids {
+			self.unlock_output(key_id)?;
+			let created_by = self.transactions
+				.iter()
+				.find(|created_by| created_by.output_ids.contains(key_id))
+				.map(|created_by| created_by.id);
+			if let Some(out) = self.outputs.get_mut(&key_id.to_hex()) {
+				if out.tx_id == Some(id) {
+					out.tx_id = created_by;
+				}
+			}
+		}
+		for key_id in &tx.output_ids {
+			self.delete_pending_output(key_id);
+		}
+		if let Some(tx) = self.transactions.iter_mut().find(|tx| tx.id == id) {
+			tx.status = status.clone();
+		}
+		if let Some(log_id) = tx.log_id {
+			self.set_tx_log_status(log_id, status);
+		}
+		Ok(())
+	}
+
+	/// Mark pending wallet transactions as confirmed once they made it on
+	/// chain, sends like their transaction log entries (see confirm_tx_log)
+	/// and payments received once their output did.
+	pub fn confirm_transactions(&mut self) {
+		let outputs = &self.outputs;
+		let status = |key_id: &keychain::Identifier| {
+			outputs.get(&key_id.to_hex()).map(|out| out.status.clone())
+		};
+		for tx in self.transactions.iter_mut() {
+			if tx.status != TxLogStatus::Pending {
+				continue;
+			}
+			let confirmed = match tx.direction {
+				TxDirection::Sent if tx.output_ids.is_empty() => tx.input_ids
+					.iter()
+					.all(|key_id| status(key_id) == Some(OutputStatus::Spent)),
+				TxDirection::Sent => tx.output_ids.iter().any(|key_id| match status(key_id) {
+					Some(OutputStatus::Unspent) | Some(OutputStatus::Spent) => true,
+					_ => false,
+				}),
+				TxDirection::Received => tx.output_ids.iter().any(|key_id| match status(key_id) {
+					Some(OutputStatus::Unspent)
+					| Some(OutputStatus::Locked)
+					| Some(OutputStatus::Spent) => true,
+					_ => false,
+				}),
+			};
+			if confirmed {
+				tx.status = TxLogStatus::Confirmed;
+			}
+		}
+	}
+
+	/// The partial transaction we received with this contribution hash, if
+	/// any.
+	pub fn find_received_tx(&self, hash: &str) -> Option<&ReceivedTx> {
+		self.received_txs.iter().find(|received| received.hash == hash)
+	}
+
+	/// Notes the completed transaction of a received partial transaction made
+	/// it to the node.
+	pub fn set_received_tx_pushed(&mut self, hash: &str) {
+		for received in self.received_txs.iter_mut() {
+			if received.hash == hash {
+				received.pushed = true;
+			}
+		}
+	}
+
+	/// Forgets the received partial transactions whose output confirmed, no
+	/// sender retries them anymore.
+	pub fn expire_received_txs(&mut self) {
+		let outputs = &self.outputs;
+		self.received_txs.retain(|received| {
+			outputs
+				.get(&received.key_id.to_hex())
+				.map(|out| out.status == OutputStatus::Unconfirmed)
+				.unwrap_or(false)
+		});
+	}
+
+	/// Lock an output data to avoid conflicting use, recording the height
+	/// it was locked at.
+	/// TODO - we should track identifier on these outputs (not just n_child)
+	pub fn lock_output(&mut self, out: &OutputData, current_height: u64) -> Result<(), Error> {
+		let same_value = self.outputs
+			.get(&out.key_id.to_hex())
+			.map(|out_to_lock| out_to_lock.value == out.value)
+			.unwrap_or(false);
+		if !same_value {
+			return Ok(());
+		}
+		self.set_status(&out.key_id, OutputStatus::Locked, "spent by a send")?;
+		if let Some(out_to_lock) = self.outputs.get_mut(&out.key_id.to_hex()) {
+			out_to_lock.locked_at = current_height;
+		}
+		Ok(())
+	}
+
+	pub fn get_output(&self, key_id: &keychain::Identifier) -> Option<&OutputData> {
+		self.outputs.get(&key_id.to_hex())
+	}
+
+	/// Tags the output with the label, none removing its label. Labels are
+	/// trimmed, an empty one also removing the label, and can't be longer
+	/// than MAX_LABEL_LEN bytes.
+	pub fn set_label(
+		&mut self,
+		key_id: &keychain::Identifier,
+		label: Option<String>,
+	) -> Result<(), Error> {
+		let label = match label.map(|label| label.trim().to_string()) {
+			Some(ref label) if label.len() > MAX_LABEL_LEN => {
+				return Err(Error::LabelTooLong {
+					len: label.len(),
+					max: MAX_LABEL_LEN,
+				})
+			}
+			Some(ref label) if label.is_empty() => None,
+			label => label,
+		};
+		let out = self.outputs
+			.get_mut(&key_id.to_hex())
+			.ok_or(Error::UnknownOutput(key_id.clone()))?;
+		out.label = label;
+		Ok(())
+	}
+
+	/// The output along with what another wallet needs to track it, see
+	/// import_output.
+	pub fn export_output(
+		&self,
+		keychain: &keychain::Keychain,
+		key_id: &keychain::Identifier,
+	) -> Result<OutputExport, Error> {
+		let out = self.get_output(key_id).ok_or(Error::UnknownOutput(key_id.clone()))?;
+		let commit = out.commit(keychain)?;
+		Ok(OutputExport {
+			output: out.clone(),
+			fingerprint: keychain.root_key_id(),
+			commit: util::to_hex(commit.as_ref().to_vec()),
+		})
+	}
+
+	/// Tracks an output exported by another wallet. One whose identifier
+	/// derives from the keychain at its child index is ours to spend, its
+	/// commitment has to be the one its key and value make. Any other is
+	/// only watched, never selected. Sends of the other wallet don't carry
+	/// over, the output isn't locked by any nor part of a wallet transaction.
+	/// An output already tracked is refused.
+	pub fn import_output(
+		&mut self,
+		keychain: &keychain::Keychain,
+		export: OutputExport,
+	) -> Result<OutputData, Error> {
+		let mut out = export.output;
+		if self.outputs.contains_key(&out.key_id.to_hex()) {
+			return Err(Error::DuplicateOutput(out.key_id));
+		}
+		let commit = commit_from_hex(&export.commit)?;
+		let ours = out.root_key_id == keychain.root_key_id()
+			&& keychain.derive_key_id(out.n_child)? == out.key_id;
+		if ours {
+			if keychain.commit(out.value, &out.key_id)? != commit {
+				return Err(Error::CommitmentMismatch(out.key_id));
+			}
+			out.watch_commit = None;
+		} else {
+			out.watch_commit = Some(export.commit);
+		}
+		if out.status == OutputStatus::Locked {
+			out.status = OutputStatus::Unspent;
+		}
+		out.locked_at = 0;
+		out.tx_id = None;
+		self.add_output(out.clone())?;
+		Ok(out)
+	}
+
+	/// The outputs matching the filter, sorted by derivation index, along
+	/// with their count and total value.
+	pub fn query(&self, filter: &OutputFilter) -> OutputQuery {
+		let mut outputs = self.outputs
+			.values()
+			.filter(|out| filter.matches(out))
+			.collect::<Vec<_>>();
+		outputs.sort_by(|a, b| {
+			a.root_key_id
+				.to_hex()
+				.cmp(&b.root_key_id.to_hex())
+				.then_with(|| a.n_child.cmp(&b.n_child))
+				.then_with(|| a.key_id.to_hex().cmp(&b.key_id.to_hex()))
+		});
+		let value = outputs
+			.iter()
+			.fold(0u64, |total, out| total.saturating_add(out.value));
+		OutputQuery {
+			count: outputs.len(),
+			outputs: outputs,
+			value: value,
+		}
+	}
+
+	/// Select spendable coins of the root key to cover the provided amount,
+	/// following the given strategy. Only the coins of eligible_coins are
+	/// considered, so never Locked, Spent or Pending ones. Confirmed coins are
+	/// preferred, the strategy only reaching for unconfirmed ones (eligible
+	/// with no minimum confirmations) to make up for what the confirmed ones
+	/// lack. Coins of the same value are taken oldest first, then by key_id,
+	/// so the same wallet always gives the same selection.
+	///
+	/// If the eligible coins can't cover the amount nothing is selected, the
+	/// error telling what they add up to. An amount of 0 is always covered,
+	/// with SelectionStrategy::All it sweeps every eligible coin.
+	pub fn select(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		minimum_confirmations: u64,
+		amount: u64,
+		strategy: SelectionStrategy,
+	) -> Result<Selection, SelectionError> {
+		let eligible = self.eligible_coins(root_key_id, current_height, minimum_confirmations);
+		let available = eligible
+			.iter()
+			.fold(0u64, |total, out| total.saturating_add(out.value));
+		if available < amount {
+			return Err(SelectionError::Insufficient {
+				available: available,
+				needed: amount,
+			});
+		}
+		Ok(Selection::new(select_confirmed_first(eligible, amount, strategy)))
+	}
+
+	/// Looks for eligible coins adding up exactly to what a send needs, so
+	/// it can do without a change output. As the fee grows with the number
+	/// of inputs, `target` gives the total needed for a number of them, None
+	/// if that can't be. Confirmed coins are tried on their own first, and no
+	/// more than `max_inputs` are spent. The search is bounded, missing a
+	/// match is possible.
+	pub fn select_exact<F>(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		minimum_confirmations: u64,
+		max_inputs: usize,
+		target: F,
+	) -> Option<Vec<OutputData>>
+	where
+		F: Fn(usize) -> Option<u64>,
+	{
+		let eligible = self.eligible_coins(root_key_id, current_height, minimum_confirmations);
+		let confirmed = eligible
+			.iter()
+			.filter(|out| out.status == OutputStatus::Unspent)
+			.cloned()
+			.collect::<Vec<_>>();
+		if let Some(coins) = find_exact(&confirmed, max_inputs, &target) {
+			return Some(coins);
+		}
+		if confirmed.len() == eligible.len() {
+			return None;
+		}
+		find_exact(&eligible, max_inputs, &target)
+	}
+
+	/// The coins of the root key that can be spent at the height with that
+	/// many confirmations, see OutputData::eligible_to_spend. They're sorted
+	/// by value, then height and key_id, so selection is deterministic.
+	pub fn eligible_coins(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		minimum_confirmations: u64,
+	) -> Vec<OutputData> {
+		let mut eligible = self.query(&OutputFilter::fingerprint(root_key_id))
+			.outputs
+			.into_iter()
+			.filter(|out| out.eligible_to_spend(current_height, minimum_confirmations))
+			.cloned()
+			.collect::<Vec<OutputData>>();
+		eligible.sort_by(|a, b| {
+			a.value
+				.cmp(&b.value)
+				.then_with(|| a.height.cmp(&b.height))
+				.then_with(|| a.key_id.to_hex().cmp(&b.key_id.to_hex()))
+		});
+		eligible
+	}
+
+	/// The balance of the outputs derived from the root key, of all the
+	/// outputs of the wallet without one, at the provided height, following
+	/// the same rules as coin selection.
+	pub fn balance(
+		&self,
+		fingerprint: Option<keychain::Identifier>,
+		current_height: u64,
+		minimum_confirmations: u64,
+	) -> Balance {
+		let filter = OutputFilter {
+			fingerprint: fingerprint,
+			..OutputFilter::default()
+		};
+		let mut balance = Balance::default();
+		for out in self.query(&filter).outputs {
+			let bucket = match out.spendability(current_height, minimum_confirmations) {
+				Spendability::Spendable => &mut balance.spendable,
+				Spendability::AwaitingConfirmation => &mut balance.awaiting_confirmation,
+				Spendability::Immature => &mut balance.immature_coinbase,
+				Spendability::HeightLocked => &mut balance.height_locked,
+				Spendability::Locked => &mut balance.locked,
+				Spendability::Spent | Spendability::WatchOnly => continue,
+			};
+			*bucket = bucket.saturating_add(out.value);
+			balance.total = balance.total.saturating_add(out.value);
+		}
+		balance
+	}
+
+	/// How the outputs derived from the root key, all the outputs of the
+	/// wallet without one, are spread by value and status at the provided
+	/// height. The outputs in the buckets are those the total of the balance
+	/// counts, what's spendable follows the same rules as coin selection.
+	pub fn stats(
+		&self,
+		fingerprint: Option<keychain::Identifier>,
+		current_height: u64,
+		minimum_confirmations: u64,
+	) -> OutputStats {
+		let filter = OutputFilter {
+			fingerprint: fingerprint,
+			..OutputFilter::default()
+		};
+		let mut stats = OutputStats::default();
+		let mut from = 0;
+		for &below in VALUE_BUCKETS.iter() {
+			stats.buckets.push(ValueBucket {
+				from: from,
+				below: Some(below),
+				..ValueBucket::default()
+			});
+			from = below;
+		}
+		stats.buckets.push(ValueBucket {
+			from: from,
+			..ValueBucket::default()
+		});
+
+		// ties broken by identifier, so the outcome doesn't depend on the
+		// order of the outputs
+		let order = |out: &OutputData| (out.value, out.key_id.to_hex());
+		for out in self.outputs.values().filter(|out| filter.matches(out)) {
+			if out.is_watch_only() {
+				stats.watch_only += 1;
+				continue;
+			}
+			match out.status {
+				OutputStatus::Pending => stats.pending += 1,
+				OutputStatus::Unconfirmed => stats.unconfirmed += 1,
+				OutputStatus::Unspent => stats.unspent += 1,
+				OutputStatus::Locked => stats.locked += 1,
+				OutputStatus::Spent => stats.spent += 1,
+			}
+			match out.spendability(current_height, minimum_confirmations) {
+				Spendability::Spent | Spendability::WatchOnly => continue,
+				Spendability::Spendable => {
+					if stats
+						.largest_spendable
+						.as_ref()
+						.map_or(true, |largest| order(out) > order(largest))
+					{
+						stats.largest_spendable = Some(out.clone());
+					}
+					if stats
+						.smallest_spendable
+						.as_ref()
+						.map_or(true, |smallest| order(out) < order(smallest))
+					{
+						stats.smallest_spendable = Some(out.clone());
+					}
+				}
+				_ => {}
+			}
+			let i = VALUE_BUCKETS.iter().take_while(|&&below| out.value >= below).count();
+			let bucket = &mut stats.buckets[i];
+			bucket.count += 1;
+			bucket.value = bucket.value.saturating_add(out.value);
+		}
+		stats
+	}
+
+	/// Total value of the outputs that would be spendable if they had
+	/// received enough confirmations, see balance.
+	pub fn unconfirmed_total(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+		minimum_confirmations: u64,
+	) -> u64 {
+		self.balance(Some(root_key_id), current_height, minimum_confirmations)
+			.awaiting_confirmation
+	}
+
+	/// Total value of the outputs received into the account (none for the
+	/// default one), confirmed or not, that no send spent or locked.
+	pub fn account_total(&self, root_key_id: keychain::Identifier, account: Option<&str>) -> u64 {
+		self.outputs
+			.values()
+			.filter(|out| {
+				out.root_key_id == root_key_id
+					&& [OutputStatus::Unconfirmed, OutputStatus::Unspent].contains(&out.status)
+					&& out.account.as_ref().map(|a| a.as_str()) == account
+			})
+			.fold(0, |total: u64, out| total.saturating_add(out.value))
+	}
+
+	/// Total value of the confirmed coinbase outputs that can't be spent
+	/// before they mature, see balance.
+	pub fn immature_total(&self, root_key_id: keychain::Identifier, current_height: u64) -> u64 {
+		self.balance(Some(root_key_id), current_height, 0).immature_coinbase
+	}
+
+	/// Total value of the outputs that can't be spent before the chain
+	/// reaches their lock height. Coinbase outputs are held back by their
+	/// maturity instead, see balance.
+	pub fn height_locked_total(
+		&self,
+		root_key_id: keychain::Identifier,
+		current_height: u64,
+	) -> u64 {
+		self.balance(Some(root_key_id), current_height, 0).height_locked
+	}
+
+	/// Next child index of the root key when we want to create a new output,
+	/// each root key counting its own: the outputs, archived outputs and
+	/// invoices of other root keys never move it, 1 for a root key the data
+	/// knows nothing of.
+	pub fn next_child(&self, root_key_id: keychain::Identifier) -> u32 {
+		// the keys of archived outputs were handed out already
+		let mut max_n = self.archived
+			.n_child
+			.get(&root_key_id.to_hex())
+			.cloned()
+			.unwrap_or(0);
+		for out in self.outputs.values() {
+			if max_n < out.n_child && out.root_key_id == root_key_id {
+				max_n = out.n_child;
+			}
+		}
+		// the keys of unpaid invoices are taken as well
+		for invoice in &self.invoices {
+			match invoice.root_key_id {
+				Some(ref id) if *id != root_key_id => {}
+				_ => max_n = max(max_n, invoice.n_child),
+			}
+		}
+		max_n + 1
+	}
+
+	/// Next child index reserve_children would hand out for the root key,
+	/// without reserving it: past both the data and the counter file of the
+	/// data directory.
+	pub fn peek_child(
+		&self,
+		data_file_dir: &str,
+		root_key_id: keychain::Identifier,
+	) -> Result<u32, Error> {
+		let counter_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, CHILD_COUNTER_FILE);
+		let counter = read_child_counter(counter_path)?;
+		let counted = counter.get(&root_key_id.to_hex()).map(|n| n + 1).unwrap_or(0);
+		Ok(max(self.next_child(root_key_id), counted))
+	}
+
+	/// Hands out `count` consecutive child indexes of the root key, returning
+	/// the first. The last one is durably written to the counter file of the
+	/// data directory before returning, so none of them is handed out again
+	/// even if the process dies before tracking the outputs they're used for.
+	/// When the counter and the data disagree, as after such a crash, the
+	/// highest index wins. Has to be called holding the data lock, from
+	/// within with_wallet.
+	pub fn reserve_children(
+		&self,
+		data_file_dir: &str,
+		root_key_id: keychain::Identifier,
+		count: usize,
+	) -> Result<u32, Error> {
+		let counter_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, CHILD_COUNTER_FILE);
+		let first = self.peek_child(data_file_dir, root_key_id.clone())?;
+		if count > 0 {
+			let mut counter = read_child_counter(counter_path)?;
+			counter.insert(root_key_id.to_hex(), first + count as u32 - 1);
+			write_child_counter(counter_path, &counter)?;
+		}
+		Ok(first)
+	}
+
+	/// Moves the child index counter of the keychain past the indexes found
+	/// in use, for data that lost track of some, as after a restore from the
+	/// seed or bringing back an old data file. The children are derived from
+	/// the first and checked against the outputs we track and whatever
+	/// on_chain knows, until gap_limit of them in a row past the highest in
+	/// use (or handed out) aren't. The counter then skips CHILD_SAFETY_MARGIN
+	/// indexes past the highest in use, and is never moved back. Returns the
+	/// next child index handed out. Has to be called holding the data lock,
+	/// from within with_wallet.
+	pub fn recover_next_child<F>(
+		&self,
+		data_file_dir: &str,
+		keychain: &keychain::Keychain,
+		gap_limit: u32,
+		mut on_chain: F,
+	) -> Result<u32, Error>
+	where
+		F: FnMut(&keychain::Identifier) -> Result<bool, Error>,
+	{
+		let root_key_id = keychain.root_key_id();
+		let counter_path = &format!("{}{}{}", data_file_dir, MAIN_SEPARATOR, CHILD_COUNTER_FILE);
+		let mut counter = read_child_counter(counter_path)?;
+		let key = root_key_id.to_hex();
+		let counted = counter.get(&key).cloned().unwrap_or(0);
+
+		let mut used = self.next_child(root_key_id) - 1;
+		let mut n_child = 0;
+		while n_child < max(used, counted).saturating_add(gap_limit) {
+			n_child += 1;
+			let key_id = keychain.derive_key_id(n_child)?;
+			if self.outputs.contains_key(&key_id.to_hex()) || on_chain(&key_id)? {
+				used = max(used, n_child);
+			}
+		}
+
+		let last = used.saturating_add(CHILD_SAFETY_MARGIN);
+		if last <= counted {
+			return Ok(counted + 1);
+		}
+		info!(
+			LOGGER,
+			"Child index {} found in use, handing out indexes from {} on",
+			used,
+			last + 1
+		);
+		counter.insert(key, last);
+		write_child_counter(counter_path, &counter)?;
+		Ok(last + 1)
+	}
+}
+
+// The last child index handed out for each root key, by its hex. A missing
+// counter file counts for nothing, the data file is relied on then. One that
+// can't be read is an error rather than taken as empty, which could hand out
+// indexes again.
+fn read_child_counter(path: &str) -> Result<HashMap<String, u32>, Error> {
+	let mut content = String::new();
+	if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut content)) {
+		if e.kind() == io::ErrorKind::NotFound {
+			return Ok(HashMap::new());
+		}
+		return Err(Error::WalletData(format!("Could not read {}: {}", path, e)));
+	}
+	let mut counter = HashMap::new();
+	for line in content.lines().filter(|line| !line.trim().is_empty()) {
+		let mut parts = line.split_whitespace();
+		match (parts.next(), parts.next().and_then(|n| n.parse::<u32>().ok()), parts.next()) {
+			(Some(key), Some(n), None) => {
+				counter.insert(key.to_string(), n);
+			}
+			_ => {
+				return Err(Error::WalletCorrupted(format!(
+					"{} is corrupted, {:?} isn't a root key and child index; remove it and \
+					 run \"grin wallet restore\" to find the indexes in use",
+					path, line
+				)))
+			}
+		}
+	}
+	Ok(counter)
+}
+
+// Replaces the counter file, through a temporary file synced to disk first so
+// a crash leaves either the previous counter or the new one.
+fn write_child_counter(path: &str, counter: &HashMap<String, u32>) -> Result<(), Error> {
+	let tmp_path = format!("{}{}", path, TMP_EXT);
+	let mut keys = counter.keys().collect::<Vec<_>>();
+	keys.sort();
+	let content = keys.iter()
+		.map(|key| format!("{} {}\n", key, counter[*key]))
+		.collect::<String>();
+	File::create(&tmp_path)
+		.and_then(|mut file| {
+			file.write_all(content.as_bytes())?;
+			file.sync_all()
+		})
+		.and_then(|_| fs::rename(&tmp_path, path))
+		.map_err(|e| Error::WalletData(format!("Could not write {}: {}", path, e)))?;
+	sync_parent_dir(path);
+	Ok(())
+}
+
+// Selects from eligible coins covering the amount following the strategy.
+fn select_confirmed_first(
+	eligible: Vec<OutputData>,
+	amount: u64,
+	strategy: SelectionStrategy,
+) -> Vec<OutputData> {
+	if strategy == SelectionStrategy::All {
+		return eligible;
+	}
+
+	// confirmed coins are spent first, unconfirmed ones only when needed
+	// as the transaction creating them may never make it to the chain
+	let (confirmed, unconfirmed): (Vec<_>, Vec<_>) = eligible
+		.into_iter()
+		.partition(|out| out.status == OutputStatus::Unspent);
+	let confirmed_total = confirmed
+		.iter()
+		.fold(0u64, |total, out| total.saturating_add(out.value));
+	if unconfirmed.is_empty() || (confirmed_total >= amount && !confirmed.is_empty()) {
+		return select_with_strategy(confirmed, amount, strategy);
+	}
+	let mut selected = confirmed;
+	selected.extend(select_with_strategy(
+		unconfirmed,
+		amount.saturating_sub(confirmed_total),
+		strategy,
+	));
+	selected
+}
+
+// Selects from coins sorted by value following the strategy.
+fn select_with_strategy(
+	coins: Vec<OutputData>,
+	amount: u64,
+	strategy: SelectionStrategy,
+) -> Vec<OutputData> {
+	match strategy {
+		SelectionStrategy::All => coins,
+		SelectionStrategy::SmallestFirst => select_until(coins.into_iter(), amount),
+		SelectionStrategy::LargestFirst => select_until(largest_first(coins).into_iter(), amount),
+		SelectionStrategy::Default => match coins.iter().position(|out| out.value >= amount) {
+			Some(pos) => vec![coins[pos].clone()],
+			None => select_until(largest_first(coins).into_iter(), amount),
+		},
+	}
+}
+
+// Coins sorted by value sorted again largest first, those of the same value
+// keeping their order (the sort is stable) rather than being reversed.
+fn largest_first(mut coins: Vec<OutputData>) -> Vec<OutputData> {
+	coins.sort_by(|a, b| b.value.cmp(&a.value));
+	coins
+}
+
+/// Most coins tried when looking for an exact match, past that the wallet
+/// settles for change.
+const EXACT_MATCH_TRIES: usize = 500;
+
+/// Depth first search of coins (sorted by value) adding up to the target,
+/// largest coins first. Coins of the same value are interchangeable, only
+/// the first is tried at each step, which keeps wallets of many same-valued
+/// coins cheap to search.
+fn find_exact<F>(
+	coins: &Vec<OutputData>,
+	max_inputs: usize,
+	target: &F,
+) -> Option<Vec<OutputData>>
+where
+	F: Fn(usize) -> Option<u64>,
+{
+	let coins = coins.iter().rev().collect::<Vec<_>>();
+	let max_inputs = min(max_inputs, coins.len());
+	let targets = (1..max_inputs + 1).filter_map(|n| target(n)).collect::<Vec<_>>();
+	let (lowest, highest) = match (targets.iter().min(), targets.iter().max()) {
+		(Some(&lowest), Some(&highest)) => (lowest, highest),
+		_ => return None,
+	};
+
+	// what's left to add from each position on, to give up on branches that
+	// can't reach the target anymore
+	let mut remaining = vec![0u64; coins.len() + 1];
+	for i in (0..coins.len()).rev() {
+		remaining[i] = remaining[i + 1].saturating_add(coins[i].value);
+	}
+
+	let mut picked = vec![];
+	let mut tries = 0;
+	let found = search_exact(
+		&coins,
+		&remaining,
+		0,
+		0,
+		max_inputs,
+		(lowest, highest),
+		target,
+		&mut picked,
+		&mut tries,
+	);
+	if found {
+		Some(picked.into_iter().map(|i| coins[i].clone()).collect())
+	} else {
+		None
+	}
+}
+
+fn search_exact<F>(
+	coins: &Vec<&OutputData>,
+	remaining: &Vec<u64>,
+	start: usize,
+	total: u64,
+	max_inputs: usize,
+	bounds: (u64, u64),
+	target: &F,
+	picked: &mut Vec<usize>,
+	tries: &mut usize,
+) -> bool
+where
+	F: Fn(usize) -> Option<u64>,
+{
+	if !picked.is_empty() && target(picked.len()) == Some(total) {
+		return true;
+	}
+	if picked.len() == max_inputs {
+		return false;
+	}
+	for i in start..coins.len() {
+		if total.saturating_add(remaining[i]) < bounds.0 || *tries >= EXACT_MATCH_TRIES {
+			return false;
+		}
+		if i > start && coins[i].value == coins[i - 1].value {
+			continue;
+		}
+		let next = match total.checked_add(coins[i].value) {
+			Some(next) if next <= bounds.1 => next,
+			_ => continue,
+		};
+		*tries += 1;
+		picked.push(i);
+		if search_exact(coins, remaining, i + 1, next, max_inputs, bounds, target, picked, tries) {
+			return true;
+		}
+		picked.pop();
+	}
+	false
+}
+
+/// Takes coins in order until their total covers the amount.
+fn select_until<I>(coins: I, amount: u64) -> Vec<OutputData>
+where
+	I: Iterator<Item = OutputData>,
+{
+	let mut selected = vec![];
+	let mut total = 0;
+	for coin in coins {
+		if total >= amount && !selected.is_empty() {
+			break;
+		}
+		// past u64::MAX any amount is covered, the caller will find out the
+		// total doesn't fit
+		total = total.saturating_add(coin.value);
+		selected.push(coin);
+	}
+	selected
+}
+
+/// Helper in serializing the information a receiver requires to build a
+/// transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JSONPartialTx {
+	// absent before version 1
+	#[serde(default)]
+	version: u16,
+	// what the receiver's output is built from, the fee is deducted from it
+	amount: u64,
+	// from version 1, spelled out so the receiver doesn't have to dig them
+	// out of the transaction
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	fee: Option<u64>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	lock_height: Option<u64>,
+	blind_sum: String,
+	tx: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	message: Option<String>,
+	// whether the receiver pushing the completed transaction should have the
+	// node broadcast it right away rather than relay it along a stem, none
+	// leaving it to the receiver
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	fluff: Option<bool>,
+}
+
+impl JSONPartialTx {
+	/// Version of the format of the partial transaction.
+	pub fn version(&self) -> u16 {
+		self.version
+	}
+
+	/// Message the sender attached to the partial transaction, if any.
+	pub fn message(&self) -> Option<&str> {
+		self.message.as_ref().map(|m| m.as_str())
+	}
+
+	/// How the sender would like the completed transaction relayed, if it
+	/// cares.
+	pub fn fluff(&self) -> Option<bool> {
+		self.fluff
+	}
+
+	/// Asks the receiver to push the completed transaction with fluff, or
+	/// along a stem, whatever it's configured with.
+	pub fn with_fluff(self, fluff: Option<bool>) -> JSONPartialTx {
+		JSONPartialTx {
+			fluff: fluff,
+			..self
+		}
+	}
+
+	/// Hash, hex encoded, of what the sender contributes: its blinding
+	/// factors and transaction. The same for every retry of a send, whatever
+	/// the version or message.
+	pub fn contribution(&self) -> String {
+		let mut data = self.blind_sum.as_bytes().to_vec();
+		data.extend_from_slice(self.tx.as_bytes());
+		util::to_hex(blake2::blake2b::blake2b(32, &[], &data).as_bytes().to_vec())
+	}
+
+	/// Encodes the partial transaction on a single line safe to paste
+	/// around: the prefix followed by the url-safe base64 of the length of
+	/// the JSON, the JSON itself and a checksum of both.
+	pub fn to_compact(&self) -> String {
+		let json = serde_json::to_vec(self).unwrap();
+		let mut data = vec![0; 4];
+		BigEndian::write_u32(&mut data, json.len() as u32);
+		data.extend_from_slice(&json);
+		let checksum = compact_checksum(&data);
+		data.extend_from_slice(&checksum);
+		format!(
+			"{}{}",
+			COMPACT_PARTIAL_TX_PREFIX,
+			base64::encode_config(&data, base64::URL_SAFE_NO_PAD)
+		)
+	}
+
+	/// Decodes a partial transaction encoded by `to_compact`. Whitespace
+	/// is ignored, as chat clients like to wrap long lines.
+	pub fn from_compact(compact: &str) -> Result<JSONPartialTx, Error> {
+		let compact = compact.trim();
+		if !compact.starts_with(COMPACT_PARTIAL_TX_PREFIX) {
+			return Err(Error::Format(format!(
+				"Compact partial transaction should start with {}",
+				COMPACT_PARTIAL_TX_PREFIX
+			)));
+		}
+		let mut encoded = compact[COMPACT_PARTIAL_TX_PREFIX.len()..]
+			.chars()
+			.filter(|c| !c.is_whitespace())
+			.collect::<String>();
+		// a lone trailing character can't encode anything, what follows it
+		// was cut
+		if encoded.len() % 4 == 1 {
+			encoded.pop();
+		}
+		let data = base64::decode_config(&encoded, base64::URL_SAFE_NO_PAD).map_err(|e| {
+			Error::Format(format!("Invalid compact partial transaction: {}", e))
+		})?;
+
+		let header_len = 4 + COMPACT_CHECKSUM_LEN;
+		if data.len() < header_len {
+			return Err(Error::CompactTxTruncated {
+				len: data.len(),
+				expected: header_len,
+			});
+		}
+		let expected = header_len + BigEndian::read_u32(&data[..4]) as usize;
+		if data.len() < expected {
+			return Err(Error::CompactTxTruncated {
+				len: data.len(),
+				expected: expected,
+			});
+		}
+		if data.len() > expected {
+			return Err(Error::Format(format!(
+				"Compact partial transaction has {} bytes past its end",
+				data.len() - expected
+			)));
+		}
+		let (content, checksum) = data.split_at(expected - COMPACT_CHECKSUM_LEN);
+		if compact_checksum(content) != checksum {
+			return Err(Error::CompactTxChecksum);
+		}
+		Ok(serde_json::from_slice(&content[4..])?)
+	}
+}
+
+fn compact_checksum(data: &[u8]) -> Vec<u8> {
+	blake2::blake2b::blake2b(COMPACT_CHECKSUM_LEN, &[], data)
+		.as_bytes()
+		.to_vec()
+}
+
+/// Parses a partial transaction as pasted by a user, either its JSON or
+/// its compact encoding.
+pub fn parse_partial_tx(partial_tx: &str) -> Result<JSONPartialTx, Error> {
+	if partial_tx.trim().starts_with(COMPACT_PARTIAL_TX_PREFIX) {
+		JSONPartialTx::from_compact(partial_tx)
+	} else {
+		Ok(serde_json::from_str(partial_tx)?)
+	}
+}
+
+/// What the receiver answers to a partial transaction it processed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReceiveResponse {
+	/// Hex encoded transaction completed by the receiver, empty when talking
+	/// to older receivers
+	#[serde(default)]
+	pub tx_hex: String,
+	/// Message the sender attached to the partial transaction, echoed back
+	#[serde(default)]
+	pub message: Option<String>,
+	/// Hex encoded commitment of the output the receiver gets paid with, for
+	/// the sender to find it on chain, absent from older receivers
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub commit: Option<String>,
+}
+
+/// What a receiver hands out to get paid a given amount: a transaction holding
+/// only its output, for the payer to add inputs, change and the fee to. The
+/// blinding factor of the output is the receiver's contribution to the
+/// kernel, it has to stay secret or the payer could spend the output, so the
+/// payer posts the transaction back to dest for the receiver to sign.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JSONInvoice {
+	/// Amount invoiced, the output of the receiver holds exactly this much
+	pub amount: u64,
+	/// Height from which the invoice can't be paid anymore
+	pub expiry_height: u64,
+	/// Hex encoded transaction holding the output of the receiver
+	pub tx: String,
+	/// Url of the receiver the payer posts the transaction to
+	pub dest: String,
+}
+
+/// What the webhook of the receiver gets posted when a payment is accepted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReceiveNotification {
+	/// Amount received, net of the fee
+	pub amount: u64,
+	/// Output created for the payment
+	pub key_id: keychain::Identifier,
+	/// Message the sender attached to the partial transaction
+	pub message: Option<String>,
+	/// When the payment was accepted, in seconds since the unix epoch
+	pub created_at: u64,
+}
+
+impl ReceiveNotification {
+	pub fn new(
+		amount: u64,
+		key_id: keychain::Identifier,
+		message: Option<String>,
+	) -> ReceiveNotification {
+		ReceiveNotification {
+			amount: amount,
+			key_id: key_id,
+			message: message,
+			created_at: now_secs(),
+		}
+	}
+}
+
+/// Checks a message fits in what can be carried along a partial transaction.
+pub fn validate_message(message: &Option<String>) -> Result<(), Error> {
+	match *message {
+		Some(ref m) if m.len() > MAX_MESSAGE_LEN => Err(Error::MessageTooLong {
+			len: m.len(),
+			max: MAX_MESSAGE_LEN,
+		}),
+		_ => Ok(()),
+	}
+}
+
+/// The label a payment received gets from its message: trimmed and cut at
+/// MAX_LABEL_LEN bytes, none for an empty message.
+pub fn label_from_message(message: &Option<String>) -> Option<String> {
+	let message = match *message {
+		Some(ref message) => message.trim(),
+		None => return None,
+	};
+	let mut len = min(message.len(), MAX_LABEL_LEN);
+	while !message.is_char_boundary(len) {
+		len -= 1;
+	}
+	match message[..len].trim_right() {
+		"" => None,
+		label => Some(label.to_string()),
+	}
+}
+
+/// Encodes the information for a partial transaction (not yet completed by the
+/// receiver) into JSON.
+pub fn build_partial_tx(
+	receive_amount: u64,
+	blind_sum: keychain::BlindingFactor,
+	tx: Transaction,
+	message: Option<String>,
+) -> JSONPartialTx {
+	JSONPartialTx {
+		version: PARTIAL_TX_VERSION,
+		amount: receive_amount,
+		fee: Some(tx.fee),
+		lock_height: Some(tx.lock_height),
+		blind_sum: util::to_hex(blind_sum.secret_key().as_ref().to_vec()),
+		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
+		message: message,
+		fluff: None,
+	}
+}
+
+/// Reads a partial transaction into the amount, sum of blinding
+/// factors and the transaction itself. Versions newer than ours are refused.
+pub fn read_partial_tx(
+	keychain: &keychain::Keychain,
+	partial_tx: &JSONPartialTx,
+) -> Result<(u64, keychain::BlindingFactor, Transaction), Error> {
+	if partial_tx.version > PARTIAL_TX_VERSION {
+		return Err(Error::VersionMismatch {
+			ours: PARTIAL_TX_VERSION,
+			theirs: partial_tx.version,
+		});
+	}
+
+	validate_message(&partial_tx.message)?;
+
+	let blind_bin = util::from_hex(partial_tx.blind_sum.clone())?;
+
+	let blinding = keychain::BlindingFactor::from_slice(keychain.secp(), &blind_bin[..])?;
+
+	let tx_bin = util::from_hex(partial_tx.tx.clone())?;
+	let tx: Transaction = ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
+		Error::Format("Could not deserialize transaction, invalid format.".to_string())
+	})?;
+
+	// the kernel gets built from the fee and lock height of the transaction,
+	// whatever was agreed on has to be what it commits to
+	if partial_tx.version >= 1 {
+		let fields = vec![
+			("fee", partial_tx.fee, tx.fee),
+			("lock height", partial_tx.lock_height, tx.lock_height),
+		];
+		for (field, stated, actual) in fields {
+			if stated != Some(actual) {
+				return Err(Error::KernelMismatch {
+					field: field,
+					stated: stated,
+					actual: actual,
+				});
+			}
+		}
+	}
+
+	Ok((partial_tx.amount, blinding, tx))
+}
+
+/// Amount in request to build a coinbase output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WalletReceiveRequest {
+	Coinbase(BlockFees),
+	PartialTransaction(String),
+	Finalize(String),
+}
+
+/// Fees in block to use for coinbase amount calculation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockFees {
+	pub fees: u64,
+	pub height: u64,
+	pub key_id: Option<keychain::Identifier>,
+}
+
+impl BlockFees {
+	pub fn key_id(&self) -> Option<keychain::Identifier> {
+		self.key_id.clone()
+	}
+}
+
+/// Response to build a coinbase output.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CbData {
 	pub output: String,
 	pub kernel: String,
 	pub key_id: String,
 }
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+	use std::env;
+	use std::path::PathBuf;
+	use std::process::Command;
+	use std::sync::{Arc, Barrier};
+	use std::sync::atomic::AtomicBool;
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::Duration;
+	use std::u64;
+
+	use core::core::build;
+	use keychain;
+	use keychain::{extkey, Identifier};
+	use super::*;
+
+	fn output(n_child: u32, value: u64, status: OutputStatus, height: u64) -> OutputData {
+		OutputData {
+			root_key_id: Identifier::zero(),
+			key_id: Identifier::from_bytes(&[n_child as u8 + 1]),
+			n_child: n_child,
+			value: value,
+			status: status,
+			height: height,
+			block_hash: None,
+			lock_height: 0,
+			is_coinbase: false,
+			locked_at: 0,
+			account: None,
+			created_at: None,
+			confirmed_at: None,
+			spent_at: None,
+			tx_id: None,
+			label: None,
+			watch_commit: None,
+		}
+	}
+
+	fn wallet(outputs: Vec<OutputData>) -> WalletData {
+		let mut wallet_data = WalletData {
+			version: DATA_FILE_VERSION,
+			outputs: HashMap::new(),
+			tx_log: vec![],
+			rx_log: vec![],
+			transactions: vec![],
+			invoices: vec![],
+			received_txs: vec![],
+			restored_height: 0,
+			archived: ArchivedIds::default(),
+			migrated_from: None,
+			deduped: false,
+		};
+		for out in outputs {
+			wallet_data.add_output(out).unwrap();
+		}
+		wallet_data
+	}
+
+	fn values(coins: &Vec<OutputData>) -> Vec<u64> {
+		coins.iter().map(|c| c.value).collect()
+	}
+
+	fn change(coins: &Vec<OutputData>, amount: u64) -> u64 {
+		coins.iter().map(|c| c.value).sum::<u64>() - amount
+	}
+
+	fn test_wallet() -> WalletData {
+		wallet(vec![
+			output(1, 5, OutputStatus::Unspent, 1),
+			output(2, 40, OutputStatus::Unspent, 1),
+			output(3, 10, OutputStatus::Unspent, 1),
+			output(4, 100, OutputStatus::Unspent, 1),
+			output(5, 1_000, OutputStatus::Locked, 1),
+		])
+	}
+
+	#[test]
+	fn keychain_error_context_preserved() {
+		let keychain_err = keychain::Error::from(extkey::Error::InvalidChildKey { n_child: 42 });
+		let err: Error = keychain_err.into();
+		match err {
+			Error::Keychain(keychain::Error::ExtendedKey(extkey::Error::InvalidChildKey {
+				n_child,
+			})) => assert_eq!(n_child, 42),
+			_ => panic!("unexpected error variant: {:?}", err),
+		}
+		assert!(format!("{}", err).contains("42"));
+	}
+
+	#[test]
+	fn not_enough_funds_in_grin() {
+		let err = Error::NotEnoughFunds {
+			missing: 1_500_000_000,
+			spendable: 250_000_000,
+			unconfirmed: 0,
+			immature: 20,
+			height_locked: 3_000_000_000,
+		};
+		assert_eq!(
+			format!("{}", err),
+			"Not enough funds, 0.25 grin spendable but 1.5 grin more needed (0 awaiting \
+			 confirmation, 0.00000002 immature coinbase, 3 locked until a later height)"
+		);
+	}
+
+	#[test]
+	fn wallet_config_defaults() {
+		// a config holding none of the settings added since the first ones
+		let json = r#"{"enable_wallet":false,"api_listen_interface":"127.0.0.1",
+			"api_listen_port":"13415","check_node_api_http_addr":"http://127.0.0.1:13413",
+			"data_file_dir":"."}"#;
+		let config: WalletConfig = serde_json::from_str(json).unwrap();
+		assert_eq!(
+			serde_json::to_value(&config).unwrap(),
+			serde_json::to_value(&WalletConfig::default()).unwrap()
+		);
+	}
+
+	#[test]
+	fn contacts() {
+		let mut config = WalletConfig::default();
+		config.add_contact("alice", "https://203.0.113.5:13415").unwrap();
+		config.add_contact("bob", "file:/tmp/bob.json").unwrap();
+		assert!(config.add_contact("", "stdout").is_err());
+		assert!(config.add_contact("al ice", "stdout").is_err());
+		assert!(config.add_contact("carol", "nowhere").is_err());
+		assert_eq!(
+			config.list_contacts(),
+			vec![
+				("alice".to_string(), "https://203.0.113.5:13415".to_string()),
+				("bob".to_string(), "file:/tmp/bob.json".to_string()),
+			]
+		);
+
+		assert_eq!(
+			config.resolve_dest("@alice").unwrap(),
+			"https://203.0.113.5:13415"
+		);
+		assert_eq!(
+			config.resolve_dest("@alice#s3cret").unwrap(),
+			"https://203.0.113.5:13415#s3cret"
+		);
+		assert_eq!(config.resolve_dest("stdout").unwrap(), "stdout");
+		match config.resolve_dest("@carol") {
+			Err(Error::UnknownContact(ref name)) => assert_eq!(name, "carol"),
+			res => panic!("expected UnknownContact, got {:?}", res),
+		}
+
+		// edited by hand in the config file
+		config.contacts.insert("dave".to_string(), "ftp://x".to_string());
+		let dest = config.resolve_dest("@dave").unwrap();
+		match Destination::parse(&dest) {
+			Err(Error::InvalidDestination(_)) => {}
+			res => panic!("expected InvalidDestination, got {:?}", res),
+		}
+
+		assert_eq!(
+			config.remove_contact("bob"),
+			Some("file:/tmp/bob.json".to_string())
+		);
+		assert_eq!(config.remove_contact("bob"), None);
+		assert_eq!(config.list_contacts().len(), 2);
+	}
+
+	#[test]
+	fn own_receiver() {
+		let self_send = |config: &WalletConfig, dest: &str| {
+			match config.check_not_self(&Destination::parse(dest).unwrap()) {
+				Ok(()) => false,
+				Err(Error::SelfSend(_)) => true,
+				Err(e) => panic!("unexpected error {:?}", e),
+			}
+		};
+
+		// listening on 127.0.0.1:13415
+		let mut config = WalletConfig::default();
+		for dest in vec![
+			"http://127.0.0.1:13415",
+			"http://127.0.0.1:13415/v1/receive/transaction",
+			"https://127.0.0.1:13415#secret",
+			"http://127.0.0.2:13415",
+			"http://[::1]:13415",
+			"http://localhost:13415",
+			"localhost:13415",
+		] {
+			assert!(self_send(&config, dest), "{} should be our own receiver", dest);
+		}
+		for dest in vec![
+			"http://127.0.0.1:13416",
+			"http://127.0.0.1",
+			"http://203.0.113.7:13415",
+			"http://[2001:db8::1]:13415",
+			"http://no-such-host.invalid:13415",
+			"stdout",
+			"self",
+			"file:/tmp/tx.json",
+		] {
+			assert!(!self_send(&config, dest), "{} isn't our own receiver", dest);
+		}
+
+		// listening on all interfaces, or on a specific one; port defaults
+		// to the scheme's
+		config.api_listen_interface = "0.0.0.0".to_string();
+		assert!(self_send(&config, "http://localhost:13415"));
+		assert!(self_send(&config, "http://0.0.0.0:13415"));
+		config.api_listen_interface = "203.0.113.7".to_string();
+		config.api_listen_port = "80".to_string();
+		assert!(self_send(&config, "http://203.0.113.7"));
+		assert!(!self_send(&config, "https://203.0.113.7"));
+		assert!(!self_send(&config, "http://localhost"));
+
+		config.api_listen_interface = "::1".to_string();
+		config.api_listen_port = "13415".to_string();
+		assert!(self_send(&config, "http://localhost:13415"));
+		assert!(self_send(&config, "http://127.0.0.1:13415"));
+
+		// deliberate loopback
+		config.allow_self_send = true;
+		assert!(!self_send(&config, "http://[::1]:13415"));
+	}
+
+	#[test]
+	fn parse_destination() {
+		let invalid = vec![
+			"",
+			"htt",
+			"ftp://x",
+			"file:",
+			"http://",
+			":13415",
+			"host:port",
+			"http://x#",
+		];
+		for dest in invalid {
+			match Destination::parse(dest) {
+				Err(Error::InvalidDestination(ref s)) => assert_eq!(s, dest),
+				res => panic!("{:?} should be invalid, got {:?}", dest, res),
+			}
+		}
+
+		assert_eq!(Destination::parse("stdout").unwrap(), Destination::Stdout);
+		assert_eq!(
+			Destination::parse("stdout:compact").unwrap(),
+			Destination::CompactStdout
+		);
+		assert_eq!(Destination::parse("self").unwrap(), Destination::SelfSpend);
+		match Destination::parse("https://example.com:13415").unwrap() {
+			Destination::Http(uri, secret) => {
+				assert_eq!(uri.scheme(), Some("https"));
+				assert_eq!(uri.host(), Some("example.com"));
+				assert_eq!(uri.port(), Some(13415));
+				assert_eq!(secret, None);
+			}
+			dest => panic!("unexpected destination {:?}", dest),
+		}
+		match Destination::parse("192.168.1.10:13415#s3cret").unwrap() {
+			Destination::Http(uri, secret) => {
+				assert_eq!(uri.scheme(), Some("https"));
+				assert_eq!(uri.host(), Some("192.168.1.10"));
+				assert_eq!(uri.port(), Some(13415));
+				assert_eq!(secret, Some("s3cret".to_string()));
+			}
+			dest => panic!("unexpected destination {:?}", dest),
+		}
+		assert_eq!(
+			Destination::parse("file:/tmp/tx.json").unwrap(),
+			Destination::File(PathBuf::from("/tmp/tx.json"))
+		);
+		assert_eq!(
+			Destination::parse("./tx.json").unwrap(),
+			Destination::File(PathBuf::from("./tx.json"))
+		);
+	}
+
+	#[test]
+	fn select_strategies() {
+		let wallet_data = test_wallet();
+		let select = |amount, strategy| {
+			wallet_data
+				.select(Identifier::zero(), 10, 1, amount, strategy)
+				.unwrap()
+				.coins
+		};
+
+		let coins = select(45, SelectionStrategy::SmallestFirst);
+		assert_eq!(values(&coins), vec![5, 10, 40]);
+		assert_eq!(change(&coins, 45), 10);
+
+		let coins = select(45, SelectionStrategy::LargestFirst);
+		assert_eq!(values(&coins), vec![100]);
+		assert_eq!(change(&coins, 45), 55);
+
+		let coins = select(45, SelectionStrategy::All);
+		assert_eq!(values(&coins), vec![5, 10, 40, 100]);
+		assert_eq!(change(&coins, 45), 110);
+
+		// smallest single coin covering the amount
+		let coins = select(30, SelectionStrategy::Default);
+		assert_eq!(values(&coins), vec![40]);
+		assert_eq!(change(&coins, 30), 10);
+
+		// no single coin is enough, largest first
+		let coins = select(120, SelectionStrategy::Default);
+		assert_eq!(values(&coins), vec![100, 40]);
+		assert_eq!(change(&coins, 120), 20);
+
+		// not enough funds, nothing is selected (the locked coin doesn't count)
+		assert_eq!(
+			wallet_data
+				.select(Identifier::zero(), 10, 1, 500, SelectionStrategy::SmallestFirst)
+				.unwrap_err(),
+			SelectionError::Insufficient {
+				available: 155,
+				needed: 500,
+			}
+		);
+	}
+
+	#[test]
+	fn select_minimum_confirmations() {
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent, 50),
+			output(2, 20, OutputStatus::Unspent, 95),
+			output(3, 40, OutputStatus::Unspent, 100),
+			output(4, 80, OutputStatus::Unconfirmed, 0),
+		]);
+		let select = |min_conf| {
+			let selection = wallet_data
+				.select(Identifier::zero(), 100, min_conf, 0, SelectionStrategy::All)
+				.unwrap();
+			values(&selection.coins)
+		};
+
+		assert_eq!(select(0), vec![10, 20, 40, 80]);
+		assert_eq!(select(1), vec![10, 20]);
+		assert_eq!(select(5), vec![10, 20]);
+		assert_eq!(select(6), vec![10]);
+		assert_eq!(select(51), Vec::<u64>::new());
+
+		assert_eq!(wallet_data.unconfirmed_total(Identifier::zero(), 100, 0), 0);
+		assert_eq!(wallet_data.unconfirmed_total(Identifier::zero(), 100, 1), 120);
+		assert_eq!(wallet_data.unconfirmed_total(Identifier::zero(), 100, 6), 140);
+	}
+
+	#[test]
+	fn select_confirmed_first() {
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent, 50),
+			output(2, 20, OutputStatus::Unspent, 95),
+			output(3, 80, OutputStatus::Unconfirmed, 0),
+			output(4, 200, OutputStatus::Unconfirmed, 0),
+		]);
+		let select = |min_conf, amount, strategy| {
+			wallet_data
+				.select(Identifier::zero(), 100, min_conf, amount, strategy)
+				.map(|selection| values(&selection.coins))
+		};
+
+		// the confirmed coins suffice, even though a single unconfirmed one
+		// would have been a better fit
+		assert_eq!(select(0, 25, SelectionStrategy::Default), Ok(vec![20, 10]));
+		assert_eq!(select(0, 25, SelectionStrategy::SmallestFirst), Ok(vec![10, 20]));
+		assert_eq!(select(0, 30, SelectionStrategy::LargestFirst), Ok(vec![20, 10]));
+
+		// they don't, all of them are spent and the strategy picks among
+		// the unconfirmed ones for the rest
+		assert_eq!(select(0, 100, SelectionStrategy::Default), Ok(vec![10, 20, 80]));
+		assert_eq!(select(0, 120, SelectionStrategy::Default), Ok(vec![10, 20, 200]));
+		assert_eq!(select(0, 250, SelectionStrategy::SmallestFirst), Ok(vec![10, 20, 80, 200]));
+		assert_eq!(select(0, 100, SelectionStrategy::LargestFirst), Ok(vec![10, 20, 200]));
+
+		// sweeping takes everything eligible
+		assert_eq!(select(0, 0, SelectionStrategy::All), Ok(vec![10, 20, 80, 200]));
+
+		// with a minimum of confirmations there's no falling back
+		let insufficient = Err(SelectionError::Insufficient {
+			available: 30,
+			needed: 100,
+		});
+		assert_eq!(select(1, 100, SelectionStrategy::Default), insufficient);
+		assert_eq!(select(1, 100, SelectionStrategy::All), insufficient);
+		assert_eq!(select(1, 30, SelectionStrategy::Default), Ok(vec![20, 10]));
+		assert_eq!(select(1, 0, SelectionStrategy::All), Ok(vec![10, 20]));
+	}
+
+	#[test]
+	fn select_edge_cases() {
+		let strategies = [
+			SelectionStrategy::Default,
+			SelectionStrategy::SmallestFirst,
+			SelectionStrategy::LargestFirst,
+			SelectionStrategy::All,
+		];
+		let maturity = global::coinbase_maturity();
+		let empty = wallet(vec![]);
+		// locked, spent, pending, immature coinbase and locked until later
+		let unspendable = wallet(vec![
+			output(1, 10, OutputStatus::Locked, 1),
+			output(2, 20, OutputStatus::Spent, 1),
+			output(3, 40, OutputStatus::Pending, 0),
+			OutputData {
+				is_coinbase: true,
+				lock_height: 9 + maturity,
+				..output(4, 80, OutputStatus::Unspent, 9)
+			},
+			OutputData {
+				lock_height: 20,
+				..output(5, 160, OutputStatus::Unspent, 1)
+			},
+		]);
+		for wallet_data in vec![empty, unspendable] {
+			for &strategy in strategies.iter() {
+				assert_eq!(
+					wallet_data
+						.select(Identifier::zero(), 10, 0, 1, strategy)
+						.unwrap_err(),
+					SelectionError::Insufficient {
+						available: 0,
+						needed: 1,
+					}
+				);
+				let selection = wallet_data
+					.select(Identifier::zero(), 10, 0, 0, strategy)
+					.unwrap();
+				assert!(selection.coins.is_empty());
+				assert_eq!(selection.total, 0);
+			}
+		}
+
+		// exactly what's available, to the last coin
+		let wallet_data = test_wallet();
+		let selection = wallet_data
+			.select(Identifier::zero(), 10, 1, 40, SelectionStrategy::Default)
+			.unwrap();
+		assert_eq!((values(&selection.coins), selection.total), (vec![40], 40));
+		for &strategy in strategies.iter() {
+			let selection = wallet_data
+				.select(Identifier::zero(), 10, 1, 155, strategy)
+				.unwrap();
+			assert_eq!((selection.coins.len(), selection.total), (4, 155));
+			assert_eq!(
+				wallet_data
+					.select(Identifier::zero(), 10, 1, 156, strategy)
+					.unwrap_err(),
+				SelectionError::Insufficient {
+					available: 155,
+					needed: 156,
+				}
+			);
+		}
+	}
+
+	#[test]
+	fn select_ties_ordered() {
+		// coins of the same value, oldest first then by key_id, whatever the
+		// order they're stored in
+		let wallet_data = wallet(vec![
+			output(4, 50, OutputStatus::Unspent, 7),
+			output(1, 50, OutputStatus::Unspent, 9),
+			output(3, 50, OutputStatus::Unspent, 3),
+			output(2, 50, OutputStatus::Unspent, 7),
+		]);
+		let select = |amount, strategy| {
+			let selection = wallet_data
+				.select(Identifier::zero(), 10, 1, amount, strategy)
+				.unwrap();
+			selection
+				.coins
+				.iter()
+				.map(|c| c.n_child)
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(select(50, SelectionStrategy::Default), vec![3]);
+		assert_eq!(select(120, SelectionStrategy::Default), vec![3, 2, 4]);
+		assert_eq!(select(120, SelectionStrategy::SmallestFirst), vec![3, 2, 4]);
+		assert_eq!(select(120, SelectionStrategy::LargestFirst), vec![3, 2, 4]);
+		assert_eq!(select(0, SelectionStrategy::All), vec![3, 2, 4, 1]);
+	}
+
+	#[test]
+	fn status_transitions() {
+		let statuses = [
+			OutputStatus::Pending,
+			OutputStatus::Unconfirmed,
+			OutputStatus::Unspent,
+			OutputStatus::Locked,
+			OutputStatus::Spent,
+		];
+		// from the status of the row to that of the column, in the order above
+		let allowed = [
+			[true, false, true, false, false],
+			[false, true, true, true, false],
+			[false, false, true, true, true],
+			[false, false, true, true, true],
+			[false, false, false, false, true],
+		];
+		for (from, row) in statuses.iter().zip(allowed.iter()) {
+			for (to, &expected) in statuses.iter().zip(row.iter()) {
+				assert_eq!(from.can_transition_to(to), expected, "{} to {}", from, to);
+			}
+		}
+	}
+
+	#[test]
+	fn status_changes_checked() {
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent, 5),
+			output(2, 20, OutputStatus::Unconfirmed, 0),
+		]);
+		let id = |n_child: u32| Identifier::from_bytes(&[n_child as u8 + 1]);
+		let status = |wallet_data: &WalletData, n_child| {
+			let out = wallet_data.get_output(&id(n_child)).unwrap();
+			(out.status.clone(), out.locked_at)
+		};
+
+		// locked by a send and unlocked by its cancellation, back where each was
+		for n_child in 1..3 {
+			let out = wallet_data.get_output(&id(n_child)).unwrap().clone();
+			wallet_data.lock_output(&out, 7).unwrap();
+			assert_eq!(status(&wallet_data, n_child), (OutputStatus::Locked, 7));
+			wallet_data.unlock_output(&id(n_child)).unwrap();
+		}
+		assert_eq!(status(&wallet_data, 1), (OutputStatus::Unspent, 0));
+		assert_eq!(status(&wallet_data, 2), (OutputStatus::Unconfirmed, 0));
+
+		// once spent there's no going back, nor locking it for another send
+		let out = wallet_data.get_output(&id(1)).unwrap().clone();
+		wallet_data
+			.set_status(&id(1), OutputStatus::Spent, "spent on chain")
+			.unwrap();
+		match wallet_data.set_status(&id(1), OutputStatus::Unspent, "found again") {
+			Err(Error::IllegalTransition {
+				ref key_id,
+				from: OutputStatus::Spent,
+				to: OutputStatus::Unspent,
+			}) => assert_eq!(key_id, &id(1)),
+			res => panic!("expected IllegalTransition, got {:?}", res),
+		}
+		match wallet_data.lock_output(&out, 8) {
+			Err(Error::IllegalTransition { .. }) => {}
+			res => panic!("expected IllegalTransition, got {:?}", res),
+		}
+		assert_eq!(status(&wallet_data, 1), (OutputStatus::Spent, 0));
+
+		// unless the chain reorganizes
+		wallet_data
+			.force_status(&id(1), OutputStatus::Unspent, "spent in a dropped block")
+			.unwrap();
+		assert_eq!(status(&wallet_data, 1), (OutputStatus::Unspent, 0));
+
+		match wallet_data.set_status(&id(3), OutputStatus::Spent, "spent on chain") {
+			Err(Error::UnknownOutput(ref key_id)) => assert_eq!(key_id, &id(3)),
+			res => panic!("expected UnknownOutput, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn select_exact_match() {
+		let mut outputs = (1..41)
+			.map(|n_child| output(n_child, 60, OutputStatus::Unspent, 1))
+			.collect::<Vec<_>>();
+		outputs.push(output(41, 25, OutputStatus::Unspent, 1));
+		outputs.push(output(42, 7, OutputStatus::Unspent, 1));
+		outputs.push(output(43, 13, OutputStatus::Unconfirmed, 0));
+		outputs.push(output(44, 500, OutputStatus::Locked, 1));
+		let wallet_data = wallet(outputs);
+		let select = |min_conf, max_inputs, amount| {
+			wallet_data
+				.select_exact(Identifier::zero(), 100, min_conf, max_inputs, |_| Some(amount))
+				.map(|coins| values(&coins))
+		};
+
+		assert_eq!(select(1, 500, 180), Some(vec![60, 60, 60]));
+		assert_eq!(select(1, 500, 32), Some(vec![25, 7]));
+		assert_eq!(select(1, 500, 2_400), Some(vec![60; 40]));
+		assert_eq!(select(1, 500, 2_432), Some([vec![60; 40], vec![25, 7]].concat()));
+		assert_eq!(select(1, 2, 180), None);
+		assert_eq!(select(1, 500, 1), None);
+		assert_eq!(select(1, 500, 2_399), None);
+		// locked coins aren't spent
+		assert_eq!(select(1, 500, 500), None);
+
+		// unconfirmed coins only when the confirmed ones can't match
+		assert_eq!(select(1, 500, 20), None);
+		assert_eq!(select(0, 500, 20), Some(vec![13, 7]));
+		assert_eq!(select(0, 500, 32), Some(vec![25, 7]));
+
+		// the target depends on the number of coins spent
+		let coins = wallet_data
+			.select_exact(Identifier::zero(), 100, 1, 500, |n| Some(140 + 3 * n as u64))
+			.unwrap();
+		assert_eq!(values(&coins), vec![60, 60, 25, 7]);
+	}
+
+	#[test]
+	fn tx_log_entry_before_context() {
+		// as logged before the audit context was recorded
+		let json = r#"{"id":3,"created_at":1500000000,"amount":1000,"fee":10,
+			"dest":"http://127.0.0.1:13415","input_ids":[],"change_ids":[],
+			"status":"Confirmed","message":"rent"}"#;
+		let entry: TxLogEntry = serde_json::from_str(json).unwrap();
+		assert_eq!((entry.id, entry.amount, entry.fee), (3, 1000, 10));
+		assert_eq!(entry.message, Some("rent".to_string()));
+		assert_eq!(entry.contact, None);
+		assert_eq!(entry.tip_height, 0);
+		assert_eq!(entry.receiver_ack, None);
+
+		let mut wallet_data = wallet(vec![]);
+		wallet_data.tx_log.push(entry);
+		wallet_data.set_tx_log_context(3, Some("landlord".to_string()), 120, None);
+		let entry = &wallet_data.tx_log[0];
+		assert_eq!((entry.contact.as_ref().unwrap().as_str(), entry.tip_height), ("landlord", 120));
+	}
+
+	#[test]
+	fn tx_log_confirmed_with_change() {
+		let mut wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Locked, 1),
+			output(2, 40, OutputStatus::Unconfirmed, 0),
+			output(3, 200, OutputStatus::Locked, 1),
+		]);
+		let with_change = wallet_data.add_tx_log_entry(
+			50,
+			10,
+			"stdout",
+			vec![output(1, 0, OutputStatus::Spent, 0).key_id],
+			vec![output(2, 0, OutputStatus::Spent, 0).key_id],
+			None,
+		);
+		let no_change = wallet_data.add_tx_log_entry(
+			190,
+			10,
+			"stdout",
+			vec![output(3, 0, OutputStatus::Spent, 0).key_id],
+			vec![],
+			None,
+		);
+		assert_eq!((with_change, no_change), (1, 2));
+
+		wallet_data.confirm_tx_log();
+		assert!(wallet_data.tx_log.iter().all(|e| e.status == TxLogStatus::Pending));
+
+		wallet_data.add_output(output(2, 40, OutputStatus::Unspent, 5)).unwrap();
+		wallet_data.add_output(output(3, 200, OutputStatus::Spent, 1)).unwrap();
+		wallet_data.confirm_tx_log();
+		assert!(wallet_data.tx_log.iter().all(|e| e.status == TxLogStatus::Confirmed));
+	}
+
+	#[test]
+	fn partial_tx_dropped_once_done() {
+		let mut wallet_data = wallet(vec![
+			output(1, 100, OutputStatus::Locked, 1),
+			output(2, 40, OutputStatus::Unconfirmed, 0),
+			output(3, 200, OutputStatus::Locked, 1),
+		]);
+		let (_, partial_tx) = partial_tx();
+		let confirmed = wallet_data.add_tx_log_entry(
+			50,
+			10,
+			"stdout",
+			vec![output(1, 0, OutputStatus::Spent, 0).key_id],
+			vec![output(2, 0, OutputStatus::Spent, 0).key_id],
+			None,
+		);
+		let cancelled = wallet_data.add_tx_log_entry(
+			190,
+			10,
+			"stdout",
+			vec![output(3, 0, OutputStatus::Spent, 0).key_id],
+			vec![],
+			None,
+		);
+		for &id in &[confirmed, cancelled] {
+			wallet_data.set_tx_log_partial_tx(id, partial_tx.clone());
+		}
+		let kept = |wallet_data: &WalletData| {
+			wallet_data
+				.tx_log
+				.iter()
+				.map(|e| e.partial_tx.is_some())
+				.collect::<Vec<_>>()
+		};
+
+		// kept while pending, to be resent
+		wallet_data.confirm_tx_log();
+		assert_eq!(kept(&wallet_data), vec![true, true]);
+
+		wallet_data.add_output(output(2, 40, OutputStatus::Unspent, 5)).unwrap();
+		wallet_data.confirm_tx_log();
+		assert_eq!(wallet_data.tx_log[0].status, TxLogStatus::Confirmed);
+		assert_eq!(kept(&wallet_data), vec![false, true]);
+
+		wallet_data.set_tx_log_status(cancelled, TxLogStatus::Cancelled);
+		assert_eq!(kept(&wallet_data), vec![false, false]);
+	}
+
+	fn lock_dir(name: &str) -> String {
+		let dir = env::temp_dir().join(format!("grin_send_lock_{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir.to_str().unwrap().to_string()
+	}
+
+	fn write_lock(dir: &str, pid: u32, since: u64) {
+		let mut file = File::create(Path::new(dir).join(SEND_LOCK_FILE)).unwrap();
+		write!(file, "{} {}", pid, since).unwrap();
+	}
+
+	#[test]
+	fn send_lock_held_by_live_process() {
+		let dir = lock_dir("live");
+		write_lock(&dir, current_pid(), now_secs());
+		match SendLock::acquire(&dir, 600) {
+			Err(Error::WalletBusy { pid, .. }) => assert_eq!(pid, current_pid()),
+			Err(e) => panic!("expected WalletBusy, got {:?}", e),
+			Ok(_) => panic!("expected WalletBusy, got the lock"),
+		}
+
+		// too old to still be trusted
+		write_lock(&dir, current_pid(), now_secs() - 601);
+		let lock = SendLock::acquire(&dir, 600).unwrap();
+		assert!(SendLock::acquire(&dir, 600).is_err());
+		drop(lock);
+		assert!(!Path::new(&dir).join(SEND_LOCK_FILE).exists());
+		SendLock::acquire(&dir, 600).unwrap();
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn send_lock_of_dead_process() {
+		let dir = lock_dir("dead");
+		let mut child = Command::new("true").spawn().unwrap();
+		child.wait().unwrap();
+		write_lock(&dir, child.id(), now_secs());
+
+		let _lock = SendLock::acquire(&dir, 600).unwrap();
+		let owner = LockOwner::read(&Path::new(&dir).join(SEND_LOCK_FILE)).unwrap();
+		assert_eq!((owner.pid, owner.host), (current_pid(), Some(hostname())));
+	}
+
+	#[test]
+	fn stale_lock_taken_over_once() {
+		let dir = lock_dir("race");
+		for _ in 0..20 {
+			write_lock(&dir, current_pid(), now_secs() - 601);
+			let barrier = Arc::new(Barrier::new(2));
+			let racers = (0..2)
+				.map(|_| {
+					let (dir, barrier) = (dir.clone(), barrier.clone());
+					thread::spawn(move || {
+						barrier.wait();
+						SendLock::acquire(&dir, 600).map_err(|e| e.to_string())
+					})
+				})
+				.collect::<Vec<_>>();
+			let results = racers
+				.into_iter()
+				.map(|racer| racer.join().unwrap())
+				.collect::<Vec<_>>();
+			assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+			let owner = LockOwner::read(&Path::new(&dir).join(SEND_LOCK_FILE)).unwrap();
+			assert_eq!(owner.pid, current_pid());
+			assert!(owner.age() < 600);
+		}
+		// nothing left behind but the lock itself, released with the last one
+		assert!(!Path::new(&dir).join(SEND_LOCK_FILE).exists());
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+	}
+
+	fn write_data_lock(dir: &str, pid: u32, since: u64, host: &str) {
+		let mut file = File::create(Path::new(dir).join(LOCK_FILE)).unwrap();
+		write!(file, "{} {} {}", pid, since, host).unwrap();
+	}
+
+	#[test]
+	fn data_lock_held_by_live_process() {
+		// however old, the lock of a live process of this host stays
+		let dir = lock_dir("data_live");
+		write_data_lock(&dir, current_pid(), now_secs() - 3_600, &hostname());
+		match WalletData::with_wallet(&dir, |_| ()) {
+			Err(Error::WalletBusy { pid, lock, .. }) => {
+				assert_eq!((pid, lock), (current_pid(), "data file"))
+			}
+			res => panic!("expected WalletBusy, got {:?}", res),
+		}
+		assert!(Path::new(&dir).join(LOCK_FILE).exists());
+
+		// one of another host can't be checked, only its age tells
+		write_data_lock(&dir, current_pid(), now_secs(), "elsewhere");
+		assert!(WalletData::with_wallet(&dir, |_| ()).is_err());
+		write_data_lock(&dir, current_pid(), now_secs() - 3_600, "elsewhere");
+		WalletData::with_wallet(&dir, |_| ()).unwrap();
+		assert!(!Path::new(&dir).join(LOCK_FILE).exists());
+	}
+
+	#[test]
+	fn unchanged_data_not_written() {
+		let dir = lock_dir("unchanged_data");
+		let path = Path::new(&dir).join(DAT_FILE).to_str().unwrap().to_string();
+		let bak_path = format!("{}{}", path, BAK_EXT);
+		WalletData::with_wallet(&dir, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent, 1))
+		}).unwrap()
+			.unwrap();
+		let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+		let (before, content) = (modified(), file_content(&path));
+
+		// only read, not written back
+		let tracked = WalletData::with_wallet(&dir, |wallet_data| wallet_data.outputs.len());
+		assert_eq!(tracked.unwrap(), 1);
+		WalletData::with_wallet(&dir, |wallet_data| {
+			wallet_data.next_child(Identifier::zero());
+			wallet_data.balance(None, 10, 1);
+		}).unwrap();
+		assert_eq!(modified(), before);
+		assert_eq!(file_content(&path), content);
+		assert!(!Path::new(&bak_path).exists());
+
+		// changed, written back with the previous data as backup
+		let key_id = Identifier::from_bytes(&[2]);
+		WalletData::with_wallet(&dir, |wallet_data| {
+			wallet_data.set_status(&key_id, OutputStatus::Spent, "spent on chain")
+		}).unwrap()
+			.unwrap();
+		assert!(modified() >= before);
+		assert!(file_content(&path) != content);
+		assert_eq!(file_content(&bak_path), content);
+		let status = WalletData::read_wallet(&dir, |wallet_data| {
+			wallet_data.get_output(&key_id).unwrap().status.clone()
+		});
+		assert_eq!(status.unwrap(), OutputStatus::Spent);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn data_lock_of_dead_process() {
+		let dir = lock_dir("data_dead");
+		let mut child = Command::new("true").spawn().unwrap();
+		child.wait().unwrap();
+		write_data_lock(&dir, child.id(), now_secs(), &hostname());
+
+		let tracked = WalletData::with_wallet(&dir, |wallet_data| wallet_data.outputs.len());
+		assert_eq!(tracked.unwrap(), 0);
+		assert!(!Path::new(&dir).join(LOCK_FILE).exists());
+	}
+
+	#[test]
+	fn panicking_change_not_written() {
+		let dir = lock_dir("panicking_change");
+		let path = Path::new(&dir).join(DAT_FILE).to_str().unwrap().to_string();
+		WalletData::with_wallet(&dir, |wallet_data| {
+			wallet_data.add_output(output(1, 10, OutputStatus::Unspent, 1))
+		}).unwrap()
+			.unwrap();
+		let content = file_content(&path);
+
+		// half way through a change, whatever the panic carries
+		let panicking: Vec<Box<Fn(&mut WalletData)>> = vec![
+			Box::new(|wallet_data: &mut WalletData| {
+				wallet_data.add_output(output(2, 20, OutputStatus::Unspent, 1)).unwrap();
+				panic!("failed at output {}", 2);
+			}),
+			Box::new(|wallet_data: &mut WalletData| {
+				let key_id = Identifier::from_bytes(&[2]);
+				wallet_data.set_status(&key_id, OutputStatus::Spent, "spent").unwrap();
+				panic!("failed");
+			}),
+			Box::new(|wallet_data: &mut WalletData| {
+				wallet_data.outputs.clear();
+				panic::resume_unwind(Box::new(7));
+			}),
+		];
+		let causes = panicking
+			.into_iter()
+			.map(|change| match WalletData::with_wallet(&dir, |wallet_data| change(wallet_data)) {
+				Err(Error::WalletCorrupted(cause)) => cause,
+				res => panic!("expected WalletCorrupted, got {:?}", res),
+			})
+			.collect::<Vec<_>>();
+		let aborted = "Wallet data change aborted, nothing was written";
+		assert_eq!(
+			causes,
+			vec![
+				format!("{}: failed at output 2", aborted),
+				format!("{}: failed", aborted),
+				format!("{}: unknown cause", aborted),
+			]
+		);
+
+		// the file as it was, no backup of it made, the lock released
+		assert_eq!(file_content(&path), content);
+		assert!(!Path::new(&format!("{}{}", path, BAK_EXT)).exists());
+		assert!(!Path::new(&dir).join(LOCK_FILE).exists());
+		let outputs = WalletData::with_wallet(&dir, |wallet_data| {
+			wallet_data
+				.outputs
+				.values()
+				.map(|out| (out.n_child, out.status.clone()))
+				.collect::<Vec<_>>()
+		});
+		assert_eq!(outputs.unwrap(), vec![(1, OutputStatus::Unspent)]);
+	}
+
+	#[test]
+	fn concurrent_access() {
+		let dir = lock_dir("concurrent_access");
+		let path = Path::new(&dir).join(DAT_FILE);
+		let (threads, changes) = (8, 20);
+
+		// the data file is valid whenever it's read, even while replaced
+		let done = Arc::new(AtomicBool::new(false));
+		let watching = done.clone();
+		let watched = path.clone();
+		let watcher = thread::spawn(move || {
+			let mut reads = 0;
+			while !watching.load(Ordering::SeqCst) {
+				let mut content = vec![];
+				if let Ok(mut file) = File::open(&watched) {
+					file.read_to_end(&mut content).unwrap();
+					serde_json::from_slice::<serde_json::Value>(&content).unwrap();
+					reads += 1;
+				}
+			}
+			reads
+		});
+
+		// each thread adds outputs of its own, spending every other one it
+		// added before, some of its changes panicking half way, reading
+		// back what it did in between
+		let workers = (0..threads)
+			.map(|t| {
+				let dir = dir.clone();
+				thread::spawn(move || for i in 0..changes {
+					let n_child = t * changes + i;
+					let change = |wallet_data: &mut WalletData, panics: bool| -> Result<(), Error> {
+						wallet_data.add_output(output(n_child, 1, OutputStatus::Unspent, 1))?;
+						if i % 2 == 1 {
+							let key_id = output(n_child - 1, 1, OutputStatus::Unspent, 1).key_id;
+							wallet_data.set_status(&key_id, OutputStatus::Spent, "spent")?;
+						}
+						if panics {
+							panic!("change {} of thread {}", i, t);
+						}
+						Ok(())
+					};
+					let attempts = if i % 5 == 4 { vec![true, false] } else { vec![false] };
+					for panics in attempts {
+						// another thread may hold the lock for longer than we wait
+						loop {
+							match WalletData::with_wallet(&dir, |wdat| change(wdat, panics)) {
+								Ok(res) => {
+									res.unwrap();
+									break;
+								}
+								Err(Error::WalletCorrupted(_)) if panics => break,
+								Err(Error::WalletBusy { .. }) => continue,
+								Err(e) => panic!("{:?}", e),
+							}
+						}
+					}
+
+					let mine = WalletData::read_wallet(&dir, |wallet_data| {
+						(t * changes..n_child + 1)
+							.filter(|&n| {
+								let key_id = output(n, 1, OutputStatus::Unspent, 1).key_id;
+								wallet_data.get_output(&key_id).is_some()
+							})
+							.count()
+					});
+					assert_eq!(mine.unwrap(), i as usize + 1);
+				})
+			})
+			.collect::<Vec<_>>();
+		for worker in workers {
+			worker.join().unwrap();
+		}
+		done.store(true, Ordering::SeqCst);
+		assert!(watcher.join().unwrap() > 0);
+
+		// no change lost, none of those that panicked made it
+		let statuses = WalletData::read_wallet(&dir, |wallet_data| {
+			let spent = wallet_data
+				.outputs
+				.values()
+				.filter(|out| out.status == OutputStatus::Spent)
+				.count();
+			(wallet_data.outputs.len(), spent)
+		});
+		let total = (threads * changes) as usize;
+		assert_eq!(statuses.unwrap(), (total, total / 2));
+		assert!(!Path::new(&dir).join(LOCK_FILE).exists());
+	}
+
+	fn partial_tx() -> (keychain::Keychain, JSONPartialTx) {
+		let keychain = keychain::Keychain::from_random_seed().unwrap();
+		let (tx, blind) = build::transaction(
+			vec![
+				build::input(100, keychain.derive_key_id(1).unwrap()),
+				build::output(60, keychain.derive_key_id(2).unwrap()),
+				build::with_fee(40),
+				build::with_lock_height(5),
+			],
+			&keychain,
+		).unwrap();
+		(keychain, build_partial_tx(100, blind, tx, None))
+	}
+
+	#[test]
+	fn partial_tx_versions() {
+		let (keychain, partial_tx) = partial_tx();
+		let mut json = serde_json::to_value(&partial_tx).unwrap();
+		assert_eq!(json["version"].as_u64(), Some(PARTIAL_TX_VERSION as u64));
+		assert_eq!(json["fee"].as_u64(), Some(40));
+		assert_eq!(json["lock_height"].as_u64(), Some(5));
+
+		// v1 as built
+		let v1: JSONPartialTx = serde_json::from_value(json.clone()).unwrap();
+		let (amount, _, tx) = read_partial_tx(&keychain, &v1).unwrap();
+		assert_eq!((amount, tx.fee, tx.lock_height), (100, 40, 5));
+
+		// v1 whose fee doesn't agree with its transaction
+		json["fee"] = serde_json::Value::from(50);
+		let bad_fee: JSONPartialTx = serde_json::from_value(json.clone()).unwrap();
+		match read_partial_tx(&keychain, &bad_fee) {
+			Err(Error::KernelMismatch {
+				field: "fee",
+				stated: Some(50),
+				actual: 40,
+			}) => {}
+			res => panic!("expected KernelMismatch, got {:?}", res),
+		}
+
+		// or that leaves its lock height out
+		json["fee"] = serde_json::Value::from(40);
+		json.as_object_mut().unwrap().remove("lock_height");
+		let no_lock_height: JSONPartialTx = serde_json::from_value(json.clone()).unwrap();
+		match read_partial_tx(&keychain, &no_lock_height) {
+			Err(Error::KernelMismatch {
+				field: "lock height",
+				stated: None,
+				actual: 5,
+			}) => {}
+			res => panic!("expected KernelMismatch, got {:?}", res),
+		}
+
+		// v0 has no version, fee nor lock height
+		{
+			let fields = json.as_object_mut().unwrap();
+			fields.remove("version");
+			fields.remove("fee");
+			fields.remove("lock_height");
+		}
+		let v0: JSONPartialTx = serde_json::from_value(json.clone()).unwrap();
+		assert_eq!(v0.version(), 0);
+		let (amount, _, tx) = read_partial_tx(&keychain, &v0).unwrap();
+		assert_eq!((amount, tx.fee, tx.lock_height), (100, 40, 5));
+
+		// anything newer than what we know is refused
+		json["version"] = serde_json::Value::from(99);
+		let v99: JSONPartialTx = serde_json::from_value(json).unwrap();
+		match read_partial_tx(&keychain, &v99) {
+			Err(Error::VersionMismatch { ours, theirs }) => {
+				assert_eq!(ours, PARTIAL_TX_VERSION);
+				assert_eq!(theirs, 99);
+			}
+			res => panic!("expected VersionMismatch, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn compact_partial_tx_round_trip() {
+		let (_, partial_tx) = partial_tx();
+		let compact = partial_tx.to_compact();
+		assert!(compact.starts_with(COMPACT_PARTIAL_TX_PREFIX));
+		assert!(!compact.contains(char::is_whitespace));
+
+		assert_eq!(JSONPartialTx::from_compact(&compact).unwrap(), partial_tx);
+		assert_eq!(parse_partial_tx(&compact).unwrap(), partial_tx);
+		let json = serde_json::to_string_pretty(&partial_tx).unwrap();
+		assert_eq!(parse_partial_tx(&json).unwrap(), partial_tx);
+
+		// wrapped over several lines by a chat client
+		let wrapped = compact
+			.as_bytes()
+			.chunks(20)
+			.map(|c| String::from_utf8(c.to_vec()).unwrap())
+			.collect::<Vec<_>>()
+			.join("\n");
+		assert_eq!(parse_partial_tx(&format!(" {}\n", wrapped)).unwrap(), partial_tx);
+	}
+
+	#[test]
+	fn corrupted_compact_partial_tx() {
+		let (_, partial_tx) = partial_tx();
+		let compact = partial_tx.to_compact();
+
+		for cut in vec![1, 2, 3, 5, compact.len() / 2, compact.len() - 10] {
+			match parse_partial_tx(&compact[..compact.len() - cut]) {
+				Err(Error::CompactTxTruncated { len, expected }) => assert!(len < expected),
+				res => panic!("cutting {} should be a truncation, got {:?}", cut, res),
+			}
+		}
+
+		let mut altered = compact.clone().into_bytes();
+		let i = compact.len() / 2;
+		altered[i] = if altered[i] == b'A' { b'B' } else { b'A' };
+		match parse_partial_tx(&String::from_utf8(altered).unwrap()) {
+			Err(Error::CompactTxChecksum) => {}
+			res => panic!("expected a checksum mismatch, got {:?}", res),
+		}
+
+		match parse_partial_tx(&format!("{}AAAA", compact)) {
+			Err(Error::Format(_)) => {}
+			res => panic!("expected a format error, got {:?}", res),
+		}
+		match parse_partial_tx(&format!("{}!!", COMPACT_PARTIAL_TX_PREFIX)) {
+			Err(Error::Format(_)) => {}
+			res => panic!("expected a format error, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn duplicate_output_refused() {
+		let mut wallet_data = wallet(vec![output(1, 100, OutputStatus::Unconfirmed, 0)]);
+		match wallet_data.add_output(output(1, 100, OutputStatus::Unspent, 3)) {
+			Err(Error::DuplicateOutput(ref key_id)) => {
+				assert_eq!(*key_id, Identifier::from_bytes(&[2]))
+			}
+			res => panic!("expected DuplicateOutput, got {:?}", res),
+		}
+		match wallet_data.add_output(output(1, 500, OutputStatus::Unconfirmed, 0)) {
+			Err(Error::ConflictingOutput {
+				ref key_id,
+				tracked,
+				value,
+			}) => {
+				assert_eq!(*key_id, Identifier::from_bytes(&[2]));
+				assert_eq!((tracked, value), (100, 500));
+			}
+			res => panic!("expected ConflictingOutput, got {:?}", res),
+		}
+		assert_eq!(wallet_data.outputs.len(), 1);
+		assert_eq!(wallet_data.outputs.values().next().unwrap().value, 100);
+
+		// a coinbase built again replaces its candidate
+		wallet_data.replace_output(output(1, 500, OutputStatus::Unconfirmed, 0));
+		assert_eq!(wallet_data.outputs.values().next().unwrap().value, 500);
+	}
+
+	#[test]
+	fn duplicate_outputs_merged() {
+		let fixture = include_str!("../tests/data/wallet_duplicates.json");
+		let mut wallet_data: WalletData = serde_json::from_str(fixture).unwrap();
+		assert_eq!(wallet_data.outputs.len(), 9);
+		let summary = wallet_data.dedupe();
+		let merged = summary
+			.merged
+			.iter()
+			.map(|merged| {
+				let status = merged.status.clone();
+				(merged.key_id.to_hex(), merged.entries, status, merged.height)
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			merged,
+			vec![
+				("2d1a1a0871a4751a5b96".to_string(), 2, OutputStatus::Locked, 14),
+				("5e1f0b8d3a7c2e964d10".to_string(), 2, OutputStatus::Unspent, 20),
+				("96d1c5fcbe3b4a2b3e41".to_string(), 3, OutputStatus::Spent, 12),
+			]
+		);
+		let hex = |ids: &Vec<Identifier>| ids.iter().map(|id| id.to_hex()).collect::<Vec<_>>();
+		assert_eq!(hex(&summary.renamed), vec!["c3e07a4f19d25b8e60a1"]);
+		assert_eq!(hex(&summary.conflicting), vec!["e0a0c4d3a4ad3bd6a192"]);
+		assert_eq!(wallet_data.outputs.len(), 6);
+
+		// the most advanced status comes with what goes with it
+		let out = &wallet_data.outputs["96d1c5fcbe3b4a2b3e41"];
+		assert_eq!(out.spent_at, Some(Timestamp(1_510_135_960)));
+		assert!(out.block_hash.is_some());
+		// the highest height, whichever entry it's of
+		let out = &wallet_data.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!((out.locked_at, out.tx_id), (30, Some(1)));
+		assert_eq!(out.label, Some("change of the rent payment".to_string()));
+		// statuses tied, the entry named after the identifier is kept, with
+		// the label only the other has
+		let out = &wallet_data.outputs["5e1f0b8d3a7c2e964d10"];
+		assert_eq!(out.account, Some("savings".to_string()));
+		assert_eq!(out.label, Some("rent of december".to_string()));
+		// renamed, the conflicting ones left as they are
+		assert!(wallet_data.outputs.contains_key("c3e07a4f19d25b8e60a1"));
+		assert!(!wallet_data.outputs.contains_key("C3E07A4F19D25B8E60A1"));
+		assert_eq!(wallet_data.outputs["e0a0c4d3a4ad3bd6a192"].value, 1_000_000_000);
+		assert_eq!(wallet_data.outputs["E0A0C4D3A4AD3BD6A192"].value, 2_000_000_000);
+
+		// only the conflicting ones left
+		let summary = wallet_data.dedupe();
+		assert!(!summary.changed());
+		assert_eq!(hex(&summary.conflicting), vec!["e0a0c4d3a4ad3bd6a192"]);
+		assert_eq!(wallet_data.outputs.len(), 6);
+	}
+
+	#[test]
+	fn duplicate_outputs_merged_on_open() {
+		let fixture = include_str!("../tests/data/wallet_duplicates.json");
+		let path = fixture_file("duplicates", fixture);
+		let dir = Path::new(&path).parent().unwrap().to_str().unwrap().to_string();
+		let copies = || {
+			fs::read_dir(&dir)
+				.unwrap()
+				.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+				.filter(|name| name.starts_with(&format!("{}.dup-", DAT_FILE)))
+				.collect::<Vec<_>>()
+		};
+
+		// merged in memory by a read, the file is left alone
+		let read = WalletData::read_wallet(&dir, |wallet_data| wallet_data.outputs.len());
+		assert_eq!(read.unwrap(), 6);
+		assert_eq!(file_content(&path), fixture);
+		assert!(copies().is_empty());
+
+		// written back merged on open, the file kept as it was
+		let opened = WalletData::with_wallet(&dir, |wallet_data| wallet_data.outputs.len());
+		assert_eq!(opened.unwrap(), 6);
+		let copies_made = copies();
+		assert_eq!(copies_made.len(), 1);
+		let copy_path = Path::new(&dir).join(&copies_made[0]);
+		assert_eq!(file_content(copy_path.to_str().unwrap()), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		assert_eq!(written["outputs"].as_object().unwrap().len(), 6);
+		let out = &written["outputs"]["2d1a1a0871a4751a5b96"];
+		assert_eq!(out["height"], serde_json::Value::from(14));
+
+		// nothing left to merge, no other copy
+		let opened = WalletData::with_wallet(&dir, |wallet_data| wallet_data.outputs.len());
+		assert_eq!(opened.unwrap(), 6);
+		assert_eq!(copies(), copies_made);
+	}
+
+	// A data file of the wallet in a fresh temporary directory, holding
+	// test_wallet, and the same wallet with an output more.
+	fn data_file(name: &str) -> (String, WalletData, WalletData) {
+		let dir = env::temp_dir().join(format!("grin_wallet_data_{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join(DAT_FILE).to_str().unwrap().to_string();
+		let old = test_wallet();
+		old.write(&path, None).unwrap();
+		let mut new = test_wallet();
+		new.add_output(output(6, 7, OutputStatus::Unspent, 2)).unwrap();
+		(path, old, new)
+	}
+
+	fn outputs_read(path: &str) -> usize {
+		WalletData::read_or_create(path, None).unwrap().outputs.len()
+	}
+
+	fn truncate(path: &str) {
+		let file = OpenOptions::new().write(true).open(path).unwrap();
+		let len = file.metadata().unwrap().len();
+		file.set_len(len / 2).unwrap();
+	}
+
+	#[test]
+	fn data_file_replaced() {
+		let (path, _, new) = data_file("replaced");
+		let bak_path = format!("{}{}", path, BAK_EXT);
+		assert!(!Path::new(&bak_path).exists());
+
+		new.write(&path, None).unwrap();
+		assert_eq!(outputs_read(&path), 6);
+		assert_eq!(WalletData::read(&bak_path, None).unwrap().outputs.len(), 5);
+		assert!(!Path::new(&format!("{}{}", path, TMP_EXT)).exists());
+	}
+
+	#[test]
+	fn crash_before_rename() {
+		// killed once the new data is written, or halfway through, but before
+		// the rename, the previous data stands
+		let (path, _, new) = data_file("crash");
+		new.write_tmp(&path, None).unwrap();
+		assert_eq!(outputs_read(&path), 5);
+		let tmp_path = new.write_tmp(&path, None).unwrap();
+		truncate(&tmp_path);
+		assert_eq!(outputs_read(&path), 5);
+
+		// the next write starts over
+		new.write(&path, None).unwrap();
+		assert_eq!(outputs_read(&path), 6);
+	}
+
+	#[test]
+	fn truncated_data_file_recovered() {
+		// cut short by a crash while written in place, the previous data is
+		// read from the backup
+		let (path, _, new) = data_file("truncated");
+		new.write(&path, None).unwrap();
+		truncate(&path);
+		assert_eq!(outputs_read(&path), 5);
+
+		// without a backup it's an error, not an empty wallet
+		fs::remove_file(format!("{}{}", path, BAK_EXT)).unwrap();
+		match WalletData::read_or_create(&path, None) {
+			Err(Error::WalletCorrupted(_)) => {}
+			res => panic!("expected WalletCorrupted, got {:?}", res),
+		}
+	}
+
+	fn corrupted_cause(path: &str) -> String {
+		match WalletData::read_or_create(path, None) {
+			Err(Error::WalletCorrupted(cause)) => cause,
+			res => panic!("expected WalletCorrupted, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn corrupted_data_file_detected() {
+		let (path, _, new) = data_file("corrupted");
+		let mut content = vec![];
+		File::open(&path).unwrap().read_to_end(&mut content).unwrap();
+		let json: serde_json::Value = serde_json::from_slice(&content).unwrap();
+		assert_eq!(json[CHECKSUM_FIELD].as_str().map(|c| c.len()), Some(64));
+
+		// a bit flipped anywhere, in the data, the version, the checksum or
+		// the JSON itself, is told apart from a newer file
+		let checksum_at = content.windows(8).position(|w| w == b"checksum").unwrap();
+		let version_at = content.windows(9).position(|w| w == b"\"version\"").unwrap() + 11;
+		assert_eq!(content[version_at], b'0' + DATA_FILE_VERSION as u8);
+		let offsets = vec![0, 1, checksum_at, checksum_at + 12, version_at, content.len() / 2,
+		                   content.len() - 3, content.len() - 1];
+		for i in offsets.into_iter().chain(0..content.len()) {
+			let mut flipped = content.clone();
+			flipped[i] ^= 1;
+			File::create(&path).unwrap().write_all(&flipped).unwrap();
+			let cause = corrupted_cause(&path);
+			assert!(cause.starts_with(&format!("{} is corrupted", path)), "byte {}", i);
+		}
+		assert!(corrupted_cause(&path).contains("no backup of it was found"));
+
+		// the checksum dropped from a file of the current version
+		let mut json = json.clone();
+		json.as_object_mut().unwrap().remove(CHECKSUM_FIELD);
+		File::create(&path).unwrap().write_all(json.to_string().as_bytes()).unwrap();
+		assert!(corrupted_cause(&path).contains("checksum is missing"));
+
+		// the most recent backup is the one told
+		let dir = Path::new(&path).parent().unwrap().join(BACKUP_DIR);
+		File::create(&path).unwrap().write_all(&content).unwrap();
+		copy_to_backup(&path, &dir).unwrap();
+		let latest = copy_to_backup(&path, &dir).unwrap();
+		File::create(&path).unwrap().write_all(&content[..content.len() / 2]).unwrap();
+		let cause = corrupted_cause(&path);
+		assert!(cause.contains(&format!("restore_backup {}", latest.path.display())), "{}", cause);
+
+		// written whole, the previous data file doesn't silently stand in for
+		// it but is told along with the most recent backup
+		File::create(&path).unwrap().write_all(&content).unwrap();
+		new.write(&path, None).unwrap();
+		let mut corrupted = file_content(&path).into_bytes();
+		let at = corrupted.windows(5).position(|w| w == b"value").unwrap() + 8;
+		corrupted[at] ^= 1;
+		File::create(&path).unwrap().write_all(&corrupted).unwrap();
+		let cause = corrupted_cause(&path);
+		assert!(cause.contains("checksum doesn't match"), "{}", cause);
+		assert!(cause.contains(&format!("restore_backup {}", latest.path.display())), "{}", cause);
+		assert!(cause.contains(&format!("restore_backup {}{}", path, BAK_EXT)), "{}", cause);
+		assert_eq!(file_content(&path).into_bytes(), corrupted);
+
+		// cut short, it does
+		truncate(&path);
+		assert_eq!(outputs_read(&path), 5);
+
+		// encrypted, the decryption tells
+		let cipher = DataCipher::new("s3cret");
+		new.write(&path, Some(&cipher)).unwrap();
+		let mut encrypted = vec![];
+		File::open(&path).unwrap().read_to_end(&mut encrypted).unwrap();
+		let last = encrypted.len() - 1;
+		encrypted[last] ^= 1;
+		File::create(&path).unwrap().write_all(&encrypted).unwrap();
+		match WalletData::read(&path, Some(&cipher)) {
+			Err(Error::WalletCorrupted(cause)) => assert!(cause.contains("can't be decrypted")),
+			res => panic!("expected WalletCorrupted, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn checksum_round_trip() {
+		let (path, _, _) = data_file("checksum");
+		let mut labelled = test_wallet();
+		let labels = vec!["\"quoted\" \\ back/slash", "line\nbreak\ttab", "café ☕ 漢字",
+		                  "\u{1}\u{2028}\u{7f}"];
+		for (n, label) in labels.into_iter().enumerate() {
+			let key_id = Identifier::from_bytes(&[n as u8 + 2]);
+			labelled.set_label(&key_id, Some(label.to_string())).unwrap();
+		}
+		labelled.add_output(output(6, u64::MAX, OutputStatus::Unspent, u64::MAX)).unwrap();
+		let cipher = DataCipher::new("s3cret");
+		for wdat in vec![wallet(vec![]), test_wallet(), labelled] {
+			let expected = serde_json::to_value(&wdat).unwrap();
+			wdat.write(&path, None).unwrap();
+			let written = file_content(&path);
+			for _ in 0..3 {
+				let read = WalletData::read(&path, None).unwrap();
+				assert_eq!(serde_json::to_value(&read).unwrap(), expected);
+				read.write(&path, None).unwrap();
+				assert_eq!(file_content(&path), written);
+			}
+			wdat.write(&path, Some(&cipher)).unwrap();
+			let read = WalletData::read(&path, Some(&cipher)).unwrap();
+			assert_eq!(serde_json::to_value(&read).unwrap(), expected);
+		}
+	}
+
+	#[test]
+	fn encrypted_data_file() {
+		let (path, _, new) = data_file("encrypted");
+		let cipher = DataCipher::new("s3cret");
+		new.write(&path, Some(&cipher)).unwrap();
+		new.write(&path, Some(&cipher)).unwrap();
+		let mut data = vec![];
+		File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+		assert!(is_encrypted(&data));
+		assert!(!data.windows(7).any(|w| w == b"outputs"));
+		let read = WalletData::read_or_create(&path, Some(&cipher)).unwrap();
+		assert_eq!(read.outputs.len(), 6);
+
+		// no passphrase or the wrong one, the backup isn't read instead
+		match WalletData::read_or_create(&path, None) {
+			Err(Error::WalletData(_)) => {}
+			res => panic!("expected WalletData, got {:?}", res),
+		}
+		match WalletData::read_or_create(&path, Some(&DataCipher::new("wrong"))) {
+			Err(Error::WrongPassphrase) => {}
+			res => panic!("expected WrongPassphrase, got {:?}", res),
+		}
+	}
+
+	#[test]
+	fn data_file_encrypted_once_written() {
+		// a plaintext data file, its plaintext backup too
+		let (path, _, new) = data_file("migrated");
+		new.write(&path, None).unwrap();
+		let bak_path = format!("{}{}", path, BAK_EXT);
+		assert!(Path::new(&bak_path).exists());
+
+		let cipher = DataCipher::new("s3cret");
+		let read = WalletData::read_or_create(&path, Some(&cipher)).unwrap();
+		read.write(&path, Some(&cipher)).unwrap();
+		assert!(is_encrypted_file(&path));
+		assert!(!Path::new(&bak_path).exists());
+		let read = WalletData::read_or_create(&path, Some(&cipher)).unwrap();
+		assert_eq!(read.outputs.len(), 6);
+
+		// backups are encrypted from then on
+		read.write(&path, Some(&cipher)).unwrap();
+		assert!(is_encrypted_file(&bak_path));
+	}
+
+	fn backup_config(name: &str) -> WalletConfig {
+		let dir = env::temp_dir().join(format!("grin_wallet_backup_{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		let mut config = WalletConfig::default();
+		config.data_file_dir = dir.to_str().unwrap().to_string();
+		config
+	}
+
+	fn owned_output(keychain: &keychain::Keychain, n_child: u32) -> OutputData {
+		OutputData {
+			root_key_id: keychain.root_key_id(),
+			..output(n_child, 100, OutputStatus::Unspent, 1)
+		}
+	}
+
+	#[test]
+	fn backup_and_restore() {
+		let config = backup_config("cycle");
+		let dir = &config.data_file_dir;
+		let keychain = keychain::Keychain::from_random_seed().unwrap();
+		WalletData::with_wallet(dir, |wdat| wdat.add_output(owned_output(&keychain, 1)))
+			.unwrap()
+			.unwrap();
+		let backup = WalletData::backup(&config, None).unwrap();
+
+		// only what's named like a backup is listed
+		let backup_dir = Path::new(dir).join(BACKUP_DIR);
+		File::create(backup_dir.join("notes.txt")).unwrap();
+		File::create(backup_dir.join("wallet-soon.dat")).unwrap();
+		assert_eq!(WalletData::list_backups(&config, None).unwrap(), vec![backup.clone()]);
+
+		WalletData::with_wallet(dir, |wdat| {
+			for out in wdat.outputs.values_mut() {
+				out.status = OutputStatus::Spent;
+			}
+			wdat.add_output(owned_output(&keychain, 2))
+		}).unwrap()
+			.unwrap();
+
+		let replaced = WalletData::restore_backup(&config, &keychain, &backup.path)
+			.unwrap()
+			.unwrap();
+		let outputs = WalletData::read_wallet(dir, |wdat| wdat.outputs.clone()).unwrap();
+		assert_eq!(outputs.len(), 1);
+		assert!(outputs.values().all(|out| out.status == OutputStatus::Unspent));
+
+		// what was replaced can be restored in turn
+		let backups = WalletData::list_backups(&config, None).unwrap();
+		assert_eq!(backups, vec![backup, replaced.clone()]);
+		WalletData::restore_backup(&config, &keychain, &replaced.path).unwrap();
+		let outputs = WalletData::read_wallet(dir, |wdat| wdat.outputs.clone()).unwrap();
+		assert_eq!(outputs.len(), 2);
+	}
+
+	#[test]
+	fn backup_of_another_wallet_refused() {
+		let other = backup_config("other");
+		let other_keychain = keychain::Keychain::from_random_seed().unwrap();
+		WalletData::with_wallet(&other.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&other_keychain, 1))
+		}).unwrap()
+			.unwrap();
+		let backup = WalletData::backup(&other, None).unwrap();
+
+		let config = backup_config("mismatch");
+		let keychain = keychain::Keychain::from_random_seed().unwrap();
+		WalletData::with_wallet(&config.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&keychain, 1))
+		}).unwrap()
+			.unwrap();
+		match WalletData::restore_backup(&config, &keychain, &backup.path) {
+			Err(Error::BackupMismatch { ours, theirs }) => {
+				assert_eq!(ours, keychain.root_key_id());
+				assert_eq!(theirs, other_keychain.root_key_id());
+			}
+			res => panic!("expected BackupMismatch, got {:?}", res),
+		}
+
+		// the data file is untouched, nothing was backed up
+		let outputs = WalletData::read_wallet(&config.data_file_dir, |wdat| wdat.outputs.clone())
+			.unwrap();
+		assert!(outputs.values().all(|out| out.root_key_id == keychain.root_key_id()));
+		assert!(WalletData::list_backups(&config, None).unwrap().is_empty());
+	}
+
+	// A fresh data directory, the configuration using it and the one using
+	// it as wallets did before subdirectories per seed.
+	fn shared_config(name: &str) -> (PathBuf, WalletConfig, WalletConfig) {
+		let dir = env::temp_dir().join(format!("grin_wallet_shared_{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		let mut config = WalletConfig::default();
+		config.data_file_dir = dir.to_str().unwrap().to_string();
+		let flat = WalletConfig {
+			flat_data_dir: true,
+			..config.clone()
+		};
+		(dir, config, flat)
+	}
+
+	fn fingerprints_in(data_file_dir: &str) -> Vec<Identifier> {
+		let mut roots = WalletData::read_wallet(data_file_dir, |wdat| {
+			wdat.outputs
+				.values()
+				.map(|out| out.root_key_id.clone())
+				.collect::<Vec<_>>()
+		}).unwrap();
+		roots.sort_by_key(|root| root.to_hex());
+		roots.dedup();
+		roots
+	}
+
+	#[test]
+	fn data_dir_per_seed() {
+		let (dir, config, _) = shared_config("seeds");
+		let ours = keychain::Keychain::from_random_seed().unwrap();
+		let theirs = keychain::Keychain::from_random_seed().unwrap();
+		let our_config = config.for_keychain(&ours).unwrap();
+		let their_config = config.for_keychain(&theirs).unwrap();
+		let our_dir = dir.join(ours.root_key_id().to_hex());
+		assert_eq!(our_config.data_file_dir, our_dir.to_str().unwrap());
+		assert!(our_config.data_file_dir != their_config.data_file_dir);
+
+		// resolved once and for all
+		for keychain in vec![&ours, &theirs] {
+			let again = our_config.for_keychain(keychain).unwrap();
+			assert_eq!(again.data_file_dir, our_config.data_file_dir);
+		}
+
+		WalletData::with_wallet(&our_config.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&ours, 1))
+		}).unwrap()
+			.unwrap();
+		// the lock of one wallet doesn't hold up the other
+		let our_lock = WalletData::acquire_data_lock(&our_config.data_file_dir).unwrap();
+		WalletData::with_wallet(&their_config.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&theirs, 1))?;
+			wdat.add_output(owned_output(&theirs, 2))
+		}).unwrap()
+			.unwrap();
+		WalletData::release_data_lock(&our_lock).unwrap();
+
+		assert_eq!(fingerprints_in(&our_config.data_file_dir), vec![ours.root_key_id()]);
+		assert_eq!(fingerprints_in(&their_config.data_file_dir), vec![theirs.root_key_id()]);
+		assert!(!dir.join(DAT_FILE).exists());
+
+		// opting out keeps the data files in the data directory
+		let flat = WalletConfig {
+			flat_data_dir: true,
+			..config
+		};
+		assert_eq!(flat.for_keychain(&ours).unwrap().data_file_dir, flat.data_file_dir);
+	}
+
+	#[test]
+	fn flat_data_files_moved() {
+		let (dir, config, flat) = shared_config("moved");
+		let keychain = keychain::Keychain::from_random_seed().unwrap();
+		WalletData::with_wallet(&flat.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&keychain, 1))?;
+			wdat.add_output(owned_output(&keychain, 2))
+		}).unwrap()
+			.unwrap();
+		WalletData::backup(&flat, None).unwrap();
+
+		let resolved = config.for_keychain(&keychain).unwrap();
+		assert!(!dir.join(DAT_FILE).exists());
+		assert!(!dir.join(BACKUP_DIR).exists());
+		let outputs = WalletData::read_wallet(&resolved.data_file_dir, |wdat| wdat.outputs.len());
+		assert_eq!(outputs.unwrap(), 2);
+		assert_eq!(WalletData::list_backups(&resolved, None).unwrap().len(), 1);
+
+		// a flat data file showing up again isn't moved over the wallet's
+		WalletData::with_wallet(&flat.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&keychain, 3))
+		}).unwrap()
+			.unwrap();
+		let again = config.for_keychain(&keychain).unwrap();
+		assert_eq!(again.data_file_dir, resolved.data_file_dir);
+		assert!(dir.join(DAT_FILE).exists());
+		let outputs = WalletData::read_wallet(&resolved.data_file_dir, |wdat| wdat.outputs.len());
+		assert_eq!(outputs.unwrap(), 2);
+	}
+
+	#[test]
+	fn flat_data_file_of_several_seeds_kept() {
+		let (dir, config, flat) = shared_config("kept");
+		let ours = keychain::Keychain::from_random_seed().unwrap();
+		let theirs = keychain::Keychain::from_random_seed().unwrap();
+		WalletData::with_wallet(&flat.data_file_dir, |wdat| {
+			wdat.add_output(owned_output(&ours, 1))?;
+			wdat.add_output(owned_output(&theirs, 1))
+		}).unwrap()
+			.unwrap();
+		let dat_file = dir.join(DAT_FILE);
+		let before = file_content(dat_file.to_str().unwrap());
+
+		for keychain in vec![&ours, &theirs] {
+			let resolved = config.for_keychain(keychain).unwrap();
+			assert!(!Path::new(&resolved.data_file_dir).join(DAT_FILE).exists());
+		}
+		assert_eq!(file_content(dat_file.to_str().unwrap()), before);
+		assert_eq!(fingerprints_in(&flat.data_file_dir).len(), 2);
+	}
+
+	// A confirmed output of the keychain, its identifier derived at the child
+	// index.
+	fn derived_output(keychain: &keychain::Keychain, n_child: u32, value: u64) -> OutputData {
+		OutputData {
+			root_key_id: keychain.root_key_id(),
+			key_id: keychain.derive_key_id(n_child).unwrap(),
+			..output(n_child, value, OutputStatus::Unspent, 1)
+		}
+	}
+
+	#[test]
+	fn output_moved_between_wallets() {
+		// the same seed on two machines
+		let old_machine = keychain::Keychain::from_seed(&[7; 32]).unwrap();
+		let new_machine = keychain::Keychain::from_seed(&[7; 32]).unwrap();
+		let out = OutputData {
+			status: OutputStatus::Locked,
+			locked_at: 40,
+			tx_id: Some(2),
+			label: Some("savings".to_string()),
+			..derived_output(&old_machine, 3, 500)
+		};
+		let exporting = wallet(vec![out.clone(), derived_output(&old_machine, 4, 600)]);
+		let export = exporting.export_output(&old_machine, &out.key_id).unwrap();
+		assert_eq!(export.fingerprint, old_machine.root_key_id());
+		let commit = old_machine.commit(500, &out.key_id).unwrap();
+		assert_eq!(export.commit, util::to_hex(commit.as_ref().to_vec()));
+		// handed over as JSON
+		let json = serde_json::to_string(&export).unwrap();
+		let export: OutputExport = serde_json::from_str(&json).unwrap();
+
+		let mut importing = wallet(vec![]);
+		let imported = importing.import_output(&new_machine, export.clone()).unwrap();
+		assert!(!imported.is_watch_only());
+		assert_eq!(imported.label, out.label);
+		// the send of the old machine that locked it doesn't carry over
+		assert_eq!(imported.status, OutputStatus::Unspent);
+		assert_eq!((imported.locked_at, imported.tx_id), (0, None));
+		let eligible = importing.eligible_coins(new_machine.root_key_id(), 10, 1);
+		assert_eq!(eligible.iter().map(|out| out.value).collect::<Vec<_>>(), vec![500]);
+		assert_eq!(importing.next_child(new_machine.root_key_id()), 4);
+		let again = importing.export_output(&new_machine, &out.key_id).unwrap();
+		assert_eq!(again.commit, export.commit);
+
+		match importing.import_output(&new_machine, export) {
+			Err(Error::DuplicateOutput(key_id)) => assert_eq!(key_id, out.key_id),
+			res => panic!("expected DuplicateOutput, got {:?}", res),
+		}
+
+		// a value that doesn't make the commitment exported
+		let key_id = old_machine.derive_key_id(4).unwrap();
+		let mut tampered = exporting.export_output(&old_machine, &key_id).unwrap();
+		tampered.output.value = 6_000;
+		match importing.import_output(&new_machine, tampered) {
+			Err(Error::CommitmentMismatch(id)) => assert_eq!(id, key_id),
+			res => panic!("expected CommitmentMismatch, got {:?}", res),
+		}
+		assert_eq!(importing.outputs.len(), 1);
+	}
+
+	#[test]
+	fn output_of_another_seed_watched() {
+		let ours = keychain::Keychain::from_random_seed().unwrap();
+		let theirs = keychain::Keychain::from_random_seed().unwrap();
+		let foreign = derived_output(&theirs, 1, 800);
+		let export = wallet(vec![foreign.clone()])
+			.export_output(&theirs, &foreign.key_id)
+			.unwrap();
+
+		let mut wdat = wallet(vec![derived_output(&ours, 1, 100)]);
+		let watched = wdat.import_output(&ours, export.clone()).unwrap();
+		assert!(watched.is_watch_only());
+		assert_eq!(watched.root_key_id, theirs.root_key_id());
+		let commit = theirs.commit(800, &foreign.key_id).unwrap();
+		assert_eq!(watched.commit(&ours).unwrap(), commit);
+		// re-exported with the commitment it came with
+		let again = wdat.export_output(&ours, &foreign.key_id).unwrap();
+		assert_eq!(again.commit, export.commit);
+
+		// passed off as ours, it doesn't derive from our seed all the same
+		let mut claimed = export.clone();
+		claimed.output.root_key_id = ours.root_key_id();
+		claimed.output.key_id = Identifier::from_bytes(&[9]);
+		assert!(wdat.import_output(&ours, claimed).unwrap().is_watch_only());
+
+		// never selected nor counted as ours
+		let eligible = wdat.eligible_coins(ours.root_key_id(), 10, 0);
+		assert_eq!(eligible.iter().map(|out| out.value).collect::<Vec<_>>(), vec![100]);
+		assert_eq!(wdat.balance(None, 10, 0).total, 100);
+		assert_eq!(wdat.balance(Some(ours.root_key_id()), 10, 0).spendable, 100);
+
+		// outputs of another seed tracked without their commitment can't be
+		// exported, nor what isn't a commitment imported
+		let untracked = derived_output(&theirs, 2, 5);
+		wdat.add_output(untracked.clone()).unwrap();
+		match wdat.export_output(&ours, &untracked.key_id) {
+			Err(Error::ForeignInput { key_id, fingerprint }) => {
+				assert_eq!((key_id, fingerprint), (untracked.key_id, theirs.root_key_id()))
+			}
+			res => panic!("expected ForeignInput, got {:?}", res),
+		}
+		let not_hex = "zz".repeat(PEDERSEN_COMMITMENT_SIZE);
+		for commit in vec!["", "08", not_hex.as_str(), &export.commit[2..]] {
+			let mut invalid = export.clone();
+			invalid.output.key_id = Identifier::from_bytes(&[10]);
+			invalid.commit = commit.to_string();
+			match wdat.import_output(&ours, invalid) {
+				Err(Error::Format(_)) => {}
+				res => panic!("expected Format for {:?}, got {:?}", commit, res),
+			}
+		}
+		assert_eq!(wdat.outputs.len(), 4);
+	}
+
+	#[test]
+	fn query_outputs() {
+		let mut wallet_data = test_wallet();
+		let mut coinbase = output(6, 60, OutputStatus::Unspent, 3);
+		coinbase.is_coinbase = true;
+		wallet_data.add_output(coinbase).unwrap();
+		wallet_data.add_output(output(7, 7, OutputStatus::Unconfirmed, 0)).unwrap();
+		let other_root = Identifier::from_bytes(&[9]);
+		wallet_data
+			.add_output(OutputData {
+				root_key_id: other_root.clone(),
+				..output(8, 800, OutputStatus::Unspent, 2)
+			})
+			.unwrap();
+
+		let query = |filter: OutputFilter| {
+			let res = wallet_data.query(&filter);
+			assert_eq!(res.count, res.outputs.len());
+			let n_childs = res.outputs.iter().map(|out| out.n_child).collect::<Vec<_>>();
+			(n_childs, res.value)
+		};
+		assert_eq!(query(OutputFilter::default()), (vec![1, 2, 3, 4, 5, 6, 7, 8], 2_022));
+		assert_eq!(
+			query(OutputFilter::fingerprint(Identifier::zero())),
+			(vec![1, 2, 3, 4, 5, 6, 7], 1_222)
+		);
+		assert_eq!(query(OutputFilter::fingerprint(other_root)), (vec![8], 800));
+		let unspent = OutputFilter {
+			status: Some(OutputStatus::Unspent),
+			..Default::default()
+		};
+		assert_eq!(query(unspent.clone()), (vec![1, 2, 3, 4, 6, 8], 1_015));
+		assert_eq!(
+			query(OutputFilter {
+				min_height: Some(2),
+				..unspent.clone()
+			}),
+			(vec![6, 8], 860)
+		);
+		assert_eq!(
+			query(OutputFilter {
+				is_coinbase: Some(false),
+				max_height: Some(2),
+				..unspent
+			}),
+			(vec![1, 2, 3, 4, 8], 955)
+		);
+		assert_eq!(
+			query(OutputFilter {
+				max_height: Some(0),
+				..Default::default()
+			}),
+			(vec![7], 7)
+		);
+
+		// as an RPC would get it, anything left out matching everything
+		let filter: OutputFilter =
+			serde_json::from_str(r#"{"status":"Unspent","is_coinbase":true}"#).unwrap();
+		assert_eq!(query(filter), (vec![6], 60));
+	}
+
+	#[test]
+	fn output_labels() {
+		let mut wallet_data = test_wallet();
+		let id = |n_child: u32| Identifier::from_bytes(&[n_child as u8 + 1]);
+		let selected = |wallet_data: &WalletData| {
+			let selection = wallet_data
+				.select(Identifier::zero(), 10, 1, 120, SelectionStrategy::Default)
+				.unwrap();
+			selection.coins.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+		let before = selected(&wallet_data);
+
+		wallet_data.set_label(&id(2), Some(" rent payment change ".to_string())).unwrap();
+		wallet_data.set_label(&id(4), Some("donation from Alice".to_string())).unwrap();
+		wallet_data.set_label(&id(3), Some("Rent, March".to_string())).unwrap();
+		let label = wallet_data.outputs[&id(2).to_hex()].label.clone();
+		assert_eq!(label, Some("rent payment change".to_string()));
+
+		// an empty label or none removes it
+		wallet_data.set_label(&id(3), Some("  ".to_string())).unwrap();
+		assert_eq!(wallet_data.outputs[&id(3).to_hex()].label, None);
+		wallet_data.set_label(&id(3), Some("Rent, March".to_string())).unwrap();
+		wallet_data.set_label(&id(4), None).unwrap();
+		assert_eq!(wallet_data.outputs[&id(4).to_hex()].label, None);
+
+		// as long as MAX_LABEL_LEN, on a tracked output
+		match wallet_data.set_label(&id(1), Some("a".repeat(MAX_LABEL_LEN + 1))) {
+			Err(Error::LabelTooLong { len, max }) => {
+				assert_eq!((len, max), (MAX_LABEL_LEN + 1, MAX_LABEL_LEN))
+			}
+			res => panic!("expected LabelTooLong, got {:?}", res),
+		}
+		wallet_data.set_label(&id(1), Some("a".repeat(MAX_LABEL_LEN))).unwrap();
+		match wallet_data.set_label(&id(9), Some("gone".to_string())) {
+			Err(Error::UnknownOutput(ref key_id)) => assert_eq!(key_id, &id(9)),
+			res => panic!("expected UnknownOutput, got {:?}", res),
+		}
+
+		// filtered on what the label holds, case included
+		let labelled = |text: &str| {
+			let filter = OutputFilter {
+				label: Some(text.to_string()),
+				..OutputFilter::default()
+			};
+			let query = wallet_data.query(&filter);
+			query.outputs.iter().map(|out| out.n_child).collect::<Vec<_>>()
+		};
+		assert_eq!(labelled("rent"), vec![2]);
+		assert_eq!(labelled("ent"), vec![2, 3]);
+		assert_eq!(labelled("aaa"), vec![1]);
+		assert!(labelled("Alice").is_empty());
+
+		// and never change what gets selected
+		assert_eq!(selected(&wallet_data), before);
+	}
+
+	#[test]
+	fn labels_from_messages() {
+		assert_eq!(label_from_message(&None), None);
+		assert_eq!(label_from_message(&Some(" \n ".to_string())), None);
+		assert_eq!(
+			label_from_message(&Some(" invoice 12 ".to_string())),
+			Some("invoice 12".to_string())
+		);
+		// cut at the limit, on a character boundary
+		let message = format!("{}é", "a".repeat(MAX_LABEL_LEN - 1));
+		assert_eq!(label_from_message(&Some(message)), Some("a".repeat(MAX_LABEL_LEN - 1)));
+		let message = "a".repeat(MAX_MESSAGE_LEN);
+		assert_eq!(label_from_message(&Some(message)), Some("a".repeat(MAX_LABEL_LEN)));
+	}
+
+	#[test]
+	fn balance_buckets() {
+		let maturity = global::coinbase_maturity();
+		let coinbase = |n_child, value, status, height| OutputData {
+			is_coinbase: true,
+			lock_height: height + maturity,
+			..output(n_child, value, status, height)
+		};
+		let wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent, 50),
+			output(2, 20, OutputStatus::Unspent, 99),
+			output(3, 40, OutputStatus::Unconfirmed, 0),
+			output(4, 80, OutputStatus::Pending, 0),
+			coinbase(5, 160, OutputStatus::Unspent, 100),
+			OutputData {
+				lock_height: 150,
+				..output(6, 320, OutputStatus::Unspent, 20)
+			},
+			output(7, 640, OutputStatus::Locked, 20),
+			output(8, 1_280, OutputStatus::Spent, 20),
+			OutputData {
+				lock_height: 0,
+				..coinbase(9, 3, OutputStatus::Unconfirmed, 0)
+			},
+			OutputData {
+				root_key_id: Identifier::from_bytes(&[9]),
+				..output(10, 5_000, OutputStatus::Unspent, 20)
+			},
+		]);
+
+		assert_eq!(
+			wallet_data.balance(Some(Identifier::zero()), 100, 2),
+			Balance {
+				spendable: 10,
+				awaiting_confirmation: 143,
+				immature_coinbase: 160,
+				height_locked: 320,
+				locked: 640,
+				total: 1_273,
+			}
+		);
+		// without confirmations needed, only what isn't on chain waits
+		let balance = wallet_data.balance(Some(Identifier::zero()), 100, 0);
+		assert_eq!((balance.spendable, balance.awaiting_confirmation), (70, 83));
+
+		// same rules as selection, and nothing counted twice or left out
+		for &height in [0, 50, 100, 150, 100 + maturity, 200 + maturity].iter() {
+			for min_conf in 0..3 {
+				let balance = wallet_data.balance(Some(Identifier::zero()), height, min_conf);
+				let selected = wallet_data.select(
+					Identifier::zero(),
+					height,
+					min_conf,
+					0,
+					SelectionStrategy::All,
+				);
+				assert_eq!(balance.spendable, selected.unwrap().total);
+				assert_eq!(
+					balance.spendable + balance.awaiting_confirmation + balance.immature_coinbase
+						+ balance.height_locked + balance.locked,
+					balance.total
+				);
+				assert_eq!(balance.total, 1_273);
+			}
+		}
+	}
+
+	#[test]
+	fn output_stats() {
+		let wallet_data = wallet(vec![
+			output(1, 500_000, OutputStatus::Unspent, 10),
+			output(2, 300_000, OutputStatus::Unspent, 10),
+			output(3, 50_000_000, OutputStatus::Unconfirmed, 0),
+			output(4, GRIN_BASE / 10, OutputStatus::Unspent, 10),
+			output(5, 2 * GRIN_BASE, OutputStatus::Locked, 10),
+			output(6, 10 * GRIN_BASE, OutputStatus::Unspent, 10),
+			output(7, 25 * GRIN_BASE, OutputStatus::Spent, 10),
+			output(8, 3 * GRIN_BASE, OutputStatus::Pending, 0),
+			OutputData {
+				watch_commit: Some("08".repeat(PEDERSEN_COMMITMENT_SIZE)),
+				..output(9, 40 * GRIN_BASE, OutputStatus::Unspent, 10)
+			},
+			OutputData {
+				root_key_id: Identifier::from_bytes(&[9]),
+				..output(10, GRIN_BASE, OutputStatus::Unspent, 10)
+			},
+		]);
+
+		let stats = wallet_data.stats(Some(Identifier::zero()), 20, 1);
+		let buckets = stats
+			.buckets
+			.iter()
+			.map(|bucket| (bucket.from, bucket.below, bucket.count, bucket.value))
+			.collect::<Vec<_>>();
+		// a bucket starts at its lower bound
+		assert_eq!(
+			buckets,
+			vec![
+				(0, Some(DUST_VALUE), 2, 800_000),
+				(DUST_VALUE, Some(GRIN_BASE / 10), 1, 50_000_000),
+				(GRIN_BASE / 10, Some(GRIN_BASE), 1, GRIN_BASE / 10),
+				(GRIN_BASE, Some(10 * GRIN_BASE), 2, 5 * GRIN_BASE),
+				(10 * GRIN_BASE, None, 1, 10 * GRIN_BASE),
+			]
+		);
+		assert_eq!(
+			(stats.pending, stats.unconfirmed, stats.unspent, stats.locked, stats.spent),
+			(1, 1, 4, 1, 1)
+		);
+		assert_eq!(stats.watch_only, 1);
+		let value = |out: &Option<OutputData>| out.as_ref().map(|out| out.value);
+		assert_eq!(value(&stats.largest_spendable), Some(10 * GRIN_BASE));
+		assert_eq!(value(&stats.smallest_spendable), Some(300_000));
+
+		// the outputs of other root keys count without one
+		let all = wallet_data.stats(None, 20, 1);
+		assert_eq!((all.buckets[3].count, all.buckets[3].value), (3, 6 * GRIN_BASE));
+		assert_eq!(all.unspent, 5);
+
+		// the buckets hold what the balance adds up, the spendable outputs
+		// being those selection would spend
+		for &height in [0, 10, 11, 20].iter() {
+			for min_conf in 0..3 {
+				let stats = wallet_data.stats(Some(Identifier::zero()), height, min_conf);
+				let balance = wallet_data.balance(Some(Identifier::zero()), height, min_conf);
+				let total = stats.buckets.iter().fold(0, |total, bucket| total + bucket.value);
+				assert_eq!(total, balance.total);
+				let count = stats.buckets.iter().fold(0, |count, bucket| count + bucket.count);
+				assert_eq!(count, 7);
+				let eligible = wallet_data.eligible_coins(Identifier::zero(), height, min_conf);
+				assert_eq!(value(&stats.smallest_spendable), eligible.first().map(|out| out.value));
+				assert_eq!(value(&stats.largest_spendable), eligible.last().map(|out| out.value));
+			}
+		}
+	}
+
+	#[test]
+	fn prune_spent_outputs() {
+		let dir = env::temp_dir().join("grin_wallet_data_prune");
+		let _ = fs::remove_dir_all(&dir);
+		let dir = dir.to_str().unwrap();
+		let key_id = |n_child: u32| Identifier::from_bytes(&[n_child as u8 + 1]);
+		WalletData::with_wallet(dir, |wallet_data| {
+			for out in vec![
+				output(1, 10, OutputStatus::Spent, 2),
+				output(2, 20, OutputStatus::Spent, 5),
+				output(3, 30, OutputStatus::Unspent, 3),
+				output(9, 40, OutputStatus::Spent, 4),
+				output(5, 50, OutputStatus::Spent, 8),
+				output(6, 60, OutputStatus::Unconfirmed, 0),
+			] {
+				wallet_data.add_output(out).unwrap();
+			}
+			// its change is still around, the second one only spent
+			wallet_data.add_tx_log_entry(5, 1, "a", vec![key_id(1)], vec![key_id(3)], None);
+			wallet_data.add_tx_log_entry(15, 1, "b", vec![key_id(2)], vec![], None);
+			wallet_data.add_tx_log_entry(35, 1, "c", vec![key_id(9)], vec![], None);
+			wallet_data.tx_log[0].status = TxLogStatus::Confirmed;
+			wallet_data.tx_log[1].status = TxLogStatus::Confirmed;
+			wallet_data.add_rx_log_entry(10, key_id(1), None, None, None);
+		}).unwrap();
+
+		let root_key_id = Identifier::zero();
+		let balance = |include_archive| {
+			WalletData::read_wallet_with_archive(dir, include_archive, |wallet_data| {
+				wallet_data.balance(Some(root_key_id.clone()), 10, 1)
+			}).unwrap()
+		};
+		let spent = |include_archive| {
+			let filter = OutputFilter {
+				status: Some(OutputStatus::Spent),
+				..OutputFilter::default()
+			};
+			WalletData::read_wallet_with_archive(dir, include_archive, |wallet_data| {
+				let query = wallet_data.query(&filter);
+				(query.count, query.value)
+			}).unwrap()
+		};
+		let before = balance(false);
+		assert_eq!(spent(false), (4, 120));
+
+		// the third send isn't confirmed yet
+		match WalletData::prune(dir, 6) {
+			Err(Error::OutputInUse { key_id: ref id, tx_id: 3 }) if *id == key_id(9) => {}
+			res => panic!("expected OutputInUse, got {:?}", res),
+		}
+		WalletData::with_wallet(dir, |wallet_data| {
+			wallet_data.tx_log[2].status = TxLogStatus::Confirmed;
+		}).unwrap();
+
+		let summary = WalletData::prune(dir, 6).unwrap();
+		assert_eq!(
+			summary,
+			PruneSummary {
+				outputs: 3,
+				tx_log_entries: 2,
+				rx_log_entries: 1,
+			}
+		);
+		assert_eq!(balance(false), before);
+		assert_eq!(balance(true), before);
+		assert_eq!(spent(false), (1, 50));
+		assert_eq!(spent(true), (4, 120));
+		let logs = |include_archive| {
+			WalletData::read_wallet_with_archive(dir, include_archive, |wallet_data| {
+				(
+					wallet_data.tx_log.iter().map(|e| e.id).collect::<Vec<_>>(),
+					wallet_data.rx_log.len(),
+				)
+			}).unwrap()
+		};
+		assert_eq!(logs(false), (vec![1], 0));
+		assert_eq!(logs(true), (vec![1, 2, 3], 1));
+
+		// archived indexes and ids aren't handed out again
+		WalletData::with_wallet(dir, |wallet_data| {
+			assert_eq!(wallet_data.next_child(root_key_id.clone()), 10);
+			assert_eq!(wallet_data.add_tx_log_entry(1, 1, "d", vec![], vec![], None), 4);
+			assert_eq!(wallet_data.add_rx_log_entry(1, key_id(7), None, None, None), 2);
+		}).unwrap();
+
+		// nothing left to prune
+		assert_eq!(WalletData::prune(dir, 6).unwrap(), PruneSummary::default());
+	}
+
+	#[test]
+	fn child_indexes_never_handed_out_twice() {
+		let dir = env::temp_dir().join("grin_wallet_data_child");
+		let _ = fs::remove_dir_all(&dir);
+		let counter_path = dir.join(CHILD_COUNTER_FILE).to_str().unwrap().to_string();
+		let dir = dir.to_str().unwrap();
+		let root_key_id = Identifier::zero();
+		let reserve = |count| {
+			let reserved = WalletData::with_wallet(dir, |wallet_data| {
+				wallet_data.reserve_children(dir, root_key_id.clone(), count)
+			});
+			reserved.unwrap().unwrap()
+		};
+
+		// the process dies once the keys are derived, before their outputs
+		// get tracked
+		assert_eq!(reserve(2), 1);
+		assert_eq!(reserve(1), 3);
+		assert_eq!(reserve(0), 4);
+
+		// then while writing the counter, the previous one stands
+		let tmp_path = format!("{}{}", counter_path, TMP_EXT);
+		File::create(&tmp_path).unwrap().write_all(b"00 1").unwrap();
+		assert_eq!(reserve(1), 4);
+
+		// outputs tracked past the counter, as by a data file restored from a
+		// backup
+		let added = WalletData::with_wallet(dir, |wallet_data| {
+			wallet_data.add_output(output(7, 10, OutputStatus::Unspent, 1))
+		});
+		added.unwrap().unwrap();
+		assert_eq!(reserve(1), 8);
+
+		// a counter lost, the data file goes, one unreadable is refused
+		fs::remove_file(&counter_path).unwrap();
+		assert_eq!(reserve(1), 8);
+		File::create(&counter_path).unwrap().write_all(b"garbage\n").unwrap();
+		let reserved = WalletData::with_wallet(dir, |wallet_data| {
+			wallet_data.reserve_children(dir, root_key_id.clone(), 1)
+		});
+		match reserved.unwrap() {
+			Err(Error::WalletCorrupted(msg)) => assert!(msg.contains("garbage"), "{}", msg),
+			res => panic!("expected WalletCorrupted, got {:?}", res),
+		}
+
+		// counters of other root keys are kept along
+		let other = Identifier::from_bytes(&[9]).to_hex();
+		let counter = format!("{} 20\n{} 41\n", root_key_id.to_hex(), other);
+		File::create(&counter_path).unwrap().write_all(counter.as_bytes()).unwrap();
+		assert_eq!(reserve(1), 21);
+		assert_eq!(read_child_counter(&counter_path).unwrap().get(&other), Some(&41));
+	}
+
+	#[test]
+	fn child_indexes_kept_per_fingerprint() {
+		let dir = &lock_dir("child_per_fingerprint");
+		let (ours, theirs) = (Identifier::from_bytes(&[1]), Identifier::from_bytes(&[2]));
+		let reserve = |root_key_id: &Identifier, count| {
+			let reserved = WalletData::with_wallet(dir, |wallet_data| {
+				wallet_data.reserve_children(dir, root_key_id.clone(), count)
+			});
+			reserved.unwrap().unwrap()
+		};
+		let track = |root_key_id: &Identifier, n_child: u32| {
+			let root: &[u8] = root_key_id.as_ref();
+			let added = WalletData::with_wallet(dir, |wallet_data| {
+				wallet_data.add_output(OutputData {
+					root_key_id: root_key_id.clone(),
+					key_id: Identifier::from_bytes(&[root[0], n_child as u8]),
+					..output(n_child, 10, OutputStatus::Unspent, 1)
+				})
+			});
+			added.unwrap().unwrap();
+		};
+
+		// allocations of both keychains interleaved, some of the outputs of
+		// each tracked, the others lost
+		let steps = vec![
+			(&ours, 1, true),
+			(&theirs, 2, false),
+			(&ours, 1, false),
+			(&theirs, 1, true),
+			(&theirs, 3, true),
+			(&ours, 2, true),
+			(&theirs, 1, false),
+			(&ours, 1, true),
+		];
+		let mut next = HashMap::new();
+		for (root_key_id, count, tracked) in steps {
+			let first = reserve(root_key_id, count);
+			// each sequence goes on from where it was, whatever the other did
+			assert_eq!(first, *next.get(&root_key_id.to_hex()).unwrap_or(&1));
+			next.insert(root_key_id.to_hex(), first + count as u32);
+			if tracked {
+				track(root_key_id, first + count as u32 - 1);
+			}
+		}
+		assert_eq!((next[&ours.to_hex()], next[&theirs.to_hex()]), (6, 8));
+		let counter_path = Path::new(dir).join(CHILD_COUNTER_FILE);
+		let counter = read_child_counter(counter_path.to_str().unwrap()).unwrap();
+		assert_eq!((counter[&ours.to_hex()], counter[&theirs.to_hex()]), (5, 7));
+
+		// a counter lost, each keychain goes on from its own outputs
+		fs::remove_file(&counter_path).unwrap();
+		assert_eq!(reserve(&ours, 1), 6);
+		assert_eq!(reserve(&theirs, 1), 7);
+		assert_eq!(reserve(&Identifier::from_bytes(&[3]), 1), 1);
+	}
+
+	#[test]
+	fn next_child_recovered() {
+		let keychain = keychain::Keychain::from_random_seed().unwrap();
+		let root_key_id = keychain.root_key_id();
+		let key_id = |n_child| keychain.derive_key_id(n_child).unwrap();
+
+		// children 1 to 3 tracked, the counter at `counted` and the children
+		// on chain used, telling apart children within the gap limit of 10 of
+		// the last one in use from those past it
+		let recover = |name: &str, counted: u32, on_chain: Vec<u32>| {
+			let dir = &lock_dir(name);
+			let counter = format!("{} {}\n", root_key_id.to_hex(), counted);
+			let counter_path = Path::new(dir).join(CHILD_COUNTER_FILE);
+			File::create(&counter_path).unwrap().write_all(counter.as_bytes()).unwrap();
+			let on_chain = on_chain.into_iter().map(|n| key_id(n).to_hex()).collect::<Vec<_>>();
+			let recovered = WalletData::with_wallet(dir, |wallet_data| -> Result<u32, Error> {
+				for n_child in 1..4 {
+					let out = OutputData {
+						root_key_id: root_key_id.clone(),
+						key_id: key_id(n_child),
+						..output(n_child, 10, OutputStatus::Unspent, 1)
+					};
+					wallet_data.add_output(out)?;
+				}
+				let recover_next_child = || {
+					wallet_data.recover_next_child(dir, &keychain, 10, |id| {
+						Ok(on_chain.contains(&id.to_hex()))
+					})
+				};
+				let next_child = recover_next_child()?;
+				// recovering again changes nothing
+				assert_eq!(recover_next_child()?, next_child);
+				Ok(next_child)
+			}).unwrap()
+				.unwrap();
+			let reserved = WalletData::with_wallet(dir, |wallet_data| {
+				wallet_data.reserve_children(dir, root_key_id.clone(), 1)
+			}).unwrap()
+				.unwrap();
+			assert_eq!(reserved, recovered);
+			recovered
+		};
+
+		// past a margin of 20 after the highest child in use
+		assert_eq!(recover("recover_none", 2, vec![]), 24);
+		assert_eq!(recover("recover_gap_5", 2, vec![8]), 29);
+		assert_eq!(recover("recover_gaps_5_10", 2, vec![8, 18]), 39);
+		assert_eq!(recover("recover_gaps_5_11", 2, vec![8, 19]), 29);
+		assert_eq!(recover("recover_gap_11", 2, vec![14]), 24);
+		// a child handed out may have been used
+		assert_eq!(recover("recover_handed_out", 30, vec![39]), 60);
+		// never moved back
+		assert_eq!(recover("recover_counted", 100, vec![8]), 101);
+	}
+
+	#[test]
+	fn fingerprints_kept_apart() {
+		let ours = Identifier::zero();
+		let other = Identifier::from_bytes(&[42]);
+		let theirs = |n_child: u32, value| OutputData {
+			root_key_id: other.clone(),
+			key_id: Identifier::from_bytes(&[100 + n_child as u8]),
+			..output(n_child, value, OutputStatus::Unspent, 1)
+		};
+		let mut wallet_data = wallet(vec![
+			output(1, 10, OutputStatus::Unspent, 1),
+			output(2, 20, OutputStatus::Unspent, 1),
+			output(3, 30, OutputStatus::Unconfirmed, 0),
+			theirs(1, 100),
+			theirs(7, 200),
+		]);
+		assert_eq!(wallet_data.next_child(ours.clone()), 4);
+		assert_eq!(wallet_data.next_child(other.clone()), 8);
+
+		// an invoice holds on to an index of its own root key, invoices that
+		// don't know theirs to one of each
+		wallet_data.add_invoice(other.clone(), Identifier::from_bytes(&[120]), 12, 5, 100);
+		assert_eq!(wallet_data.next_child(ours.clone()), 4);
+		assert_eq!(wallet_data.next_child(other.clone()), 13);
+		wallet_data.add_invoice(ours.clone(), Identifier::from_bytes(&[121]), 20, 5, 100);
+		wallet_data.invoices[1].root_key_id = None;
+		assert_eq!(wallet_data.next_child(ours.clone()), 21);
+		assert_eq!(wallet_data.next_child(other.clone()), 21);
+
+		let balance = wallet_data.balance(Some(ours.clone()), 10, 1);
+		assert_eq!((balance.spendable, balance.awaiting_confirmation), (30, 30));
+		assert_eq!(balance.total, 60);
+		let balance = wallet_data.balance(Some(other.clone()), 10, 1);
+		assert_eq!((balance.spendable, balance.total), (300, 300));
+		let balance = wallet_data.balance(None, 10, 1);
+		assert_eq!((balance.spendable, balance.total), (330, 360));
+
+		// selection never reaches for the coins of another root key
+		let select = |root_key_id: &Identifier, amount, strategy| {
+			wallet_data
+				.select(root_key_id.clone(), 10, 0, amount, strategy)
+				.map(|selection| values(&selection.coins))
+		};
+		assert_eq!(select(&ours, 60, SelectionStrategy::Default), Ok(vec![10, 20, 30]));
+		assert_eq!(select(&ours, 0, SelectionStrategy::All), Ok(vec![10, 20, 30]));
+		assert_eq!(
+			select(&ours, 61, SelectionStrategy::Default),
+			Err(SelectionError::Insufficient {
+				available: 60,
+				needed: 61,
+			})
+		);
+		assert_eq!(select(&other, 0, SelectionStrategy::All), Ok(vec![100, 200]));
+	}
+
+	// Writes the content of a data file fixture of wallet/tests/data to a
+	// fresh directory, returning the path of the data file.
+	fn fixture_file(name: &str, fixture: &str) -> String {
+		let dir = env::temp_dir().join(format!("grin_wallet_fixture_{}", name));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join(DAT_FILE).to_str().unwrap().to_string();
+		File::create(&path).unwrap().write_all(fixture.as_bytes()).unwrap();
+		path
+	}
+
+	fn file_content(path: &str) -> String {
+		let mut content = String::new();
+		File::open(path).unwrap().read_to_string(&mut content).unwrap();
+		content
+	}
+
+	#[test]
+	fn data_file_v0_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v0.json");
+		let path = fixture_file("v0", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		assert_eq!(wdat.outputs.len(), 3);
+		let out = &wdat.outputs["96d1c5fcbe3b4a2b3e41"];
+		assert_eq!(out.root_key_id.to_hex(), "7bf78cf4db2bdd3e8dcb");
+		assert_eq!((out.n_child, out.value), (1, 50_000_000_000));
+		assert_eq!((out.height, out.lock_height), (12, 1012));
+		assert_eq!((out.is_coinbase, out.locked_at, out.account.clone()), (false, 0, None));
+		assert_eq!(wdat.outputs["2d1a1a0871a4751a5b96"].status, OutputStatus::Unconfirmed);
+		assert_eq!(wdat.outputs["e0a0c4d3a4ad3bd6a192"].status, OutputStatus::Spent);
+		assert!(wdat.tx_log.is_empty() && wdat.rx_log.is_empty() && wdat.invoices.is_empty());
+		assert_eq!((wdat.restored_height, wdat.archived.clone()), (0, ArchivedIds::default()));
+
+		// only migrated in memory, the file is left alone until written
+		let copy_path = format!("{}.v0", path);
+		assert!(!Path::new(&copy_path).exists());
+		assert_eq!(file_content(&path), fixture);
+
+		// written back in the current version, the file kept as it was, and
+		// writing again doesn't replace the copy
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&copy_path), fixture);
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&copy_path), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		assert_eq!(written["version"], serde_json::Value::from(DATA_FILE_VERSION));
+		assert!(written.get("migrated_from").is_none());
+		let out = &written["outputs"]["96d1c5fcbe3b4a2b3e41"];
+		assert_eq!(out["is_coinbase"], serde_json::Value::Bool(false));
+
+		// nothing left to migrate
+		fs::remove_file(&copy_path).unwrap();
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.outputs.len(), 3);
+		wdat.write(&path, None).unwrap();
+		assert!(!Path::new(&copy_path).exists());
+	}
+
+	#[test]
+	fn data_file_v1_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v1.json");
+		let path = fixture_file("v1", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		let out = &wdat.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!((out.status.clone(), out.locked_at), (OutputStatus::Locked, 30));
+		assert_eq!(out.account, Some("savings".to_string()));
+		assert!(wdat.outputs["96d1c5fcbe3b4a2b3e41"].is_coinbase);
+		assert!(wdat.outputs.values().all(|out| out.block_hash.is_none()));
+		assert_eq!(wdat.restored_height, 25);
+		assert_eq!(wdat.archived.n_child["7bf78cf4db2bdd3e8dcb"], 3);
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v1", path)), fixture);
+		assert!(!Path::new(&format!("{}.v0", path)).exists());
+	}
+
+	#[test]
+	fn read_while_locked() {
+		let fixture = include_str!("../tests/data/wallet_v1.json");
+		let path = fixture_file("read_while_locked", fixture);
+		let dir = Path::new(&path).parent().unwrap().to_str().unwrap().to_string();
+		let modified = || fs::metadata(&path).unwrap().modified().unwrap();
+		let before = modified();
+
+		// a writer holds the lock, a read neither waits for it nor writes
+		let lock_file_path = WalletData::acquire_data_lock(&dir).unwrap();
+		let (tx, rx) = mpsc::channel();
+		let reader_dir = dir.clone();
+		thread::spawn(move || {
+			let read = WalletData::read_wallet(&reader_dir, |wallet_data| {
+				(wallet_data.version, wallet_data.outputs.len())
+			});
+			tx.send(read.unwrap()).unwrap();
+		});
+		let read = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert_eq!(read, (DATA_FILE_VERSION, 2));
+		assert_eq!(modified(), before);
+		assert_eq!(file_content(&path), fixture);
+		// no migration copy, nothing but the data file and the lock
+		assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+		WalletData::release_data_lock(&lock_file_path).unwrap();
+	}
+
+	#[test]
+	fn data_file_v2_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v2.json");
+		let path = fixture_file("v2", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		let out = &wdat.outputs["96d1c5fcbe3b4a2b3e41"];
+		let hash = "0f3a7c9e14d2b58e6a0c3f71d9e2b4a6c8f05e3d17a9b2c4e6f8013579bdf246";
+		assert_eq!(out.block_hash, Some(hash.to_string()));
+		assert_eq!(wdat.outputs["2d1a1a0871a4751a5b96"].block_hash, None);
+		assert!(wdat.outputs.values().all(|out| {
+			out.created_at.is_none() && out.confirmed_at.is_none() && out.spent_at.is_none()
+		}));
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v2", path)), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		let out = &written["outputs"]["2d1a1a0871a4751a5b96"];
+		assert_eq!(out["created_at"], serde_json::Value::Null);
+	}
+
+	#[test]
+	fn data_file_v3_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v3.json");
+		let path = fixture_file("v3", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		let out = &wdat.outputs["96d1c5fcbe3b4a2b3e41"];
+		assert_eq!((out.created_at, out.spent_at), (None, None));
+		assert_eq!(out.confirmed_at, Some(Timestamp(1_509_890_607)));
+		let out = &wdat.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!(out.created_at, Some(Timestamp(1_509_961_262)));
+		assert!(wdat.outputs.values().all(|out| out.tx_id.is_none()));
+		assert!(wdat.transactions.is_empty());
+		assert_eq!(wdat.archived.tx_id, 0);
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v3", path)), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		let out = &written["outputs"]["2d1a1a0871a4751a5b96"];
+		assert_eq!(out["tx_id"], serde_json::Value::Null);
+		assert_eq!(written["transactions"], serde_json::Value::Array(vec![]));
+	}
+
+	#[test]
+	fn data_file_v4_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v4.json");
+		let path = fixture_file("v4", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		assert_eq!(wdat.outputs["96d1c5fcbe3b4a2b3e41"].tx_id, None);
+		let key_id = wdat.outputs["2d1a1a0871a4751a5b96"].key_id.clone();
+		let tx = wdat.transaction_of(&key_id).unwrap();
+		assert_eq!((tx.id, tx.direction, tx.log_id), (1, TxDirection::Received, Some(1)));
+		assert_eq!((tx.amount, tx.fee), (20_000_000_000, 8_000_000));
+		assert_eq!(tx.status, TxLogStatus::Pending);
+		assert_eq!((tx.input_ids.clone(), tx.output_ids.clone()), (vec![], vec![key_id.clone()]));
+		assert!(wdat.outputs.values().all(|out| out.label.is_none()));
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v4", path)), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		let out = &written["outputs"]["2d1a1a0871a4751a5b96"];
+		assert_eq!(out["label"], serde_json::Value::Null);
+	}
+
+	#[test]
+	fn data_file_v5_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v5.json");
+		let path = fixture_file("v5", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		let out = &wdat.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!(out.label, Some("change of the rent payment".to_string()));
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v5", path)), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		let mut json: serde_json::Value = serde_json::from_str(fixture).unwrap();
+		migrate_v6(&mut json).unwrap();
+		migrate_v7(&mut json).unwrap();
+		json["version"] = serde_json::Value::from(DATA_FILE_VERSION);
+		assert_eq!(written[CHECKSUM_FIELD], serde_json::Value::from(data_checksum(&json).unwrap()));
+	}
+
+	#[test]
+	fn data_file_v6_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v6.json");
+		let path = fixture_file("v6", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		assert_eq!(wdat.outputs.len(), 2);
+		assert!(wdat.outputs.values().all(|out| !out.is_watch_only()));
+		let out = &wdat.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!(out.label, Some("change of the rent payment".to_string()));
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v6", path)), fixture);
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		let out = &written["outputs"]["96d1c5fcbe3b4a2b3e41"];
+		assert_eq!(out["watch_commit"], serde_json::Value::Null);
+	}
+
+	#[test]
+	fn data_file_v7_migrated() {
+		let fixture = include_str!("../tests/data/wallet_v7.json");
+		let path = fixture_file("v7", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, DATA_FILE_VERSION);
+		assert!(wdat.invoices.is_empty());
+		assert!(wdat.outputs["c3e07a4f19d25b8e60a1"].is_watch_only());
+		wdat.write(&path, None).unwrap();
+		assert_eq!(file_content(&format!("{}.v7", path)), fixture);
+		let ours = wdat.outputs["96d1c5fcbe3b4a2b3e41"].root_key_id.clone();
+		let watched = wdat.outputs["c3e07a4f19d25b8e60a1"].root_key_id.clone();
+
+		// invoices issued before their root key was kept get the one of the
+		// wallet's own outputs, the watch-only one is of another seed
+		let mut json: serde_json::Value = serde_json::from_str(fixture).unwrap();
+		json["invoices"] = serde_json::from_str(
+			r#"[{"key_id":"5e1f0b8d3a7c2e964d10","n_child":5,"amount":1,"expiry_height":1440,
+			"created_at":1510071645},{"key_id":"a4c1e7f3902b6d58e1f0","n_child":6,
+			"root_key_id":null,"amount":1,"expiry_height":1440,"created_at":1510071645}]"#,
+		).unwrap();
+		json[CHECKSUM_FIELD] = serde_json::Value::from(data_checksum(&json).unwrap());
+		let path = fixture_file("v7_invoices", &json.to_string());
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert!(wdat.invoices.iter().all(|invoice| invoice.root_key_id == Some(ours.clone())));
+		assert_eq!(wdat.next_child(ours.clone()), 7);
+		assert_eq!(wdat.next_child(watched.clone()), 5);
+
+		// with outputs of two root keys of its own, which one they're of
+		// can't be told
+		let other = Identifier::from_bytes(&[42]);
+		json["archived"]["n_child"][other.to_hex()] = serde_json::Value::from(2);
+		json[CHECKSUM_FIELD] = serde_json::Value::from(data_checksum(&json).unwrap());
+		let path = fixture_file("v7_invoices_roots", &json.to_string());
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert!(wdat.invoices.iter().all(|invoice| invoice.root_key_id.is_none()));
+		assert_eq!(wdat.next_child(ours), 7);
+		assert_eq!(wdat.next_child(other), 7);
+	}
+
+	#[test]
+	fn data_file_v8_read_as_is() {
+		let fixture = include_str!("../tests/data/wallet_v8.json");
+		let path = fixture_file("v8", fixture);
+		let wdat = WalletData::read_or_create(&path, None).unwrap();
+		assert_eq!(wdat.version, 8);
+		assert_eq!(wdat.outputs["96d1c5fcbe3b4a2b3e41"].label, None);
+		let out = &wdat.outputs["2d1a1a0871a4751a5b96"];
+		assert_eq!(out.label, Some("change of the rent payment".to_string()));
+		assert_eq!(out.tx_id, Some(1));
+		assert!(!out.is_watch_only());
+		let watched = &wdat.outputs["c3e07a4f19d25b8e60a1"];
+		assert!(watched.is_watch_only());
+		assert_eq!(watched.spendability(100, 1), Spendability::WatchOnly);
+		let invoice = &wdat.invoices[0];
+		assert_eq!(invoice.root_key_id, Some(out.root_key_id.clone()));
+		assert_eq!(wdat.next_child(out.root_key_id.clone()), 6);
+		assert_eq!(wdat.next_child(watched.root_key_id.clone()), 5);
+		assert!(!Path::new(&format!("{}.v8", path)).exists());
+
+		// and written back the same, checksum included
+		wdat.write(&path, None).unwrap();
+		let written: serde_json::Value = serde_json::from_str(&file_content(&path)).unwrap();
+		assert_eq!(written, serde_json::from_str::<serde_json::Value>(fixture).unwrap());
+	}
+
+	#[test]
+	fn newer_data_file_refused() {
+		let fixture = include_str!("../tests/data/wallet_v8.json");
+		let mut json: serde_json::Value = serde_json::from_str(fixture).unwrap();
+		json["version"] = serde_json::Value::from(DATA_FILE_VERSION + 1);
+		json[CHECKSUM_FIELD] = serde_json::Value::from(data_checksum(&json).unwrap());
+		// with its checksum or without one, newer files may not hold any
+		let mut unchecked = json.clone();
+		unchecked.as_object_mut().unwrap().remove(CHECKSUM_FIELD);
+		for json in vec![json, unchecked] {
+			let path = fixture_file("newer", &json.to_string());
+			// not mistaken for a damaged file the backup would stand in for
+			File::create(format!("{}{}", path, BAK_EXT))
+				.unwrap()
+				.write_all(fixture.as_bytes())
+				.unwrap();
+			match WalletData::read_or_create(&path, None) {
+				Err(Error::DataFileVersion { ours, theirs }) => {
+					assert_eq!((ours, theirs), (DATA_FILE_VERSION, DATA_FILE_VERSION + 1))
+				}
+				res => panic!("expected DataFileVersion, got {:?}", res),
+			}
+			assert_eq!(file_content(&path), json.to_string());
+		}
+	}
+}
@@ -0,0 +1,137 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notifies the webhook of the receiver of the payments it accepts, so order
+//! systems don't have to poll the wallet. Notifications are posted from a
+//! thread of their own and a webhook that can't be reached never holds up or
+//! fails a receive.
+
+use std::thread;
+use std::time::Duration;
+
+use ring::{digest, hmac};
+use serde_json;
+
+use api;
+use types::{ReceiveNotification, WalletConfig};
+use util;
+use util::LOGGER;
+
+/// Header holding the hex encoded HMAC-SHA256 of the body of a notification,
+/// keyed with the receive_webhook_secret of the config
+pub const WEBHOOK_SIGNATURE_HEADER: &'static str = "X-Grin-Signature";
+
+/// How many times posting a notification is attempted before giving up
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled on each attempt
+const WEBHOOK_RETRY_DELAY_MS: u64 = 500;
+
+/// Posts the notification to the webhook of the config, if there's one. The
+/// handle of the thread doing it is returned for those who want to wait.
+pub fn notify_receive(
+	config: &WalletConfig,
+	notification: ReceiveNotification,
+) -> Option<thread::JoinHandle<()>> {
+	let url = match config.receive_webhook_url {
+		Some(ref url) => url.clone(),
+		None => return None,
+	};
+	let mut headers = vec![];
+	if let Some(ref secret) = config.receive_webhook_secret {
+		// the body posted is this same serialization
+		let body = serde_json::to_string(&notification).unwrap();
+		headers.push((WEBHOOK_SIGNATURE_HEADER, sign(secret, &body)));
+	}
+
+	let handle = thread::spawn(move || {
+		let mut delay = WEBHOOK_RETRY_DELAY_MS;
+		for attempt in 1..(WEBHOOK_ATTEMPTS + 1) {
+			match api::client::post_with_headers(&url, &notification, headers.clone()) {
+				Ok(()) => return,
+				Err(e) => warn!(
+					LOGGER,
+					"Failed to notify {} of payment {} (attempt {} of {}): {}",
+					url,
+					notification.key_id,
+					attempt,
+					WEBHOOK_ATTEMPTS,
+					e
+				),
+			}
+			if attempt < WEBHOOK_ATTEMPTS {
+				thread::sleep(Duration::from_millis(delay));
+				delay *= 2;
+			}
+		}
+		error!(LOGGER, "Gave up notifying {} of payment {}", url, notification.key_id);
+	});
+	Some(handle)
+}
+
+/// Hex encoded HMAC-SHA256 of the body keyed with the secret.
+fn sign(secret: &str, body: &str) -> String {
+	let key = hmac::SigningKey::new(&digest::SHA256, secret.as_bytes());
+	util::to_hex(hmac::sign(&key, body.as_bytes()).as_ref().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+	use keychain::Identifier;
+	use client;
+	use super::*;
+
+	#[test]
+	fn signature() {
+		// test case 2 of RFC 4231
+		assert_eq!(
+			sign("Jefe", "what do ya want for nothing?"),
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[test]
+	fn payment_notified() {
+		// the first attempt fails, the retry goes through
+		let mut replies = vec!["500 Internal Server Error", "200 OK"].into_iter();
+		let (addr, requests) = client::test::recording_stub_server(2, move |_| {
+			replies.next().map(|status| (status, String::new()))
+		});
+		let mut config = WalletConfig::default();
+		config.receive_webhook_url = Some(format!("{}/payments", addr));
+		config.receive_webhook_secret = Some("s3cret".to_string());
+
+		let message = Some("order 7".to_string());
+		let notification = ReceiveNotification::new(990, Identifier::from_bytes(&[1]), message);
+		notify_receive(&config, notification.clone())
+			.unwrap()
+			.join()
+			.unwrap();
+
+		let requests = requests.lock().unwrap();
+		assert_eq!(requests.len(), 2);
+		let (ref head, ref body) = requests[1];
+		assert_eq!(serde_json::from_str::<ReceiveNotification>(body).unwrap(), notification);
+		let signature = format!("{}: {}", WEBHOOK_SIGNATURE_HEADER, sign("s3cret", body));
+		assert!(
+			head.lines().any(|l| l.to_lowercase() == signature.to_lowercase()),
+			"{}",
+			head
+		);
+
+		// without a webhook nothing happens
+		config.receive_webhook_url = None;
+		assert!(notify_receive(&config, notification).is_none());
+	}
+}
@@ -0,0 +1,118 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_keychain as keychain;
+extern crate grin_wallet as wallet;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+
+use core::core::build;
+use keychain::Keychain;
+use wallet::{build_send_tx, Error, FeePaidBy, SelectionStrategy, SendAmount, TxBuilder,
+             WalletConfig};
+
+// Creates a wallet in a fresh temporary directory holding confirmed outputs
+// of the provided values, its data file written by hand.
+fn test_wallet(values: Vec<u64>) -> (WalletConfig, Keychain) {
+	let keychain = Keychain::from_random_seed().unwrap();
+	let dir = env::temp_dir().join(format!("grin_wallet_builder_{}", keychain.root_key_id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+
+	let outputs = values
+		.iter()
+		.enumerate()
+		.map(|(i, value)| {
+			let n_child = i as u32 + 1;
+			let key_id = keychain.derive_key_id(n_child).unwrap().to_hex();
+			format!(
+				r#""{}":{{"root_key_id":"{}","key_id":"{}","n_child":{},"value":{},
+				"status":"Unspent","height":1,"lock_height":0,"is_coinbase":false}}"#,
+				key_id,
+				keychain.root_key_id().to_hex(),
+				key_id,
+				n_child,
+				value
+			)
+		})
+		.collect::<Vec<_>>();
+	File::create(dir.join("wallet.dat"))
+		.unwrap()
+		.write_all(format!(r#"{{"outputs":{{{}}}}}"#, outputs.join(",")).as_bytes())
+		.unwrap();
+
+	let mut config = WalletConfig::default();
+	config.data_file_dir = dir.to_str().unwrap().to_string();
+	(config, keychain)
+}
+
+#[test]
+fn send_through_tx_builder() {
+	let (config, keychain) = test_wallet(vec![100_000, 200_000, 300_000]);
+
+	let builder = TxBuilder::new(&config, &keychain, SendAmount::Exact(250_000), 10)
+		.selection_strategy(SelectionStrategy::SmallestFirst)
+		.change_outputs(2)
+		.fee(Some(1_000));
+	let estimate = builder.estimate().unwrap();
+	assert_eq!(estimate.inputs.len(), 2);
+	assert_eq!(estimate.total_input, 300_000);
+	assert_eq!(estimate.fee, 1_000);
+	assert_eq!((estimate.change, estimate.change_outputs), (50_000, 2));
+
+	// estimating changed nothing, building gets the same
+	let (tx, blind_sum, summary) = builder.build().unwrap();
+	assert_eq!(summary.estimate, estimate);
+	assert_eq!(summary.change_key_ids.len(), 2);
+	assert_eq!((tx.inputs.len(), tx.outputs.len(), tx.fee), (2, 2, 1_000));
+
+	// the recipient completes it, the fee coming out of the amount
+	let recipient = Keychain::from_random_seed().unwrap();
+	let (final_tx, _) = build::transaction(
+		vec![
+			build::initial_tx(tx),
+			build::with_excess(blind_sum),
+			build::output(
+				summary.estimate.amount - summary.estimate.fee,
+				recipient.derive_key_id(1).unwrap(),
+			),
+		],
+		&recipient,
+	).unwrap();
+	final_tx.validate(&recipient.secp()).unwrap();
+
+	// the coins it spends are locked, the change isn't spendable yet
+	let estimate = TxBuilder::new(&config, &keychain, SendAmount::Max, 10).estimate().unwrap();
+	assert_eq!(estimate.total_input, 300_000);
+	let spent = &summary.estimate.inputs;
+	assert!(estimate.inputs.iter().all(|input| !spent.contains(input)));
+	match build_send_tx(
+		&config,
+		&keychain,
+		SendAmount::Exact(400_000),
+		10,
+		1,
+		0,
+		SelectionStrategy::Default,
+		None,
+		FeePaidBy::Recipient,
+	) {
+		Err(Error::NotEnoughFunds { .. }) => {}
+		Err(e) => panic!("expected NotEnoughFunds, got {:?}", e),
+		Ok(_) => panic!("expected NotEnoughFunds"),
+	}
+}